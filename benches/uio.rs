@@ -0,0 +1,115 @@
+//! Benchmarks against the mock backend: `cargo bench --features mock`.
+//!
+//! Interrupt latency needs a real device, see `examples/bench.rs`.
+
+#[macro_use]
+extern crate criterion;
+extern crate uio;
+
+use criterion::{Criterion, Throughput};
+use std::hint::black_box;
+use uio::mock::MockUio;
+
+const MAP_SIZE: u64 = 0x10000;
+
+fn mock() -> MockUio {
+    let mut mock = MockUio::new(0).unwrap();
+    mock.add_mapping(0x1000_0000, MAP_SIZE, "regs").unwrap();
+    mock.add_resource(0, MAP_SIZE).unwrap();
+    mock
+}
+
+fn mmap(c: &mut Criterion) {
+    let mock = mock();
+    let dev = mock.open().unwrap();
+    let mut group = c.benchmark_group("mmap");
+    group.bench_function("map_region", |b| {
+        b.iter(|| drop(black_box(dev.map_region(0).unwrap())))
+    });
+    group.bench_function("map_resource_region", |b| {
+        b.iter(|| drop(black_box(dev.map_resource_region(0).unwrap())))
+    });
+    group.finish();
+}
+
+fn sysfs(c: &mut Criterion) {
+    let mock = mock();
+    let mut dev = mock.open().unwrap();
+    let mut group = c.benchmark_group("sysfs");
+    group.bench_function("get_name", |b| {
+        b.iter(|| black_box(dev.get_name().unwrap()))
+    });
+    group.bench_function("get_event_count", |b| {
+        b.iter(|| black_box(dev.get_event_count().unwrap()))
+    });
+    group.bench_function("get_mapping_info", |b| {
+        b.iter(|| black_box(dev.get_mapping_info().unwrap()))
+    });
+    group.bench_function("get_resource_info", |b| {
+        b.iter(|| black_box(dev.get_resource_info().unwrap()))
+    });
+    dev.preopen().unwrap();
+    group.bench_function("get_event_count_preopened", |b| {
+        b.iter(|| black_box(dev.get_event_count().unwrap()))
+    });
+    group.finish();
+}
+
+fn accessors(c: &mut Criterion) {
+    let mock = mock();
+    let dev = mock.open().unwrap();
+    let region = dev.map_region(0).unwrap();
+    let words = region.len() / 4;
+    let mut group = c.benchmark_group("accessors");
+    group.throughput(Throughput::Bytes(region.len() as u64));
+    group.bench_function("read_u32", |b| {
+        b.iter(|| {
+            let mut sum = 0u32;
+            for i in 0..words {
+                sum = sum.wrapping_add(region.read_u32(i * 4));
+            }
+            black_box(sum)
+        })
+    });
+    group.bench_function("write_u32", |b| {
+        b.iter(|| {
+            for i in 0..words {
+                region.write_u32(i * 4, i as u32);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn copies(c: &mut Criterion) {
+    let mock = mock();
+    let dev = mock.open().unwrap();
+    let region = dev.map_region(0).unwrap();
+    let len = region.len() - 8;
+    let mut buf = vec![0u8; len];
+    let mut group = c.benchmark_group("copies");
+    group.throughput(Throughput::Bytes(len as u64));
+    // At an odd offset `write_bytes` falls back to byte accesses
+    group.bench_function("write_bytes_unaligned", |b| {
+        b.iter(|| region.write_bytes(1, black_box(&buf)))
+    });
+    group.bench_function("write_bytes", |b| {
+        b.iter(|| region.write_bytes(0, black_box(&buf)))
+    });
+    group.bench_function("copy_from_slice_unaligned", |b| {
+        b.iter(|| region.copy_from_slice(1, black_box(&buf)))
+    });
+    group.bench_function("copy_from_slice", |b| {
+        b.iter(|| region.copy_from_slice(0, black_box(&buf)))
+    });
+    group.bench_function("read_bytes", |b| {
+        b.iter(|| region.read_bytes(0, black_box(&mut buf)))
+    });
+    group.bench_function("copy_to_slice", |b| {
+        b.iter(|| region.copy_to_slice(0, black_box(&mut buf)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, mmap, sysfs, accessors, copies);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! Measures the cost of common operations on a real UIO device.
+//!
+//! ```text
+//! $ cargo run --release --example bench -- <uio_num> [map] [interrupts]
+//! ```
+//!
+//! Times opening the device, mapping `map` (default 0), sysfs queries and
+//! 32-bit register reads/writes. If `interrupts` is given, also waits for
+//! that many interrupts and reports the time from re-enabling the
+//! interrupt to `irq_wait` returning, and how many events were missed
+//! in between. Writes go to the start of the mapping, only run this on a
+//! device where that is harmless.
+
+extern crate uio;
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+use uio::{LockMode, UioDevice, UioDeviceBuilder};
+
+const ITERATIONS: u32 = 1000;
+
+fn time<F: FnMut()>(name: &str, iterations: u32, mut f: F) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    println!("{:<20} {:>10.2?}/iter", name, start.elapsed() / iterations);
+}
+
+fn open(uio_num: usize) -> UioDevice {
+    UioDeviceBuilder::new(uio_num)
+        .lock(LockMode::Unlocked)
+        .open()
+        .unwrap_or_else(|e| {
+            eprintln!("bench: {}", e);
+            process::exit(1);
+        })
+}
+
+fn main() {
+    let args: Vec<usize> = env::args()
+        .skip(1)
+        .map(|a| {
+            a.parse().unwrap_or_else(|_| {
+                eprintln!("usage: bench <uio_num> [map] [interrupts]");
+                process::exit(1);
+            })
+        })
+        .collect();
+    let uio_num = *args.first().unwrap_or(&0);
+    let map = *args.get(1).unwrap_or(&0);
+
+    time("open", ITERATIONS, || drop(open(uio_num)));
+
+    let dev = open(uio_num);
+    time("map_region", ITERATIONS, || {
+        drop(dev.map_region(map).unwrap())
+    });
+    time("get_name", ITERATIONS, || drop(dev.get_name().unwrap()));
+    time("get_event_count", ITERATIONS, || {
+        dev.get_event_count().unwrap();
+    });
+
+    let region = dev.map_region(map).unwrap();
+    let words = region.len().min(4096) / 4;
+    time("read_u32", ITERATIONS * words as u32, || {
+        region.read_u32(0);
+    });
+    let value = region.read_u32(0);
+    time("write_u32", ITERATIONS * words as u32, || {
+        region.write_u32(0, value)
+    });
+    drop(region);
+
+    if let Some(&interrupts) = args.get(2) {
+        irq_latency(&dev, interrupts);
+    }
+}
+
+fn irq_latency(dev: &UioDevice, interrupts: usize) {
+    let mut latencies = Vec::with_capacity(interrupts);
+    let mut missed = 0;
+    let mut last = dev.get_event_count().unwrap();
+    for _ in 0..interrupts {
+        dev.irq_enable().unwrap();
+        let start = Instant::now();
+        let count = dev.irq_wait().unwrap();
+        latencies.push(start.elapsed());
+        missed += count.wrapping_sub(last).saturating_sub(1);
+        last = count;
+    }
+    latencies.sort();
+    let total: Duration = latencies.iter().sum();
+    println!(
+        "irq_wait             min {:.2?} median {:.2?} max {:.2?} mean {:.2?}, {} missed",
+        latencies[0],
+        latencies[latencies.len() / 2],
+        latencies[latencies.len() - 1],
+        total / latencies.len() as u32,
+        missed
+    );
+}
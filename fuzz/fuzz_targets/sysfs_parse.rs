@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to all sysfs parsers: `cargo fuzz run sysfs_parse`.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate uio;
+
+use uio::parse;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse::hex(data);
+    let _ = parse::decimal(data);
+    let _ = parse::number(data);
+    if let Ok(s) = std::str::from_utf8(data) {
+        let _ = parse::index("map", s);
+    }
+    if let Some(resources) = parse::resources(data) {
+        for r in resources {
+            let _ = r.size();
+        }
+    }
+    let _ = parse::uevent(data);
+    let _ = parse::config_header(data);
+});
@@ -0,0 +1,39 @@
+/* C API of the uio crate (built with the `capi` feature), see src/capi.rs. */
+#ifndef UIO_H
+#define UIO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct uio_device uio_device;
+
+/* Returns NULL (open) or -1 (others) on failure and sets errno. */
+uio_device *uio_open(size_t uio_num, int blocking);
+int uio_close(uio_device *dev);
+
+void *uio_map(uio_device *dev, size_t mapping, size_t *len);
+void *uio_map_resource(uio_device *dev, size_t bar);
+int uio_unmap(uio_device *dev, void *addr);
+
+uint8_t uio_read8(const void *base, size_t offset);
+uint16_t uio_read16(const void *base, size_t offset);
+uint32_t uio_read32(const void *base, size_t offset);
+uint64_t uio_read64(const void *base, size_t offset);
+void uio_write8(void *base, size_t offset, uint8_t value);
+void uio_write16(void *base, size_t offset, uint16_t value);
+void uio_write32(void *base, size_t offset, uint32_t value);
+void uio_write64(void *base, size_t offset, uint64_t value);
+
+int uio_irq_control(uio_device *dev, int enable);
+/* Returns the interrupt event count. */
+int64_t uio_irq_wait(uio_device *dev);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* UIO_H */
@@ -0,0 +1,504 @@
+//! Command-line tool to inspect and poke UIO devices.
+//!
+//! Run `uioctl help` for usage.
+
+extern crate uio;
+
+use std::convert::TryFrom;
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use uio::svd;
+use uio::{LockMode, MappedRegion, UioDevice, UioDeviceBuilder, UioError};
+
+const USAGE: &str = "\
+usage: uioctl [--svd <file>] <command> [arguments]
+
+commands:
+  list [--json]                          list all UIO devices, with --json
+                                         as an array of device descriptions
+  info <dev>                             print device info, mappings and resources
+  dump <dev> <map> <offset> [count]      print `count` 32-bit words of a mapping
+  peek <dev> <map> <offset> [width]      read a register (width 8, 16, 32 or 64)
+  poke <dev> <map> <offset> <value> [width]
+                                         write a register (width 8, 16, 32 or 64)
+  wait <dev> [count]                     enable and wait for `count` interrupts
+  monitor <dev> [--interval <ms>] [<map>:<offset>...]
+                                         print the event count, the interrupt
+                                         rate and 32-bit registers every
+                                         interval (default 1000 ms) until killed
+
+<dev> is the UIO number (0 for /dev/uio0), <map> the mapping index.
+Numbers may be given in decimal or hex (0x prefix).
+
+With --svd, peek and poke also take a register name from the CMSIS-SVD file
+instead of <offset> and [width]: PERIPHERAL.REG or PERIPHERAL.REG.FIELD. The
+offset in the mapping follows from the mapping's physical address. monitor
+takes such names after <map>: as well.";
+
+/// `monitor` repeats the column names after this many lines.
+const HEADER_EVERY: u64 = 20;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("uioctl: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (svd, args) = match args.first().map(String::as_str) {
+        Some("--svd") => {
+            let path = args
+                .get(1)
+                .ok_or_else(|| format!("missing file\n\n{}", USAGE))?;
+            let svd = svd::Device::load(path).map_err(|e| format!("{}: {}", path, e))?;
+            (Some(svd), &args[2..])
+        }
+        _ => (None, args),
+    };
+    let cmd = args.first().map(String::as_str).unwrap_or("help");
+    let args = if args.is_empty() { args } else { &args[1..] };
+    // With an SVD file, a register name may take the place of <offset>
+    if let (Some(svd), "peek" | "poke") = (&svd, cmd) {
+        if let Some(name) = args.get(2).filter(|arg| parse_num(arg).is_none()) {
+            let (peripheral, location) = svd.lookup(name).map_err(|e| e.to_string())?;
+            let (uio_num, map) = (num(args, 0, "dev")?, num(args, 1, "map")?);
+            return match cmd {
+                "peek" => peek_register(uio_num, map, peripheral, location),
+                _ => poke_register(uio_num, map, peripheral, location, num(args, 3, "value")?),
+            };
+        }
+    }
+    match cmd {
+        "list" => match args.first().map(String::as_str) {
+            None => list(),
+            Some("--json") => list_json(),
+            Some(arg) => Err(format!("unknown option '{}'\n\n{}", arg, USAGE)),
+        },
+        "info" => info(num(args, 0, "dev")?),
+        "dump" => dump(
+            num(args, 0, "dev")?,
+            num(args, 1, "map")?,
+            num(args, 2, "offset")?,
+            opt_num(args, 3, "count")?.unwrap_or(16),
+        ),
+        "peek" => peek(
+            num(args, 0, "dev")?,
+            num(args, 1, "map")?,
+            num(args, 2, "offset")?,
+            opt_num(args, 3, "width")?.unwrap_or(32),
+        ),
+        "poke" => poke(
+            num(args, 0, "dev")?,
+            num(args, 1, "map")?,
+            num(args, 2, "offset")?,
+            num(args, 3, "value")?,
+            opt_num(args, 4, "width")?.unwrap_or(32),
+        ),
+        "wait" => wait(num(args, 0, "dev")?, opt_num(args, 1, "count")?),
+        "monitor" => monitor(num(args, 0, "dev")?, svd.as_ref(), &args[1..]),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command '{}'\n\n{}", cmd, USAGE)),
+    }
+}
+
+/// Parses a decimal or 0x-prefixed hexadecimal number.
+fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn opt_num<T: TryFrom<u64>>(args: &[String], idx: usize, what: &str) -> Result<Option<T>, String> {
+    match args.get(idx) {
+        Some(arg) => parse_num(arg)
+            .and_then(|n| T::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| format!("invalid {} '{}'", what, arg)),
+        None => Ok(None),
+    }
+}
+
+fn num<T: TryFrom<u64>>(args: &[String], idx: usize, what: &str) -> Result<T, String> {
+    opt_num(args, idx, what)?.ok_or_else(|| format!("missing {}\n\n{}", what, USAGE))
+}
+
+/// Opens a device without taking the lock, so a running driver isn't disturbed.
+fn open(uio_num: usize, read_only: bool) -> Result<UioDevice, String> {
+    UioDeviceBuilder::new(uio_num)
+        .lock(LockMode::Unlocked)
+        .read_only(read_only)
+        .open()
+        .map_err(|e| e.to_string())
+}
+
+fn list() -> Result<(), String> {
+    for uio_num in uio::list_devices().map_err(|e| e.to_string())? {
+        let name = open(uio_num, true)
+            .and_then(|dev| dev.get_name().map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| format!("<{}>", e));
+        println!("uio{}\t{}", uio_num, name);
+    }
+    Ok(())
+}
+
+/// Prints one JSON object per device, as from `UioDevice::to_json`. Devices
+/// which can't be described are `{"uio_num": N, "error": <ErrorSummary>}`.
+fn list_json() -> Result<(), String> {
+    let mut entries = Vec::new();
+    for uio_num in uio::list_devices().map_err(|e| e.to_string())? {
+        let entry = UioDeviceBuilder::new(uio_num)
+            .lock(LockMode::Unlocked)
+            .read_only(true)
+            .open()
+            .and_then(|mut dev| dev.to_json());
+        entries.push(entry.unwrap_or_else(|e| {
+            format!(
+                "{{\"uio_num\":{},\"error\":{}}}",
+                uio_num,
+                e.summary().to_json()
+            )
+        }));
+    }
+    println!("[{}]", entries.join(","));
+    Ok(())
+}
+
+fn info(uio_num: usize) -> Result<(), String> {
+    let mut dev = open(uio_num, true)?;
+    let s = |e: UioError| e.to_string();
+    println!("device:   {}", dev.get_dev_path().as_ref().display());
+    println!("name:     {}", dev.get_name().map_err(s)?);
+    println!("version:  {}", dev.get_version().map_err(s)?);
+    println!("events:   {}", dev.get_event_count().map_err(s)?);
+    println!("kind:     {:?}", dev.device_kind().map_err(s)?);
+    let siblings = dev.siblings().map_err(s)?;
+    if !siblings.is_empty() {
+        let names: Vec<_> = siblings.iter().map(|n| format!("uio{}", n)).collect();
+        println!("siblings: {}", names.join(" "));
+    }
+
+    let mappings = dev.get_mapping_info().map_err(s)?;
+    if !mappings.is_empty() {
+        println!("mappings:");
+    }
+    for m in mappings {
+        println!(
+            "  map{}\taddr {:#x}\tsize {:#x}\t{}{}",
+            m.index,
+            m.addr,
+            m.len,
+            m.name,
+            m.label.map(|l| format!(" ({})", l)).unwrap_or_default()
+        );
+    }
+    for r in dev.sub_regions().map_err(s)? {
+        println!(
+            "    {}\tmap{} offset {:#x}\tsize {:#x}",
+            r.name, r.mapping, r.offset, r.len
+        );
+    }
+
+    let resources = dev.resources().map_err(s)?;
+    if !resources.is_empty() {
+        println!("resources:");
+    }
+    for r in resources {
+        println!(
+            "  resource{}\tsize {:#x}{}",
+            r.index,
+            r.size,
+            if r.mappable { "" } else { "\t(not mappable)" }
+        );
+    }
+    Ok(())
+}
+
+fn dump(uio_num: usize, map: usize, offset: usize, count: usize) -> Result<(), String> {
+    let dev = open(uio_num, true)?;
+    let region = dev.map_region(map).map_err(|e| e.to_string())?;
+    if offset.checked_add(4).is_none_or(|end| end > region.len()) {
+        return Err(format!(
+            "offset {:#x} is out of bounds (size {:#x})",
+            offset,
+            region.len()
+        ));
+    }
+    for i in 0..count {
+        let Some(off) = i.checked_mul(4).and_then(|d| offset.checked_add(d)) else {
+            break;
+        };
+        if off.checked_add(4).is_none_or(|end| end > region.len()) {
+            break;
+        }
+        if i % 4 == 0 {
+            if i > 0 {
+                println!();
+            }
+            print!("{:08x}:", off);
+        }
+        print!(" {:08x}", region.read_u32(off));
+    }
+    println!();
+    Ok(())
+}
+
+fn check_access(len: usize, offset: usize, width: usize) -> Result<(), String> {
+    let bytes = match width {
+        8 | 16 | 32 | 64 => width / 8,
+        _ => return Err(format!("invalid width {}", width)),
+    };
+    if !offset.is_multiple_of(bytes) || offset.checked_add(bytes).is_none_or(|end| end > len) {
+        return Err(format!(
+            "offset {:#x} is misaligned or out of bounds (size {:#x})",
+            offset, len
+        ));
+    }
+    Ok(())
+}
+
+fn read(region: &MappedRegion, offset: usize, width: usize) -> u64 {
+    match width {
+        8 => region.read_u8(offset) as u64,
+        16 => region.read_u16(offset) as u64,
+        32 => region.read_u32(offset) as u64,
+        _ => region.read_u64(offset),
+    }
+}
+
+fn write(region: &MappedRegion, offset: usize, width: usize, value: u64) {
+    match width {
+        8 => region.write_u8(offset, value as u8),
+        16 => region.write_u16(offset, value as u16),
+        32 => region.write_u32(offset, value as u32),
+        _ => region.write_u64(offset, value),
+    }
+}
+
+fn peek(uio_num: usize, map: usize, offset: usize, width: usize) -> Result<(), String> {
+    let dev = open(uio_num, true)?;
+    let region = dev.map_region(map).map_err(|e| e.to_string())?;
+    check_access(region.len(), offset, width)?;
+    let value = read(&region, offset, width);
+    println!("{:#0w$x}", value, w = width / 4 + 2);
+    Ok(())
+}
+
+fn poke(uio_num: usize, map: usize, offset: usize, value: u64, width: usize) -> Result<(), String> {
+    let dev = open(uio_num, false)?;
+    let region = dev.map_region(map).map_err(|e| e.to_string())?;
+    check_access(region.len(), offset, width)?;
+    if width < 64 && value >> width != 0 {
+        return Err(format!("value {:#x} does not fit in {} bits", value, width));
+    }
+    write(&region, offset, width, value);
+    Ok(())
+}
+
+/// The offset of the register at `location` of `peripheral` in mapping
+/// `map`, from the physical addresses of both.
+fn register_offset(
+    dev: &mut UioDevice,
+    map: usize,
+    peripheral: &svd::Peripheral,
+    location: svd::Location,
+) -> Result<usize, String> {
+    let mapping = dev
+        .get_mapping_info()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|m| m.index == map)
+        .ok_or_else(|| format!("no map {}", map))?;
+    peripheral
+        .base_address
+        .checked_add(location.offset)
+        .and_then(|addr| addr.checked_sub(mapping.addr))
+        .filter(|&offset| offset < mapping.len)
+        .map(|offset| offset as usize)
+        .ok_or_else(|| {
+            format!(
+                "{} at {:#x} is not in map{} ({:#x}, size {:#x})",
+                peripheral.name, peripheral.base_address, map, mapping.addr, mapping.len
+            )
+        })
+}
+
+/// Prints a register, or a field shifted down to bit 0.
+fn peek_register(
+    uio_num: usize,
+    map: usize,
+    peripheral: &svd::Peripheral,
+    location: svd::Location,
+) -> Result<(), String> {
+    let mut dev = open(uio_num, true)?;
+    let offset = register_offset(&mut dev, map, peripheral, location)?;
+    let region = dev.map_region(map).map_err(|e| e.to_string())?;
+    let width = location.size as usize;
+    check_access(region.len(), offset, width)?;
+    let value = read(&region, offset, width);
+    if location.is_field() {
+        println!("{:#x}", location.extract(value));
+    } else {
+        println!("{:#0w$x}", value, w = width / 4 + 2);
+    }
+    Ok(())
+}
+
+/// Writes a register, or a field with a read-modify-write of its register.
+fn poke_register(
+    uio_num: usize,
+    map: usize,
+    peripheral: &svd::Peripheral,
+    location: svd::Location,
+    value: u64,
+) -> Result<(), String> {
+    let mut dev = open(uio_num, false)?;
+    let offset = register_offset(&mut dev, map, peripheral, location)?;
+    let region = dev.map_region(map).map_err(|e| e.to_string())?;
+    let width = location.size as usize;
+    check_access(region.len(), offset, width)?;
+    let old = if location.is_field() {
+        read(&region, offset, width)
+    } else {
+        0
+    };
+    let new = location
+        .insert(old, value)
+        .ok_or_else(|| format!("value {:#x} does not fit", value))?;
+    write(&region, offset, width, new);
+    Ok(())
+}
+
+fn wait(uio_num: usize, count: Option<u64>) -> Result<(), String> {
+    let dev = open(uio_num, false)?;
+    let mut received = 0;
+    while count.is_none_or(|c| received < c) {
+        dev.irq_enable().map_err(|e| e.to_string())?;
+        let events = dev.irq_wait().map_err(|e| e.to_string())?;
+        println!("interrupt (event count {})", events);
+        received += 1;
+    }
+    Ok(())
+}
+
+/// A register shown by `monitor`.
+struct Watch {
+    label: String,
+    map: usize,
+    offset: usize,
+    width: usize,
+    /// Set for fields, which are shown shifted down to bit 0
+    field: Option<svd::Location>,
+}
+
+impl Watch {
+    /// Parses `<map>:<offset>`, or `<map>:<name>` with an SVD file.
+    fn parse(dev: &mut UioDevice, svd: Option<&svd::Device>, spec: &str) -> Result<Watch, String> {
+        let (map, reg) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("invalid register '{}', expected <map>:<offset>", spec))?;
+        let map = parse_num(map)
+            .and_then(|m| usize::try_from(m).ok())
+            .ok_or_else(|| format!("invalid map '{}'", map))?;
+        let (offset, width, field) = match (parse_num(reg), svd) {
+            (Some(offset), _) => (offset as usize, 32, None),
+            (None, Some(svd)) => {
+                let (peripheral, location) = svd.lookup(reg).map_err(|e| e.to_string())?;
+                let offset = register_offset(dev, map, peripheral, location)?;
+                let field = Some(location).filter(|l| l.is_field());
+                (offset, location.size as usize, field)
+            }
+            (None, None) => return Err(format!("invalid offset '{}'", reg)),
+        };
+        Ok(Watch {
+            label: spec.to_string(),
+            map,
+            offset,
+            width,
+            field,
+        })
+    }
+
+    fn column_width(&self) -> usize {
+        self.label.len().max(self.width / 4 + 2)
+    }
+}
+
+/// Prints a line with the event count, the interrupt rate since the last
+/// line and the registers every interval. Only reads, so it can watch a
+/// device while its driver runs.
+fn monitor(uio_num: usize, svd: Option<&svd::Device>, args: &[String]) -> Result<(), String> {
+    let mut interval = Duration::from_secs(1);
+    let mut specs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--interval" {
+            let ms = args
+                .next()
+                .and_then(|ms| parse_num(ms))
+                .filter(|&ms| ms > 0)
+                .ok_or_else(|| format!("invalid interval\n\n{}", USAGE))?;
+            interval = Duration::from_millis(ms);
+        } else {
+            specs.push(arg.as_str());
+        }
+    }
+
+    let mut dev = open(uio_num, true)?;
+    let watches = specs
+        .iter()
+        .map(|spec| Watch::parse(&mut dev, svd, spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut regions: Vec<(usize, MappedRegion)> = Vec::new();
+    for w in &watches {
+        if !regions.iter().any(|&(map, _)| map == w.map) {
+            let region = dev.map_region(w.map).map_err(|e| e.to_string())?;
+            regions.push((w.map, region));
+        }
+        let region = &regions.iter().find(|&&(map, _)| map == w.map).unwrap().1;
+        check_access(region.len(), w.offset, w.width)?;
+    }
+
+    let s = |e: UioError| e.to_string();
+    let start = Instant::now();
+    let (mut last, mut last_count) = (start, dev.get_event_count().map_err(s)?);
+    let mut lines = 0u64;
+    loop {
+        if lines.is_multiple_of(HEADER_EVERY) {
+            print!("{:>10} {:>10} {:>10}", "time", "events", "irq/s");
+            for w in &watches {
+                print!("  {:>w$}", w.label, w = w.column_width());
+            }
+            println!();
+        }
+        thread::sleep(interval);
+        let now = Instant::now();
+        let count = dev.get_event_count().map_err(s)?;
+        let rate = count.wrapping_sub(last_count) as f64 / now.duration_since(last).as_secs_f64();
+        print!(
+            "{:>10.3} {:>10} {:>10.1}",
+            now.duration_since(start).as_secs_f64(),
+            count,
+            rate
+        );
+        for w in &watches {
+            let region = &regions.iter().find(|&&(map, _)| map == w.map).unwrap().1;
+            let value = read(region, w.offset, w.width);
+            let value = match w.field {
+                Some(field) => format!("{:#x}", field.extract(value)),
+                None => format!("{:#0w$x}", value, w = w.width / 4 + 2),
+            };
+            print!("  {:>w$}", value, w = w.column_width());
+        }
+        println!();
+        (last, last_count) = (now, count);
+        lines += 1;
+    }
+}
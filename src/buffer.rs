@@ -0,0 +1,171 @@
+//! Aligned host buffers for staging data.
+//!
+//! Data copied into device memory with `MappedRegion::write_bytes` (or read
+//! back with `read_bytes`) is best staged in a buffer whose start and length
+//! match what the device and the copy expect. A `Vec<u8>` only guarantees
+//! byte alignment, an `AlignedBuf` is aligned and padded as requested:
+//!
+//! ```
+//! use uio::buffer::{AlignedBuf, CACHELINE};
+//!
+//! // A 100 byte frame, cacheline aligned and padded to 64-byte bursts
+//! let mut frame = AlignedBuf::with_padding(100, CACHELINE, 64);
+//! frame[..4].copy_from_slice(&[1, 2, 3, 4]);
+//! assert_eq!(frame.as_ptr() as usize % CACHELINE, 0);
+//! assert_eq!(frame.padded().len(), 128);
+//! // regs.write_bytes(FIFO, frame.padded());
+//! ```
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+
+/// The cacheline size of common CPUs.
+pub const CACHELINE: usize = 64;
+
+/// The page size.
+pub const PAGE: usize = 4096;
+
+/// A zero-initialized byte buffer with a given alignment, padded to a
+/// multiple of a given size.
+///
+/// It dereferences to its `len` bytes; `padded` includes the padding, which
+/// stays zero unless written through `padded_mut`.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Allocates `len` bytes aligned to `align`.
+    ///
+    /// # Panics
+    /// If `align` is not a power of two or the size overflows.
+    pub fn new(len: usize, align: usize) -> AlignedBuf {
+        AlignedBuf::with_padding(len, align, 1)
+    }
+
+    /// Allocates `len` bytes aligned to `align`, followed by padding up to a
+    /// multiple of `pad_to` (e.g. the device's burst size).
+    ///
+    /// # Panics
+    /// If `align` is not a power of two, `pad_to` is 0 or the size
+    /// overflows.
+    pub fn with_padding(len: usize, align: usize, pad_to: usize) -> AlignedBuf {
+        assert!(pad_to > 0, "padding to a multiple of 0");
+        let size = len
+            .checked_next_multiple_of(pad_to)
+            .expect("buffer size overflows");
+        let layout = Layout::from_size_align(size, align).expect("invalid buffer alignment");
+        let ptr = if size == 0 {
+            // A dangling but aligned pointer, like an empty `Vec`
+            NonNull::new(align as *mut u8).expect("alignment is never 0")
+        } else {
+            // Safety: the size is not zero
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+        AlignedBuf { ptr, len, layout }
+    }
+
+    /// Allocates a buffer with the contents `data`, aligned and padded like
+    /// `with_padding`.
+    pub fn from_slice(data: &[u8], align: usize, pad_to: usize) -> AlignedBuf {
+        let mut buf = AlignedBuf::with_padding(data.len(), align, pad_to);
+        buf.copy_from_slice(data);
+        buf
+    }
+
+    /// The alignment of the start of the buffer.
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// The contents including the padding.
+    pub fn padded(&self) -> &[u8] {
+        // Safety: the allocation has `layout.size()` initialized bytes
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    /// The contents including the padding.
+    pub fn padded_mut(&mut self) -> &mut [u8] {
+        // Safety: the allocation has `layout.size()` initialized bytes
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.padded()[..self.len]
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let len = self.len;
+        &mut self.padded_mut()[..len]
+    }
+}
+
+impl Clone for AlignedBuf {
+    fn clone(&self) -> AlignedBuf {
+        let mut buf = AlignedBuf::with_padding(self.layout.size(), self.align(), 1);
+        buf.len = self.len;
+        buf.padded_mut().copy_from_slice(self.padded());
+        buf
+    }
+}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len)
+            .field("padded_len", &self.layout.size())
+            .field("align", &self.align())
+            .finish()
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.layout.size() > 0 {
+            // Safety: allocated with this layout in `with_padding`
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_buffers() {
+        let mut buf = AlignedBuf::with_padding(10, PAGE, 16);
+        assert_eq!(buf.as_ptr() as usize % PAGE, 0);
+        assert_eq!((buf.len(), buf.padded().len(), buf.align()), (10, 16, PAGE));
+        assert!(buf.padded().iter().all(|&b| b == 0));
+        buf.copy_from_slice(b"0123456789");
+
+        let copy = buf.clone();
+        assert_eq!(copy.as_ptr() as usize % PAGE, 0);
+        assert_eq!(copy.padded(), buf.padded());
+        assert_eq!(&copy[..], b"0123456789");
+
+        let words = AlignedBuf::from_slice(&[1, 2, 3, 4, 5], 4, 4);
+        assert_eq!(words.padded(), [1, 2, 3, 4, 5, 0, 0, 0]);
+
+        let empty = AlignedBuf::new(0, CACHELINE);
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_ptr() as usize % CACHELINE, 0);
+        assert_eq!(empty.clone().padded().len(), 0);
+    }
+}
@@ -0,0 +1,201 @@
+//! C API, enabled with the `capi` feature. See `include/uio.h`.
+//!
+//! Build a shared library with:
+//!
+//! ```text
+//! $ cargo rustc --release --features capi --crate-type cdylib
+//! ```
+//!
+//! Functions returning `int` return 0 on success and -1 on failure,
+//! functions returning pointers return NULL on failure. In both cases `errno`
+//! is set to the underlying OS error (or `EIO` if there is none).
+
+use libc::{self, c_int, c_void, size_t};
+use std::ptr;
+use {LockMode, UioDevice, UioDeviceBuilder, UioError};
+
+/// An open device, `uio_device` in C.
+pub struct Device(UioDevice);
+
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+fn fail<T>(e: &UioError, ret: T) -> T {
+    set_errno(e.raw_os_error().unwrap_or(libc::EIO));
+    ret
+}
+
+/// Opens `/dev/uio<uio_num>`. If `blocking` is non-zero, waits for other
+/// processes to release the device instead of failing with `EWOULDBLOCK`.
+#[no_mangle]
+pub extern "C" fn uio_open(uio_num: size_t, blocking: c_int) -> *mut Device {
+    let mode = if blocking != 0 {
+        LockMode::Blocking
+    } else {
+        LockMode::NonBlocking
+    };
+    match UioDeviceBuilder::new(uio_num).lock(mode).open() {
+        Ok(dev) => Box::into_raw(Box::new(Device(dev))),
+        Err(e) => fail(&e, ptr::null_mut()),
+    }
+}
+
+/// Closes the device, unmapping all its mappings.
+///
+/// # Safety
+/// `dev` must be NULL or returned by `uio_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn uio_close(dev: *mut Device) -> c_int {
+    if dev.is_null() {
+        return 0;
+    }
+    match Box::from_raw(dev).0.close() {
+        Ok(()) => 0,
+        Err(e) => fail(&e, -1),
+    }
+}
+
+/// Maps `maps/map<mapping>` and stores its length in `len` (if not NULL).
+///
+/// # Safety
+/// `dev` must be a valid device and `len` NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn uio_map(
+    dev: *mut Device,
+    mapping: size_t,
+    len: *mut size_t,
+) -> *mut c_void {
+    let dev = &(*dev).0;
+    let size = match dev.map_size(mapping) {
+        Ok(size) => size,
+        Err(e) => return fail(&e, ptr::null_mut()),
+    };
+    match dev.map_mapping(mapping) {
+        Ok(addr) => {
+            if !len.is_null() {
+                // The mapping succeeded, so its size fits
+                *len = size as size_t;
+            }
+            addr
+        }
+        Err(e) => fail(&e, ptr::null_mut()),
+    }
+}
+
+/// Maps the PCI BAR `device/resource<bar>`.
+///
+/// # Safety
+/// `dev` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn uio_map_resource(dev: *mut Device, bar: size_t) -> *mut c_void {
+    match (*dev).0.map_resource(bar) {
+        Ok(addr) => addr,
+        Err(e) => fail(&e, ptr::null_mut()),
+    }
+}
+
+/// Unmaps a mapping returned by `uio_map` or `uio_map_resource`.
+///
+/// # Safety
+/// `dev` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn uio_unmap(dev: *mut Device, addr: *mut c_void) -> c_int {
+    match (*dev).0.unmap(addr) {
+        Ok(()) => 0,
+        Err(e) => fail(&e, -1),
+    }
+}
+
+macro_rules! register_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Reads a `", stringify!($ty), "` at byte `offset` of a mapping.")]
+            ///
+            /// # Safety
+            /// `base + offset` must be a valid, aligned address in a mapping.
+            #[no_mangle]
+            pub unsafe extern "C" fn $read(base: *const c_void, offset: size_t) -> $ty {
+                ptr::read_volatile((base as *const u8).add(offset) as *const $ty)
+            }
+
+            #[doc = concat!("Writes a `", stringify!($ty), "` at byte `offset` of a mapping.")]
+            ///
+            /// # Safety
+            /// `base + offset` must be a valid, aligned address in a mapping.
+            #[no_mangle]
+            pub unsafe extern "C" fn $write(base: *mut c_void, offset: size_t, value: $ty) {
+                ptr::write_volatile((base as *mut u8).add(offset) as *mut $ty, value)
+            }
+        )*
+    };
+}
+
+register_accessors!(
+    u8, uio_read8, uio_write8;
+    u16, uio_read16, uio_write16;
+    u32, uio_read32, uio_write32;
+    u64, uio_read64, uio_write64
+);
+
+/// Enables (`enable` non-zero) or disables the interrupt.
+///
+/// # Safety
+/// `dev` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn uio_irq_control(dev: *mut Device, enable: c_int) -> c_int {
+    let dev = &(*dev).0;
+    let res = if enable != 0 {
+        dev.irq_enable()
+    } else {
+        dev.irq_disable()
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            set_errno(e.raw_os_error().unwrap_or(libc::EIO));
+            -1
+        }
+    }
+}
+
+/// Waits for an interrupt and returns the event count, or -1 on failure.
+///
+/// # Safety
+/// `dev` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn uio_irq_wait(dev: *mut Device) -> i64 {
+    match (*dev).0.irq_wait() {
+        Ok(count) => count as i64,
+        Err(e) => {
+            set_errno(e.raw_os_error().unwrap_or(libc::EIO));
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    #[test]
+    fn map_and_access() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = Box::into_raw(Box::new(Device(mock.open().unwrap())));
+        unsafe {
+            let mut len = 0;
+            let base = uio_map(dev, 0, &mut len);
+            assert!(!base.is_null());
+            assert_eq!(len, 0x1000);
+            uio_write32(base, 0x10, 0xabcd);
+            assert_eq!(uio_read16(base, 0x10), 0xabcd);
+            assert_eq!(uio_irq_control(dev, 1), 0);
+
+            assert!(uio_map(dev, 1, ptr::null_mut()).is_null());
+            assert_eq!(*libc::__errno_location(), libc::ENOENT);
+            assert_eq!(uio_close(dev), 0);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Codecs for reading structs from and writing them to a `MappedRegion`.
+//!
+//! A `Codec<T>` converts a `T` from and to the raw bytes at some offset of a
+//! region, so a register block can be read, modified and written back in one
+//! call each:
+//!
+//! ```ignore
+//! use uio::codec::Deku;
+//!
+//! #[derive(DekuRead, DekuWrite)]
+//! struct Status { ready: u8, #[deku(pad_bytes_after = "1")] level: u16 }
+//!
+//! let mut status: Status = region.read_struct::<_, Deku<4>>(0x40)?;
+//! status.level = 3;
+//! region.write_struct::<_, Deku<4>>(0x40, &status)?;
+//! ```
+//!
+//! `ZeroCopy` (feature `zerocopy`) handles plain `#[repr(C)]` structs and
+//! `Deku<N>` (feature `deku`) structs encoded in `N` bytes. Other formats can
+//! be supported by implementing `Codec`.
+
+/// Decodes and encodes `T` as `len()` bytes of a region.
+pub trait Codec<T> {
+    type Error;
+
+    /// Number of bytes `T` occupies in the region.
+    fn len() -> usize;
+
+    /// Decodes a `T` from `bytes`, which are exactly `len()` bytes long.
+    fn decode(bytes: &[u8]) -> Result<T, Self::Error>;
+
+    /// Encodes `value` into `bytes`, which are exactly `len()` bytes long.
+    fn encode(value: &T, bytes: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Copies `T` bit by bit, for types implementing the `zerocopy` traits.
+#[cfg(feature = "zerocopy")]
+#[derive(Debug)]
+pub enum ZeroCopy {}
+
+#[cfg(feature = "zerocopy")]
+impl<T> Codec<T> for ZeroCopy
+where
+    T: zerocopy::FromBytes + zerocopy::IntoBytes + zerocopy::Immutable,
+{
+    type Error = ::std::convert::Infallible;
+
+    fn len() -> usize {
+        ::std::mem::size_of::<T>()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, Self::Error> {
+        Ok(T::read_from_bytes(bytes).expect("slice has the size of T"))
+    }
+
+    fn encode(value: &T, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        bytes.copy_from_slice(value.as_bytes());
+        Ok(())
+    }
+}
+
+/// Uses `deku` to decode and encode `T` in `N` bytes.
+#[cfg(feature = "deku")]
+#[derive(Debug)]
+pub enum Deku<const N: usize> {}
+
+#[cfg(feature = "deku")]
+impl<T, const N: usize> Codec<T> for Deku<N>
+where
+    T: for<'a> deku::DekuContainerRead<'a> + deku::DekuContainerWrite,
+{
+    type Error = deku::DekuError;
+
+    fn len() -> usize {
+        N
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, Self::Error> {
+        T::from_bytes((bytes, 0)).map(|(_, value)| value)
+    }
+
+    fn encode(value: &T, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let encoded = value.to_bytes()?;
+        if encoded.len() != N {
+            return Err(deku::DekuError::InvalidParam(
+                format!("encoded {} bytes instead of {}", encoded.len(), N).into(),
+            ));
+        }
+        bytes.copy_from_slice(&encoded);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! Wide volatile copies between memory and device memory.
+//!
+//! Device memory is copied in the widest aligned accesses available: bytes
+//! and 64-bit words up to a block boundary, then 256-bit (AVX) or 128-bit
+//! (SSE2) blocks on x86-64, then words and bytes for the rest. Every device
+//! access is a single volatile load or store of its width, so a write
+//! combining mapping sees full bursts, and an uncached one a fraction of the
+//! transactions of a byte-wise copy.
+
+use std::mem;
+use std::ptr;
+
+/// Copies `src` to the device memory at `dst`.
+///
+/// # Safety
+/// `dst` must be valid for volatile writes of `src.len()` bytes.
+pub unsafe fn to_device(dst: *mut u8, src: &[u8]) {
+    let len = src.len();
+    let mut i = head(dst as usize, len, 8);
+    bytes_to_device(dst, &src[..i]);
+    let words = i + head(dst as usize + i, len - i, wide::BLOCK) / 8 * 8;
+    while i < words {
+        ptr::write_volatile(dst.add(i) as *mut u64, read_word(&src[i..]));
+        i += 8;
+    }
+    i += wide::to_device(dst.add(i), &src[i..]);
+    while len - i >= 8 {
+        ptr::write_volatile(dst.add(i) as *mut u64, read_word(&src[i..]));
+        i += 8;
+    }
+    bytes_to_device(dst.add(i), &src[i..]);
+}
+
+/// Copies the device memory at `src` into `dst`.
+///
+/// # Safety
+/// `src` must be valid for volatile reads of `dst.len()` bytes.
+pub unsafe fn from_device(dst: &mut [u8], src: *const u8) {
+    let len = dst.len();
+    let mut i = head(src as usize, len, 8);
+    bytes_from_device(&mut dst[..i], src);
+    let words = i + head(src as usize + i, len - i, wide::BLOCK) / 8 * 8;
+    while i < words {
+        let word = ptr::read_volatile(src.add(i) as *const u64);
+        dst[i..i + 8].copy_from_slice(&word.to_ne_bytes());
+        i += 8;
+    }
+    i += wide::from_device(&mut dst[i..], src.add(i));
+    while len - i >= 8 {
+        let word = ptr::read_volatile(src.add(i) as *const u64);
+        dst[i..i + 8].copy_from_slice(&word.to_ne_bytes());
+        i += 8;
+    }
+    bytes_from_device(&mut dst[i..], src.add(i));
+}
+
+unsafe fn bytes_to_device(dst: *mut u8, src: &[u8]) {
+    for (j, &b) in src.iter().enumerate() {
+        ptr::write_volatile(dst.add(j), b);
+    }
+}
+
+unsafe fn bytes_from_device(dst: &mut [u8], src: *const u8) {
+    for (j, b) in dst.iter_mut().enumerate() {
+        *b = ptr::read_volatile(src.add(j));
+    }
+}
+
+/// The number of bytes from `addr` to the next multiple of `align`, at
+/// most `len`.
+fn head(addr: usize, len: usize, align: usize) -> usize {
+    (addr.wrapping_neg() % align).min(len)
+}
+
+/// The CRC-32 (IEEE 802.3, as used by zlib and Ethernet) of `bytes`,
+/// continuing from `crc`, which is 0 for the first chunk.
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn read_word(bytes: &[u8]) -> u64 {
+    let mut word = [0; mem::size_of::<u64>()];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_ne_bytes(word)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod wide {
+    use std::arch::x86_64::*;
+    use std::ptr;
+
+    /// The alignment of the device side of the block copies.
+    pub const BLOCK: usize = 32;
+
+    /// Copies whole blocks of `src` to the `BLOCK` aligned `dst`, returns
+    /// the number of bytes copied.
+    pub unsafe fn to_device(dst: *mut u8, src: &[u8]) -> usize {
+        if is_x86_feature_detected!("avx") {
+            to_device_avx(dst, src)
+        } else {
+            to_device_sse2(dst, src)
+        }
+    }
+
+    /// Copies whole blocks from the `BLOCK` aligned `src` into `dst`,
+    /// returns the number of bytes copied.
+    pub unsafe fn from_device(dst: &mut [u8], src: *const u8) -> usize {
+        if is_x86_feature_detected!("avx") {
+            from_device_avx(dst, src)
+        } else {
+            from_device_sse2(dst, src)
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    unsafe fn to_device_avx(dst: *mut u8, src: &[u8]) -> usize {
+        let blocks = src.len() / 32;
+        for k in 0..blocks {
+            let v = _mm256_loadu_si256(src.as_ptr().add(k * 32) as *const __m256i);
+            ptr::write_volatile(dst.add(k * 32) as *mut __m256i, v);
+        }
+        blocks * 32
+    }
+
+    #[target_feature(enable = "avx")]
+    unsafe fn from_device_avx(dst: &mut [u8], src: *const u8) -> usize {
+        let blocks = dst.len() / 32;
+        for k in 0..blocks {
+            let v = ptr::read_volatile(src.add(k * 32) as *const __m256i);
+            _mm256_storeu_si256(dst.as_mut_ptr().add(k * 32) as *mut __m256i, v);
+        }
+        blocks * 32
+    }
+
+    unsafe fn to_device_sse2(dst: *mut u8, src: &[u8]) -> usize {
+        let blocks = src.len() / 16;
+        for k in 0..blocks {
+            let v = _mm_loadu_si128(src.as_ptr().add(k * 16) as *const __m128i);
+            ptr::write_volatile(dst.add(k * 16) as *mut __m128i, v);
+        }
+        blocks * 16
+    }
+
+    unsafe fn from_device_sse2(dst: &mut [u8], src: *const u8) -> usize {
+        let blocks = dst.len() / 16;
+        for k in 0..blocks {
+            let v = ptr::read_volatile(src.add(k * 16) as *const __m128i);
+            _mm_storeu_si128(dst.as_mut_ptr().add(k * 16) as *mut __m128i, v);
+        }
+        blocks * 16
+    }
+}
+
+/// Other architectures copy 64-bit words.
+#[cfg(not(target_arch = "x86_64"))]
+mod wide {
+    pub const BLOCK: usize = 8;
+
+    pub unsafe fn to_device(_dst: *mut u8, _src: &[u8]) -> usize {
+        0
+    }
+
+    pub unsafe fn from_device(_dst: &mut [u8], _src: *const u8) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_copies() {
+        let src: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut mem = vec![0u64; 40];
+        let base = mem.as_mut_ptr() as *mut u8;
+        // All head, block and tail combinations
+        for offset in 0..40 {
+            for len in [0, 1, 7, 8, 15, 31, 32, 33, 64, 100, 150] {
+                let src = &src[..len];
+                let mut back = vec![0; len];
+                unsafe {
+                    ptr::write_bytes(base, 0xff, 320);
+                    to_device(base.add(offset), src);
+                    from_device(&mut back, base.add(offset));
+                    assert_eq!(*base.add(offset + len), 0xff);
+                }
+                assert_eq!(back, src, "offset {} len {}", offset, len);
+            }
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,214 @@
+//! Names from the device tree of platform devices.
+//!
+//! Platform devices described in a device tree have their node linked at
+//! `/sys/class/uio/uioX/device/of_node`. The node names the memory regions
+//! in `reg-names`, in the order of the UIO mappings, and may describe blocks
+//! within them as child nodes:
+//!
+//! ```text
+//! dma@a0000000 {
+//!     reg = <0xa0000000 0x10000>, <0xa0010000 0x1000>;
+//!     reg-names = "ctrl", "desc";
+//!     #address-cells = <1>;
+//!     #size-cells = <1>;
+//!     ranges = <0x0 0xa0000000 0x10000>;
+//!     mm2s@0 { reg = <0x0 0x30>; };
+//!     s2mm@30 { reg = <0x30 0x30>; };
+//! };
+//! ```
+//!
+//! `UioDevice::get_mapping_info` labels mappings with their `reg-names`,
+//! `UioDevice::map_by_name` accepts them and `UioDevice::sub_regions` finds
+//! the child nodes (`mm2s` in mapping 0 at offset 0 here).
+//!
+//! Child addresses are translated with the node's `ranges` (or taken as
+//! physical addresses if it is empty), so the node's parent bus has to map
+//! its addresses 1:1 to physical ones, as the usual `simple-bus` does.
+//! Malformed properties are ignored.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A block of registers within a mapping, described by a child node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SubRegion {
+    /// Name of the child node, without the unit address
+    pub name: String,
+    /// Index of the mapping containing the block
+    pub mapping: usize,
+    /// Offset of the block in the mapping
+    pub offset: u64,
+    /// Size in bytes
+    pub len: u64,
+}
+
+/// A device tree node in `/sys/firmware/devicetree`.
+#[derive(Debug, Clone)]
+pub struct Node {
+    path: PathBuf,
+}
+
+impl Node {
+    /// The node at `path`, if it exists. Symbolic links (like `of_node`) are
+    /// resolved, so the parent node can be found.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Option<Node>> {
+        match fs::canonicalize(path) {
+            Ok(path) => Ok(Some(Node { path })),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The node name without the unit address, e.g. "mm2s" for `mm2s@0`.
+    pub fn name(&self) -> String {
+        let name = self
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match name.find('@') {
+            Some(i) => name[..i].to_string(),
+            None => name,
+        }
+    }
+
+    /// The raw value of the property `name`, `None` if the node doesn't have
+    /// it.
+    pub fn property(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path.join(name)) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The string list property `name` (e.g. `reg-names`).
+    pub fn strings(&self, name: &str) -> io::Result<Vec<String>> {
+        let Some(value) = self.property(name)? else {
+            return Ok(Vec::new());
+        };
+        let value = value.strip_suffix(b"\0").unwrap_or(&value);
+        if value.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(value
+            .split(|&b| b == 0)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect())
+    }
+
+    /// The property `name` as big-endian 32-bit cells, `None` if the node
+    /// doesn't have it or its length is not a multiple of 4.
+    pub fn cells(&self, name: &str) -> io::Result<Option<Vec<u32>>> {
+        Ok(self
+            .property(name)?
+            .filter(|value| value.len().is_multiple_of(4))
+            .map(|value| {
+                value
+                    .chunks(4)
+                    .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect()
+            }))
+    }
+
+    /// The child nodes, sorted by name.
+    pub fn children(&self) -> io::Result<Vec<Node>> {
+        let mut children = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                children.push(Node { path: entry.path() });
+            }
+        }
+        children.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(children)
+    }
+
+    /// The `#address-cells` and `#size-cells` of the node's children.
+    fn child_cells(&self) -> io::Result<(usize, usize)> {
+        let cells = |name, default| -> io::Result<usize> {
+            Ok(match self.cells(name)?.as_deref() {
+                Some(&[n]) => n as usize,
+                _ => default,
+            })
+        };
+        Ok((cells("#address-cells", 2)?, cells("#size-cells", 1)?))
+    }
+
+    /// The physical address ranges of the blocks described by the child
+    /// nodes, with their names. Children without `reg` are skipped, and
+    /// there are none if the node has no `ranges`.
+    pub fn child_ranges(&self) -> io::Result<Vec<(String, u64, u64)>> {
+        let Some(ranges) = self.cells("ranges")? else {
+            return Ok(Vec::new());
+        };
+        let (address_cells, size_cells) = self.child_cells()?;
+        let parent_cells = match Node::open(self.path.join(".."))? {
+            Some(parent) => parent.child_cells()?.0,
+            None => 2,
+        };
+        let entry_len = address_cells + parent_cells + size_cells;
+        if entry_len == 0 {
+            return Ok(Vec::new());
+        }
+        let ranges: Vec<(u64, u64, u64)> = ranges
+            .chunks_exact(entry_len)
+            .filter_map(|entry| {
+                let (child, rest) = entry.split_at(address_cells);
+                let (parent, size) = rest.split_at(parent_cells);
+                Some((number(child)?, number(parent)?, number(size)?))
+            })
+            .collect();
+
+        let mut blocks = Vec::new();
+        for child in self.children()? {
+            let Some(reg) = child.cells("reg")? else {
+                continue;
+            };
+            if reg.len() < address_cells + size_cells {
+                continue;
+            }
+            let (Some(addr), Some(len)) = (
+                number(&reg[..address_cells]),
+                number(&reg[address_cells..address_cells + size_cells]),
+            ) else {
+                continue;
+            };
+            let translated = if ranges.is_empty() {
+                Some(addr)
+            } else {
+                ranges
+                    .iter()
+                    .find(|&&(child, _, size)| {
+                        addr >= child && addr.saturating_add(len) <= child.saturating_add(size)
+                    })
+                    .and_then(|&(child, parent, _)| parent.checked_add(addr - child))
+            };
+            if let Some(addr) = translated {
+                blocks.push((child.name(), addr, len));
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+/// The number in big-endian `cells`, if it fits 64 bits.
+fn number(cells: &[u32]) -> Option<u64> {
+    if cells.len() > 2 && cells[..cells.len() - 2].iter().any(|&c| c != 0) {
+        return None;
+    }
+    Some(
+        cells
+            .iter()
+            .fold(0u64, |n, &c| n.wrapping_shl(32) | u64::from(c)),
+    )
+}
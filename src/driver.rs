@@ -0,0 +1,1535 @@
+//! A common lifecycle for drivers of UIO devices.
+//!
+//! A process managing several IP cores registers a factory per kind of
+//! device with a `Registry`, which binds a driver instance to every matching
+//! UIO device and dispatches their interrupts:
+//!
+//! ```ignore
+//! use uio::driver::{Match, Registry, UioDriver};
+//!
+//! struct Dma;
+//!
+//! impl UioDriver for Dma {
+//!     fn handle_irq(&mut self, device: &UioDevice, _count: u32) -> io::Result<()> {
+//!         // acknowledge the interrupt in the core's registers
+//!         device.irq_enable()
+//!     }
+//! }
+//!
+//! let mut registry = Registry::new();
+//! registry.register(Match::Compatible("xlnx,axi-dma-1.00.a".into()), |_| Box::new(Dma));
+//! registry.probe()?;
+//! loop {
+//!     registry.wait(None)?;
+//! }
+//! ```
+//!
+//! A watchdog detects devices which stopped interrupting, e.g. a stuck FPGA
+//! pipeline: with `Registry::set_watchdog(0, Duration::from_millis(100))`,
+//! `wait` calls `UioDriver::irq_timeout` of the driver of uio0 whenever it
+//! didn't interrupt for 100ms. By default this fails `wait` with `TimedOut`.
+//!
+//! A flaky interrupt line can fire faster than a driver can keep up with.
+//! `Registry::set_rate_limit` limits the interrupts of a device to bursts
+//! of a few followed by at most one per interval. Interrupts beyond that are
+//! held back and handed to the driver as one (with the latest event count)
+//! once the limit allows, after `UioDriver::irqs_suppressed` reported how
+//! many were merged into it. Until then the driver doesn't acknowledge the
+//! interrupt and `wait` doesn't re-enable it (see
+//! `UioDeviceBuilder::auto_reenable`), which keeps a level-triggered line
+//! masked.
+//!
+//! Components which only observe a device's interrupts (statistics, a
+//! control loop, the data path) can subscribe to them through a
+//! `Broadcast` attached with `Registry::set_broadcast`, also from other
+//! threads than the one dispatching. Every subscriber has a bounded queue;
+//! one which falls behind loses the oldest events and learns how many with
+//! `RecvError::Lagged`:
+//!
+//! ```ignore
+//! let irqs = Broadcast::new();
+//! let stats = irqs.subscribe(64);
+//! registry.set_broadcast(0, Some(irqs.clone()))?;
+//! // on another thread
+//! loop {
+//!     match stats.recv(None) {
+//!         Ok(count) => println!("interrupt, count {}", count),
+//!         Err(RecvError::Lagged(n)) => println!("missed {} interrupts", n),
+//!         Err(_) => break,
+//!     }
+//! }
+//! ```
+//!
+//! Devices lose their state when the system sleeps. `Registry::suspend`
+//! calls `UioDriver::suspend`, masks the interrupts and saves the registers
+//! given to `Registry::preserve_registers`; `Registry::resume` writes them
+//! back, calls `UioDriver::resume` and enables the interrupts again. The
+//! crate doesn't talk to logind itself: a daemon holding a delay inhibitor
+//! (`systemd-inhibit --what=sleep --mode=delay`, or its D-Bus equivalent)
+//! calls them when `PrepareForSleep` is signalled, other setups from a
+//! `system-sleep` hook or their own power management code:
+//!
+//! ```ignore
+//! registry.preserve_registers(0, 0, 0..0x100, &[STATUS..STATUS + 4])?;
+//! // PrepareForSleep(true)
+//! registry.suspend()?;
+//! // PrepareForSleep(false)
+//! registry.resume()?;
+//! ```
+//!
+//! A `Dispatcher` runs a registry on a background thread, which can be set
+//! up for low interrupt latency with a `ThreadConfig`:
+//!
+//! ```ignore
+//! let mut config = ThreadConfig::new();
+//! config
+//!     .cpus([3])
+//!     .scheduling(Scheduling::Fifo(80))
+//!     .prefault_stack(256 * 1024);
+//! let dispatcher = Dispatcher::spawn(&config, || {
+//!     let mut registry = Registry::new();
+//!     registry.register(Match::Name("dma".into()), |_| Box::new(Dma));
+//!     registry.probe()?;
+//!     Ok(registry)
+//! })?;
+//! ```
+
+use libc;
+use snapshot::Snapshot;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::hint;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use sys;
+use {list_devices_in, parse, LockMode, UioDevice, UioDeviceBuilder, UioError};
+
+/// What a driver is bound by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceId {
+    pub uio_num: usize,
+    /// The UIO name of the device (`/sys/class/uio/uioX/name`)
+    pub name: String,
+    /// The device tree `compatible` strings of platform devices, most
+    /// specific first
+    pub compatible: Vec<String>,
+}
+
+impl DeviceId {
+    /// Identifies the device with the sysfs directory `sysfs`.
+    fn read(uio_num: usize, sysfs: &Path) -> io::Result<DeviceId> {
+        let name = fs::read_to_string(sysfs.join("name"))?.trim().to_string();
+        let compatible = match fs::read(sysfs.join("device/of_node/compatible")) {
+            Ok(bytes) => parse::string_list(&bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(DeviceId {
+            uio_num,
+            name,
+            compatible,
+        })
+    }
+}
+
+/// Which devices a driver factory is registered for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Match {
+    /// Devices with this UIO name
+    Name(String),
+    /// Devices with this device tree `compatible` string
+    Compatible(String),
+}
+
+impl Match {
+    fn matches(&self, id: &DeviceId) -> bool {
+        match *self {
+            Match::Name(ref name) => id.name == *name,
+            Match::Compatible(ref compatible) => id.compatible.contains(compatible),
+        }
+    }
+}
+
+/// The driver of a single device, created by a factory registered with a
+/// `Registry`.
+pub trait UioDriver {
+    /// Called once after the device was bound, e.g. to reset the core and
+    /// enable its interrupts. Binding fails if this fails.
+    fn init(&mut self, device: &UioDevice) -> io::Result<()> {
+        let _ = device;
+        Ok(())
+    }
+
+    /// Called for every interrupt of the device with the event count. The
+    /// driver has to acknowledge the interrupt and re-enable it if needed.
+    fn handle_irq(&mut self, device: &UioDevice, event_count: u32) -> io::Result<()>;
+
+    /// Called by the watchdog (see `Registry::set_watchdog`) when the device
+    /// didn't interrupt for `elapsed`. Called again after every further
+    /// period without an interrupt.
+    ///
+    /// The default fails with `TimedOut`, which `Registry::wait` returns.
+    fn irq_timeout(&mut self, device: &UioDevice, elapsed: Duration) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "no interrupt from uio{} for {:?}",
+                device.get_num(),
+                elapsed
+            ),
+        ))
+    }
+
+    /// Called before `handle_irq` when `suppressed` interrupts were merged
+    /// into the next call by the rate limit (see `Registry::set_rate_limit`).
+    fn irqs_suppressed(&mut self, device: &UioDevice, suppressed: u32) -> io::Result<()> {
+        let _ = (device, suppressed);
+        Ok(())
+    }
+
+    /// Called by `Registry::suspend` before the registers are saved and the
+    /// interrupt is masked, e.g. to stop DMA transfers.
+    fn suspend(&mut self, device: &UioDevice) -> io::Result<()> {
+        let _ = device;
+        Ok(())
+    }
+
+    /// Called by `Registry::resume` after the registers were restored and
+    /// before the interrupt is enabled again, e.g. to restart the core.
+    fn resume(&mut self, device: &UioDevice) -> io::Result<()> {
+        let _ = device;
+        Ok(())
+    }
+
+    /// Called once before the device is closed.
+    fn shutdown(&mut self, device: &UioDevice) -> io::Result<()> {
+        let _ = device;
+        Ok(())
+    }
+}
+
+type Factory = Box<dyn Fn(&DeviceId) -> Box<dyn UioDriver>>;
+
+/// The interrupt deadline of a device.
+struct Watchdog {
+    period: Duration,
+    /// The last interrupt, or when the watchdog was set or last fired
+    last: Instant,
+}
+
+impl Watchdog {
+    fn deadline(&self) -> Instant {
+        self.last + self.period
+    }
+}
+
+/// How often the interrupts of a device are handed to its driver, see
+/// `Registry::set_rate_limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Interrupts handled right away after a quiet period
+    pub burst: u32,
+    /// Minimum time between interrupts once the burst is used up
+    pub min_interval: Duration,
+}
+
+/// A token bucket enforcing a `RateLimit`.
+struct Limiter {
+    limit: RateLimit,
+    tokens: u32,
+    /// When the last token was added
+    refilled: Instant,
+    /// The event count of the interrupt held back, if any
+    pending: Option<u32>,
+    /// Interrupts merged into `pending`
+    suppressed: u32,
+    /// Interrupts merged since the limit was set
+    total_suppressed: u64,
+}
+
+impl Limiter {
+    fn new(limit: RateLimit) -> Limiter {
+        Limiter {
+            limit,
+            tokens: limit.burst,
+            refilled: Instant::now(),
+            pending: None,
+            suppressed: 0,
+            total_suppressed: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.limit.min_interval == Duration::from_secs(0) || self.tokens >= self.limit.burst {
+            self.refilled = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let new = elapsed.as_nanos() / self.limit.min_interval.as_nanos();
+        if new > 0 {
+            let new = new.min(u128::from(self.limit.burst - self.tokens)) as u32;
+            self.tokens += new;
+            self.refilled += self.limit.min_interval * new;
+        }
+    }
+
+    /// Takes a token if one is available.
+    fn take(&mut self, now: Instant) -> bool {
+        if self.limit.min_interval == Duration::from_secs(0) {
+            return true;
+        }
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// When the interrupt held back can be handled.
+    fn deadline(&self) -> Option<Instant> {
+        self.pending?;
+        Some(if self.tokens > 0 {
+            self.refilled
+        } else {
+            self.refilled + self.limit.min_interval
+        })
+    }
+}
+
+/// Why `Subscriber::recv` returned no event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The queue overflowed and this many of the oldest events were
+    /// dropped since the last `recv`. The next `recv` returns the oldest
+    /// event still queued.
+    Lagged(u64),
+    /// No interrupt within the timeout
+    TimedOut,
+    /// The device was unbound or the broadcast detached, and all queued
+    /// events were received
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Lagged(n) => write!(f, "subscriber lagged behind by {} interrupts", n),
+            RecvError::TimedOut => f.write_str("no interrupt within the timeout"),
+            RecvError::Closed => f.write_str("the broadcast is closed"),
+        }
+    }
+}
+
+impl Error for RecvError {}
+
+#[derive(Debug)]
+struct QueueState {
+    events: VecDeque<u32>,
+    capacity: usize,
+    /// Events dropped since the last `recv`
+    lagged: u64,
+    closed: bool,
+}
+
+/// The queue of a subscriber, shared with the broadcast.
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Hands the interrupts of a device to any number of subscribers, see
+/// `Registry::set_broadcast`. Clones share the subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcast {
+    subscribers: Arc<Mutex<Vec<Weak<Queue>>>>,
+}
+
+impl Broadcast {
+    /// A broadcast without subscribers.
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Weak<Queue>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Adds a subscriber which receives all interrupts from now on and
+    /// queues up to `capacity` of them.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn subscribe(&self, capacity: usize) -> Subscriber {
+        assert!(capacity > 0, "subscriber queues need a capacity");
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                lagged: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        self.subscribers().push(Arc::downgrade(&queue));
+        Subscriber { queue }
+    }
+
+    /// The number of live subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers()
+            .iter()
+            .filter(|q| q.strong_count() > 0)
+            .count()
+    }
+
+    /// Queues `event_count` for every subscriber, dropping the oldest event
+    /// of full queues.
+    fn publish(&self, event_count: u32) {
+        self.subscribers().retain(|queue| {
+            let Some(queue) = queue.upgrade() else {
+                return false;
+            };
+            let mut state = queue.lock();
+            if state.events.len() == state.capacity {
+                state.events.pop_front();
+                state.lagged += 1;
+            }
+            state.events.push_back(event_count);
+            queue.ready.notify_one();
+            true
+        });
+    }
+
+    /// Ends all subscriptions, subscribers receive `RecvError::Closed` once
+    /// their queues are empty.
+    fn close(&self) {
+        for queue in self.subscribers().drain(..) {
+            if let Some(queue) = queue.upgrade() {
+                queue.lock().closed = true;
+                queue.ready.notify_all();
+            }
+        }
+    }
+}
+
+/// Receives the interrupts of a device from a `Broadcast`.
+#[derive(Debug)]
+pub struct Subscriber {
+    queue: Arc<Queue>,
+}
+
+impl Subscriber {
+    /// Waits up to `timeout` (forever if `None`) for an interrupt and
+    /// returns its event count, or reports that events were dropped.
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<u32, RecvError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.queue.lock();
+        loop {
+            if state.lagged > 0 {
+                return Err(RecvError::Lagged(mem::replace(&mut state.lagged, 0)));
+            }
+            if let Some(event_count) = state.events.pop_front() {
+                return Ok(event_count);
+            }
+            if state.closed {
+                return Err(RecvError::Closed);
+            }
+            state = match deadline {
+                None => self
+                    .queue
+                    .ready
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::from_secs(0) {
+                        return Err(RecvError::TimedOut);
+                    }
+                    self.queue
+                        .ready
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+    }
+
+    /// The number of queued events.
+    pub fn len(&self) -> usize {
+        self.queue.lock().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Registers saved across suspend, see `Registry::preserve_registers`.
+struct Preserved {
+    mapping: usize,
+    range: Range<usize>,
+    skip: Vec<Range<usize>>,
+    /// Taken by `suspend`
+    saved: Option<Snapshot>,
+}
+
+struct Binding {
+    id: DeviceId,
+    device: UioDevice,
+    driver: Box<dyn UioDriver>,
+    watchdog: Option<Watchdog>,
+    limiter: Option<Limiter>,
+    preserved: Vec<Preserved>,
+    broadcast: Option<Broadcast>,
+}
+
+impl Binding {
+    fn suspend(&mut self) -> io::Result<()> {
+        self.driver.suspend(&self.device)?;
+        self.device.irq_disable()?;
+        for preserved in &mut self.preserved {
+            let region = self.device.map_region(preserved.mapping)?;
+            preserved.saved = Some(Snapshot::take(
+                &region,
+                preserved.range.clone(),
+                &preserved.skip,
+            ));
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        for preserved in &mut self.preserved {
+            if let Some(saved) = preserved.saved.take() {
+                saved.restore(&self.device.map_region(preserved.mapping)?);
+            }
+        }
+        if let Some(ref mut watchdog) = self.watchdog {
+            watchdog.last = Instant::now();
+        }
+        self.driver.resume(&self.device)?;
+        self.device.irq_enable()
+    }
+
+    /// Enables the interrupt again after the driver handled one, if the
+    /// device was opened with `auto_reenable`.
+    fn reenable(&self) -> io::Result<()> {
+        let (_, _, _, auto_reenable) = self.device.options();
+        if auto_reenable {
+            self.device.irq_enable()?;
+        }
+        Ok(())
+    }
+
+    /// Hands the interrupt to the driver, or holds it back if the rate limit
+    /// is exceeded. Returns whether the driver was called.
+    fn handle_irq(&mut self, event_count: u32) -> io::Result<bool> {
+        if let Some(ref broadcast) = self.broadcast {
+            broadcast.publish(event_count);
+        }
+        let now = Instant::now();
+        if let Some(ref mut watchdog) = self.watchdog {
+            watchdog.last = now;
+        }
+        if let Some(ref mut limiter) = self.limiter {
+            if limiter.pending.is_some() || !limiter.take(now) {
+                if limiter.pending.replace(event_count).is_some() {
+                    limiter.suppressed += 1;
+                    limiter.total_suppressed += 1;
+                }
+                return Ok(false);
+            }
+        }
+        self.driver.handle_irq(&self.device, event_count)?;
+        Ok(true)
+    }
+
+    /// Hands the interrupt held back by the rate limit to the driver, if
+    /// the limit allows it by now. Returns whether the driver was called.
+    fn flush(&mut self, now: Instant) -> io::Result<bool> {
+        let Some(ref mut limiter) = self.limiter else {
+            return Ok(false);
+        };
+        if limiter.pending.is_none() || !limiter.take(now) {
+            return Ok(false);
+        }
+        let event_count = limiter.pending.take().expect("an interrupt is pending");
+        let suppressed = mem::replace(&mut limiter.suppressed, 0);
+        if suppressed > 0 {
+            self.driver.irqs_suppressed(&self.device, suppressed)?;
+        }
+        self.driver.handle_irq(&self.device, event_count)?;
+        self.reenable()?;
+        Ok(true)
+    }
+}
+
+/// Binds drivers to devices and dispatches interrupts to them.
+///
+/// Dropping the registry shuts down all drivers, ignoring errors; use
+/// `shutdown` to see them.
+#[derive(Default)]
+pub struct Registry {
+    factories: Vec<(Match, Factory)>,
+    bound: Vec<Binding>,
+    suspended: bool,
+}
+
+impl Registry {
+    /// An empty registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Registers `factory` to create the driver for devices matching `m`.
+    /// The first matching registration wins.
+    pub fn register<F>(&mut self, m: Match, factory: F) -> &mut Self
+    where
+        F: Fn(&DeviceId) -> Box<dyn UioDriver> + 'static,
+    {
+        self.factories.push((m, Box::new(factory)));
+        self
+    }
+
+    /// Binds a driver to the already opened `device`. Returns false (and
+    /// closes the device) if no driver matches.
+    pub fn bind(&mut self, device: UioDevice) -> io::Result<bool> {
+        let id = DeviceId::read(device.get_num(), device.sysfs_path())?;
+        let Some((_, factory)) = self.factories.iter().find(|(m, _)| m.matches(&id)) else {
+            return Ok(false);
+        };
+        let mut driver = factory(&id);
+        driver.init(&device)?;
+        self.bound.push(Binding {
+            id,
+            device,
+            driver,
+            watchdog: None,
+            limiter: None,
+            preserved: Vec::new(),
+            broadcast: None,
+        });
+        Ok(true)
+    }
+
+    /// Opens and binds all unbound UIO devices a driver matches. Devices
+    /// locked by another process or already open in this one are skipped.
+    /// Returns the number of newly bound devices.
+    pub fn probe(&mut self) -> io::Result<usize> {
+        self.probe_in(Path::new("/sys/class/uio"), Path::new("/dev"))
+    }
+
+    /// Like `probe`, with custom sysfs and device directories (see
+    /// `UioDeviceBuilder::sysfs_root` and `dev_root`).
+    pub fn probe_in(&mut self, sysfs_root: &Path, dev_root: &Path) -> io::Result<usize> {
+        let mut count = 0;
+        for uio_num in list_devices_in(sysfs_root)? {
+            if self.bound.iter().any(|b| b.id.uio_num == uio_num) {
+                continue;
+            }
+            let id = DeviceId::read(uio_num, &sysfs_root.join(format!("uio{}", uio_num)))?;
+            if !self.factories.iter().any(|(m, _)| m.matches(&id)) {
+                continue;
+            }
+            let device = match UioDeviceBuilder::new(uio_num)
+                .sysfs_root(sysfs_root)
+                .dev_root(dev_root)
+                .lock(LockMode::NonBlocking)
+                .open()
+            {
+                Ok(device) => device,
+                Err(UioError::Locked { .. }) | Err(UioError::AlreadyClaimed { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if self.bind(device)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// The bound devices.
+    pub fn devices(&self) -> impl Iterator<Item = (&DeviceId, &UioDevice)> {
+        self.bound.iter().map(|b| (&b.id, &b.device))
+    }
+
+    fn binding(&mut self, uio_num: usize) -> io::Result<&mut Binding> {
+        self.bound
+            .iter_mut()
+            .find(|b| b.id.uio_num == uio_num)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no driver bound to uio{}", uio_num),
+                )
+            })
+    }
+
+    /// Calls the `handle_irq` of the driver of /dev/uio`uio_num`, for
+    /// callers which wait for interrupts themselves. Returns false if the
+    /// interrupt was held back by the rate limit, see
+    /// `flush_rate_limited`.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device.
+    pub fn handle_irq(&mut self, uio_num: usize, event_count: u32) -> io::Result<bool> {
+        self.binding(uio_num)?.handle_irq(event_count)
+    }
+
+    /// Limits how often the driver of /dev/uio`uio_num` is called for
+    /// interrupts, or removes the limit if `None`.
+    ///
+    /// Up to `limit.burst` interrupts are handled right away; after that one
+    /// more is allowed every `limit.min_interval`. Interrupts arriving in
+    /// between are held back and merged into one, which `wait` (or
+    /// `flush_rate_limited`) hands to the driver once allowed. Removing the
+    /// limit drops an interrupt held back.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device and with
+    /// `InvalidInput` if `limit.burst` is 0, which would hold back
+    /// interrupts forever.
+    pub fn set_rate_limit(&mut self, uio_num: usize, limit: Option<RateLimit>) -> io::Result<()> {
+        if limit.is_some_and(|l| l.burst == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limit burst must be at least 1",
+            ));
+        }
+        self.binding(uio_num)?.limiter = limit.map(Limiter::new);
+        Ok(())
+    }
+
+    /// The number of interrupts of /dev/uio`uio_num` merged into others by
+    /// the rate limit since it was set.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device.
+    pub fn suppressed_irqs(&mut self, uio_num: usize) -> io::Result<u64> {
+        Ok(self
+            .binding(uio_num)?
+            .limiter
+            .as_ref()
+            .map_or(0, |l| l.total_suppressed))
+    }
+
+    /// Hands interrupts held back by rate limits to their drivers once the
+    /// limits allow, for callers which wait for interrupts themselves.
+    /// Returns the number of handled interrupts.
+    ///
+    /// All due interrupts are handled even if some drivers fail, the first
+    /// error is returned.
+    pub fn flush_rate_limited(&mut self) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut res = Ok(0);
+        for binding in &mut self.bound {
+            let r = binding.flush(now);
+            res = match (res, r) {
+                (Ok(n), Ok(flushed)) => Ok(n + flushed as usize),
+                (Ok(_), Err(e)) => Err(e),
+                (Err(e), _) => Err(e),
+            };
+        }
+        res
+    }
+
+    /// Expects an interrupt of /dev/uio`uio_num` at least every `period`
+    /// from now on, or stops expecting interrupts if `None`. When the
+    /// device misses its deadline, `wait` or `check_watchdogs` call the
+    /// `irq_timeout` of its driver.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device.
+    pub fn set_watchdog(&mut self, uio_num: usize, period: Option<Duration>) -> io::Result<()> {
+        self.binding(uio_num)?.watchdog = period.map(|period| Watchdog {
+            period,
+            last: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Hands every interrupt of /dev/uio`uio_num` to the subscribers of
+    /// `broadcast` (before the driver, also interrupts held back by a rate
+    /// limit), or stops if `None`. Subscriptions of a broadcast which is
+    /// replaced, or whose device is shut down, are closed.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device.
+    pub fn set_broadcast(
+        &mut self,
+        uio_num: usize,
+        broadcast: Option<Broadcast>,
+    ) -> io::Result<()> {
+        let binding = self.binding(uio_num)?;
+        if let Some(old) = mem::replace(&mut binding.broadcast, broadcast) {
+            old.close();
+        }
+        Ok(())
+    }
+
+    /// Saves `range` of the mapping `mapping` of /dev/uio`uio_num` in
+    /// `suspend` and writes it back in `resume`, except for the byte ranges
+    /// in `skip` (see `Snapshot::take`). Can be called several times per
+    /// device, ranges are restored in the order they were added.
+    ///
+    /// Fails with `NotFound` if no driver is bound to the device and with
+    /// `InvalidInput` if the range is out of bounds of the mapping.
+    pub fn preserve_registers(
+        &mut self,
+        uio_num: usize,
+        mapping: usize,
+        range: Range<usize>,
+        skip: &[Range<usize>],
+    ) -> io::Result<()> {
+        let binding = self.binding(uio_num)?;
+        let len = binding.device.map_region(mapping)?.len();
+        if range.start > range.end || range.end > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:#x}..{:#x} is out of bounds of mapping {} ({:#x} bytes)",
+                    range.start, range.end, mapping, len
+                ),
+            ));
+        }
+        binding.preserved.push(Preserved {
+            mapping,
+            range,
+            skip: skip.to_vec(),
+            saved: None,
+        });
+        Ok(())
+    }
+
+    /// Prepares all devices for system sleep: calls `UioDriver::suspend`,
+    /// masks the interrupt and saves the registers given to
+    /// `preserve_registers`, device by device. Does nothing if already
+    /// suspended.
+    ///
+    /// All devices are suspended even if some fail, the first error is
+    /// returned. Either way the registry counts as suspended and `resume`
+    /// has to be called.
+    pub fn suspend(&mut self) -> io::Result<()> {
+        if mem::replace(&mut self.suspended, true) {
+            return Ok(());
+        }
+        let mut res = Ok(());
+        for binding in &mut self.bound {
+            let r = binding.suspend();
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+
+    /// Undoes `suspend` after waking up: writes the saved registers back,
+    /// calls `UioDriver::resume` and enables the interrupt, in reverse
+    /// order of the devices. Watchdogs start a new period. Does nothing if
+    /// not suspended.
+    ///
+    /// All devices are resumed even if some fail, the first error is
+    /// returned.
+    pub fn resume(&mut self) -> io::Result<()> {
+        if !mem::replace(&mut self.suspended, false) {
+            return Ok(());
+        }
+        let mut res = Ok(());
+        for binding in self.bound.iter_mut().rev() {
+            let r = binding.resume();
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+
+    /// Whether `suspend` was called without `resume`.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Calls `irq_timeout` for every device which missed its watchdog
+    /// deadline and re-arms the watchdog, for callers which wait for
+    /// interrupts themselves. Returns the number of expired watchdogs.
+    ///
+    /// All expired watchdogs fire even if some drivers fail, the first
+    /// error is returned.
+    pub fn check_watchdogs(&mut self) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut res = Ok(0);
+        for binding in &mut self.bound {
+            let Some(ref mut watchdog) = binding.watchdog else {
+                continue;
+            };
+            if watchdog.deadline() > now {
+                continue;
+            }
+            let elapsed = now - watchdog.last;
+            watchdog.last = now;
+            let r = binding.driver.irq_timeout(&binding.device, elapsed);
+            res = match (res, r) {
+                (Ok(n), Ok(())) => Ok(n + 1),
+                (Ok(_), Err(e)) => Err(e),
+                (Err(e), _) => Err(e),
+            };
+        }
+        res
+    }
+
+    /// The earliest watchdog deadline or time an interrupt held back by a
+    /// rate limit is due.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.bound
+            .iter()
+            .flat_map(|b| {
+                let watchdog = b.watchdog.as_ref().map(Watchdog::deadline);
+                let limiter = b.limiter.as_ref().and_then(Limiter::deadline);
+                watchdog.into_iter().chain(limiter)
+            })
+            .min()
+    }
+
+    /// Waits up to `timeout` (forever if `None`) for interrupts of any bound
+    /// device and dispatches them. Returns the number of handled
+    /// interrupts, 0 on timeout.
+    ///
+    /// Expired watchdogs (see `set_watchdog`) fire while waiting. If a
+    /// driver's `irq_timeout` fails, `wait` returns its error. Interrupts
+    /// held back by a rate limit are handled once it allows, interrupts
+    /// which are held back don't count. For devices opened with
+    /// `auto_reenable` the interrupt is enabled again once the driver
+    /// handled it, not while it is held back.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        let fds: Vec<_> = self.bound.iter().map(|b| b.device.as_raw_fd()).collect();
+        let end = timeout.map(|t| Instant::now() + t);
+        loop {
+            let now = Instant::now();
+            let wake = match (end, self.next_deadline()) {
+                (Some(end), Some(deadline)) => Some(end.min(deadline)),
+                (end, deadline) => end.or(deadline),
+            };
+            let timeout_ms = wake.map_or(-1, |wake| {
+                // Round up so we don't spin on sub-millisecond remainders
+                let left = wake.saturating_duration_since(now);
+                left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            });
+            let ready = sys::poll_many(&fds, timeout_ms)?;
+            let mut handled = 0;
+            for &i in &ready {
+                let binding = &mut self.bound[i];
+                // Not `irq_wait`, which would re-enable an interrupt the rate
+                // limit is about to hold back
+                let count = binding.device.read_irq_event()?;
+                if binding.handle_irq(count)? {
+                    binding.reenable()?;
+                    handled += 1;
+                }
+            }
+            handled += self.flush_rate_limited()?;
+            self.check_watchdogs()?;
+            if handled > 0 || end.is_some_and(|end| Instant::now() >= end) {
+                return Ok(handled);
+            }
+        }
+    }
+
+    /// Shuts down all drivers and closes their devices. All drivers are
+    /// shut down even if some fail, the first error is returned.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        for mut binding in self.bound.drain(..) {
+            if let Some(ref broadcast) = binding.broadcast {
+                broadcast.close();
+            }
+            let r = binding.driver.shutdown(&binding.device);
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field(
+                "factories",
+                &self.factories.iter().map(|(m, _)| m).collect::<Vec<_>>(),
+            )
+            .field(
+                "bound",
+                &self.bound.iter().map(|b| &b.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// A real-time scheduling policy with its priority (1 to 99).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduling {
+    /// `SCHED_FIFO`: runs until it blocks or a higher priority thread
+    /// becomes runnable
+    Fifo(i32),
+    /// `SCHED_RR`: like `Fifo`, but with time slices among threads of the
+    /// same priority
+    RoundRobin(i32),
+}
+
+/// How the handler thread of a `Dispatcher` is set up.
+///
+/// Real-time scheduling needs `CAP_SYS_NICE` (or an `RLIMIT_RTPRIO`). The
+/// pre-faulted stack stays resident only if the process also locks its
+/// memory (`mlockall(MCL_CURRENT | MCL_FUTURE)`).
+#[derive(Debug, Clone, Default)]
+pub struct ThreadConfig {
+    name: Option<String>,
+    cpus: Vec<usize>,
+    scheduling: Option<Scheduling>,
+    prefault_stack: usize,
+}
+
+/// Stack beyond the pre-faulted part, for the code running the thread.
+const STACK_RESERVE: usize = 64 * 1024;
+
+/// How often a `Dispatcher` checks whether it should stop.
+const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+impl ThreadConfig {
+    /// A normal thread, as spawned by `std::thread::spawn`.
+    pub fn new() -> ThreadConfig {
+        ThreadConfig::default()
+    }
+
+    /// Names the thread.
+    pub fn name<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Pins the thread to the CPUs `cpus`. By default it may run on any
+    /// CPU of the process.
+    pub fn cpus<I: IntoIterator<Item = usize>>(&mut self, cpus: I) -> &mut Self {
+        self.cpus = cpus.into_iter().collect();
+        self
+    }
+
+    /// Runs the thread with a real-time scheduling policy.
+    pub fn scheduling(&mut self, scheduling: Scheduling) -> &mut Self {
+        self.scheduling = Some(scheduling);
+        self
+    }
+
+    /// Touches `bytes` of stack when the thread starts, so handlers don't
+    /// take page faults on it later. The stack is grown to fit.
+    pub fn prefault_stack(&mut self, bytes: usize) -> &mut Self {
+        self.prefault_stack = bytes;
+        self
+    }
+
+    /// Applies the CPU affinity, scheduling and stack settings to the
+    /// calling thread. Done by `Dispatcher::spawn` on the handler thread.
+    pub fn apply(&self) -> io::Result<()> {
+        if !self.cpus.is_empty() {
+            sys::set_affinity(&self.cpus)?;
+        }
+        match self.scheduling {
+            Some(Scheduling::Fifo(priority)) => sys::set_scheduler(libc::SCHED_FIFO, priority)?,
+            Some(Scheduling::RoundRobin(priority)) => sys::set_scheduler(libc::SCHED_RR, priority)?,
+            None => {}
+        }
+        if self.prefault_stack > 0 {
+            prefault_stack(self.prefault_stack);
+        }
+        Ok(())
+    }
+
+    fn builder(&self) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(ref name) = self.name {
+            builder = builder.name(name.clone());
+        }
+        if self.prefault_stack > 0 {
+            builder = builder.stack_size(self.prefault_stack + STACK_RESERVE);
+        }
+        builder
+    }
+}
+
+/// Writes to every page of the next `bytes` of stack.
+#[inline(never)]
+fn prefault_stack(bytes: usize) {
+    let mut chunk = [0u8; 16 * 1024];
+    for i in (0..chunk.len()).step_by(4096) {
+        // Safety: the index is in bounds
+        unsafe { ptr::write_volatile(chunk.as_mut_ptr().add(i), 1) };
+    }
+    if bytes > chunk.len() {
+        prefault_stack(bytes - chunk.len());
+    }
+    // Keep the chunk alive across the call, so the frames don't overlap
+    hint::black_box(&chunk);
+}
+
+/// Runs a `Registry` on a background thread, dispatching interrupts until
+/// it is stopped or fails.
+#[derive(Debug)]
+pub struct Dispatcher {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl Dispatcher {
+    /// Spawns a thread set up with `config`, which creates its registry with
+    /// `setup` and then calls `Registry::wait` in a loop.
+    ///
+    /// The registry is created on the handler thread, so drivers need not
+    /// be `Send`. Fails if the thread can't be set up or `setup` fails.
+    pub fn spawn<F>(config: &ThreadConfig, setup: F) -> io::Result<Dispatcher>
+    where
+        F: FnOnce() -> io::Result<Registry> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let (started, start) = mpsc::channel();
+        let builder = config.builder();
+        let config = config.clone();
+        let thread = builder.spawn(move || {
+            let registry = config.apply().and_then(|()| setup());
+            let mut registry = match registry {
+                Ok(registry) => {
+                    let _ = started.send(Ok(()));
+                    registry
+                }
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    return Ok(());
+                }
+            };
+            while !stopped.load(Ordering::Relaxed) {
+                registry.wait(Some(STOP_INTERVAL))?;
+            }
+            registry.shutdown()
+        })?;
+        match start.recv() {
+            Ok(Ok(())) => Ok(Dispatcher { stop, thread }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::other("dispatcher thread panicked")),
+        }
+    }
+
+    /// Whether the thread ended because dispatching failed. `stop` returns
+    /// the error.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Stops dispatching, shuts down the drivers and returns the first
+    /// error of the thread.
+    pub fn stop(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("dispatcher thread panicked")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    struct Logger {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl UioDriver for Logger {
+        fn init(&mut self, device: &UioDevice) -> io::Result<()> {
+            let entry = format!("{} init uio{}", self.name, device.get_num());
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        fn handle_irq(&mut self, _device: &UioDevice, count: u32) -> io::Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} irq {}", self.name, count));
+            Ok(())
+        }
+
+        fn irq_timeout(&mut self, _device: &UioDevice, _elapsed: Duration) -> io::Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} timeout", self.name));
+            Ok(())
+        }
+
+        fn irqs_suppressed(&mut self, _device: &UioDevice, suppressed: u32) -> io::Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} suppressed {}", self.name, suppressed));
+            Ok(())
+        }
+
+        fn suspend(&mut self, device: &UioDevice) -> io::Result<()> {
+            let regs = device.map_region(0)?;
+            let entry = format!("{} suspend {:#x}", self.name, regs.read_u32(0));
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        fn resume(&mut self, device: &UioDevice) -> io::Result<()> {
+            let regs = device.map_region(0)?;
+            let entry = format!("{} resume {:#x}", self.name, regs.read_u32(0));
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        fn shutdown(&mut self, _device: &UioDevice) -> io::Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} shutdown", self.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registry() {
+        let dma = MockUio::new(0).unwrap();
+        dma.set_name("dma0").unwrap();
+        dma.set_attr(
+            "device/of_node/compatible",
+            "xlnx,axi-dma-1.00.a\0xlnx,axi-dma",
+        )
+        .unwrap();
+        let gpio = MockUio::new(1).unwrap();
+        gpio.set_name("gpio").unwrap();
+        let other = MockUio::new(2).unwrap();
+        other.set_name("other").unwrap();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::new();
+        let l = log.clone();
+        registry.register(Match::Compatible("xlnx,axi-dma".into()), move |_| {
+            Box::new(Logger {
+                name: "dma",
+                log: l.clone(),
+            })
+        });
+        let l = log.clone();
+        registry.register(Match::Name("gpio".into()), move |_| {
+            Box::new(Logger {
+                name: "gpio",
+                log: l.clone(),
+            })
+        });
+
+        // Each mock has its own directories, so bind them one by one
+        assert!(registry.bind(dma.open().unwrap()).unwrap());
+        assert_eq!(
+            registry
+                .probe_in(&gpio.sysfs_root(), &gpio.dev_root())
+                .unwrap(),
+            1
+        );
+        assert!(!registry.bind(other.open().unwrap()).unwrap());
+        assert_eq!(registry.devices().count(), 2);
+
+        registry.handle_irq(1, 7).unwrap();
+        assert!(registry.handle_irq(2, 1).is_err());
+        registry.shutdown().unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "dma init uio0",
+                "gpio init uio1",
+                "gpio irq 7",
+                "dma shutdown",
+                "gpio shutdown"
+            ]
+        );
+    }
+
+    struct Silent;
+
+    impl UioDriver for Silent {
+        fn handle_irq(&mut self, _device: &UioDevice, _count: u32) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn watchdog() {
+        let dma = MockUio::new(0).unwrap();
+        dma.set_name("dma").unwrap();
+        let gpio = MockUio::new(1).unwrap();
+        gpio.set_name("gpio").unwrap();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::new();
+        let l = log.clone();
+        registry.register(Match::Name("dma".into()), move |_| {
+            Box::new(Logger {
+                name: "dma",
+                log: l.clone(),
+            })
+        });
+        registry.register(Match::Name("gpio".into()), |_| Box::new(Silent));
+        assert!(registry.bind(dma.open().unwrap()).unwrap());
+        assert!(registry.bind(gpio.open().unwrap()).unwrap());
+        assert!(registry
+            .set_watchdog(2, Some(Duration::from_millis(1)))
+            .is_err());
+
+        let period = Duration::from_millis(20);
+        registry.set_watchdog(0, Some(period)).unwrap();
+        assert_eq!(registry.check_watchdogs().unwrap(), 0);
+        ::std::thread::sleep(period);
+        assert_eq!(registry.check_watchdogs().unwrap(), 1);
+        // Re-armed
+        assert_eq!(registry.check_watchdogs().unwrap(), 0);
+        ::std::thread::sleep(period / 2);
+        registry.handle_irq(0, 1).unwrap();
+        ::std::thread::sleep(period / 2);
+        assert_eq!(registry.check_watchdogs().unwrap(), 0);
+        assert_eq!(log.lock().unwrap()[1..], ["dma timeout", "dma irq 1"]);
+
+        // The default `irq_timeout` reports the missed deadline
+        registry.set_watchdog(0, None).unwrap();
+        registry
+            .set_watchdog(1, Some(Duration::from_secs(0)))
+            .unwrap();
+        let err = registry.check_watchdogs().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let gpio = MockUio::new(0).unwrap();
+        gpio.set_name("gpio").unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::new();
+        let l = log.clone();
+        registry.register(Match::Name("gpio".into()), move |_| {
+            Box::new(Logger {
+                name: "gpio",
+                log: l.clone(),
+            })
+        });
+        assert!(registry.bind(gpio.open().unwrap()).unwrap());
+        let period = Duration::from_millis(100);
+        let limit = RateLimit {
+            burst: 2,
+            min_interval: period,
+        };
+        assert!(registry.set_rate_limit(1, Some(limit)).is_err());
+        let err = registry
+            .set_rate_limit(0, Some(RateLimit { burst: 0, ..limit }))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        registry.set_rate_limit(0, Some(limit)).unwrap();
+
+        // The burst passes, the rest is merged into the last interrupt
+        assert!(registry.handle_irq(0, 1).unwrap());
+        assert!(registry.handle_irq(0, 2).unwrap());
+        assert!(!registry.handle_irq(0, 3).unwrap());
+        assert!(!registry.handle_irq(0, 4).unwrap());
+        assert!(!registry.handle_irq(0, 5).unwrap());
+        assert_eq!(registry.flush_rate_limited().unwrap(), 0);
+        assert_eq!(registry.suppressed_irqs(0).unwrap(), 2);
+        assert!(registry.next_deadline().unwrap() > Instant::now());
+
+        ::std::thread::sleep(period);
+        assert_eq!(registry.flush_rate_limited().unwrap(), 1);
+        assert_eq!(registry.flush_rate_limited().unwrap(), 0);
+        assert_eq!(
+            log.lock().unwrap()[1..],
+            [
+                "gpio irq 1",
+                "gpio irq 2",
+                "gpio suppressed 2",
+                "gpio irq 5"
+            ]
+        );
+
+        registry.set_rate_limit(0, None).unwrap();
+        assert!(registry.handle_irq(0, 6).unwrap());
+        assert_eq!(registry.suppressed_irqs(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn rate_limit_keeps_masked() {
+        use mock::Fault;
+        use std::fs::{self, OpenOptions};
+        use std::io::Write;
+        use Operation;
+
+        let gpio = MockUio::new(0).unwrap();
+        gpio.set_name("gpio").unwrap();
+        // The mock's device file is read for event counts, enable writes
+        // are appended to it
+        fs::write(gpio.dev_path(), [1u32.to_ne_bytes(), [0; 4]].concat()).unwrap();
+        let mut registry = Registry::new();
+        registry.register(Match::Name("gpio".into()), |_| Box::new(Silent));
+        let dev = gpio.builder().auto_reenable(true).open().unwrap();
+        assert!(registry.bind(dev).unwrap());
+        let limit = RateLimit {
+            burst: 1,
+            min_interval: Duration::from_secs(3600),
+        };
+        registry.set_rate_limit(0, Some(limit)).unwrap();
+
+        let now = Duration::from_secs(0);
+        assert_eq!(registry.wait(Some(now)).unwrap(), 1);
+        assert_eq!(fs::metadata(gpio.dev_path()).unwrap().len(), 12);
+
+        // Held back: an enable would fail with the injected fault
+        let mut devfile = OpenOptions::new()
+            .append(true)
+            .open(gpio.dev_path())
+            .unwrap();
+        devfile.write_all(&2u32.to_ne_bytes()).unwrap();
+        gpio.inject(Operation::Write, Fault::Error(libc::EIO));
+        assert_eq!(registry.wait(Some(now)).unwrap(), 0);
+        assert_eq!(registry.suppressed_irqs(0).unwrap(), 0);
+        assert_eq!(fs::metadata(gpio.dev_path()).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let mut dma = MockUio::new(0).unwrap();
+        dma.set_name("dma").unwrap();
+        dma.add_mapping(0x4000_0000, 0x1000, "regs").unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::new();
+        let l = log.clone();
+        registry.register(Match::Name("dma".into()), move |_| {
+            Box::new(Logger {
+                name: "dma",
+                log: l.clone(),
+            })
+        });
+        assert!(registry.bind(dma.open().unwrap()).unwrap());
+        let err = registry
+            .preserve_registers(0, 0, 0xf00..0x1100, &[])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        registry
+            .preserve_registers(0, 0, 0..0x10, &[4..8, 8..12])
+            .unwrap();
+
+        let regs = registry.bound[0].device.map_mapping(0).unwrap() as *mut u32;
+        unsafe {
+            regs.write_volatile(0x1);
+            regs.add(2).write_volatile(0x2);
+        }
+        registry.suspend().unwrap();
+        assert!(registry.is_suspended());
+        // Suspending twice is harmless
+        registry.suspend().unwrap();
+        // Lost while asleep
+        unsafe {
+            regs.write_volatile(0);
+            regs.add(2).write_volatile(0);
+        }
+        registry.resume().unwrap();
+        assert!(!registry.is_suspended());
+        assert_eq!(unsafe { regs.read_volatile() }, 0x1);
+        // Skipped
+        assert_eq!(unsafe { regs.add(2).read_volatile() }, 0);
+        assert_eq!(
+            log.lock().unwrap()[1..],
+            ["dma suspend 0x1", "dma resume 0x1"]
+        );
+        registry.resume().unwrap();
+        assert_eq!(log.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn broadcast() {
+        let gpio = MockUio::new(0).unwrap();
+        gpio.set_name("gpio").unwrap();
+        let mut registry = Registry::new();
+        registry.register(Match::Name("gpio".into()), |_| Box::new(Silent));
+        assert!(registry.bind(gpio.open().unwrap()).unwrap());
+        let irqs = Broadcast::new();
+        let control = irqs.subscribe(8);
+        let stats = irqs.subscribe(2);
+        assert!(registry.set_broadcast(1, Some(irqs.clone())).is_err());
+        registry.set_broadcast(0, Some(irqs.clone())).unwrap();
+        assert_eq!(irqs.subscriber_count(), 2);
+
+        for count in 1..=4 {
+            registry.handle_irq(0, count).unwrap();
+        }
+        let timeout = Some(Duration::from_secs(0));
+        assert_eq!(control.len(), 4);
+        assert_eq!(
+            (1..=4).map(|_| control.recv(timeout)).collect::<Vec<_>>(),
+            [Ok(1), Ok(2), Ok(3), Ok(4)]
+        );
+        assert_eq!(control.recv(timeout), Err(RecvError::TimedOut));
+        // The slow subscriber lost the oldest two
+        assert_eq!(stats.recv(timeout), Err(RecvError::Lagged(2)));
+        assert_eq!(stats.recv(timeout), Ok(3));
+        assert_eq!(stats.recv(timeout), Ok(4));
+
+        drop(stats);
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(control.recv(None), Ok(5));
+                assert_eq!(control.recv(None), Err(RecvError::Closed));
+            });
+            registry.handle_irq(0, 5).unwrap();
+            registry.shutdown().unwrap();
+        });
+        assert_eq!(irqs.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn dispatcher() {
+        let mut config = ThreadConfig::new();
+        config.name("uio-irq").prefault_stack(256 * 1024);
+        let dispatcher = Dispatcher::spawn(&config, || {
+            assert_eq!(thread::current().name(), Some("uio-irq"));
+            Ok(Registry::new())
+        })
+        .unwrap();
+        assert!(!dispatcher.is_finished());
+        dispatcher.stop().unwrap();
+
+        let err = Dispatcher::spawn(&config, || Err(io::ErrorKind::NotFound.into())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        config.cpus([1 << 20]);
+        let err = Dispatcher::spawn(&config, || Ok(Registry::new())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,384 @@
+//! Streaming data through a FIFO register.
+//!
+//! Many soft IP cores (e.g. the Xilinx AXI-Stream FIFO) expose a data FIFO
+//! as a single register, next to a status register with its occupancy. A
+//! `FifoReader` drains such a FIFO as an `Iterator` of words or through
+//! `io::Read`, a `FifoWriter` fills one through `io::Write`:
+//!
+//! ```ignore
+//! use uio::fifo::{FifoConfig, FifoReader, Width};
+//!
+//! let config = FifoConfig {
+//!     data: 0x20,
+//!     status: 0x1c,
+//!     width: Width::U32,
+//!     // Occupancy in words
+//!     ready_mask: 0x7fff_ffff,
+//! };
+//! let words: Vec<u64> = FifoReader::new(&regs, config).collect();
+//! ```
+//!
+//! Neither side blocks: iteration ends and reads or writes fail with
+//! `WouldBlock` when the FIFO is empty or full. The status register is read
+//! only when the occupancy seen last is used up, so a burst costs one status
+//! read plus one access per word.
+
+use std::io;
+use DeviceMemory;
+
+/// The size of a FIFO word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl Width {
+    /// The size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::U8 => 1,
+            Width::U16 => 2,
+            Width::U32 => 4,
+            Width::U64 => 8,
+        }
+    }
+}
+
+/// Where the registers of a FIFO are and how to read its status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FifoConfig {
+    /// Offset of the data register
+    pub data: usize,
+    /// Offset of the 32-bit status register
+    pub status: usize,
+    /// Size of the data register and of the words in the FIFO
+    pub width: Width,
+    /// The bits of the status register holding the number of words which
+    /// can be read (for readers) or written (for writers). A single bit
+    /// works as a ready flag that allows one word at a time. Must not be 0.
+    pub ready_mask: u32,
+}
+
+impl FifoConfig {
+    /// The number of words the status register allows to transfer.
+    fn ready<M: DeviceMemory + ?Sized>(&self, regs: &M) -> u32 {
+        (regs.read_u32(self.status) & self.ready_mask) >> self.ready_mask.trailing_zeros()
+    }
+
+    fn read_word<M: DeviceMemory + ?Sized>(&self, regs: &M) -> u64 {
+        match self.width {
+            Width::U8 => regs.read_u8(self.data) as u64,
+            Width::U16 => regs.read_u16(self.data) as u64,
+            Width::U32 => regs.read_u32(self.data) as u64,
+            Width::U64 => regs.read_u64(self.data),
+        }
+    }
+
+    fn write_word<M: DeviceMemory + ?Sized>(&self, regs: &M, word: u64) {
+        match self.width {
+            Width::U8 => regs.write_u8(self.data, word as u8),
+            Width::U16 => regs.write_u16(self.data, word as u16),
+            Width::U32 => regs.write_u32(self.data, word as u32),
+            Width::U64 => regs.write_u64(self.data, word),
+        }
+    }
+}
+
+fn would_block(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, format!("the FIFO is {}", what))
+}
+
+/// Reads words from a FIFO register until it is empty.
+///
+/// As `io::Read`, words are returned in native byte order. A word which
+/// doesn't fit into the buffer is kept for the next read.
+#[derive(Debug)]
+pub struct FifoReader<'a, M: DeviceMemory + ?Sized> {
+    regs: &'a M,
+    config: FifoConfig,
+    /// Words the status register allowed which weren't read yet
+    ready: u32,
+    /// The rest of a word partially returned by `read`
+    partial: [u8; 8],
+    partial_len: usize,
+}
+
+impl<'a, M: DeviceMemory + ?Sized> FifoReader<'a, M> {
+    /// Reads from the FIFO described by `config`.
+    ///
+    /// # Panics
+    /// If `config.ready_mask` is 0.
+    pub fn new(regs: &'a M, config: FifoConfig) -> FifoReader<'a, M> {
+        assert!(config.ready_mask != 0, "FIFO ready_mask must not be 0");
+        FifoReader {
+            regs,
+            config,
+            ready: 0,
+            partial: [0; 8],
+            partial_len: 0,
+        }
+    }
+
+    /// The number of words in the FIFO, as reported by the status register.
+    pub fn available(&self) -> u32 {
+        self.config.ready(self.regs)
+    }
+
+    /// Reads a word, `None` if the FIFO is empty.
+    pub fn read_word(&mut self) -> Option<u64> {
+        if self.ready == 0 {
+            self.ready = self.config.ready(self.regs);
+            if self.ready == 0 {
+                return None;
+            }
+        }
+        self.ready -= 1;
+        Some(self.config.read_word(self.regs))
+    }
+}
+
+impl<'a, M: DeviceMemory + ?Sized> Iterator for FifoReader<'a, M> {
+    type Item = u64;
+
+    /// Ends when the FIFO is empty, so it can be iterated again later.
+    fn next(&mut self) -> Option<u64> {
+        self.read_word()
+    }
+}
+
+impl<'a, M: DeviceMemory + ?Sized> io::Read for FifoReader<'a, M> {
+    /// Fails with `WouldBlock` if the FIFO is empty and nothing was read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let width = self.config.width.bytes();
+        let mut n = 0;
+        while n < buf.len() {
+            if self.partial_len == 0 {
+                let Some(word) = self.read_word() else {
+                    break;
+                };
+                let bytes = word.to_ne_bytes();
+                let bytes = if cfg!(target_endian = "big") {
+                    &bytes[8 - width..]
+                } else {
+                    &bytes[..width]
+                };
+                self.partial[..width].copy_from_slice(bytes);
+                self.partial_len = width;
+            }
+            let start = width - self.partial_len;
+            let len = self.partial_len.min(buf.len() - n);
+            buf[n..n + len].copy_from_slice(&self.partial[start..start + len]);
+            self.partial_len -= len;
+            n += len;
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(would_block("empty"));
+        }
+        Ok(n)
+    }
+}
+
+/// Writes words to a FIFO register while it has space.
+///
+/// As `io::Write`, words are taken in native byte order. Trailing bytes
+/// which don't make up a whole word are kept until more data or `flush`,
+/// which pads them with zeros.
+#[derive(Debug)]
+pub struct FifoWriter<'a, M: DeviceMemory + ?Sized> {
+    regs: &'a M,
+    config: FifoConfig,
+    /// Free words the status register reported which weren't written yet
+    ready: u32,
+    partial: [u8; 8],
+    partial_len: usize,
+}
+
+impl<'a, M: DeviceMemory + ?Sized> FifoWriter<'a, M> {
+    /// Writes to the FIFO described by `config`.
+    ///
+    /// # Panics
+    /// If `config.ready_mask` is 0.
+    pub fn new(regs: &'a M, config: FifoConfig) -> FifoWriter<'a, M> {
+        assert!(config.ready_mask != 0, "FIFO ready_mask must not be 0");
+        FifoWriter {
+            regs,
+            config,
+            ready: 0,
+            partial: [0; 8],
+            partial_len: 0,
+        }
+    }
+
+    /// The number of words the FIFO has space for, as reported by the
+    /// status register.
+    pub fn space(&self) -> u32 {
+        self.config.ready(self.regs)
+    }
+
+    /// Writes a word (truncated to the width), returns false if the FIFO is
+    /// full.
+    pub fn write_word(&mut self, word: u64) -> bool {
+        if self.ready == 0 {
+            self.ready = self.config.ready(self.regs);
+            if self.ready == 0 {
+                return false;
+            }
+        }
+        self.ready -= 1;
+        self.config.write_word(self.regs, word);
+        true
+    }
+
+    /// Writes the buffered partial word, padded with zeros if `pad`.
+    /// Returns false if the FIFO is full.
+    fn write_partial(&mut self, pad: bool) -> bool {
+        let width = self.config.width.bytes();
+        if self.partial_len == 0 || (self.partial_len < width && !pad) {
+            return true;
+        }
+        let mut bytes = [0u8; 8];
+        if cfg!(target_endian = "big") {
+            bytes[8 - width..8 - width + self.partial_len]
+                .copy_from_slice(&self.partial[..self.partial_len]);
+        } else {
+            bytes[..self.partial_len].copy_from_slice(&self.partial[..self.partial_len]);
+        }
+        if !self.write_word(u64::from_ne_bytes(bytes)) {
+            return false;
+        }
+        self.partial_len = 0;
+        true
+    }
+}
+
+impl<'a, M: DeviceMemory + ?Sized> io::Write for FifoWriter<'a, M> {
+    /// Fails with `WouldBlock` if the FIFO is full and nothing was taken.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let width = self.config.width.bytes();
+        let mut n = 0;
+        while n < buf.len() {
+            if !self.write_partial(false) {
+                break;
+            }
+            let len = (width - self.partial_len).min(buf.len() - n);
+            self.partial[self.partial_len..self.partial_len + len]
+                .copy_from_slice(&buf[n..n + len]);
+            self.partial_len += len;
+            n += len;
+        }
+        // Push out a completed word right away
+        self.write_partial(false);
+        if n == 0 && !buf.is_empty() {
+            return Err(would_block("full"));
+        }
+        Ok(n)
+    }
+
+    /// Writes a trailing partial word padded with zeros. Fails with
+    /// `WouldBlock` if the FIFO is full.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write_partial(true) {
+            Ok(())
+        } else {
+            Err(would_block("full"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    const DATA: usize = 0x0;
+    const STATUS: usize = 0x4;
+
+    fn config(width: Width) -> FifoConfig {
+        FifoConfig {
+            data: DATA,
+            status: STATUS,
+            width,
+            ready_mask: 0xff << 8,
+        }
+    }
+
+    /// A FIFO of `capacity` words, reads of the data register pop from it
+    /// and writes push to it.
+    fn fifo(capacity: usize, reader: bool) -> (MockMemory, Arc<Mutex<VecDeque<u64>>>) {
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let mut regs = MockMemory::new(0x10);
+        let q = queue.clone();
+        regs.on_read(move |_, offset, _| {
+            let mut q = q.lock().unwrap();
+            match offset {
+                DATA => q.pop_front(),
+                STATUS if reader => Some((q.len() as u64) << 8),
+                STATUS => Some(((capacity - q.len()) as u64) << 8),
+                _ => None,
+            }
+        });
+        let q = queue.clone();
+        regs.on_write(move |_, offset, _, value| {
+            if offset == DATA {
+                q.lock().unwrap().push_back(value);
+            }
+        });
+        (regs, queue)
+    }
+
+    #[test]
+    fn reader() {
+        let (regs, queue) = fifo(16, true);
+        queue.lock().unwrap().extend([1, 2, 3]);
+        let mut reader = FifoReader::new(&regs, config(Width::U32));
+        assert_eq!(reader.available(), 3);
+        assert_eq!(Iterator::by_ref(&mut reader).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(reader.next(), None);
+
+        queue.lock().unwrap().extend([0x0403_0201, 0x0807_0605]);
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(buf, [1, 2, 3]);
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 5);
+        assert_eq!(buf[..5], [4, 5, 6, 7, 8]);
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn writer() {
+        let (regs, queue) = fifo(2, false);
+        let mut writer = FifoWriter::new(&regs, config(Width::U16));
+        assert_eq!(writer.space(), 2);
+        assert!(writer.write_word(0x1_0001));
+        // The last word is kept back until there is space
+        assert_eq!(writer.write(&[2, 0, 3, 0, 4, 0]).unwrap(), 4);
+        let err = writer.write(&[4, 0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(*queue.lock().unwrap(), [1, 2]);
+
+        queue.lock().unwrap().clear();
+        assert_eq!(writer.write(&[4]).unwrap(), 1);
+        assert_eq!(queue.lock().unwrap().len(), 1);
+        writer.flush().unwrap();
+        assert_eq!(*queue.lock().unwrap(), [3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ready_mask")]
+    fn empty_ready_mask() {
+        let (regs, _) = fifo(2, true);
+        let config = FifoConfig {
+            ready_mask: 0,
+            ..config(Width::U32)
+        };
+        FifoReader::new(&regs, config);
+    }
+}
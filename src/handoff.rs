@@ -0,0 +1,224 @@
+//! Handing open devices to other processes.
+//!
+//! A privileged broker can open and lock a device and pass it over a Unix
+//! socket to an unprivileged worker, which gets a fully usable `UioDevice`
+//! without access to `/dev/uioX` itself:
+//!
+//! ```ignore
+//! // broker
+//! let (broker, worker) = UnixStream::pair()?;
+//! handoff::send(&broker, UioDevice::try_new(0)?)?;
+//!
+//! // worker (e.g. after fork and dropping privileges)
+//! let dev = handoff::recv(&worker)?;
+//! dev.irq_wait()?;
+//! ```
+//!
+//! The file descriptor is sent with `SCM_RIGHTS`, together with a short text
+//! message carrying the UIO number, the sysfs and device directories and the
+//! options the device was opened with. Since the lock belongs to the open
+//! file, the worker takes over the broker's lock.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use {parse, sys};
+use {Access, LockMode, UioDevice, UioDeviceBuilder};
+
+/// First line of every message, to detect peers speaking something else.
+const MAGIC: &str = "uio-handoff 1";
+
+/// Maximum size of a message.
+const MAX_MESSAGE: usize = 4096;
+
+/// Sends `device` over `socket` and closes it in this process.
+///
+/// The device is consumed so that the broker's claim on it (see
+/// `UioDeviceBuilder::shared`) goes away while the lock stays held by the
+/// receiver. Tracked mappings are unmapped like in `UioDevice::into_parts`.
+/// If sending fails, the device is closed.
+pub fn send(socket: &UnixStream, device: UioDevice) -> io::Result<()> {
+    let message = encode(&device)?;
+    let (fd, _) = device.into_parts();
+    let n = sys::send_fd(socket.as_raw_fd(), &message, fd.as_raw_fd())?;
+    if n != message.len() {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+    Ok(())
+}
+
+/// Receives a device sent with `send` from `socket`.
+///
+/// Fails with `UnexpectedEof` if the peer closed the socket and with
+/// `InvalidData` if the message is not a device handoff.
+pub fn recv(socket: &UnixStream) -> io::Result<UioDevice> {
+    let mut buf = vec![0; MAX_MESSAGE];
+    let (n, fd) = sys::recv_fd(socket.as_raw_fd(), &mut buf)?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let builder = decode(&buf[..n])?;
+    let fd = fd.ok_or_else(|| invalid("no file descriptor in the message"))?;
+    Ok(builder.open_fd(fd)?)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid handoff: {}", msg),
+    )
+}
+
+/// The directory containing `path`, which must not contain line breaks.
+fn parent(path: &Path) -> io::Result<&[u8]> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    let bytes = parent.as_os_str().as_bytes();
+    if bytes.contains(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't hand off {}", path.display()),
+        ));
+    }
+    Ok(bytes)
+}
+
+fn encode(device: &UioDevice) -> io::Result<Vec<u8>> {
+    let (lock_mode, access, read_only, auto_reenable) = device.options();
+    let mut message = format!(
+        "{}\nnum {}\nlock {}\naccess {}\nread_only {}\nauto_reenable {}\n",
+        MAGIC,
+        device.get_num(),
+        match lock_mode {
+            LockMode::Blocking => "blocking",
+            LockMode::NonBlocking => "nonblocking",
+            LockMode::Unlocked => "unlocked",
+        },
+        match access {
+            Access::Exclusive => "exclusive",
+            Access::Control => "control",
+            Access::Shared => "shared",
+        },
+        read_only as u8,
+        auto_reenable as u8
+    )
+    .into_bytes();
+    for (key, path) in [
+        ("sysfs_root", device.sysfs_path()),
+        ("dev_root", device.get_dev_path().as_ref()),
+    ] {
+        message.extend_from_slice(key.as_bytes());
+        message.push(b' ');
+        message.extend_from_slice(parent(path)?);
+        message.push(b'\n');
+    }
+    Ok(message)
+}
+
+/// Parses a message into a builder which opens the sent device. Unknown
+/// keys are ignored.
+fn decode(message: &[u8]) -> io::Result<UioDeviceBuilder> {
+    let mut lines = message.split(|&b| b == b'\n');
+    if lines.next() != Some(MAGIC.as_bytes()) {
+        return Err(invalid("bad magic"));
+    }
+    let flag = |value: &[u8]| match value {
+        b"0" => Ok(false),
+        b"1" => Ok(true),
+        _ => Err(invalid("bad flag")),
+    };
+    let mut num = None;
+    let mut lock = LockMode::NonBlocking;
+    let mut access = Access::Exclusive;
+    let (mut read_only, mut auto_reenable) = (false, false);
+    let (mut sysfs_root, mut dev_root) = (None, None);
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (key, value) = match line.iter().position(|&b| b == b' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => return Err(invalid("line without value")),
+        };
+        match key {
+            b"num" => num = Some(parse::decimal(value).ok_or_else(|| invalid("bad num"))?),
+            b"lock" => {
+                lock = match value {
+                    b"blocking" => LockMode::Blocking,
+                    b"nonblocking" => LockMode::NonBlocking,
+                    b"unlocked" => LockMode::Unlocked,
+                    _ => return Err(invalid("bad lock mode")),
+                }
+            }
+            b"access" => {
+                access = match value {
+                    b"exclusive" => Access::Exclusive,
+                    b"control" => Access::Control,
+                    b"shared" => Access::Shared,
+                    _ => return Err(invalid("bad access")),
+                }
+            }
+            b"read_only" => read_only = flag(value)?,
+            b"auto_reenable" => auto_reenable = flag(value)?,
+            b"sysfs_root" => sysfs_root = Some(PathBuf::from(OsStr::from_bytes(value))),
+            b"dev_root" => dev_root = Some(PathBuf::from(OsStr::from_bytes(value))),
+            _ => {}
+        }
+    }
+    let num = num.ok_or_else(|| invalid("no num"))?;
+    let mut builder = UioDeviceBuilder::new(num as usize);
+    builder
+        .lock(lock)
+        .access(access)
+        .read_only(read_only)
+        .auto_reenable(auto_reenable);
+    if let Some(root) = sysfs_root {
+        builder.sysfs_root(root);
+    }
+    if let Some(root) = dev_root {
+        builder.dev_root(root);
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    #[test]
+    fn send_recv() {
+        let mut mock = MockUio::new(2).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.builder().access(Access::Control).open().unwrap();
+        dev.map_region(0).unwrap().write_u32(0, 42);
+
+        let (broker, worker) = UnixStream::pair().unwrap();
+        send(&broker, dev).unwrap();
+        let dev = recv(&worker).unwrap();
+        assert_eq!(dev.get_num(), 2);
+        assert_eq!(
+            dev.options(),
+            (LockMode::NonBlocking, Access::Control, false, false)
+        );
+        assert_eq!(dev.map_region(0).unwrap().read_u32(0), 42);
+
+        // The worker holds the lock now
+        assert!(mock.hold_lock().is_err());
+        drop(dev);
+        assert!(mock.hold_lock().is_ok());
+
+        drop(broker);
+        assert_eq!(
+            recv(&worker).map(|_| ()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert!(decode(b"hello\n").is_err());
+        assert!(decode(b"uio-handoff 1\naccess control\n").is_err());
+        assert!(decode(b"uio-handoff 1\nnum 1\nread_only yes\n").is_err());
+        assert!(decode(b"uio-handoff 1\nnum 1\nfuture 7\n").is_ok());
+    }
+}
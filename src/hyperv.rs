@@ -0,0 +1,536 @@
+//! Hyper-V VMBus devices bound to `uio_hv_generic`.
+//!
+//! `uio_hv_generic` exposes a VMBus channel as a UIO device with these
+//! mappings:
+//!
+//! | index | name           | contents                                    |
+//! |-------|----------------|---------------------------------------------|
+//! | 0     | `txrx_rings`   | outbound (TX) ring followed by inbound (RX) |
+//! | 1     | `int_page`     | interrupt page                              |
+//! | 2     | `monitor_page` | monitor pages                               |
+//! | 3     | `recv_buf`     | receive buffer (netvsc only)                |
+//! | 4     | `send_buf`     | send buffer (netvsc only)                   |
+//!
+//! `VmbusChannel` maps the rings and sends and receives packets in the
+//! VMBus ring layout, signalling the host through the UIO device:
+//!
+//! ```ignore
+//! use uio::hyperv::{self, VmbusChannel};
+//!
+//! let dev = UioDevice::blocking_new(0)?;
+//! let channel = VmbusChannel::new(&dev)?;
+//! channel.send(hyperv::PACKET_DATA_INBAND, hyperv::FLAG_COMPLETION_REQUESTED, 1, &request)?;
+//! dev.irq_wait()?;
+//! let mut reply = Vec::new();
+//! while let Some(packet) = channel.recv(&mut reply)? {
+//!     handle(packet, &reply);
+//! }
+//! ```
+//!
+//! `RingBuffer` implements the ring protocol on any `DeviceMemory`, so it can
+//! be tested against a `mock::MockMemory`.
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{fence, Ordering};
+use {DeviceMemory, MappedRegion, UioDevice, UioError};
+
+/// Mapping index of the TX and RX rings.
+pub const TXRX_RINGS: usize = 0;
+/// Mapping index of the interrupt page.
+pub const INT_PAGE: usize = 1;
+/// Mapping index of the monitor pages.
+pub const MONITOR_PAGE: usize = 2;
+/// Mapping index of the receive buffer of network devices.
+pub const RECV_BUF: usize = 3;
+/// Mapping index of the send buffer of network devices.
+pub const SEND_BUF: usize = 4;
+
+/// Packet type of data sent in the ring itself (`VM_PKT_DATA_INBAND`).
+pub const PACKET_DATA_INBAND: u16 = 6;
+/// Packet type of data in transfer pages (`VM_PKT_DATA_USING_XFER_PAGES`).
+pub const PACKET_DATA_XFER_PAGES: u16 = 7;
+/// Packet type of data referenced by guest physical addresses
+/// (`VM_PKT_DATA_USING_GPA_DIRECT`).
+pub const PACKET_DATA_GPA_DIRECT: u16 = 9;
+/// Packet type of completions (`VM_PKT_COMP`).
+pub const PACKET_COMPLETION: u16 = 11;
+/// Packet flag asking the other side for a completion.
+pub const FLAG_COMPLETION_REQUESTED: u16 = 1;
+
+/// Size of the ring header, the data follows it.
+const HEADER_LEN: usize = 4096;
+const WRITE_INDEX: usize = 0;
+const READ_INDEX: usize = 4;
+const INTERRUPT_MASK: usize = 8;
+const PENDING_SEND_SIZE: usize = 12;
+/// Size of `vmpacket_descriptor`.
+const DESCRIPTOR_LEN: usize = 16;
+/// Size of the trailer after each packet (the previous write index).
+const TRAILER_LEN: usize = 8;
+
+/// The header of a received packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// One of the `PACKET_*` types
+    pub packet_type: u16,
+    /// `FLAG_*` bits
+    pub flags: u16,
+    pub transaction_id: u64,
+}
+
+/// One direction of a VMBus channel: a header page with the read and write
+/// indices followed by the circular data area.
+#[derive(Debug)]
+pub struct RingBuffer<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    mem: &'r M,
+    /// Offset of the header in `mem`
+    offset: usize,
+    /// Size of the data area
+    size: usize,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> RingBuffer<'r, M> {
+    /// A ring of `len` bytes (including the header page) at `offset`.
+    ///
+    /// # Panics
+    /// If the ring isn't larger than its header or doesn't fit `mem`.
+    pub fn new(mem: &'r M, offset: usize, len: usize) -> Self {
+        assert!(
+            len > HEADER_LEN && offset.checked_add(len).is_some_and(|end| end <= mem.len()),
+            "ring of {:#x} bytes at offset {:#x} doesn't fit memory of {:#x} bytes",
+            len,
+            offset,
+            mem.len()
+        );
+        RingBuffer {
+            mem,
+            offset,
+            size: len - HEADER_LEN,
+        }
+    }
+
+    /// Size of the data area in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Offset into the data area where the next packet is written.
+    pub fn write_index(&self) -> usize {
+        self.mem.read_u32(self.offset + WRITE_INDEX) as usize
+    }
+
+    /// Offset into the data area of the next packet to read.
+    pub fn read_index(&self) -> usize {
+        self.mem.read_u32(self.offset + READ_INDEX) as usize
+    }
+
+    /// Whether the reader of the ring asked not to be interrupted.
+    pub fn interrupt_mask(&self) -> bool {
+        self.mem.read_u32(self.offset + INTERRUPT_MASK) != 0
+    }
+
+    /// The number of free bytes the writer waits for, 0 if it doesn't wait.
+    pub fn pending_send_size(&self) -> usize {
+        self.mem.read_u32(self.offset + PENDING_SEND_SIZE) as usize
+    }
+
+    /// The read and write index, checked to be aligned offsets into the
+    /// data area, which the other side could have corrupted.
+    fn indices(&self) -> io::Result<(usize, usize)> {
+        let (read, write) = (self.read_index(), self.write_index());
+        if read >= self.size || write >= self.size || read % 8 != 0 || write % 8 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupt ring indices: read {:#x}, write {:#x}, size {:#x}",
+                    read, write, self.size
+                ),
+            ));
+        }
+        Ok((read, write))
+    }
+
+    /// The number of bytes between the indices.
+    fn used(&self, read: usize, write: usize) -> usize {
+        if write >= read {
+            write - read
+        } else {
+            self.size - read + write
+        }
+    }
+
+    /// Number of bytes written but not yet read.
+    ///
+    /// Fails with `InvalidData` if the indices are corrupt.
+    pub fn bytes_to_read(&self) -> io::Result<usize> {
+        let (read, write) = self.indices()?;
+        Ok(self.used(read, write))
+    }
+
+    /// Number of free bytes.
+    ///
+    /// Fails with `InvalidData` if the indices are corrupt.
+    pub fn bytes_to_write(&self) -> io::Result<usize> {
+        Ok(self.size - self.bytes_to_read()?)
+    }
+
+    /// Appends a packet with `payload`.
+    ///
+    /// Fails with `WouldBlock` if the ring doesn't have enough free space
+    /// and with `InvalidData` if its indices are corrupt.
+    pub fn write_packet(
+        &self,
+        packet_type: u16,
+        flags: u16,
+        transaction_id: u64,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let len = DESCRIPTOR_LEN + payload.len().div_ceil(8) * 8;
+        let total = len + TRAILER_LEN;
+        let (read, start) = self.indices()?;
+        // The ring can't be filled completely, equal indices mean empty
+        if total >= self.size - self.used(read, start) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("ring has no space for a packet of {} bytes", total),
+            ));
+        }
+        let len8 = u16::try_from(len / 8)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet payload too large"))?;
+
+        let mut packet = Vec::with_capacity(total);
+        packet.extend_from_slice(&packet_type.to_le_bytes());
+        packet.extend_from_slice(&((DESCRIPTOR_LEN / 8) as u16).to_le_bytes());
+        packet.extend_from_slice(&len8.to_le_bytes());
+        packet.extend_from_slice(&flags.to_le_bytes());
+        packet.extend_from_slice(&transaction_id.to_le_bytes());
+        packet.extend_from_slice(payload);
+        packet.resize(len, 0);
+        packet.extend_from_slice(&((start as u64) << 32).to_le_bytes());
+
+        self.copy_to(start, &packet);
+        // Publish the packet only after its contents
+        fence(Ordering::SeqCst);
+        self.mem.write_u32(
+            self.offset + WRITE_INDEX,
+            ((start + total) % self.size) as u32,
+        );
+        Ok(())
+    }
+
+    /// Removes the next packet from the ring, copying its payload into
+    /// `payload`. Returns `None` if the ring is empty.
+    ///
+    /// Fails with `InvalidData` if the indices or the packet descriptor are
+    /// corrupt, in which case the packet is not consumed.
+    pub fn read_packet(&self, payload: &mut Vec<u8>) -> io::Result<Option<Packet>> {
+        let (start, write) = self.indices()?;
+        let available = self.used(start, write);
+        if available == 0 {
+            return Ok(None);
+        }
+        let mut desc = [0u8; DESCRIPTOR_LEN];
+        self.copy_from(start, &mut desc);
+        let u16_at = |o: usize| u16::from_le_bytes([desc[o], desc[o + 1]]);
+        let data_offset = u16_at(2) as usize * 8;
+        let len = u16_at(4) as usize * 8;
+        if data_offset < DESCRIPTOR_LEN || data_offset > len || len + TRAILER_LEN > available {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupt packet at {:#x}: offset {:#x}, length {:#x}, {:#x} bytes available",
+                    start, data_offset, len, available
+                ),
+            ));
+        }
+
+        payload.clear();
+        payload.resize(len - data_offset, 0);
+        self.copy_from((start + data_offset) % self.size, payload);
+        let mut transaction_id = [0u8; 8];
+        transaction_id.copy_from_slice(&desc[8..]);
+        // Finish reading before the writer may reuse the space
+        fence(Ordering::SeqCst);
+        self.mem.write_u32(
+            self.offset + READ_INDEX,
+            ((start + len + TRAILER_LEN) % self.size) as u32,
+        );
+        Ok(Some(Packet {
+            packet_type: u16_at(0),
+            flags: u16_at(6),
+            transaction_id: u64::from_le_bytes(transaction_id),
+        }))
+    }
+
+    /// Copies `bytes` into the data area at `pos`, wrapping around its end.
+    fn copy_to(&self, pos: usize, bytes: &[u8]) {
+        let first = bytes.len().min(self.size - pos);
+        let data = self.offset + HEADER_LEN;
+        self.mem.write_bytes(data + pos, &bytes[..first]);
+        self.mem.write_bytes(data, &bytes[first..]);
+    }
+
+    /// Fills `buf` from the data area at `pos`, wrapping around its end.
+    fn copy_from(&self, pos: usize, buf: &mut [u8]) {
+        let first = buf.len().min(self.size - pos);
+        let data = self.offset + HEADER_LEN;
+        let (head, tail) = buf.split_at_mut(first);
+        self.mem.read_bytes(data + pos, head);
+        self.mem.read_bytes(data, tail);
+    }
+}
+
+/// The primary channel of a `uio_hv_generic` device.
+pub struct VmbusChannel<'a> {
+    device: &'a UioDevice,
+    rings: MappedRegion<'a>,
+}
+
+impl<'a> VmbusChannel<'a> {
+    /// Maps the rings of `device`.
+    pub fn new(device: &'a UioDevice) -> Result<VmbusChannel<'a>, UioError> {
+        Ok(VmbusChannel {
+            device,
+            rings: device.map_region(TXRX_RINGS)?,
+        })
+    }
+
+    /// The outbound ring, read by the host.
+    pub fn tx(&self) -> RingBuffer<'_> {
+        RingBuffer::new(&self.rings, 0, self.rings.len() / 2)
+    }
+
+    /// The inbound ring, written by the host.
+    pub fn rx(&self) -> RingBuffer<'_> {
+        let half = self.rings.len() / 2;
+        RingBuffer::new(&self.rings, half, half)
+    }
+
+    /// Sends a packet to the host, signalling it if it is waiting for data.
+    ///
+    /// Fails with `WouldBlock` if the TX ring is full.
+    pub fn send(
+        &self,
+        packet_type: u16,
+        flags: u16,
+        transaction_id: u64,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let tx = self.tx();
+        let was_empty = tx.bytes_to_read()? == 0;
+        tx.write_packet(packet_type, flags, transaction_id, payload)?;
+        // Like the kernel, only signal if the host may have seen an empty
+        // ring and stopped reading
+        if was_empty && !tx.interrupt_mask() {
+            self.signal()?;
+        }
+        Ok(())
+    }
+
+    /// Receives the next packet from the host into `payload`, or returns
+    /// `None` if there is none. Signals the host if it waits for space to
+    /// send.
+    pub fn recv(&self, payload: &mut Vec<u8>) -> io::Result<Option<Packet>> {
+        let rx = self.rx();
+        let free_before = rx.bytes_to_write()?;
+        let packet = rx.read_packet(payload)?;
+        let pending = rx.pending_send_size();
+        if packet.is_some()
+            && pending != 0
+            && free_before < pending
+            && pending <= rx.bytes_to_write()?
+        {
+            self.signal()?;
+        }
+        Ok(packet)
+    }
+
+    /// Signals the host. `uio_hv_generic` does this when 1 is written to
+    /// the device, which also unmasks the interrupt.
+    pub fn signal(&self) -> io::Result<()> {
+        self.device.irq_enable()
+    }
+}
+
+/// Monitor parameters of one side of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonitorParams {
+    /// Whether the monitor bit is set
+    pub pending: u32,
+    /// Latency of the monitor in 100ns units
+    pub latency: u32,
+    /// Connection id signalled through the monitor
+    pub conn_id: u32,
+}
+
+/// The monitor page parameters of a channel, see `VmbusInfo::monitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonitorInfo {
+    pub monitor_id: u32,
+    /// Host to guest
+    pub server: MonitorParams,
+    /// Guest to host
+    pub client: MonitorParams,
+}
+
+/// VMBus attributes of a `uio_hv_generic` device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmbusInfo {
+    /// Device class GUID, e.g. `{f8615163-df3e-46c5-913f-f2d2f965ed0e}`
+    pub class_id: String,
+    /// Instance GUID
+    pub device_id: String,
+    /// Relative id of the primary channel
+    pub relid: u32,
+    /// `None` if the channel doesn't use the monitor pages
+    pub monitor: Option<MonitorInfo>,
+}
+
+/// Reads the VMBus attributes of `device` from sysfs.
+pub fn info(device: &UioDevice) -> Result<VmbusInfo, UioError> {
+    let dir = device.sysfs_path().join("device");
+    let number = |name: &str| -> Result<u32, UioError> {
+        let value = device.read_number(dir.join(name))?;
+        Ok(value as u32)
+    };
+    // The kernel hides the monitor attributes if the channel doesn't use
+    // the monitor pages
+    let monitor = match number("monitor_id") {
+        Ok(monitor_id) => {
+            let params = |side: &str| -> Result<MonitorParams, UioError> {
+                Ok(MonitorParams {
+                    pending: number(&format!("{}_monitor_pending", side))?,
+                    latency: number(&format!("{}_monitor_latency", side))?,
+                    conn_id: number(&format!("{}_monitor_conn_id", side))?,
+                })
+            };
+            Some(MonitorInfo {
+                monitor_id,
+                server: params("server")?,
+                client: params("client")?,
+            })
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    Ok(VmbusInfo {
+        class_id: device.read_static(&dir.join("class_id"))?,
+        device_id: device.read_static(&dir.join("device_id"))?,
+        relid: number("id")?,
+        monitor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockMemory, MockUio};
+
+    #[test]
+    fn ring_round_trip() {
+        let mem = MockMemory::new(HEADER_LEN + 0x100);
+        let ring = RingBuffer::new(&mem, 0, mem.len());
+        // Start close to the end so the second packet wraps around
+        mem.write_u32(WRITE_INDEX, 0xd0);
+        mem.write_u32(READ_INDEX, 0xd0);
+
+        ring.write_packet(PACKET_DATA_INBAND, 0, 1, b"hello")
+            .unwrap();
+        ring.write_packet(PACKET_COMPLETION, FLAG_COMPLETION_REQUESTED, 2, &[7; 20])
+            .unwrap();
+        assert_eq!(ring.bytes_to_read().unwrap(), 32 + 48);
+        assert_eq!(ring.write_index(), 0x20);
+
+        let mut payload = Vec::new();
+        let packet = ring.read_packet(&mut payload).unwrap().unwrap();
+        assert_eq!(
+            (packet.packet_type, packet.transaction_id),
+            (PACKET_DATA_INBAND, 1)
+        );
+        assert_eq!(&payload[..5], b"hello");
+        let packet = ring.read_packet(&mut payload).unwrap().unwrap();
+        assert_eq!(packet.flags, FLAG_COMPLETION_REQUESTED);
+        let mut expected = vec![7; 20];
+        expected.resize(24, 0);
+        assert_eq!(payload, expected);
+        assert_eq!(ring.read_packet(&mut payload).unwrap(), None);
+
+        let err = ring
+            .write_packet(PACKET_DATA_INBAND, 0, 3, &[0; 0xe8])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // A descriptor claiming more data than available
+        ring.write_packet(PACKET_DATA_INBAND, 0, 4, &[]).unwrap();
+        mem.write_u16(HEADER_LEN + 0x20 + 4, 0x10);
+        let err = ring.read_packet(&mut payload).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_indices() {
+        let mem = MockMemory::new(HEADER_LEN + 0x100);
+        let ring = RingBuffer::new(&mem, 0, mem.len());
+        let mut payload = Vec::new();
+        for (read, write) in [(0, 0x100), (0x1000, 0), (0, 0x14)] {
+            mem.write_u32(READ_INDEX, read);
+            mem.write_u32(WRITE_INDEX, write);
+            let err = ring.bytes_to_read().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = ring.bytes_to_write().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = ring.read_packet(&mut payload).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = ring
+                .write_packet(PACKET_DATA_INBAND, 0, 1, &[])
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // Nothing was consumed or published
+        assert_eq!((ring.read_index(), ring.write_index()), (0, 0x14));
+    }
+
+    #[test]
+    fn vmbus_channel() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000_0000, 4 * HEADER_LEN as u64, "txrx_rings")
+            .unwrap();
+        let dev = mock.open().unwrap();
+        let channel = VmbusChannel::new(&dev).unwrap();
+        assert_eq!(channel.tx().size(), HEADER_LEN);
+
+        channel.send(PACKET_DATA_INBAND, 0, 9, &[1, 2, 3]).unwrap();
+        assert_eq!(channel.tx().bytes_to_read().unwrap(), 32);
+        let mut payload = Vec::new();
+        assert_eq!(channel.recv(&mut payload).unwrap(), None);
+    }
+
+    #[test]
+    fn vmbus_info() {
+        let mock = MockUio::new(0).unwrap();
+        mock.set_attr("device/class_id", "{f8615163-df3e-46c5-913f-f2d2f965ed0e}")
+            .unwrap();
+        mock.set_attr("device/device_id", "{0ad95ecd-2c5d-4b7e-9e4e-9d2d7c9f1b51}")
+            .unwrap();
+        mock.set_attr("device/id", "14").unwrap();
+        let dev = mock.open().unwrap();
+        let vmbus = info(&dev).unwrap();
+        assert_eq!(vmbus.relid, 14);
+        assert_eq!(vmbus.monitor, None);
+
+        mock.set_attr("device/monitor_id", "3").unwrap();
+        for side in &["server", "client"] {
+            mock.set_attr(format!("device/{}_monitor_pending", side), "1")
+                .unwrap();
+            mock.set_attr(format!("device/{}_monitor_latency", side), "100")
+                .unwrap();
+            mock.set_attr(format!("device/{}_monitor_conn_id", side), "4")
+                .unwrap();
+        }
+        let monitor = info(&dev).unwrap().monitor.unwrap();
+        assert_eq!(monitor.monitor_id, 3);
+        assert_eq!(monitor.client.latency, 100);
+        assert_eq!(monitor.server.conn_id, 4);
+    }
+}
@@ -1,9 +1,82 @@
+#[cfg(feature = "deku")]
+extern crate deku;
+#[cfg(feature = "nix")]
 extern crate fs2;
 extern crate libc;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "nix")]
 extern crate nix;
+// The PyO3 macros refer to `::core`
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "zerocopy")]
+extern crate zerocopy;
 
+#[macro_use]
+mod trace;
+
+pub mod buffer;
+#[cfg(all(target_os = "linux", feature = "capi"))]
+pub mod capi;
+pub mod codec;
+#[cfg(target_os = "linux")]
+mod copy;
+#[cfg(target_os = "linux")]
+pub mod devicetree;
+#[cfg(all(target_os = "linux", feature = "events"))]
+pub mod driver;
+#[cfg(target_os = "linux")]
+pub mod fifo;
+#[cfg(target_os = "linux")]
+pub mod handoff;
+#[cfg(target_os = "linux")]
+pub mod hyperv;
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(all(target_os = "linux", any(test, feature = "mock")))]
+pub mod mock;
+#[cfg(all(target_os = "linux", feature = "events"))]
+pub mod monitor;
+#[cfg(target_os = "linux")]
+pub mod nic;
+mod offset;
+pub mod parse;
+#[cfg(target_os = "linux")]
+pub mod profile;
+#[cfg(all(target_os = "linux", feature = "python"))]
+mod python;
+#[cfg(all(target_os = "linux", feature = "events"))]
+pub mod reactor;
+#[cfg(target_os = "linux")]
+pub mod record;
+#[cfg(target_os = "linux")]
+mod region;
+#[cfg(target_os = "linux")]
+pub mod shared;
+#[cfg(target_os = "linux")]
+pub mod snapshot;
+#[cfg(target_os = "linux")]
+pub mod spin;
+#[cfg(all(target_os = "linux", feature = "svd"))]
+pub mod svd;
+#[cfg(target_os = "linux")]
+mod sys;
+#[cfg(all(target_os = "linux", feature = "pci"))]
+pub mod window;
+#[cfg(all(target_os = "linux", feature = "xilinx"))]
+pub mod xilinx;
 
 #[cfg(target_os = "linux")]
 pub use linux::*;
+pub use offset::*;
+#[cfg(target_os = "linux")]
+pub use region::*;
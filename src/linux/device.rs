@@ -0,0 +1,1714 @@
+//! Opening, locking and claiming UIO devices.
+
+use super::error::{LockHolder, Operation, UioError};
+use super::injected_error;
+use super::irq::EventLog;
+use super::mmio::{Mapping, TrackedMappings};
+#[cfg(feature = "pci")]
+use super::pci::{PciIdentity, ResourceInfo};
+use super::sysfs::{driver_profile, DeviceKind, MappingInfo, MetadataCache};
+use devicetree::SubRegion;
+use libc;
+use parse;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use sys;
+
+/// Finds the PID of the process holding a lock on the inode `ino` of the
+/// device `major:minor` in the contents of `/proc/locks`.
+///
+/// Blocked waiters (`->` entries) and OFD locks (which report a PID of -1) are
+/// ignored.
+pub(super) fn parse_lock_owner(locks: &str, major: u32, minor: u32, ino: u64) -> Option<u32> {
+    let needle = format!("{:02x}:{:02x}:{}", major, minor, ino);
+    for line in locks.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // e.g. "1: FLOCK  ADVISORY  WRITE 1234 00:05:678 0 EOF"
+        if fields.len() < 6 || fields[1] == "->" {
+            continue;
+        }
+        if fields[5] == needle {
+            if let Ok(pid) = fields[4].parse::<u32>() {
+                return Some(pid);
+            }
+        }
+    }
+    None
+}
+
+/// An opened and locked UIO device.
+///
+/// # Thread safety
+///
+/// `UioDevice` is `Send` and `Sync`. All methods except `reopen` take `&self`,
+/// so a device can be shared through an `Arc`, e.g., with one thread blocking
+/// in `irq_wait` while another one configures the device. Alternatively
+/// `try_clone` creates an independent handle for the interrupt path.
+///
+/// # Real-time use
+///
+/// After a setup phase which opens the device, maps its regions and calls
+/// `preopen`, the following perform no heap allocation, path formatting or
+/// file opens (without the `tracing` and `metrics` features, and as long as
+/// they don't fail):
+///
+/// - the register accessors of `MappedRegion` (and `DeviceMemory`)
+/// - `irq_wait`, `irq_enable` and `irq_disable`
+/// - `get_event_count` and `wait_event_count`
+///
+/// So they can be called from a real-time thread, e.g.:
+///
+/// ```no_run
+/// # use uio::{DeviceMemory, UioDevice};
+/// let mut dev = UioDevice::try_new(0).unwrap();
+/// dev.preopen().unwrap();
+/// let regs = dev.map_region(0).unwrap();
+/// loop {
+///     dev.irq_wait().unwrap();
+///     regs.write_u32(0x4, regs.read_u32(0x4)); // acknowledge
+///     dev.irq_enable().unwrap();
+/// }
+/// ```
+pub struct UioDevice {
+    pub(super) uio_num: usize,
+    /// Path to the device file (e.g. /dev/uio0)
+    pub(super) dev_path: PathBuf,
+    /// Path to the sysfs directory of the device (e.g. /sys/class/uio/uio0)
+    pub(super) sysfs: PathBuf,
+    pub(super) devfile: File,
+    /// How the device file is opened again by `reopen`
+    dev_options: OpenOptions,
+    lock_mode: LockMode,
+    pub(super) access: Access,
+    pub(super) read_only: bool,
+    pub(super) auto_reenable: bool,
+    /// Mappings created by this device which will be unmapped on close/drop
+    pub(super) mappings: TrackedMappings,
+    /// Shared by all clones, the last one to go away releases the lock
+    claim: Arc<Claim>,
+    /// Files opened ahead of time by `preopen`, keyed by path
+    pub(super) preopened: Arc<HashMap<PathBuf, File>>,
+    /// The `event` attribute opened by `preopen`, read without allocating
+    pub(super) event: Option<Arc<File>>,
+    /// Cached sysfs metadata, if enabled with `cache_metadata`
+    pub(super) metadata: Option<Mutex<MetadataCache>>,
+    /// Where interrupts are recorded, shared by all clones
+    pub(super) event_log: Option<EventLog>,
+}
+
+/// Devices claimed by handles in this process, keyed by the device and inode
+/// numbers of their device file.
+///
+/// `flock` only keeps other processes away, so this makes a second open of
+/// the same device within the process fail (or share the first handle)
+/// instead of letting two components silently drive the same hardware.
+static CLAIMS: Mutex<BTreeMap<(u64, u64), Weak<Claim>>> = Mutex::new(BTreeMap::new());
+
+fn claims() -> MutexGuard<'static, BTreeMap<(u64, u64), Weak<Claim>>> {
+    CLAIMS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The claim of all handles to a device on it. Dropping the last handle
+/// releases the claim, which unlocks the device.
+#[derive(Debug)]
+struct Claim {
+    /// Key in `CLAIMS`, `None` for unlocked and shared devices which are not
+    /// claimed
+    key: Option<(u64, u64)>,
+    /// A duplicate of the locked device file, which shared handles are
+    /// created from
+    file: Option<File>,
+    read_only: bool,
+    /// How taking the lock went
+    lock_stats: LockStats,
+}
+
+impl Claim {
+    /// A claim for a device opened with `LockMode::Unlocked` or
+    /// `Access::Shared`, which doesn't keep other handles away.
+    fn unclaimed(read_only: bool, lock_stats: LockStats) -> Arc<Claim> {
+        Arc::new(Claim {
+            key: None,
+            file: None,
+            read_only,
+            lock_stats,
+        })
+    }
+
+    fn key(devfile: &File) -> io::Result<(u64, u64)> {
+        let metadata = devfile.metadata()?;
+        Ok((metadata.dev(), metadata.ino()))
+    }
+
+    /// The live claim on the device `devfile` refers to, if any.
+    fn find(devfile: &File) -> io::Result<Option<Arc<Claim>>> {
+        let key = Claim::key(devfile)?;
+        Ok(claims().get(&key).and_then(Weak::upgrade))
+    }
+
+    /// Claims the device of the locked `devfile`, replacing any previous
+    /// claim.
+    fn register(devfile: &File, read_only: bool, lock_stats: LockStats) -> io::Result<Arc<Claim>> {
+        let key = Claim::key(devfile)?;
+        let claim = Arc::new(Claim {
+            key: Some(key),
+            file: Some(devfile.try_clone()?),
+            read_only,
+            lock_stats,
+        });
+        claims().insert(key, Arc::downgrade(&claim));
+        Ok(claim)
+    }
+
+    /// Removes the claim from `CLAIMS` (unless it was replaced) and, if
+    /// `unlock` is set, unlocks the device.
+    fn release(&mut self, unlock: bool) -> io::Result<()> {
+        if let Some(key) = self.key.take() {
+            let mut claims = claims();
+            if claims.get(&key).is_some_and(|c| c.strong_count() == 0) {
+                claims.remove(&key);
+            }
+        }
+        match self.file.take() {
+            Some(ref file) if unlock => sys::unlock(file).and(sys::unlock_byte(file, MAPPING_LOCK)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let _ = self.release(true);
+    }
+}
+
+/// How a `UioDevice` is locked when it is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Block until an exclusive lock on the device is obtained.
+    Blocking,
+    /// Fail with `UioError::Locked` if the device is locked by someone else.
+    NonBlocking,
+    /// Don't lock the device at all.
+    Unlocked,
+}
+
+/// What a `UioDevice` is opened for, which determines whom the lock
+/// excludes (see `UioDeviceBuilder::access`).
+///
+/// | held \ requested | `Exclusive` | `Control` | `Shared` |
+/// |------------------|-------------|-----------|----------|
+/// | `Exclusive`      | locked      | locked    | locked   |
+/// | `Control`        | locked      | locked    | ok       |
+/// | `Shared`         | locked      | ok        | ok       |
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Access {
+    /// Sole access to the device, as with a plain `flock`. Read-only
+    /// handles can't keep `Shared` handles away.
+    #[default]
+    Exclusive,
+    /// Interrupt handling and configuration. Only one process can control a
+    /// device, but processes with `Shared` access can map it at the same
+    /// time.
+    Control,
+    /// Mapping access only, e.g. to read telemetry from a BAR, alongside
+    /// other `Shared` handles and a `Control` handle. Interrupts can't be
+    /// enabled or disabled through a shared handle.
+    Shared,
+}
+
+/// How the lock of a device was taken when it was opened, see
+/// `UioDevice::lock_stats`.
+///
+/// With `LockMode::Blocking`, every lock is tried without blocking first, so
+/// the process holding it can be recorded before waiting for it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Time spent taking the lock, including waiting for it
+    pub waited: Duration,
+    /// How often a lock was found taken and had to be waited for (the
+    /// mapping lock of `Access::Exclusive` and `Access::Shared` handles
+    /// counts separately)
+    pub contended: u32,
+    /// The process which held the lock when it was first found taken, if
+    /// it could be determined
+    pub holder: Option<LockHolder>,
+}
+
+/// The byte of the device file which `Exclusive` handles lock exclusively
+/// and `Shared` handles lock shared, with OFD locks (which are independent
+/// of the `flock` taken for control access).
+const MAPPING_LOCK: u64 = 0;
+
+/// How often to retry opening a device which isn't fully set up yet.
+///
+/// Right after hotplug or binding a driver, udev may not have created the
+/// device file yet and sysfs attributes can fail with `EAGAIN` or `ENOENT`
+/// for a few milliseconds. With a policy set through
+/// `UioDeviceBuilder::retry`, opening retries on these errors, doubling the
+/// delay after every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between attempts
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// 8 attempts within about 100ms.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 8,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(32),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Calls `op` until it succeeds, fails with a permanent error or the
+    /// attempts are used up, and returns the last result.
+    fn run<T, F>(&self, what: &str, mut op: F) -> Result<T, UioError>
+    where
+        F: FnMut() -> Result<T, UioError>,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(ref e) if attempt < self.attempts && is_transient(e) => {
+                    #[cfg(feature = "tracing")]
+                    ::tracing::debug!(attempt, error = %e, what, "uio_retry");
+                    #[cfg(not(feature = "tracing"))]
+                    let _ = (e, what);
+                }
+                res => return res,
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(self.max_delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// Whether `e` may go away by itself while a device is being set up.
+fn is_transient(e: &UioError) -> bool {
+    match *e {
+        UioError::Io { .. } => {
+            matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::ENOENT))
+        }
+        _ => false,
+    }
+}
+
+/// Options to configure how a `UioDevice` is opened.
+///
+/// ```no_run
+/// use uio::{LockMode, UioDeviceBuilder};
+///
+/// let dev = UioDeviceBuilder::new(0)
+///     .lock(LockMode::Blocking)
+///     .auto_reenable(true)
+///     .open()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UioDeviceBuilder {
+    pub(super) uio_num: usize,
+    lock_mode: LockMode,
+    access: Access,
+    custom_flags: i32,
+    sysfs_root: PathBuf,
+    dev_root: PathBuf,
+    read_only: bool,
+    /// `None` to follow the driver profile
+    auto_reenable: Option<bool>,
+    cache_metadata: bool,
+    shared: bool,
+    retry: Option<RetryPolicy>,
+    open_options: Option<OpenOptions>,
+    event_log: Option<EventLog>,
+}
+
+impl UioDeviceBuilder {
+    /// Creates a builder for /dev/uio`uio_num` with default options.
+    ///
+    /// By default the device is locked without blocking, opened for reading
+    /// and writing and looked up in `/dev` and `/sys/class/uio`.
+    pub fn new(uio_num: usize) -> UioDeviceBuilder {
+        UioDeviceBuilder {
+            uio_num,
+            lock_mode: LockMode::NonBlocking,
+            access: Access::Exclusive,
+            custom_flags: 0,
+            sysfs_root: PathBuf::from("/sys/class/uio"),
+            dev_root: PathBuf::from("/dev"),
+            read_only: false,
+            auto_reenable: None,
+            cache_metadata: false,
+            shared: false,
+            retry: None,
+            open_options: None,
+            event_log: None,
+        }
+    }
+
+    /// Creates a builder for the device with the sysfs directory `syspath`,
+    /// as handed out by udev (e.g.
+    /// `/sys/devices/platform/amba/a0000000.dma/uio/uio2`).
+    ///
+    /// The UIO number and the device file are taken from the `DEVNAME` in
+    /// the `uevent` attribute, or from the directory name if there is none.
+    /// The sysfs attributes are read from `syspath`, not from
+    /// `/sys/class/uio`.
+    pub fn from_syspath<P: AsRef<Path>>(syspath: P) -> Result<UioDeviceBuilder, UioError> {
+        let syspath = syspath.as_ref();
+        let not_uio = || UioError::Io {
+            op: Operation::Read,
+            path: syspath.to_path_buf(),
+            source: io::Error::new(io::ErrorKind::InvalidInput, "not a UIO device directory"),
+        };
+        let dir_name = syspath
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(not_uio)?;
+        let uio_num = parse::index("uio", dir_name).ok_or_else(not_uio)?;
+        let uevent = syspath.join("uevent");
+        let devname = match fs::read_to_string(&uevent) {
+            Ok(contents) => contents
+                .lines()
+                .find_map(|l| l.strip_prefix("DEVNAME="))
+                .map(PathBuf::from),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(UioError::io(Operation::Read, uevent)(e)),
+        };
+        let mut builder = UioDeviceBuilder::new(uio_num);
+        if let Some(devname) = devname {
+            // The device file has to be found as `uio<N>` like the directory
+            if devname.file_name().and_then(|n| n.to_str()) != Some(dir_name) {
+                return Err(not_uio());
+            }
+            if let Some(dir) = devname.parent() {
+                builder.dev_root(Path::new("/dev").join(dir));
+            }
+        }
+        builder.sysfs_root(syspath.parent().unwrap_or(Path::new("/")));
+        Ok(builder)
+    }
+
+    /// Sets how the device is locked.
+    pub fn lock(&mut self, mode: LockMode) -> &mut Self {
+        self.lock_mode = mode;
+        self
+    }
+
+    /// Sets what the device is opened for (default: `Access::Exclusive`).
+    ///
+    /// E.g., one process can handle interrupts and configure the device with
+    /// `Control` access while a monitoring process maps a BAR with `Shared`
+    /// access:
+    ///
+    /// ```no_run
+    /// use uio::{Access, UioDeviceBuilder};
+    ///
+    /// let telemetry = UioDeviceBuilder::new(0)
+    ///     .access(Access::Shared)
+    ///     .read_only(true)
+    ///     .open()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Handles with `Shared` access are not claimed in the process (see
+    /// `shared`), so any number of them can be opened.
+    pub fn access(&mut self, access: Access) -> &mut Self {
+        self.access = access;
+        self
+    }
+
+    /// Passes additional flags (e.g., `libc::O_SYNC`) to `open(2)`. Ignored
+    /// if `open_options` is set.
+    pub fn custom_flags(&mut self, flags: i32) -> &mut Self {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Sets the directory containing the `uioN` sysfs directories
+    /// (default: `/sys/class/uio`).
+    pub fn sysfs_root<P: Into<PathBuf>>(&mut self, root: P) -> &mut Self {
+        self.sysfs_root = root.into();
+        self
+    }
+
+    /// Sets the directory containing the `uioN` device files (default: `/dev`).
+    pub fn dev_root<P: Into<PathBuf>>(&mut self, root: P) -> &mut Self {
+        self.dev_root = root.into();
+        self
+    }
+
+    /// Opens the device and its resources read-only and maps them without
+    /// write permission. Enabling or disabling interrupts is not possible in
+    /// this mode.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Re-enables the interrupt before every `irq_wait`, as needed by drivers
+    /// like `uio_pci_generic` which disable the interrupt after each event.
+    ///
+    /// By default this is enabled for writable devices with control access
+    /// whose driver profile (see `UioDevice::profile`) requires it.
+    pub fn auto_reenable(&mut self, auto_reenable: bool) -> &mut Self {
+        self.auto_reenable = Some(auto_reenable);
+        self
+    }
+
+    /// Caches sysfs metadata: the name, version and mapping attributes are
+    /// read once on first use and the `event` file is kept open, so hot
+    /// paths calling e.g. `map_size` or `get_event_count` don't open and
+    /// read files every time. Use `UioDevice::refresh` after the device
+    /// changed (e.g., after reconfiguring an FPGA).
+    pub fn cache_metadata(&mut self, cache: bool) -> &mut Self {
+        self.cache_metadata = cache;
+        self
+    }
+
+    /// If the device is already open in this process, returns a handle
+    /// sharing it (like `UioDevice::try_clone`) instead of failing with
+    /// `UioError::AlreadyClaimed`. The new handle uses the lock and file of
+    /// the existing one, including its read-only mode.
+    ///
+    /// Devices opened with `LockMode::Unlocked` are never claimed, so they
+    /// can always be opened again.
+    pub fn shared(&mut self, shared: bool) -> &mut Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Retries opening the device file and reading sysfs attributes while
+    /// opening on `EAGAIN` and `ENOENT` (see `RetryPolicy`), instead of
+    /// failing right away. Not set by default.
+    ///
+    /// Locking is never retried, see `lock` for waiting for the lock.
+    pub fn retry(&mut self, policy: Option<RetryPolicy>) -> &mut Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Records every interrupt returned by `irq_wait` (and by
+    /// `UioDeviceGroup`) in `log`. Not set by default.
+    pub fn event_log(&mut self, log: Option<EventLog>) -> &mut Self {
+        self.event_log = log;
+        self
+    }
+
+    /// Opens the device file with `options` instead of for reading and
+    /// (unless `read_only`) writing with `custom_flags`, e.g. for drivers
+    /// which behave differently depending on the open flags:
+    ///
+    /// ```no_run
+    /// use std::fs::OpenOptions;
+    /// use std::os::unix::fs::OpenOptionsExt;
+    /// use uio::UioDeviceBuilder;
+    ///
+    /// let mut options = OpenOptions::new();
+    /// options.read(true).write(true).custom_flags(libc::O_SYNC);
+    /// let dev = UioDeviceBuilder::new(0)
+    ///     .open_options(Some(options))
+    ///     .open()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// `reopen` uses the same options. The options have to allow what the
+    /// device is used for: `read_only` still decides whether mappings are
+    /// writable and interrupts can be controlled, and resource files are
+    /// opened as usual.
+    pub fn open_options(&mut self, options: Option<OpenOptions>) -> &mut Self {
+        self.open_options = options;
+        self
+    }
+
+    /// The options the device file is opened with.
+    fn dev_options(&self) -> OpenOptions {
+        match self.open_options {
+            Some(ref options) => options.clone(),
+            None => {
+                let mut options = OpenOptions::new();
+                options
+                    .read(true)
+                    .write(!self.read_only)
+                    .custom_flags(self.custom_flags);
+                options
+            }
+        }
+    }
+
+    fn dev_path(&self) -> PathBuf {
+        self.dev_root.join(format!("uio{}", self.uio_num))
+    }
+
+    /// Calls `op` once or according to the retry policy.
+    fn retrying<T, F>(&self, what: &str, mut op: F) -> Result<T, UioError>
+    where
+        F: FnMut() -> Result<T, UioError>,
+    {
+        match self.retry {
+            Some(ref policy) => policy.run(what, op),
+            None => op(),
+        }
+    }
+
+    /// Opens and locks the device.
+    ///
+    /// Fails with `UioError::AlreadyClaimed` if the device is already open in
+    /// this process, unless `shared` is set.
+    pub fn open(&self) -> Result<UioDevice, UioError> {
+        span!("uio_open", uio_num = self.uio_num);
+        let dev_path = self.dev_path();
+        let options = self.dev_options();
+        let devfile = self.retrying("open", || {
+            timed!(
+                "open",
+                injected_error(Operation::Open, &dev_path).and_then(|()| options.open(&dev_path)),
+                path = dev_path
+            )
+            .map_err(UioError::io(Operation::Open, &dev_path))
+        })?;
+        self.build(devfile)
+    }
+
+    /// Creates the device from an already opened device file descriptor.
+    ///
+    /// See `UioDevice::from_fd`.
+    pub fn open_fd(&self, fd: fd::OwnedFd) -> Result<UioDevice, UioError> {
+        let devfile = File::from(fd);
+        sys::set_cloexec(&devfile, true).map_err(UioError::io(Operation::Open, self.dev_path()))?;
+        self.build(devfile)
+    }
+
+    /// Determines which process currently holds the lock on the device file
+    /// in the configured device directory, see `UioDevice::lock_holder`.
+    pub fn lock_holder(&self) -> io::Result<Option<LockHolder>> {
+        lock_holder(&self.dev_path())
+    }
+
+    fn build(&self, devfile: File) -> Result<UioDevice, UioError> {
+        let dev_path = self.dev_path();
+        let sysfs = self.sysfs_root.join(format!("uio{}", self.uio_num));
+        let (devfile, claim) = self.claim(devfile, &dev_path)?;
+        let auto_reenable = self.auto_reenable.unwrap_or_else(|| {
+            !claim.read_only
+                && self.access != Access::Shared
+                && self
+                    .retrying("driver_profile", || driver_profile(&sysfs))
+                    .is_ok_and(|p| p.is_some_and(|p| p.reenable))
+        });
+        Ok(UioDevice {
+            uio_num: self.uio_num,
+            sysfs,
+            dev_path,
+            devfile,
+            dev_options: self.dev_options(),
+            lock_mode: self.lock_mode,
+            access: self.access,
+            read_only: claim.read_only,
+            auto_reenable,
+            mappings: TrackedMappings::default(),
+            claim,
+            preopened: Arc::new(HashMap::new()),
+            event: None,
+            metadata: if self.cache_metadata {
+                Some(Mutex::default())
+            } else {
+                None
+            },
+            event_log: self.event_log.clone(),
+        })
+    }
+
+    /// Locks `devfile` and claims the device for this process. If the device
+    /// is already claimed and `shared` is set, returns a duplicate of the
+    /// claimed file instead.
+    fn claim(&self, devfile: File, dev_path: &Path) -> Result<(File, Arc<Claim>), UioError> {
+        if self.lock_mode == LockMode::Unlocked || self.access == Access::Shared {
+            let stats = lock(&devfile, dev_path, self.lock_mode, self.access)?;
+            return Ok((devfile, Claim::unclaimed(self.read_only, stats)));
+        }
+        if let Some(claim) =
+            Claim::find(&devfile).map_err(UioError::io(Operation::Lock, dev_path))?
+        {
+            if !self.shared {
+                return Err(UioError::AlreadyClaimed {
+                    path: dev_path.into(),
+                });
+            }
+            let file = match claim.file {
+                Some(ref file) => file
+                    .try_clone()
+                    .map_err(UioError::io(Operation::Open, dev_path))?,
+                None => devfile,
+            };
+            return Ok((file, claim));
+        }
+        let stats = lock(&devfile, dev_path, self.lock_mode, self.access)?;
+        let claim = Claim::register(&devfile, self.read_only, stats)
+            .map_err(UioError::io(Operation::Lock, dev_path))?;
+        Ok((devfile, claim))
+    }
+}
+
+/// Returns the numbers of all UIO devices (e.g. `[0, 1]` for uio0 and uio1).
+pub fn list_devices() -> Result<Vec<usize>, UioError> {
+    list_devices_in(Path::new("/sys/class/uio"))
+}
+
+/// Returns the numbers of all UIO devices in the sysfs directory `root`.
+///
+/// See `UioDeviceBuilder::sysfs_root`.
+pub fn list_devices_in(root: &Path) -> Result<Vec<usize>, UioError> {
+    let mut devices = Vec::new();
+    let Some(entries) = UioDevice::read_dir_if_exists(root)? else {
+        return Ok(devices);
+    };
+    for entry in entries {
+        let entry = entry.map_err(UioError::io(Operation::ReadDir, root))?;
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str() else {
+            continue;
+        };
+        if let Some(num) = parse::index("uio", name) {
+            devices.push(num);
+        }
+    }
+    devices.sort_unstable();
+    Ok(devices)
+}
+
+/// Locks `devfile` for `access` according to `mode`, reporting the lock
+/// holder on contention.
+fn lock(
+    devfile: &File,
+    path: &Path,
+    mode: LockMode,
+    access: Access,
+) -> Result<LockStats, UioError> {
+    let start = Instant::now();
+    let mut stats = LockStats::default();
+    let res =
+        timed!(
+            "lock",
+            match mode {
+                LockMode::Blocking => injected_error(Operation::Lock, path)
+                    .and_then(|()| lock_access(devfile, path, access, Some(&mut stats))),
+                LockMode::NonBlocking => injected_error(Operation::Lock, path)
+                    .and_then(|()| lock_access(devfile, path, access, None)),
+                LockMode::Unlocked => Ok(()),
+            },
+            path = path,
+            mode = mode,
+            access = access
+        );
+    stats.waited = start.elapsed();
+    metric!(
+        histogram,
+        "uio_lock_wait_seconds",
+        path,
+        record(stats.waited.as_secs_f64())
+    );
+    #[cfg(feature = "tracing")]
+    if stats.contended > 0 {
+        ::tracing::info!(
+            path = ?path,
+            waited_us = stats.waited.as_micros() as u64,
+            contended = stats.contended,
+            holder_pid = stats.holder.as_ref().map(|h| h.pid),
+            holder_command = stats.holder.as_ref().and_then(|h| h.command.as_deref()),
+            "uio_lock_contended"
+        );
+    }
+    match res {
+        Ok(()) => Ok(stats),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(UioError::Locked {
+            path: path.into(),
+            holder: lock_holder(path).unwrap_or(None),
+        }),
+        Err(e) => Err(UioError::io(Operation::Lock, path)(e)),
+    }
+}
+
+/// Takes the locks `access` needs on `devfile`: the `flock` for all but
+/// shared access, and the `MAPPING_LOCK` byte for all but control access.
+///
+/// Waits for contended locks if `stats` is given, recording the contention
+/// in it.
+fn lock_access(
+    devfile: &File,
+    path: &Path,
+    access: Access,
+    mut stats: Option<&mut LockStats>,
+) -> io::Result<()> {
+    if access != Access::Shared {
+        acquire(path, stats.as_deref_mut(), |wait| {
+            if wait {
+                sys::lock_exclusive(devfile)
+            } else {
+                sys::try_lock_exclusive(devfile)
+            }
+        })?;
+    }
+    let res = match access {
+        // Exclusive OFD locks need a writable file, read-only handles can
+        // only exclude controllers
+        Access::Exclusive => match acquire(path, stats, |wait| {
+            sys::lock_byte(devfile, MAPPING_LOCK, true, wait)
+        }) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EBADF) => Ok(()),
+            res => res,
+        },
+        Access::Shared => acquire(path, stats, |wait| {
+            sys::lock_byte(devfile, MAPPING_LOCK, false, wait)
+        }),
+        Access::Control => Ok(()),
+    };
+    if res.is_err() && access == Access::Exclusive {
+        let _ = sys::unlock(devfile);
+    }
+    res
+}
+
+/// Takes a lock with `take(wait)`, first without waiting. If the lock is
+/// taken and `stats` is given, records the contention and waits for it.
+fn acquire<F>(path: &Path, stats: Option<&mut LockStats>, mut take: F) -> io::Result<()>
+where
+    F: FnMut(bool) -> io::Result<()>,
+{
+    match (take(false), stats) {
+        (Err(ref e), Some(stats)) if e.kind() == io::ErrorKind::WouldBlock => {
+            stats.contended += 1;
+            if stats.holder.is_none() {
+                stats.holder = lock_holder(path).unwrap_or(None);
+            }
+            take(true)
+        }
+        (res, _) => res,
+    }
+}
+
+/// Determines which process holds the lock on the device file `path`.
+fn lock_holder(path: &Path) -> io::Result<Option<LockHolder>> {
+    let metadata = fs::metadata(path)?;
+    let dev = metadata.dev() as libc::dev_t;
+    let locks = fs::read_to_string("/proc/locks")?;
+
+    Ok(
+        parse_lock_owner(&locks, libc::major(dev), libc::minor(dev), metadata.ino()).map(|pid| {
+            let command = fs::read_to_string(format!("/proc/{}/comm", pid))
+                .ok()
+                .map(|c| c.trim().to_string());
+            LockHolder { pid, command }
+        }),
+    )
+}
+
+impl fd::AsFd for UioDevice {
+    fn as_fd(&self) -> fd::BorrowedFd<'_> {
+        self.devfile.as_fd()
+    }
+}
+
+impl fd::AsRawFd for UioDevice {
+    fn as_raw_fd(&self) -> fd::RawFd {
+        self.devfile.as_raw_fd()
+    }
+}
+
+/// A description of a UIO device, as returned by `UioDevice::info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UioDeviceInfo {
+    /// UIO device number (e.g. 0 for /dev/uio0)
+    pub uio_num: usize,
+    pub name: String,
+    pub version: String,
+    pub event_count: u32,
+    pub kind: DeviceKind,
+    pub mappings: Vec<MappingInfo>,
+    #[cfg(feature = "pci")]
+    pub resources: Vec<ResourceInfo>,
+    /// The kernel driver, e.g. "uio_pci_generic"
+    pub driver: Option<String>,
+    /// The identity of PCI devices
+    #[cfg(feature = "pci")]
+    pub pci: Option<PciIdentity>,
+    /// Blocks described by the device tree (see `UioDevice::sub_regions`)
+    pub sub_regions: Vec<SubRegion>,
+}
+
+#[cfg(feature = "json")]
+impl UioDeviceInfo {
+    /// The description as a JSON object, with the field names of this
+    /// struct, e.g. for inventory tools.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("device info is always serializable")
+    }
+}
+
+impl UioDevice {
+    /// Returns a builder to open /dev/uio`uio_num` with custom options.
+    pub fn builder(uio_num: usize) -> UioDeviceBuilder {
+        UioDeviceBuilder::new(uio_num)
+    }
+
+    /// Opens `path` for reading and, unless the device is read-only, writing.
+    pub(super) fn open_file(&self, path: &Path) -> Result<File, UioError> {
+        injected_error(Operation::Open, path)
+            .and_then(|()| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(!self.read_only)
+                    .open(path)
+            })
+            .map_err(UioError::io(Operation::Open, path))
+    }
+
+    #[deprecated(since = "0.3.0", note = "Use blocking_new or try_new instead")]
+    pub fn new(uio_num: usize) -> Result<UioDevice, UioError> {
+        Self::blocking_new(uio_num)
+    }
+
+    /// Creates a new UIO device for Linux.
+    ///
+    /// This variant will block until it can obtain an exclusive lock on the
+    /// uio device.
+    ///
+    /// # Arguments
+    ///  * uio_num - UIO index of device (i.e., 1 for /dev/uio1)
+    pub fn blocking_new(uio_num: usize) -> Result<UioDevice, UioError> {
+        UioDeviceBuilder::new(uio_num)
+            .lock(LockMode::Blocking)
+            .open()
+    }
+
+    /// Creates a new UIO device for Linux.
+    ///
+    /// This variant will return `UioError::Locked` instead of blocking, if it
+    /// can't obtain an exclusive lock on the uio device.
+    ///
+    /// # Arguments
+    ///  * uio_num - UIO index of device (i.e., 1 for /dev/uio1)
+    pub fn try_new(uio_num: usize) -> Result<UioDevice, UioError> {
+        UioDeviceBuilder::new(uio_num)
+            .lock(LockMode::NonBlocking)
+            .open()
+    }
+
+    /// Opens the device with the sysfs directory `syspath` without blocking,
+    /// see `UioDeviceBuilder::from_syspath`.
+    ///
+    /// ```no_run
+    /// let dev = uio::UioDevice::open_by_syspath(
+    ///     "/sys/devices/platform/amba/a0000000.dma/uio/uio2",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(dev.get_num(), 2);
+    /// ```
+    pub fn open_by_syspath<P: AsRef<Path>>(syspath: P) -> Result<UioDevice, UioError> {
+        UioDeviceBuilder::from_syspath(syspath)?.open()
+    }
+
+    /// Creates a UIO device from an already opened /dev/uioX file descriptor.
+    ///
+    /// This is useful if the device was opened by another (privileged)
+    /// process and handed over. The lock is taken without blocking; since
+    /// `flock` locks belong to the open file, this succeeds if the sender
+    /// already holds the lock on `fd`.
+    ///
+    /// # Arguments
+    ///  * fd - Open file descriptor of /dev/uio`uio_num`
+    ///  * uio_num - UIO index of device (i.e., 1 for /dev/uio1)
+    pub fn from_fd(fd: fd::OwnedFd, uio_num: usize) -> Result<UioDevice, UioError> {
+        UioDeviceBuilder::new(uio_num).open_fd(fd)
+    }
+
+    /// Determines which process currently holds the lock on /dev/uio`uio_num`.
+    ///
+    /// This inspects `/proc/locks` and returns `None` if the device is not
+    /// locked or the owner can not be identified (e.g., for OFD locks). See
+    /// `UioDeviceBuilder::lock_holder` for devices under another device
+    /// directory.
+    ///
+    /// # Arguments
+    ///  * uio_num - UIO index of device (i.e., 1 for /dev/uio1)
+    pub fn lock_holder(uio_num: usize) -> io::Result<Option<LockHolder>> {
+        UioDeviceBuilder::new(uio_num).lock_holder()
+    }
+
+    /// Describes the device: its name, version, kind, event count, mappings,
+    /// PCI resources (see `resources`), driver, PCI identity and device tree
+    /// sub-regions.
+    pub fn info(&mut self) -> Result<UioDeviceInfo, UioError> {
+        let mut mappings = self.get_mapping_info()?;
+        mappings.sort_by_key(|m| m.index);
+        let kind = self.device_kind()?;
+        Ok(UioDeviceInfo {
+            uio_num: self.uio_num,
+            name: self.get_name()?,
+            version: self.get_version()?,
+            event_count: self.get_event_count()?,
+            #[cfg(feature = "pci")]
+            pci: if kind == DeviceKind::Pci {
+                Some(self.pci_identity()?)
+            } else {
+                None
+            },
+            kind,
+            mappings,
+            #[cfg(feature = "pci")]
+            resources: self.resources()?,
+            driver: self.driver_name()?,
+            sub_regions: self.sub_regions()?,
+        })
+    }
+
+    /// The description of the device (see `info`) as JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&mut self) -> Result<String, UioError> {
+        Ok(self.info()?.to_json())
+    }
+
+    /// Closes the device, unmapping all (non-leaked) mappings and releasing
+    /// the lock.
+    ///
+    /// Dropping the device does the same but ignores errors. Pointers
+    /// obtained from `map_resource` or `map_mapping` must not be used
+    /// afterwards.
+    pub fn close(mut self) -> Result<(), UioError> {
+        let mappings: Vec<Mapping> = self.tracked_mappings().drain(..).collect();
+        let mut res = Ok(());
+        for mapping in mappings {
+            if let Err(e) = self.unmap_mapping(&mapping) {
+                res = Err(e);
+            }
+        }
+        let claim = mem::replace(
+            &mut self.claim,
+            Claim::unclaimed(self.read_only, LockStats::default()),
+        );
+        if let Ok(mut claim) = Arc::try_unwrap(claim) {
+            claim
+                .release(true)
+                .map_err(UioError::io(Operation::Lock, &self.dev_path))?;
+        }
+        res
+    }
+
+    /// How taking the lock went when the device was opened (or last
+    /// reopened), e.g. to find out which process kept it locked. Handles
+    /// sharing a lock (see `try_clone` and `UioDeviceBuilder::shared`) report
+    /// the same statistics; unlocked ones report zero.
+    pub fn lock_stats(&self) -> &LockStats {
+        &self.claim.lock_stats
+    }
+
+    /// Creates a new handle to the same device by duplicating the file descriptor.
+    ///
+    /// The clone shares the lock with `self` (it is released once the last
+    /// handle is dropped) but not the mappings: mappings created through one
+    /// handle are only tracked and unmapped by that handle. A typical use is
+    /// to dedicate one handle to `irq_wait` on a blocking thread while
+    /// another one performs control operations.
+    pub fn try_clone(&self) -> io::Result<UioDevice> {
+        Ok(UioDevice {
+            uio_num: self.uio_num,
+            dev_path: self.dev_path.clone(),
+            sysfs: self.sysfs.clone(),
+            devfile: self.devfile.try_clone()?,
+            dev_options: self.dev_options.clone(),
+            lock_mode: self.lock_mode,
+            access: self.access,
+            read_only: self.read_only,
+            auto_reenable: self.auto_reenable,
+            mappings: TrackedMappings::default(),
+            claim: self.claim.clone(),
+            preopened: self.preopened.clone(),
+            event: self.event.clone(),
+            metadata: self.metadata.as_ref().map(|_| Mutex::default()),
+            event_log: self.event_log.clone(),
+        })
+    }
+
+    /// Controls whether the device file descriptor is inherited across `exec`.
+    ///
+    /// All file descriptors opened by this crate have `O_CLOEXEC` set by
+    /// default (including ones passed to `from_fd`). Passing `true` clears the
+    /// flag for the device file so an exec'd worker process can use it.
+    pub fn set_inheritable(&self, inheritable: bool) -> Result<(), UioError> {
+        sys::set_cloexec(&self.devfile, !inheritable)
+            .map_err(UioError::io(Operation::Write, &self.dev_path))
+    }
+
+    /// Dissolves the device into its file descriptor and UIO number.
+    ///
+    /// The lock stays held by the returned file descriptor (it is released
+    /// when the last copy of it is closed) and the close-on-exec flag is left
+    /// as is, but the device is no longer claimed in this process once the
+    /// last handle is gone. Tracked mappings are unmapped like in `close`.
+    /// The parts can be turned back into a device with `from_fd`.
+    pub fn into_parts(self) -> (fd::OwnedFd, usize) {
+        // The other fields, including the tracked mappings, are dropped as
+        // usual
+        let UioDevice {
+            uio_num,
+            devfile,
+            claim,
+            ..
+        } = self;
+        if let Ok(mut claim) = Arc::try_unwrap(claim) {
+            let _ = claim.release(false);
+        }
+        (devfile.into(), uio_num)
+    }
+
+    /// Reopens the device after a reset (e.g., FPGA reconfiguration or PCI reset).
+    ///
+    /// This releases the lock, opens /dev/uioX again (with the options it
+    /// was opened with, see `UioDeviceBuilder::open_options`), re-takes the lock
+    /// (blocking only if the device was opened with `LockMode::Blocking`)
+    /// and remaps all tracked mappings at their previous
+    /// addresses, so existing pointers refer to the fresh device state.
+    /// Mappings which can't be restored (e.g., because the region shrunk) are
+    /// unmapped and their addresses are returned. Handles created with
+    /// `try_clone` keep referring to the old file and should be recreated.
+    ///
+    /// If the lock can't be taken on the new file, the device keeps using
+    /// the old one and takes the lock on it again. Should someone else have
+    /// taken the lock in between, the device is left unlocked and unclaimed,
+    /// like one opened with `LockMode::Unlocked`.
+    pub fn reopen(&mut self) -> Result<Vec<*mut libc::c_void>, UioError> {
+        let devfile = injected_error(Operation::Open, &self.dev_path)
+            .and_then(|()| self.dev_options.open(&self.dev_path))
+            .map_err(UioError::io(Operation::Open, &self.dev_path))?;
+        // The lock of the old file would keep the new one from being locked
+        let _ = sys::unlock(&self.devfile);
+        let _ = sys::unlock_byte(&self.devfile, MAPPING_LOCK);
+        let claim = lock(&devfile, &self.dev_path, self.lock_mode, self.access).and_then(|stats| {
+            if self.lock_mode == LockMode::Unlocked || self.access == Access::Shared {
+                Ok(Claim::unclaimed(self.read_only, stats))
+            } else {
+                Claim::register(&devfile, self.read_only, stats)
+                    .map_err(UioError::io(Operation::Lock, &self.dev_path))
+            }
+        });
+        self.claim = match claim {
+            Ok(claim) => claim,
+            Err(e) => {
+                // Closing the new file drops any lock taken on it
+                drop(devfile);
+                if self.lock_mode != LockMode::Unlocked
+                    && lock_access(&self.devfile, &self.dev_path, self.access, None).is_err()
+                {
+                    self.claim = Claim::unclaimed(self.read_only, LockStats::default());
+                }
+                return Err(e);
+            }
+        };
+        self.devfile = devfile;
+        self.refresh();
+
+        let old: Vec<Mapping> = self.tracked_mappings().drain(..).collect();
+        let mut invalidated = Vec::new();
+        for mapping in old {
+            match self.mmap_source(
+                mapping.source,
+                mapping.start,
+                Some(mapping.len),
+                Some(mapping.addr),
+            ) {
+                Ok(m) => self.tracked_mappings().push(Mapping {
+                    region: mapping.region,
+                    ..m
+                }),
+                Err(_) => {
+                    let _ = mapping.unmap();
+                    invalidated.push(mapping.addr as *mut libc::c_void);
+                }
+            }
+        }
+
+        Ok(invalidated)
+    }
+
+    /// UIO device number (e.g. 0 for /dev/uio0)
+    pub fn get_num(&self) -> usize {
+        self.uio_num
+    }
+
+    /// Path to UIO device file (e.g. "/dev/uio0")
+    pub fn get_dev_path(&self) -> impl AsRef<std::path::Path> {
+        self.dev_path.clone()
+    }
+
+    /// The sysfs directory of the device (e.g. /sys/class/uio/uio0).
+    pub(crate) fn sysfs_path(&self) -> &Path {
+        &self.sysfs
+    }
+
+    /// The options the device was opened with: lock mode, access, read-only
+    /// and automatic re-enabling of the interrupt.
+    pub(crate) fn options(&self) -> (LockMode, Access, bool, bool) {
+        (
+            self.lock_mode,
+            self.access,
+            self.read_only,
+            self.auto_reenable,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use linux::pci_mock;
+
+    #[test]
+    fn open() {
+        let mock = pci_mock();
+        let res = mock.open();
+        match res {
+            Err(e) => {
+                panic!("Can not open device {}: {}", mock.dev_path().display(), e);
+            }
+            Ok(_f) => (),
+        }
+    }
+
+    #[test]
+    fn lock_owner() {
+        let locks = "1: POSIX  ADVISORY  WRITE 812 00:19:1023 0 EOF\n\
+                     2: FLOCK  ADVISORY  WRITE 1337 00:05:478 0 EOF\n\
+                     2: -> FLOCK  ADVISORY  WRITE 4242 00:05:478 0 EOF\n\
+                     3: OFDLCK ADVISORY  WRITE -1 00:05:479 0 EOF\n";
+        assert_eq!(::linux::parse_lock_owner(locks, 0, 5, 478), Some(1337));
+        assert_eq!(::linux::parse_lock_owner(locks, 0, 5, 479), None);
+        assert_eq!(::linux::parse_lock_owner(locks, 0, 5, 1), None);
+    }
+
+    #[test]
+    fn list() {
+        let mock = pci_mock();
+        let devices = ::linux::list_devices_in(&mock.sysfs_root()).unwrap();
+        assert_eq!(devices, vec![0]);
+    }
+
+    #[test]
+    fn lock_contention() {
+        let mock = pci_mock();
+        let holder = mock.hold_lock().unwrap();
+        match mock.open() {
+            Err(::linux::UioError::Locked { holder, .. }) => {
+                assert_eq!(holder.map(|h| h.pid), Some(::std::process::id()));
+            }
+            res => panic!("expected lock contention, got {:?}", res.map(|_| ())),
+        }
+        let owner = mock.builder().lock_holder().unwrap();
+        assert_eq!(owner.map(|h| h.pid), Some(::std::process::id()));
+        drop(holder);
+        assert_eq!(mock.builder().lock_holder().unwrap(), None);
+
+        // Clones share the lock, dropping one keeps the device locked
+        let dev = mock.open().unwrap();
+        let clone = dev.try_clone().unwrap();
+        drop(dev);
+        assert!(mock.open().is_err());
+        drop(clone);
+        assert!(mock.open().is_ok());
+    }
+
+    #[test]
+    fn reopen_keeps_lock() {
+        use linux::{Operation, UioError};
+        use mock::Fault;
+
+        let mock = pci_mock();
+        let mut dev = mock.open().unwrap();
+        // Someone else taking the lock of the new file first
+        mock.inject(Operation::Lock, Fault::Error(libc::EWOULDBLOCK));
+        match dev.reopen() {
+            Err(UioError::Locked { .. }) => {}
+            res => panic!("expected lock contention, got {:?}", res),
+        }
+        mock.clear_faults();
+        // Locked again through the old file
+        assert!(mock.hold_lock().is_err());
+        assert!(mock.open().is_err());
+        dev.reopen().unwrap();
+        assert!(mock.hold_lock().is_err());
+        drop(dev);
+
+        let holder = mock.hold_lock().unwrap();
+        drop(holder);
+        assert!(mock.open().is_ok());
+    }
+
+    #[test]
+    fn lock_stats() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mock = pci_mock();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.lock_stats().contended, 0);
+        assert_eq!(dev.lock_stats().holder, None);
+        drop(dev);
+
+        let holder = mock.hold_lock().unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(holder);
+        });
+        let dev = mock
+            .builder()
+            .lock(::linux::LockMode::Blocking)
+            .open()
+            .unwrap();
+        release.join().unwrap();
+        let stats = dev.lock_stats();
+        assert_eq!(stats.contended, 1);
+        assert_eq!(
+            stats.holder.as_ref().map(|h| h.pid),
+            Some(::std::process::id())
+        );
+        assert!(stats.waited >= Duration::from_millis(10));
+        assert_eq!(dev.try_clone().unwrap().lock_stats(), stats);
+    }
+
+    #[test]
+    fn double_open() {
+        let mock = pci_mock();
+        let dev = mock.open().unwrap();
+        let err = mock.open().map(|_| ()).unwrap_err();
+        assert!(matches!(err, ::linux::UioError::AlreadyClaimed { .. }));
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        // A shared handle keeps the device claimed and locked after the
+        // first one is gone
+        let shared = mock.builder().shared(true).open().unwrap();
+        dev.close().unwrap();
+        assert!(mock.open().is_err());
+        assert!(mock.hold_lock().is_err());
+        drop(shared);
+        assert!(mock.hold_lock().is_ok());
+
+        // Unlocked devices are not claimed
+        let dev = mock.open().unwrap();
+        let unlocked = mock
+            .builder()
+            .lock(::linux::LockMode::Unlocked)
+            .open()
+            .unwrap();
+        drop(dev);
+        assert!(mock.open().is_ok());
+        drop(unlocked);
+    }
+
+    #[test]
+    fn open_options() {
+        use linux::UioDevice;
+        use std::fs::OpenOptions;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mock = pci_mock();
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_APPEND | libc::O_SYNC);
+        let mut dev = mock.builder().open_options(Some(options)).open().unwrap();
+        let flags = |dev: &UioDevice| unsafe { libc::fcntl(dev.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags(&dev) & libc::O_SYNC, libc::O_SYNC);
+        dev.reopen().unwrap();
+        assert_eq!(flags(&dev) & libc::O_SYNC, libc::O_SYNC);
+        assert_eq!(flags(&dev) & libc::O_ACCMODE, libc::O_RDWR);
+        drop(dev);
+
+        let dev = mock.open().unwrap();
+        assert_eq!(flags(&dev) & libc::O_SYNC, 0);
+    }
+
+    #[test]
+    fn from_syspath() {
+        use linux::UioDeviceBuilder;
+        use std::fs;
+
+        let mock = pci_mock();
+        let syspath = mock.sysfs_path();
+        let open = || {
+            UioDeviceBuilder::from_syspath(&syspath)?
+                .dev_root(mock.dev_root())
+                .custom_flags(libc::O_APPEND)
+                .open()
+        };
+        let dev = open().unwrap();
+        assert_eq!(dev.get_num(), 0);
+        assert_eq!(dev.get_name().unwrap(), "uio_pci_generic");
+        drop(dev);
+
+        fs::write(syspath.join("uevent"), "MAJOR=243\nMINOR=0\nDEVNAME=uio0\n").unwrap();
+        assert!(open().is_ok());
+        fs::write(syspath.join("uevent"), "DEVNAME=uio1\n").unwrap();
+        assert!(open().is_err());
+        assert!(UioDeviceBuilder::from_syspath(mock.root()).is_err());
+    }
+
+    #[test]
+    fn retry() {
+        use linux::{Operation, RetryPolicy};
+        use mock::Fault;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let mock = pci_mock();
+        // Not created by udev yet
+        mock.inject(Operation::Open, Fault::Error(libc::ENOENT));
+        assert!(mock.open().is_err());
+        let policy = RetryPolicy {
+            attempts: 1000,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                mock.clear_faults();
+            });
+            assert!(mock.builder().retry(Some(policy)).open().is_ok());
+        });
+
+        // Permanent errors are not retried
+        mock.inject(Operation::Open, Fault::Error(libc::EACCES));
+        let start = Instant::now();
+        let err = mock
+            .builder()
+            .retry(Some(policy))
+            .open()
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn access_modes() {
+        use linux::{Access, LockMode, UioError};
+
+        let mock = pci_mock();
+        let open = |access| mock.builder().access(access).open();
+        let shared = open(Access::Shared).unwrap();
+        let control = open(Access::Control).unwrap();
+        let telemetry = open(Access::Shared).unwrap();
+        assert_eq!(
+            shared.irq_enable().unwrap_err().kind(),
+            ::std::io::ErrorKind::PermissionDenied
+        );
+        control.irq_enable().unwrap();
+
+        // Control access excludes other controllers, shared access only
+        // exclusive handles
+        drop(control);
+        assert!(matches!(
+            open(Access::Exclusive),
+            Err(UioError::Locked { .. })
+        ));
+        assert!(mock.hold_lock().is_ok());
+        drop((shared, telemetry));
+
+        let exclusive = open(Access::Exclusive).unwrap();
+        assert!(matches!(open(Access::Shared), Err(UioError::Locked { .. })));
+        drop(exclusive);
+        assert!(mock
+            .builder()
+            .access(Access::Shared)
+            .lock(LockMode::Blocking)
+            .open()
+            .is_ok());
+    }
+
+    #[test]
+    fn reopen() {
+        let mock = pci_mock();
+        let mut dev = mock.open().unwrap();
+        let ptr = dev.map_mapping(0).unwrap() as *mut u32;
+        unsafe { ptr.write_volatile(42) };
+        assert_eq!(dev.reopen().unwrap(), vec![]);
+        assert_eq!(unsafe { ptr.read_volatile() }, 42);
+        dev.close().unwrap();
+    }
+
+    #[test]
+    fn thread_safety() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<::linux::UioDevice>();
+        assert_send_sync::<::MappedRegion>();
+    }
+
+    #[test]
+    fn fault_injection() {
+        use mock::Fault;
+        use Operation;
+
+        let mock = pci_mock();
+        mock.inject(Operation::Open, Fault::Error(libc::EACCES));
+        match mock.open() {
+            Err(::linux::UioError::PermissionDenied { .. }) => (),
+            res => panic!("expected EACCES, got {:?}", res.map(|_| ())),
+        }
+        mock.clear_faults();
+
+        let holder = mock.hold_lock().unwrap();
+        assert!(matches!(mock.open(), Err(::linux::UioError::Locked { .. })));
+        drop(holder);
+
+        let dev = mock.open().unwrap();
+        mock.inject(Operation::Read, Fault::ShortRead);
+        assert_eq!(dev.get_name().unwrap(), "uio_pci_");
+        assert_eq!(
+            dev.irq_wait().unwrap_err().kind(),
+            ::std::io::ErrorKind::UnexpectedEof
+        );
+        mock.clear_faults();
+
+        let region = dev.map_region(0).unwrap();
+        mock.unplug();
+        assert_eq!(
+            dev.get_name().unwrap_err().raw_os_error(),
+            Some(libc::ENODEV)
+        );
+        assert!(dev.map_region(0).is_err());
+        assert_eq!(
+            dev.irq_enable().unwrap_err().raw_os_error(),
+            Some(libc::ENODEV)
+        );
+        drop(region);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics::{
+            Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+            SharedString, Unit,
+        };
+        use std::sync::{Arc, Mutex};
+
+        type Samples = Arc<Mutex<Vec<(String, String, f64)>>>;
+
+        /// Records the name, device label and value of every update.
+        struct Sample(Samples, String, String);
+
+        impl CounterFn for Sample {
+            fn increment(&self, value: u64) {
+                let sample = (self.1.clone(), self.2.clone(), value as f64);
+                self.0.lock().unwrap().push(sample);
+            }
+
+            fn absolute(&self, _: u64) {}
+        }
+
+        impl HistogramFn for Sample {
+            fn record(&self, value: f64) {
+                let sample = (self.1.clone(), self.2.clone(), value);
+                self.0.lock().unwrap().push(sample);
+            }
+        }
+
+        struct Collector(Samples);
+
+        impl Collector {
+            fn sample(&self, key: &Key) -> Arc<Sample> {
+                let device = key
+                    .labels()
+                    .find(|l| l.key() == "device")
+                    .map(|l| l.value().to_string())
+                    .unwrap_or_default();
+                Arc::new(Sample(self.0.clone(), key.name().to_string(), device))
+            }
+        }
+
+        impl Recorder for Collector {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                Counter::from_arc(self.sample(key))
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::from_arc(self.sample(key))
+            }
+        }
+
+        let mock = pci_mock();
+        let samples = Samples::default();
+        metrics::with_local_recorder(&Collector(samples.clone()), || {
+            let dev = mock.open().unwrap();
+            let _region = dev.map_region(0).unwrap();
+            dev.irq_wait().unwrap();
+        });
+
+        let device = mock.dev_path().display().to_string();
+        let samples = samples.lock().unwrap();
+        let names: Vec<&str> = samples.iter().map(|s| s.0.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "uio_lock_wait_seconds",
+                "uio_mapped_bytes_total",
+                "uio_irq_wait_seconds",
+                "uio_interrupts_total"
+            ]
+        );
+        assert!(samples.iter().all(|s| s.1 == device));
+        assert_eq!(samples[1].2, 4096.0);
+        assert_eq!(samples[3].2, 1.0);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing() {
+        use std::fmt;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Records the names of new spans and the messages of events with
+        /// their `ok` field.
+        #[derive(Default, Clone)]
+        struct Collector {
+            spans: Arc<Mutex<Vec<&'static str>>>,
+            events: Arc<Mutex<Vec<Fields>>>,
+            next_id: Arc<AtomicU64>,
+        }
+
+        #[derive(Debug, Default, PartialEq)]
+        struct Fields(String, Option<bool>);
+
+        impl Visit for Fields {
+            fn record_bool(&mut self, field: &Field, value: bool) {
+                if field.name() == "ok" {
+                    self.1 = Some(value);
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        impl Subscriber for Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.spans.lock().unwrap().push(span.metadata().name());
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.events.lock().unwrap().push(fields);
+            }
+
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let mock = pci_mock();
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), || {
+            let dev = mock.open().unwrap();
+            let _region = dev.map_region(0).unwrap();
+            dev.irq_wait().unwrap();
+        });
+
+        assert!(collector.spans.lock().unwrap().contains(&"uio_open"));
+        let events = collector.events.lock().unwrap();
+        for name in ["open", "lock", "mmap", "irq_wait"] {
+            assert!(
+                events.contains(&Fields(name.to_string(), Some(true))),
+                "no {} event in {:?}",
+                name,
+                events
+            );
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        use serde_json::{json, Value};
+
+        let mut mock = ::mock::MockUio::new(0).unwrap();
+        mock.set_name("uio_pdrv_genirq").unwrap();
+        mock.set_version("0.1").unwrap();
+        mock.set_subsystem("platform").unwrap();
+        mock.add_mapping(0xfebf_0000, 0x1000, "regs").unwrap();
+        let mut dev = mock.open().unwrap();
+        let info: Value = serde_json::from_str(&dev.to_json().unwrap()).unwrap();
+        assert_eq!(info["uio_num"], 0);
+        assert_eq!(info["name"], "uio_pdrv_genirq");
+        assert_eq!(info["version"], "0.1");
+        assert_eq!(info["kind"], "Platform");
+        assert_eq!(
+            info["mappings"],
+            json!([{
+                "index": 0,
+                "addr": 0xfebf_0000u64,
+                "len": 0x1000,
+                "name": "regs",
+                "label": null,
+            }])
+        );
+        assert!(info["sub_regions"].is_array());
+        drop(dev);
+
+        let _holder = mock.hold_lock().unwrap();
+        let summary = mock.open().err().unwrap().summary().to_json();
+        let summary: Value = serde_json::from_str(&summary).unwrap();
+        assert_eq!(summary["kind"], "WouldBlock");
+        assert_eq!(summary["path"], mock.dev_path().to_str().unwrap());
+        assert_eq!(summary["os_error"], libc::EWOULDBLOCK);
+        assert!(summary["message"]
+            .as_str()
+            .unwrap()
+            .contains("is locked by"));
+    }
+}
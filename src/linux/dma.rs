@@ -0,0 +1,115 @@
+//! DMA buffers in UIO mappings (feature `dma`).
+//!
+//! Devices which do DMA need the bus address of the memory they are given.
+//! For memory exposed as a UIO mapping (a reserved memory region, or the
+//! buffers `uio_dmem_genirq` allocates when the device is opened), that is
+//! the mapping's `addr`:
+//!
+//! ```ignore
+//! let buf = dev.map_dma_buffer(1)?;
+//! buf.write_bytes(0, &packet);
+//! dma.transfer(buf.bus_addr(), packet.len() as u32)?;
+//! ```
+
+use std::io;
+use std::ops::Deref;
+
+use super::{Operation, UioDevice, UioError};
+use MappedRegion;
+
+/// The values `uio_dmem_genirq` reports as the address of DMA buffers which
+/// are not allocated, i.e. while the device is not open: all ones in the
+/// width of `phys_addr_t`, which is 32 bits on e.g. Zynq-7000.
+const DMEM_MAP_ERRORS: [u64; 2] = [!0, u32::MAX as u64];
+
+/// A mapping used as a DMA buffer, with its bus address.
+///
+/// Dereferences to the `MappedRegion` for accessing the buffer.
+#[derive(Debug)]
+pub struct DmaBuffer<'a> {
+    region: MappedRegion<'a>,
+    bus_addr: u64,
+}
+
+impl<'a> DmaBuffer<'a> {
+    /// The bus address of the start of the buffer.
+    pub fn bus_addr(&self) -> u64 {
+        self.bus_addr
+    }
+
+    /// The bus address of `offset` in the buffer.
+    ///
+    /// # Panics
+    ///
+    /// If `offset` is beyond the end of the buffer.
+    pub fn bus_addr_of(&self, offset: usize) -> u64 {
+        assert!(
+            offset <= self.region.len(),
+            "offset {:#x} is outside the DMA buffer (len {:#x})",
+            offset,
+            self.region.len()
+        );
+        self.bus_addr + offset as u64
+    }
+
+    /// The mapped buffer, without its bus address.
+    pub fn into_region(self) -> MappedRegion<'a> {
+        self.region
+    }
+}
+
+impl<'a> Deref for DmaBuffer<'a> {
+    type Target = MappedRegion<'a>;
+
+    fn deref(&self) -> &MappedRegion<'a> {
+        &self.region
+    }
+}
+
+impl UioDevice {
+    /// Maps mapping `mapping` as a DMA buffer, with the bus address from
+    /// `maps/mapN/addr`.
+    ///
+    /// The address of buffers allocated when the device is opened (see
+    /// `ResourceLayout::MapsWithDma`) is only valid while it stays open, so
+    /// it is read after opening and the buffer borrows the device.
+    pub fn map_dma_buffer(&self, mapping: usize) -> Result<DmaBuffer<'_>, UioError> {
+        let bus_addr = self.map_addr(mapping)?;
+        if DMEM_MAP_ERRORS.contains(&bus_addr) {
+            return Err(UioError::Io {
+                op: Operation::Read,
+                path: self.sysfs_path().join(format!("maps/map{}/addr", mapping)),
+                source: io::Error::other("DMA buffer is not allocated"),
+            });
+        }
+        Ok(DmaBuffer {
+            region: self.map_region(mapping)?,
+            bus_addr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mock::MockUio;
+
+    #[test]
+    fn dma_buffers() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0xa000_0000, 0x1000, "regs").unwrap();
+        mock.add_mapping(0x3f00_0000, 0x2000, "dmem").unwrap();
+        mock.add_mapping(!0, 0x1000, "dmem").unwrap();
+        mock.add_mapping(0xffff_ffff, 0x1000, "dmem").unwrap();
+        let dev = mock.open().unwrap();
+
+        let buf = dev.map_dma_buffer(1).unwrap();
+        assert_eq!(buf.bus_addr(), 0x3f00_0000);
+        assert_eq!(buf.bus_addr_of(0x10), 0x3f00_0010);
+        assert_eq!(buf.len(), 0x2000);
+        buf.write_u32(0x10, 7);
+        assert_eq!(buf.into_region().read_u32(0x10), 7);
+
+        assert!(dev.map_dma_buffer(2).is_err());
+        assert!(dev.map_dma_buffer(3).is_err());
+    }
+}
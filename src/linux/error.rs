@@ -0,0 +1,338 @@
+//! Errors of UIO device operations.
+
+use libc;
+use parse;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The operation during which a `UioError` occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Operation {
+    Open,
+    Read,
+    Write,
+    ReadDir,
+    Metadata,
+    Lock,
+    Map,
+    Unmap,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match *self {
+            Operation::Open => "open",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::ReadDir => "list",
+            Operation::Metadata => "stat",
+            Operation::Lock => "lock",
+            Operation::Map => "map",
+            Operation::Unmap => "unmap",
+        };
+        f.write_str(op)
+    }
+}
+
+#[derive(Debug)]
+pub enum UioError {
+    /// The address is not a mapping of this device.
+    Address,
+    /// The region backed by `path` has an unusable size (e.g., zero).
+    Size { path: PathBuf },
+    /// `op` on `path` failed.
+    Io {
+        op: Operation,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Mapping or unmapping the region backed by `path` failed.
+    Map {
+        op: Operation,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The contents of `path` could not be parsed.
+    Parse {
+        path: PathBuf,
+        value: String,
+        reason: parse::NumberError,
+    },
+    /// Access to `path` was denied, `group` is the group owning the file.
+    PermissionDenied {
+        path: PathBuf,
+        group: Option<String>,
+    },
+    /// The device `path` is locked by another process (`holder`, if known).
+    Locked {
+        path: PathBuf,
+        holder: Option<LockHolder>,
+    },
+    /// No mapping in the `maps` directory `path` is called `name`.
+    UnknownMapping { path: PathBuf, name: String },
+    /// The device `path` is already open in this process.
+    AlreadyClaimed { path: PathBuf },
+    /// The driver rejected writing `value` to the attribute `path` (EINVAL).
+    InvalidValue { path: PathBuf, value: String },
+}
+
+impl UioError {
+    /// Returns a closure wrapping an `io::Error` that occurred during `op` on `path`.
+    ///
+    /// Failures to open or write a file due to missing permissions become
+    /// `PermissionDenied`.
+    pub(super) fn io<P: Into<PathBuf>>(
+        op: Operation,
+        path: P,
+    ) -> impl FnOnce(io::Error) -> UioError {
+        let path = path.into();
+        move |source| match (op, source.kind()) {
+            (Operation::Open, io::ErrorKind::PermissionDenied)
+            | (Operation::Write, io::ErrorKind::PermissionDenied) => {
+                let group = file_group(&path);
+                UioError::PermissionDenied { path, group }
+            }
+            _ => UioError::Io { op, path, source },
+        }
+    }
+
+    /// The path of the file the error relates to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            UioError::Address => None,
+            UioError::Size { ref path }
+            | UioError::Io { ref path, .. }
+            | UioError::Map { ref path, .. }
+            | UioError::Parse { ref path, .. }
+            | UioError::PermissionDenied { ref path, .. }
+            | UioError::Locked { ref path, .. }
+            | UioError::UnknownMapping { ref path, .. }
+            | UioError::AlreadyClaimed { ref path }
+            | UioError::InvalidValue { ref path, .. } => Some(path),
+        }
+    }
+}
+
+impl fmt::Display for UioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UioError::Address => f.write_str("address is not a mapping of this device"),
+            UioError::Size { ref path } => write!(f, "{} has an invalid size", path.display()),
+            UioError::Io {
+                op,
+                ref path,
+                ref source,
+            }
+            | UioError::Map {
+                op,
+                ref path,
+                ref source,
+            } => write!(f, "failed to {} {}: {}", op, path.display(), source),
+            UioError::Parse {
+                ref path,
+                ref value,
+                reason,
+            } => write!(
+                f,
+                "failed to parse {:?} from {}: {}",
+                value,
+                path.display(),
+                reason
+            ),
+            UioError::PermissionDenied {
+                ref path,
+                group: Some(ref group),
+            } => write!(
+                f,
+                "permission denied for {}: add the user to group '{}' or grant access \
+                 with a udev rule (e.g., SUBSYSTEM==\"uio\", GROUP=\"{}\", MODE=\"0660\")",
+                path.display(),
+                group,
+                group
+            ),
+            UioError::PermissionDenied {
+                ref path,
+                group: None,
+            } => write!(
+                f,
+                "permission denied for {}: run as root or grant access with a udev rule \
+                 (e.g., SUBSYSTEM==\"uio\", GROUP=\"uio\", MODE=\"0660\")",
+                path.display()
+            ),
+            UioError::Locked {
+                ref path,
+                holder: Some(ref holder),
+            } => write!(f, "{} is locked by {}", path.display(), holder),
+            UioError::Locked {
+                ref path,
+                holder: None,
+            } => write!(f, "{} is locked by another process", path.display()),
+            UioError::UnknownMapping { ref path, ref name } => {
+                write!(f, "no mapping named {:?} in {}", name, path.display())
+            }
+            UioError::AlreadyClaimed { ref path } => write!(
+                f,
+                "{} is already open in this process: share it with try_clone or \
+                 UioDeviceBuilder::shared",
+                path.display()
+            ),
+            UioError::InvalidValue {
+                ref path,
+                ref value,
+            } => write!(
+                f,
+                "{} does not accept the value {:?}",
+                path.display(),
+                value
+            ),
+        }
+    }
+}
+
+impl Error for UioError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            UioError::Io { ref source, .. } | UioError::Map { ref source, .. } => Some(source),
+            UioError::Parse { ref reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl UioError {
+    /// A summary of the error which can be serialized (with the `serde`
+    /// feature) or compared.
+    pub fn summary(&self) -> ErrorSummary {
+        ErrorSummary {
+            kind: format!("{:?}", self.kind()),
+            path: self.path().map(Path::to_path_buf),
+            os_error: self.raw_os_error(),
+            message: self.to_string(),
+        }
+    }
+
+    /// The OS error code (errno) underlying this error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match *self {
+            UioError::Io { ref source, .. } | UioError::Map { ref source, .. } => {
+                source.raw_os_error()
+            }
+            UioError::PermissionDenied { .. } => Some(libc::EACCES),
+            UioError::Locked { .. } => Some(libc::EWOULDBLOCK),
+            UioError::UnknownMapping { .. } => Some(libc::ENOENT),
+            UioError::AlreadyClaimed { .. } => Some(libc::EBUSY),
+            UioError::InvalidValue { .. } => Some(libc::EINVAL),
+            UioError::Address | UioError::Size { .. } | UioError::Parse { .. } => None,
+        }
+    }
+
+    /// The `io::ErrorKind` that best describes this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            UioError::Io { ref source, .. } | UioError::Map { ref source, .. } => source.kind(),
+            UioError::PermissionDenied { .. } => io::ErrorKind::PermissionDenied,
+            UioError::Locked { .. } => io::ErrorKind::WouldBlock,
+            UioError::UnknownMapping { .. } => io::ErrorKind::NotFound,
+            UioError::AlreadyClaimed { .. } => io::ErrorKind::ResourceBusy,
+            UioError::Address | UioError::InvalidValue { .. } => io::ErrorKind::InvalidInput,
+            UioError::Size { .. } | UioError::Parse { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Converts to an `io::Error` of the same `kind()` which wraps the `UioError`.
+///
+/// The original error (including `raw_os_error()`) can be recovered with
+/// `io::Error::get_ref` and `downcast_ref::<UioError>()`.
+impl From<UioError> for io::Error {
+    fn from(e: UioError) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// A process holding the lock on a `/dev/uio*` device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LockHolder {
+    /// PID of the lock owner as reported by `/proc/locks`
+    pub pid: u32,
+    /// Command name of the lock owner (from `/proc/<pid>/comm`), if readable
+    pub command: Option<String>,
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.command {
+            Some(ref command) => write!(f, "pid {} ({})", self.pid, command),
+            None => write!(f, "pid {}", self.pid),
+        }
+    }
+}
+
+/// Looks up the name of the group owning `path` in `/etc/group`.
+fn file_group(path: &Path) -> Option<String> {
+    let gid = fs::metadata(path).ok()?.gid();
+    let groups = fs::read_to_string("/etc/group").ok()?;
+    groups.lines().find_map(|line| {
+        // e.g. "uio:x:993:alice,bob"
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        match fields.nth(1)?.parse::<u32>() {
+            Ok(id) if id == gid => Some(name.to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// A serializable description of a `UioError`, see `UioError::summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ErrorSummary {
+    /// The `io::ErrorKind` of the error, e.g. "PermissionDenied"
+    pub kind: String,
+    /// The file the error relates to
+    pub path: Option<PathBuf>,
+    /// The underlying errno
+    pub os_error: Option<i32>,
+    /// The error message
+    pub message: String,
+}
+
+#[cfg(feature = "json")]
+impl ErrorSummary {
+    /// The summary as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("error summaries are always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn error_context() {
+        use std::error::Error;
+        let err = ::linux::UioError::Io {
+            op: ::linux::Operation::Open,
+            path: "/sys/class/uio/uio0/name".into(),
+            source: ::std::io::Error::from_raw_os_error(2),
+        };
+        assert!(err
+            .to_string()
+            .starts_with("failed to open /sys/class/uio/uio0/name: "));
+        assert!(err.source().is_some());
+        assert_eq!(err.raw_os_error(), Some(2));
+
+        let io_err = ::std::io::Error::from(err);
+        assert_eq!(io_err.kind(), ::std::io::ErrorKind::NotFound);
+        let inner = io_err.get_ref().unwrap();
+        assert!(inner.downcast_ref::<::linux::UioError>().is_some());
+    }
+}
@@ -0,0 +1,266 @@
+//! Reconfiguring the FPGA behind a device through `fpga_manager`.
+//!
+//! On Zynq and similar systems the programmable logic can be reloaded while
+//! Linux runs, but the UIO device has to be out of the way: accessing the
+//! logic while it is being reconfigured hangs the bus. `reconfigure_with`
+//! does the whole dance:
+//!
+//! ```ignore
+//! let manager = FpgaManager::new(0);
+//! dev.reconfigure_with(&manager, "design.bit.bin", |stage| {
+//!     println!("{:?}", stage);
+//! })?;
+//! ```
+
+use super::device::{list_devices_in, UioDevice};
+use super::error::{Operation, UioError};
+use libc;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use sys;
+
+/// How long to wait for the UIO device to reappear after binding.
+const BIND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A step of `UioDevice::reconfigure_with`, reported before it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconfigureStage {
+    /// Masking the interrupt and making the mappings inaccessible
+    Quiesce,
+    /// Unbinding the UIO driver from the device
+    Unbind,
+    /// Loading the bitstream
+    Load,
+    /// Binding the UIO driver again
+    Bind,
+    /// Reopening the device and restoring the mappings
+    Reopen,
+}
+
+/// An FPGA manager in `/sys/class/fpga_manager`.
+#[derive(Debug, Clone)]
+pub struct FpgaManager {
+    path: PathBuf,
+    flags: Option<u32>,
+}
+
+impl FpgaManager {
+    /// Flag for partial reconfiguration
+    pub const PARTIAL_RECONFIG: u32 = 1 << 0;
+
+    /// `/sys/class/fpga_manager/fpga<index>`.
+    pub fn new(index: usize) -> FpgaManager {
+        FpgaManager::at(format!("/sys/class/fpga_manager/fpga{}", index))
+    }
+
+    /// The manager with the sysfs directory `path`.
+    pub fn at<P: Into<PathBuf>>(path: P) -> FpgaManager {
+        FpgaManager {
+            path: path.into(),
+            flags: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sets the `flags` written before loading (e.g. `PARTIAL_RECONFIG`),
+    /// which are left as they are by default.
+    pub fn flags(&mut self, flags: u32) -> &mut Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// The state of the manager, "operating" if a bitstream is loaded.
+    pub fn state(&self) -> io::Result<String> {
+        Ok(fs::read_to_string(self.path.join("state"))?
+            .trim()
+            .to_string())
+    }
+
+    /// Loads the bitstream `firmware`, a file name relative to the firmware
+    /// directory (usually `/lib/firmware`).
+    ///
+    /// Fails if the manager isn't "operating" afterwards.
+    pub fn load(&self, firmware: &str) -> Result<(), UioError> {
+        if let Some(flags) = self.flags {
+            let path = self.path.join("flags");
+            fs::write(&path, format!("{:x}", flags))
+                .map_err(UioError::io(Operation::Write, path))?;
+        }
+        let path = self.path.join("firmware");
+        fs::write(&path, firmware).map_err(UioError::io(Operation::Write, &path))?;
+        let state = self
+            .state()
+            .map_err(UioError::io(Operation::Read, self.path.join("state")))?;
+        if state != "operating" {
+            return Err(UioError::Io {
+                op: Operation::Write,
+                path,
+                source: io::Error::other(format!("loading {} failed: {}", firmware, state)),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl UioDevice {
+    /// Loads the bitstream `firmware` (see `FpgaManager::load`) into the
+    /// FPGA behind this device and reopens it afterwards.
+    ///
+    /// The interrupt is masked and the tracked mappings are made
+    /// inaccessible, so stray accesses fault instead of hanging the bus.
+    /// Then the UIO driver is unbound from the device, the bitstream is
+    /// loaded and the driver is bound again. The device may come back with
+    /// a different UIO number (see `get_num`). Finally it is reopened like
+    /// with `reopen`, which maps the tracked mappings again at their
+    /// addresses and returns the addresses of those which can't be
+    /// restored. The interrupt stays masked.
+    ///
+    /// `progress` is called before every stage. If loading fails, the
+    /// driver is bound again before the error is returned, but the device
+    /// stays quiesced and has to be reopened.
+    pub fn reconfigure_with<F>(
+        &mut self,
+        manager: &FpgaManager,
+        firmware: &str,
+        mut progress: F,
+    ) -> Result<Vec<*mut libc::c_void>, UioError>
+    where
+        F: FnMut(ReconfigureStage),
+    {
+        let device = self.sysfs.join("device");
+        let parent = fs::canonicalize(&device).map_err(UioError::io(Operation::Read, &device))?;
+        let driver_link = device.join("driver");
+        let driver =
+            fs::canonicalize(&driver_link).map_err(UioError::io(Operation::Read, &driver_link))?;
+        let name = parent
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        progress(ReconfigureStage::Quiesce);
+        self.irq_disable()
+            .map_err(UioError::io(Operation::Write, &self.dev_path))?;
+        for mapping in self.tracked_mappings().iter() {
+            unsafe { sys::protect_none(mapping.addr as *mut libc::c_void, mapping.len) }
+                .map_err(UioError::io(Operation::Map, &self.dev_path))?;
+        }
+
+        progress(ReconfigureStage::Unbind);
+        let unbind = driver.join("unbind");
+        fs::write(&unbind, &name).map_err(UioError::io(Operation::Write, unbind))?;
+
+        progress(ReconfigureStage::Load);
+        let loaded = manager.load(firmware);
+
+        progress(ReconfigureStage::Bind);
+        let bind = driver.join("bind");
+        let bound = fs::write(&bind, &name).map_err(UioError::io(Operation::Write, bind));
+        loaded?;
+        bound?;
+
+        progress(ReconfigureStage::Reopen);
+        let uio_num = wait_for_uio(&parent.join("uio"))?;
+        let sysfs_root = self.sysfs.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let dev_root = self
+            .dev_path
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .to_path_buf();
+        self.uio_num = uio_num;
+        self.sysfs = sysfs_root.join(format!("uio{}", uio_num));
+        self.dev_path = dev_root.join(format!("uio{}", uio_num));
+        let deadline = Instant::now() + BIND_TIMEOUT;
+        while !self.dev_path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.reopen()
+    }
+}
+
+/// Waits for a UIO device to appear in the `uio` directory of the parent
+/// device and returns its number.
+fn wait_for_uio(dir: &Path) -> Result<usize, UioError> {
+    let deadline = Instant::now() + BIND_TIMEOUT;
+    loop {
+        if let Some(&num) = list_devices_in(dir)?.first() {
+            return Ok(num);
+        }
+        if Instant::now() >= deadline {
+            return Err(UioError::Io {
+                op: Operation::ReadDir,
+                path: dir.to_path_buf(),
+                source: io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no UIO device appeared after binding",
+                ),
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    #[test]
+    fn reconfigure() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x4000_0000, 0x1000, "regs").unwrap();
+        mock.set_driver("uio_pdrv_genirq").unwrap();
+        fs::create_dir_all(mock.sysfs_path().join("device/uio/uio0")).unwrap();
+        let manager_dir = mock.root().join("fpga0");
+        fs::create_dir_all(&manager_dir).unwrap();
+        fs::write(manager_dir.join("state"), "operating\n").unwrap();
+        let mut manager = FpgaManager::at(&manager_dir);
+        manager.flags(FpgaManager::PARTIAL_RECONFIG);
+
+        let mut dev = mock
+            .builder()
+            .access(::linux::Access::Control)
+            .open()
+            .unwrap();
+        let regs = dev.map_mapping(0).unwrap() as *mut u32;
+        unsafe { regs.write_volatile(42) };
+        let mut stages = Vec::new();
+        let invalidated = dev
+            .reconfigure_with(&manager, "design.bit.bin", |s| stages.push(s))
+            .unwrap();
+        assert!(invalidated.is_empty());
+        assert_eq!(
+            stages,
+            [
+                ReconfigureStage::Quiesce,
+                ReconfigureStage::Unbind,
+                ReconfigureStage::Load,
+                ReconfigureStage::Bind,
+                ReconfigureStage::Reopen
+            ]
+        );
+        // Mapped again at the same address
+        assert_eq!(unsafe { regs.read_volatile() }, 42);
+        let driver = mock.root().join("sys/bus/drivers/uio_pdrv_genirq");
+        assert_eq!(fs::read_to_string(driver.join("unbind")).unwrap(), "device");
+        assert_eq!(fs::read_to_string(driver.join("bind")).unwrap(), "device");
+        assert_eq!(
+            fs::read_to_string(manager_dir.join("firmware")).unwrap(),
+            "design.bit.bin"
+        );
+        assert_eq!(fs::read_to_string(manager_dir.join("flags")).unwrap(), "1");
+
+        fs::write(manager_dir.join("state"), "write error\n").unwrap();
+        fs::remove_file(driver.join("bind")).unwrap();
+        assert!(dev
+            .reconfigure_with(&manager, "broken.bin", |_| {})
+            .is_err());
+        // Bound again anyway
+        assert!(driver.join("bind").exists());
+    }
+}
@@ -0,0 +1,283 @@
+//! Devices which together make up one function.
+//!
+//! Some hardware exposes a single function as several UIO devices, e.g. a
+//! core with its control registers, data buffers and interrupts split over
+//! three nodes. A `UioDeviceGroup` opens them as a unit:
+//!
+//! ```ignore
+//! let mut group = UioDeviceGroup::open(vec![
+//!     ("control", UioDeviceBuilder::new(3)),
+//!     ("data", UioDeviceBuilder::new(4)),
+//!     ("events", UioDeviceBuilder::new(5)),
+//! ])?;
+//! let regs = group["control"].map_region(0)?;
+//! for (role, count) in group.wait(None)? {
+//!     println!("{}: {}", role, count);
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::ops::Index;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use super::device::{UioDevice, UioDeviceBuilder, UioDeviceInfo};
+use super::error::UioError;
+use sys;
+
+/// Several devices opened, locked and closed together, each with a role.
+///
+/// Members are closed in the reverse order of opening when the group is
+/// dropped or closed.
+pub struct UioDeviceGroup {
+    /// In the order given to `open`
+    members: Vec<(String, UioDevice)>,
+    /// Indices into `members`, in the order the devices were opened
+    opened: Vec<usize>,
+}
+
+impl UioDeviceGroup {
+    /// Opens and locks the devices of `members`, each under the given role.
+    ///
+    /// Devices are opened in the order of their UIO numbers, so processes
+    /// opening overlapping groups with `LockMode::Blocking` can't deadlock.
+    /// If any device fails to open, the ones opened before are closed again
+    /// and the error is returned.
+    ///
+    /// # Panics
+    ///
+    /// If a role is given twice.
+    pub fn open<I, S>(members: I) -> Result<UioDeviceGroup, UioError>
+    where
+        I: IntoIterator<Item = (S, UioDeviceBuilder)>,
+        S: Into<String>,
+    {
+        let members: Vec<(String, UioDeviceBuilder)> =
+            members.into_iter().map(|(r, b)| (r.into(), b)).collect();
+        for (i, (role, _)) in members.iter().enumerate() {
+            assert!(
+                members[..i].iter().all(|(r, _)| r != role),
+                "role {:?} is used twice",
+                role
+            );
+        }
+        let mut order: Vec<usize> = (0..members.len()).collect();
+        order.sort_by_key(|&i| members[i].1.uio_num);
+
+        let mut devices: Vec<Option<UioDevice>> = members.iter().map(|_| None).collect();
+        let mut opened = Vec::with_capacity(order.len());
+        for i in order {
+            match members[i].1.open() {
+                Ok(device) => {
+                    devices[i] = Some(device);
+                    opened.push(i);
+                }
+                Err(e) => {
+                    for j in opened.into_iter().rev() {
+                        drop(devices[j].take());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(UioDeviceGroup {
+            members: members
+                .into_iter()
+                .zip(devices)
+                .map(|((role, _), device)| (role, device.expect("all devices are open")))
+                .collect(),
+            opened,
+        })
+    }
+
+    /// The device with the role `role`.
+    pub fn get(&self, role: &str) -> Option<&UioDevice> {
+        self.members
+            .iter()
+            .find(|(r, _)| r == role)
+            .map(|(_, device)| device)
+    }
+
+    /// The device with the role `role`, e.g. to call `info` on it.
+    pub fn get_mut(&mut self, role: &str) -> Option<&mut UioDevice> {
+        self.members
+            .iter_mut()
+            .find(|(r, _)| r == role)
+            .map(|(_, device)| device)
+    }
+
+    /// The roles and devices, in the order given to `open`.
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &UioDevice)> {
+        self.members
+            .iter()
+            .map(|(role, device)| (role.as_str(), device))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The descriptions of all devices (see `UioDevice::info`) with their
+    /// roles, in the order given to `open`.
+    pub fn info(&mut self) -> Result<Vec<(String, UioDeviceInfo)>, UioError> {
+        self.members
+            .iter_mut()
+            .map(|(role, device)| Ok((role.clone(), device.info()?)))
+            .collect()
+    }
+
+    /// Waits up to `timeout` (forever if `None`) for interrupts of any of
+    /// the devices and returns the roles of the devices which interrupted
+    /// with their event counts, none on timeout.
+    ///
+    /// Interrupts are enabled before waiting for devices opened with
+    /// `auto_reenable`, like in `UioDevice::irq_wait`.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<(&str, u32)>> {
+        let timeout_ms = timeout.map_or(-1, |t| {
+            // Round up so we don't spin on sub-millisecond remainders
+            t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        });
+        for (_, device) in &self.members {
+            if device.auto_reenable {
+                device.irq_enable()?;
+            }
+        }
+        let fds: Vec<_> = self.members.iter().map(|(_, d)| d.as_raw_fd()).collect();
+        let mut events = Vec::new();
+        for i in sys::poll_many(&fds, timeout_ms)? {
+            let (ref role, ref device) = self.members[i];
+            // Already enabled above
+            let count = device.read_irq_event()?;
+            events.push((role.as_str(), count));
+        }
+        Ok(events)
+    }
+
+    /// Closes all devices in the reverse order of opening, see
+    /// `UioDevice::close`. All devices are closed even if some fail, the
+    /// first error is returned.
+    pub fn close(mut self) -> Result<(), UioError> {
+        let mut res = Ok(());
+        for device in self.take_in_close_order() {
+            let r = device.close();
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+
+    /// Removes the devices from the group in the reverse order of opening.
+    fn take_in_close_order(&mut self) -> Vec<UioDevice> {
+        let mut devices: Vec<Option<UioDevice>> =
+            self.members.drain(..).map(|(_, d)| Some(d)).collect();
+        self.opened
+            .drain(..)
+            .rev()
+            .filter_map(|i| devices[i].take())
+            .collect()
+    }
+}
+
+impl Index<&str> for UioDeviceGroup {
+    type Output = UioDevice;
+
+    /// # Panics
+    ///
+    /// If no device has the role `role`.
+    fn index(&self, role: &str) -> &UioDevice {
+        self.get(role)
+            .unwrap_or_else(|| panic!("no device has the role {:?}", role))
+    }
+}
+
+impl fmt::Debug for UioDeviceGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.devices()
+                    .map(|(role, device)| (role, device.get_num())),
+            )
+            .finish()
+    }
+}
+
+impl Drop for UioDeviceGroup {
+    fn drop(&mut self) {
+        for device in self.take_in_close_order() {
+            drop(device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+    use std::fs;
+
+    fn builder(mock: &MockUio) -> UioDeviceBuilder {
+        let mut builder = mock.builder();
+        builder.auto_reenable(false);
+        builder
+    }
+
+    #[test]
+    fn group() {
+        let mut control = MockUio::new(5).unwrap();
+        control.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        control.set_name("control").unwrap();
+        let data = MockUio::new(3).unwrap();
+        let events = MockUio::new(4).unwrap();
+        // The mock device files return their contents as event counts
+        fs::write(events.dev_path(), 9u32.to_ne_bytes()).unwrap();
+
+        let mut group = UioDeviceGroup::open(vec![
+            ("control", builder(&control)),
+            ("data", builder(&data)),
+            ("events", builder(&events)),
+        ])
+        .unwrap();
+        assert_eq!(group.len(), 3);
+        assert_eq!(group.opened, [1, 2, 0]);
+        assert_eq!(group["events"].get_num(), 4);
+        assert!(group.get("status").is_none());
+        assert_eq!(
+            group.devices().map(|(r, _)| r).collect::<Vec<_>>(),
+            ["control", "data", "events"]
+        );
+        group["control"].map_region(0).unwrap().write_u32(0, 1);
+
+        let info = group.info().unwrap();
+        assert_eq!(info[0].0, "control");
+        assert_eq!(info[0].1.name, "control");
+        assert_eq!(info[0].1.mappings.len(), 1);
+
+        fs::write(control.dev_path(), 1u32.to_ne_bytes()).unwrap();
+        fs::write(data.dev_path(), 2u32.to_ne_bytes()).unwrap();
+        let got = group.wait(Some(Duration::from_secs(0))).unwrap();
+        assert_eq!(got, [("control", 1), ("data", 2), ("events", 9)]);
+
+        assert!(control.hold_lock().is_err());
+        group.close().unwrap();
+        assert!(control.hold_lock().is_ok());
+        assert!(data.hold_lock().is_ok());
+    }
+
+    #[test]
+    fn open_is_all_or_nothing() {
+        let a = MockUio::new(0).unwrap();
+        let b = MockUio::new(1).unwrap();
+        let _held = b.hold_lock().unwrap();
+        match UioDeviceGroup::open(vec![("a", a.builder()), ("b", b.builder())]) {
+            Err(UioError::Locked { .. }) => {}
+            res => panic!("expected Locked, got {:?}", res),
+        }
+        assert!(a.hold_lock().is_ok());
+    }
+}
@@ -0,0 +1,607 @@
+//! Waiting for and masking interrupts.
+
+use super::device::{Access, UioDevice};
+use super::error::{Operation, UioError};
+use super::{injected_error, injected_short_read};
+use parse;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::fd;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::os::unix::prelude::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use sys;
+
+/// Parses the contents of the `event` attribute.
+fn parse_event(bytes: &[u8]) -> Result<u32, parse::NumberError> {
+    parse::number(bytes).and_then(|v| u32::try_from(v).map_err(|_| parse::NumberError::Overflow))
+}
+
+/// Keeps the interrupt of a device disabled, see
+/// `UioDevice::irq_disabled_guard`.
+#[must_use = "the interrupt is re-enabled when the guard is dropped"]
+pub struct IrqDisabledGuard<'a> {
+    device: &'a UioDevice,
+}
+
+impl<'a> Drop for IrqDisabledGuard<'a> {
+    fn drop(&mut self) {
+        // There's no way to report the error from drop
+        let _ = self.device.irq_enable();
+    }
+}
+
+/// A handle to a device for waiting for its interrupts only, see
+/// `UioDevice::irq_handle`.
+///
+/// It doesn't lock the device, can't map it and doesn't read sysfs, so a
+/// watcher (e.g. another process the file descriptor is passed to) can wait
+/// for interrupts while the `UioDevice` stays with the owner. The kernel
+/// counts events per open file, so waiting on the handle doesn't take
+/// interrupts away from the owner.
+#[derive(Debug)]
+pub struct IrqHandle {
+    file: File,
+    path: PathBuf,
+    /// Whether the interrupt may be enabled and disabled through the handle
+    control: bool,
+}
+
+impl IrqHandle {
+    /// Opens /dev/uio`uio_num` read-only for waiting, without locking it.
+    /// The interrupt can't be enabled or disabled through the handle.
+    pub fn open(uio_num: usize) -> Result<IrqHandle, UioError> {
+        IrqHandle::open_in(Path::new("/dev"), uio_num)
+    }
+
+    /// Like `open`, with a custom device directory (see
+    /// `UioDeviceBuilder::dev_root`).
+    pub fn open_in(dev_root: &Path, uio_num: usize) -> Result<IrqHandle, UioError> {
+        let path = dev_root.join(format!("uio{}", uio_num));
+        let file = injected_error(Operation::Open, &path)
+            .and_then(|()| File::open(&path))
+            .map_err(UioError::io(Operation::Open, &path))?;
+        Ok(IrqHandle {
+            file,
+            path,
+            control: false,
+        })
+    }
+
+    /// Creates a handle from the file descriptor of a device file, e.g. one
+    /// received from the owner of the device. The interrupt can be enabled
+    /// and disabled if the descriptor is writable.
+    pub fn from_fd(fd: fd::OwnedFd) -> io::Result<IrqHandle> {
+        let file = File::from(fd);
+        sys::set_cloexec(&file, true)?;
+        let control = sys::is_writable(&file)?;
+        Ok(IrqHandle {
+            file,
+            path: PathBuf::new(),
+            control,
+        })
+    }
+
+    /// Waits for an interrupt and returns the event count.
+    pub fn wait(&self) -> io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        injected_error(Operation::Read, &self.path)?;
+        (&self.file).read_exact(&mut bytes)?;
+        Ok(u32::from_ne_bytes(bytes))
+    }
+
+    /// Waits up to `timeout` (forever if `None`) for an interrupt and
+    /// returns the event count, `None` on timeout.
+    pub fn wait_timeout(&self, timeout: Option<Duration>) -> io::Result<Option<u32>> {
+        let timeout_ms = timeout.map_or(-1, |t| {
+            // Round up so we don't spin on sub-millisecond remainders
+            t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        });
+        if !sys::poll_readable(self.file.as_raw_fd(), timeout_ms)? {
+            return Ok(None);
+        }
+        self.wait().map(Some)
+    }
+
+    /// Enables the interrupt, like `UioDevice::irq_enable`.
+    ///
+    /// Fails with `PermissionDenied` for handles from `open`, from
+    /// descriptors opened read-only and from devices opened read-only or
+    /// with `Access::Shared`.
+    pub fn enable(&self) -> io::Result<()> {
+        self.write_control(1)
+    }
+
+    /// Disables the interrupt, see `enable`.
+    pub fn disable(&self) -> io::Result<()> {
+        self.write_control(0)
+    }
+
+    fn write_control(&self, value: u32) -> io::Result<()> {
+        if !self.control {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the interrupt handle can only wait",
+            ));
+        }
+        injected_error(Operation::Write, &self.path)?;
+        (&self.file).write_all(&value.to_ne_bytes())
+    }
+}
+
+impl fd::AsFd for IrqHandle {
+    fn as_fd(&self) -> fd::BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl fd::AsRawFd for IrqHandle {
+    fn as_raw_fd(&self) -> fd::RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl From<IrqHandle> for fd::OwnedFd {
+    fn from(handle: IrqHandle) -> fd::OwnedFd {
+        handle.file.into()
+    }
+}
+
+/// An interrupt recorded in an `EventLog`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqEvent {
+    /// When the wait for the interrupt returned
+    pub at: Instant,
+    /// The event counter read from the device file
+    pub count: u32,
+}
+
+#[derive(Debug)]
+struct LogState {
+    events: VecDeque<IrqEvent>,
+    /// Events overwritten since the log was created or cleared
+    dropped: u64,
+}
+
+/// The last interrupts of a device, for finding out what it was doing
+/// before a failure:
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use uio::{EventLog, UioDeviceBuilder};
+///
+/// let log = EventLog::new(1024);
+/// let dev = UioDeviceBuilder::new(0)
+///     .event_log(Some(log.clone()))
+///     .open()
+///     .unwrap();
+/// // ... on failure
+/// for event in log.since(Duration::from_secs(1)) {
+///     eprintln!("{:?}: count {}", event.at, event.count);
+/// }
+/// ```
+///
+/// A fixed number of events is kept, once it is full the oldest event is
+/// overwritten. Recording doesn't allocate. Clones share the log.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    state: Arc<Mutex<LogState>>,
+    capacity: usize,
+}
+
+impl EventLog {
+    /// A log keeping the last `capacity` interrupts.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn new(capacity: usize) -> EventLog {
+        assert!(capacity > 0, "an event log needs a capacity");
+        EventLog {
+            state: Arc::new(Mutex::new(LogState {
+                events: VecDeque::with_capacity(capacity),
+                dropped: 0,
+            })),
+            capacity,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records an interrupt with the event counter `count` now.
+    pub fn record(&self, count: u32) {
+        let at = Instant::now();
+        let mut state = self.lock();
+        if state.events.len() == self.capacity {
+            state.events.pop_front();
+            state.dropped += 1;
+        }
+        state.events.push_back(IrqEvent { at, count });
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of recorded events.
+    pub fn len(&self) -> usize {
+        self.lock().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of events which were overwritten.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    /// The recorded events, oldest first.
+    pub fn events(&self) -> Vec<IrqEvent> {
+        self.lock().events.iter().copied().collect()
+    }
+
+    /// The events recorded within the last `window`, oldest first.
+    pub fn since(&self, window: Duration) -> Vec<IrqEvent> {
+        let now = Instant::now();
+        self.lock()
+            .events
+            .iter()
+            .filter(|e| now.saturating_duration_since(e.at) <= window)
+            .copied()
+            .collect()
+    }
+
+    /// Removes all events and resets `dropped`.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.events.clear();
+        state.dropped = 0;
+    }
+}
+
+impl UioDevice {
+    /// The log interrupts are recorded in, see
+    /// `UioDeviceBuilder::event_log`.
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.event_log.as_ref()
+    }
+
+    /// A handle for waiting for interrupts of this device, sharing its
+    /// file description (like `try_clone`, it keeps referring to the old
+    /// file after `reopen`). The interrupt can be enabled and disabled
+    /// through it if it can through `self`.
+    pub fn irq_handle(&self) -> io::Result<IrqHandle> {
+        Ok(IrqHandle {
+            file: self.devfile.try_clone()?,
+            path: self.dev_path.clone(),
+            control: self.access != Access::Shared && !self.read_only,
+        })
+    }
+
+    /// The amount of events.
+    ///
+    /// Doesn't allocate after `preopen` (or the first call with
+    /// `cache_metadata`).
+    pub fn get_event_count(&self) -> Result<u32, UioError> {
+        if let Some(ref event) = self.event {
+            return self.read_event(event);
+        }
+        if let Some(ref cache) = self.metadata {
+            let mut cache = self.metadata_cache(cache);
+            if cache.event.is_none() {
+                let filename = self.sysfs.join("event");
+                let f = File::open(&filename).map_err(UioError::io(Operation::Open, &filename))?;
+                cache.event = Some(f);
+            }
+            let event = cache.event.as_ref().expect("event file was opened");
+            return self.read_event(event);
+        }
+        let filename = self.sysfs.join("event");
+        let buffer = self.read_file(&filename)?;
+        parse_event(buffer.as_bytes()).map_err(|reason| UioError::Parse {
+            path: filename,
+            value: buffer,
+            reason,
+        })
+    }
+
+    /// Reads the open `event` attribute into a stack buffer, so only errors
+    /// allocate.
+    fn read_event(&self, event: &File) -> Result<u32, UioError> {
+        let mut buf = [0u8; 32];
+        let n = event
+            .read_at(&mut buf, 0)
+            .map_err(|e| UioError::io(Operation::Read, self.sysfs.join("event"))(e))?;
+        parse_event(&buf[..n]).map_err(|reason| UioError::Parse {
+            path: self.sysfs.join("event"),
+            value: String::from_utf8_lossy(&buf[..n]).trim().to_string(),
+            reason,
+        })
+    }
+
+    /// Fails for handles which may not control interrupts.
+    fn check_control(&self) -> io::Result<()> {
+        if self.access == Access::Shared {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is opened with shared access, interrupts need control access",
+                    self.dev_path.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Enable interrupt
+    ///
+    /// Fails with `PermissionDenied` for handles opened with `Access::Shared`.
+    pub fn irq_enable(&self) -> io::Result<()> {
+        self.check_control()?;
+        let bytes = 1u32.to_ne_bytes();
+        injected_error(Operation::Write, &self.dev_path)?;
+        (&self.devfile).write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Disable interrupt
+    ///
+    /// Fails with `PermissionDenied` for handles opened with `Access::Shared`.
+    pub fn irq_disable(&self) -> io::Result<()> {
+        self.check_control()?;
+        let bytes = 0u32.to_ne_bytes();
+        injected_error(Operation::Write, &self.dev_path)?;
+        (&self.devfile).write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Waits until the event counter reaches `target` and returns it.
+    ///
+    /// Blocks until interrupts bring the count to (at least) `target`, e.g.
+    /// to let the device finish N conversions, or fails with
+    /// `io::ErrorKind::TimedOut` after `timeout` (`None` waits forever). The
+    /// comparison handles the counter wrapping around. If the device was
+    /// opened with `auto_reenable`, the interrupt is enabled before waiting.
+    pub fn wait_event_count(&self, target: u32, timeout: Option<Duration>) -> io::Result<u32> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let count = self.get_event_count()?;
+            if count.wrapping_sub(target) as i32 >= 0 {
+                return Ok(count);
+            }
+            let timeout_ms = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::from_secs(0) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("event count is {}, waited for {}", count, target),
+                        ));
+                    }
+                    // Round up so we don't spin on sub-millisecond remainders
+                    left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+            if self.auto_reenable {
+                self.irq_enable()?;
+            }
+            if sys::poll_readable(self.devfile.as_raw_fd(), timeout_ms)? {
+                // Consume the event so the next poll blocks, the count is read
+                // from sysfs above
+                let mut bytes = [0u8; 4];
+                let _ = (&self.devfile).read(&mut bytes)?;
+            }
+        }
+    }
+
+    /// Disables the interrupt until the returned guard is dropped, which
+    /// re-enables it (also when unwinding from a panic).
+    ///
+    /// ```no_run
+    /// # let dev = uio::UioDevice::try_new(0).unwrap();
+    /// {
+    ///     let _masked = dev.irq_disabled_guard().unwrap();
+    ///     // reconfigure the device without racing the interrupt handler
+    /// }
+    /// ```
+    pub fn irq_disabled_guard(&self) -> io::Result<IrqDisabledGuard<'_>> {
+        self.irq_disable()?;
+        Ok(IrqDisabledGuard { device: self })
+    }
+
+    /// Wait for interrupt
+    ///
+    /// If the device was opened with `auto_reenable`, the interrupt is
+    /// enabled before waiting.
+    pub fn irq_wait(&self) -> io::Result<u32> {
+        if self.auto_reenable {
+            self.irq_enable()?;
+        }
+        self.read_irq_event()
+    }
+
+    /// Waits for an interrupt without enabling it first.
+    pub(crate) fn read_irq_event(&self) -> io::Result<u32> {
+        let mut bytes: [u8; 4] = [0, 0, 0, 0];
+        injected_error(Operation::Read, &self.dev_path)?;
+        if injected_short_read(&self.dev_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short read of the event count",
+            ));
+        }
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        timed!(
+            "irq_wait",
+            (&self.devfile).read_exact(&mut bytes),
+            path = self.dev_path
+        )?;
+        metric!(
+            histogram,
+            "uio_irq_wait_seconds",
+            self.dev_path,
+            record(start.elapsed().as_secs_f64())
+        );
+        metric!(counter, "uio_interrupts_total", self.dev_path, increment(1));
+        let count = u32::from_ne_bytes(bytes);
+        if let Some(ref log) = self.event_log {
+            log.record(count);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use mock::MockUio;
+
+    #[test]
+    fn irq_guard() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mock = MockUio::new(0).unwrap();
+        let dev = mock.open().unwrap();
+        // The mock device file is opened with O_APPEND, so control writes
+        // end up at its end
+        let last_write = || {
+            let mut f = ::std::fs::File::open(mock.dev_path()).unwrap();
+            f.seek(SeekFrom::End(-4)).unwrap();
+            let mut bytes = [0u8; 4];
+            f.read_exact(&mut bytes).unwrap();
+            u32::from_ne_bytes(bytes)
+        };
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _masked = dev.irq_disabled_guard().unwrap();
+            assert_eq!(last_write(), 0);
+            panic!("in critical section");
+        }));
+        assert!(res.is_err());
+        assert_eq!(last_write(), 1);
+    }
+
+    #[test]
+    fn irq_handle() {
+        use linux::IrqHandle;
+        use std::fs;
+        use std::io::ErrorKind;
+
+        let mock = MockUio::new(0).unwrap();
+        let dev = mock.open().unwrap();
+        fs::write(mock.dev_path(), 7u32.to_ne_bytes()).unwrap();
+
+        // Opening doesn't need the lock
+        let watcher = IrqHandle::open_in(&mock.dev_root(), 0).unwrap();
+        assert_eq!(watcher.wait_timeout(None).unwrap(), Some(7));
+        assert_eq!(
+            watcher.enable().unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        let handle = dev.irq_handle().unwrap();
+        assert_eq!(handle.wait().unwrap(), 7);
+        handle.disable().unwrap();
+        drop(dev);
+        // The handle doesn't hold the lock
+        assert!(mock.hold_lock().is_ok());
+
+        assert!(IrqHandle::open_in(&mock.dev_root(), 1).is_err());
+
+        // Descriptors only get control if they are writable
+        let file = fs::File::open(mock.dev_path()).unwrap();
+        let handle = IrqHandle::from_fd(file.into()).unwrap();
+        assert_eq!(
+            handle.enable().unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(mock.dev_path())
+            .unwrap();
+        let handle = IrqHandle::from_fd(file.into()).unwrap();
+        handle.enable().unwrap();
+    }
+
+    #[test]
+    fn event_log() {
+        use linux::EventLog;
+        use std::fs;
+        use std::time::Duration;
+
+        let mock = MockUio::new(0).unwrap();
+        let log = EventLog::new(2);
+        let dev = mock
+            .builder()
+            .auto_reenable(false)
+            .event_log(Some(log.clone()))
+            .open()
+            .unwrap();
+        let counts = [3u32, 4, 5]
+            .iter()
+            .flat_map(|c| c.to_ne_bytes())
+            .collect::<Vec<_>>();
+        fs::write(mock.dev_path(), counts).unwrap();
+        for _ in 0..3 {
+            dev.irq_wait().unwrap();
+        }
+        let events = log.events();
+        assert_eq!(events.iter().map(|e| e.count).collect::<Vec<_>>(), [4, 5]);
+        assert!(events[0].at <= events[1].at);
+        assert_eq!(log.dropped(), 1);
+        assert_eq!(dev.event_log().unwrap().len(), 2);
+        assert_eq!(log.since(Duration::from_secs(60)), events);
+        ::std::thread::sleep(Duration::from_millis(5));
+        assert!(log.since(Duration::from_millis(1)).is_empty());
+
+        log.clear();
+        assert!(log.is_empty());
+        assert_eq!(log.dropped(), 0);
+
+        // Dissolving the device lets go of its reference
+        drop(dev.into_parts());
+        assert_eq!(::std::sync::Arc::strong_count(&log.state), 1);
+    }
+
+    #[test]
+    fn wait_event_count() {
+        use std::time::Duration;
+
+        let mock = MockUio::new(0).unwrap();
+        let dev = mock.open().unwrap();
+        mock.set_event_count(3).unwrap();
+        assert_eq!(dev.wait_event_count(2, None).unwrap(), 3);
+
+        let err = dev
+            .wait_event_count(5, Some(Duration::from_millis(20)))
+            .unwrap_err();
+        assert_eq!(err.kind(), ::std::io::ErrorKind::TimedOut);
+
+        ::std::thread::scope(|s| {
+            s.spawn(|| {
+                ::std::thread::sleep(Duration::from_millis(10));
+                mock.set_event_count(5).unwrap();
+            });
+            assert_eq!(
+                dev.wait_event_count(5, Some(Duration::from_secs(10)))
+                    .unwrap(),
+                5
+            );
+        });
+
+        // Wrap-around
+        mock.set_event_count(2).unwrap();
+        assert_eq!(dev.wait_event_count(u32::MAX - 1, None).unwrap(), 2);
+    }
+}
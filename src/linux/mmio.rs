@@ -0,0 +1,434 @@
+//! Mapping device memory.
+
+use super::device::UioDevice;
+use super::error::{Operation, UioError};
+use super::{injected_error, PAGESIZE};
+use libc;
+#[cfg(feature = "pci")]
+use profile::ResourceLayout;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::prelude::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard};
+use sys;
+use MappedRegion;
+
+/// The file backing a `Mapping`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MapSource {
+    /// /sys/class/uio/uioX/device/resourceN
+    #[cfg(feature = "pci")]
+    Resource(usize),
+    /// /sys/class/uio/uioX/maps/mapN, mapped through /dev/uioX
+    Mapping(usize),
+}
+
+/// A mapping returned by `map_mapping_ptr` or `map_resource_ptr`: a
+/// non-null pointer to its start and its length.
+///
+/// Pointers into the mapping are only handed out for offsets where a value
+/// of the requested type fits and is aligned. The mapping is not unmapped
+/// when this is dropped; pass `as_c_void()` to `UioDevice::unmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioPtr {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl MmioPtr {
+    /// The start of the mapping.
+    pub fn as_non_null(self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    pub fn as_ptr(self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// The start of the mapping, as returned by `map_mapping`.
+    pub fn as_c_void(self) -> *mut libc::c_void {
+        self.ptr.as_ptr() as *mut libc::c_void
+    }
+
+    /// Size of the mapping in bytes.
+    pub fn len(self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    /// A pointer to the `T` at `offset`, `None` if it doesn't fit in the
+    /// mapping or `offset` is not aligned for `T`.
+    pub fn at<T>(self, offset: usize) -> Option<NonNull<T>> {
+        let end = offset.checked_add(mem::size_of::<T>())?;
+        if end > self.len
+            || !(self.ptr.as_ptr() as usize + offset).is_multiple_of(mem::align_of::<T>())
+        {
+            return None;
+        }
+        // In bounds of a mapping which doesn't start at 0, so not null
+        Some(unsafe { NonNull::new_unchecked(self.ptr.as_ptr().add(offset) as *mut T) })
+    }
+}
+
+impl From<MmioPtr> for NonNull<u8> {
+    fn from(ptr: MmioPtr) -> NonNull<u8> {
+        ptr.ptr
+    }
+}
+
+/// A memory mapping created by a `UioDevice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Mapping {
+    pub(super) addr: usize,
+    pub(super) len: usize,
+    pub(super) source: MapSource,
+    /// Offset of the mapping within `source`
+    pub(super) start: u64,
+    /// Owned by a `MappedRegion`, which unmaps it when dropped
+    pub(super) region: bool,
+}
+
+impl Mapping {
+    pub(super) fn unmap(&self) -> io::Result<()> {
+        unsafe { sys::munmap(self.addr as *mut libc::c_void, self.len) }
+    }
+}
+
+/// The mappings a `UioDevice` created, unmapped when it is dropped.
+#[derive(Debug, Default)]
+pub(super) struct TrackedMappings(Mutex<Vec<Mapping>>);
+
+impl Drop for TrackedMappings {
+    fn drop(&mut self) {
+        let mappings = self.0.get_mut().unwrap_or_else(|e| e.into_inner());
+        for mapping in mappings.drain(..) {
+            let _ = mapping.unmap();
+        }
+    }
+}
+
+impl UioDevice {
+    /// Maps memory region `index` of the device where its driver puts it:
+    /// PCI BAR `index` for `uio_pci_generic`, mapping `index` otherwise.
+    ///
+    /// Without the `pci` feature, this is always mapping `index`.
+    pub fn map_memory(&self, index: usize) -> Result<MappedRegion<'_>, UioError> {
+        #[cfg(feature = "pci")]
+        {
+            if let Some(ResourceLayout::PciBars) = self.profile()?.map(|p| p.layout) {
+                return self.map_resource_region(index);
+            }
+        }
+        self.map_region(index)
+    }
+
+    pub(super) fn tracked_mappings(&self) -> MutexGuard<'_, Vec<Mapping>> {
+        // A panic while holding the lock can't leave the Vec in an invalid state.
+        self.mappings.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Opens the file backing `source`.
+    ///
+    /// Returns the file (if it isn't the device file itself), the current
+    /// size of the region and its offset within the file.
+    pub(super) fn open_source(
+        &self,
+        source: MapSource,
+    ) -> Result<(Option<File>, u64, usize), UioError> {
+        match source {
+            #[cfg(feature = "pci")]
+            MapSource::Resource(_) => {
+                let filename = self.source_path(source);
+                let f = match self.preopened.get(&filename) {
+                    Some(f) => f
+                        .try_clone()
+                        .map_err(UioError::io(Operation::Open, &filename))?,
+                    None => self.open_file(&filename)?,
+                };
+                let metadata = f
+                    .metadata()
+                    .map_err(UioError::io(Operation::Metadata, &filename))?;
+                Ok((Some(f), metadata.len(), 0))
+            }
+            MapSource::Mapping(mapping) => Ok((None, self.map_size(mapping)?, mapping * PAGESIZE)),
+        }
+    }
+
+    /// Converts the physical size of `source` to an in-process length.
+    fn mappable_len(&self, source: MapSource, size: u64) -> Result<usize, UioError> {
+        usize::try_from(size).map_err(|_| UioError::Size {
+            path: self.source_path(source),
+        })
+    }
+
+    /// Maps `len` bytes (all if `None`) of `source` from `start`, at exactly
+    /// `fixed_addr` if given.
+    ///
+    /// Only resources can be mapped from a `start` other than 0, and it
+    /// must be a multiple of the page size.
+    pub(super) fn mmap_source(
+        &self,
+        source: MapSource,
+        start: u64,
+        len: Option<usize>,
+        fixed_addr: Option<usize>,
+    ) -> Result<Mapping, UioError> {
+        let (file, size, offset) = self.open_source(source)?;
+        let size_err = || UioError::Size {
+            path: self.source_path(source),
+        };
+        let len = match len {
+            Some(len) => len,
+            None => self.mappable_len(source, size.saturating_sub(start))?,
+        };
+        if start.checked_add(len as u64).is_none_or(|end| end > size) {
+            return Err(size_err());
+        }
+        if len == 0 {
+            return Err(size_err());
+        }
+        if start != 0 && (file.is_none() || !start.is_multiple_of(PAGESIZE as u64)) {
+            return Err(size_err());
+        }
+        let offset = offset + self.mappable_len(source, start)?;
+        let fd = match file {
+            Some(ref f) => f.as_raw_fd(),
+            None => self.as_raw_fd(),
+        };
+
+        injected_error(Operation::Map, &self.source_path(source)).map_err(|e| UioError::Map {
+            op: Operation::Map,
+            path: self.source_path(source),
+            source: e,
+        })?;
+        let res = timed!(
+            "mmap",
+            unsafe { sys::mmap(fixed_addr, len, !self.read_only, fd, offset) },
+            source = source,
+            len = len,
+            offset = offset
+        );
+        let addr = res.map_err(|e| UioError::Map {
+            op: Operation::Map,
+            path: self.source_path(source),
+            source: e,
+        })?;
+        metric!(
+            counter,
+            "uio_mapped_bytes_total",
+            self.dev_path,
+            increment(len as u64)
+        );
+        Ok(Mapping {
+            addr: addr as usize,
+            len,
+            source,
+            start,
+            region: false,
+        })
+    }
+
+    /// Path of the file backing `source`.
+    fn source_path(&self, source: MapSource) -> PathBuf {
+        match source {
+            #[cfg(feature = "pci")]
+            MapSource::Resource(bar_nr) => self.sysfs.join(format!("device/resource{}", bar_nr)),
+            MapSource::Mapping(_) => self.dev_path.clone(),
+        }
+    }
+
+    pub(super) fn unmap_mapping(&self, mapping: &Mapping) -> Result<(), UioError> {
+        mapping.unmap().map_err(|e| UioError::Map {
+            op: Operation::Unmap,
+            path: self.source_path(mapping.source),
+            source: e,
+        })
+    }
+
+    pub(super) fn map_tracked(&self, source: MapSource) -> Result<MmioPtr, UioError> {
+        let mapping = self.mmap_source(source, 0, None, None)?;
+        self.tracked_mappings().push(mapping);
+        Ok(MmioPtr {
+            ptr: NonNull::new(mapping.addr as *mut u8).expect("mmap returned null"),
+            len: mapping.len,
+        })
+    }
+
+    /// Unmaps a mapping previously returned by `map_resource` or `map_mapping`
+    /// (or their `_ptr` variants).
+    ///
+    /// Returns `UioError::Address` if `addr` is not a mapping of this device
+    /// or belongs to a `MappedRegion`, which unmaps it when dropped.
+    pub fn unmap(&self, addr: *mut libc::c_void) -> Result<(), UioError> {
+        self.unmap_tracked(addr as usize, false)
+    }
+
+    /// Unmaps the mapping of a `MappedRegion` being dropped.
+    pub(crate) fn unmap_region(&self, addr: *mut libc::c_void) -> Result<(), UioError> {
+        self.unmap_tracked(addr as usize, true)
+    }
+
+    fn unmap_tracked(&self, addr: usize, region: bool) -> Result<(), UioError> {
+        let mut mappings = self.tracked_mappings();
+        let idx = mappings
+            .iter()
+            .position(|m| m.addr == addr && m.region == region)
+            .ok_or(UioError::Address)?;
+        self.unmap_mapping(&mappings[idx])?;
+        mappings.swap_remove(idx);
+        Ok(())
+    }
+
+    /// Stops tracking a mapping so it stays valid after the device is closed.
+    ///
+    /// The caller becomes responsible for unmapping the memory. Returns false
+    /// if `addr` is not a mapping of this device or belongs to a
+    /// `MappedRegion`.
+    pub fn leak_mapping(&self, addr: *mut libc::c_void) -> bool {
+        self.untrack(addr as usize, false)
+    }
+
+    /// Releases the mapping of a `MappedRegion` from the device.
+    pub(crate) fn leak_region(&self, addr: *mut libc::c_void) -> bool {
+        self.untrack(addr as usize, true)
+    }
+
+    fn untrack(&self, addr: usize, region: bool) -> bool {
+        let mut mappings = self.tracked_mappings();
+        match mappings
+            .iter()
+            .position(|m| m.addr == addr && m.region == region)
+        {
+            Some(idx) => {
+                mappings.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Map an available memory mapping.
+    ///
+    /// # Arguments
+    ///  * mapping: The given index of the mapping (i.e., 1 for /sys/class/uio/uioX/maps/map1)
+    pub fn map_mapping(&self, mapping: usize) -> Result<*mut libc::c_void, UioError> {
+        self.map_mapping_ptr(mapping).map(MmioPtr::as_c_void)
+    }
+
+    /// Like `map_mapping`, but returns the mapping as an `MmioPtr`.
+    pub fn map_mapping_ptr(&self, mapping: usize) -> Result<MmioPtr, UioError> {
+        self.map_tracked(MapSource::Mapping(mapping))
+    }
+
+    /// Maps an available memory mapping as a `MappedRegion`.
+    ///
+    /// The region is unmapped when dropped.
+    ///
+    /// # Arguments
+    ///  * mapping: The given index of the mapping (i.e., 1 for /sys/class/uio/uioX/maps/map1)
+    pub fn map_region(&self, mapping: usize) -> Result<MappedRegion<'_>, UioError> {
+        self.map_tracked_region(MapSource::Mapping(mapping))
+    }
+
+    /// Maps the mapping called `name` as a `MappedRegion`, see
+    /// `mapping_index`.
+    ///
+    /// Unlike indices, names don't change when mappings are added to or
+    /// reordered in the device tree.
+    pub fn map_by_name(&self, name: &str) -> Result<MappedRegion<'_>, UioError> {
+        self.map_region(self.mapping_index(name)?)
+    }
+
+    pub(super) fn map_tracked_region(
+        &self,
+        source: MapSource,
+    ) -> Result<MappedRegion<'_>, UioError> {
+        let mapping = self.mmap_source(source, 0, None, None)?;
+        Ok(self.track_region(mapping))
+    }
+
+    /// Tracks `mapping` as owned by the returned region, so `unmap` can't
+    /// pull it out from under the region's accessors.
+    pub(super) fn track_region(&self, mapping: Mapping) -> MappedRegion<'_> {
+        self.tracked_mappings().push(Mapping {
+            region: true,
+            ..mapping
+        });
+        MappedRegion::new(
+            self,
+            mapping.addr as *mut libc::c_void,
+            mapping.len,
+            !self.read_only,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use mock::MockUio;
+    use std::ptr;
+
+    #[test]
+    fn map_by_name() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "control").unwrap();
+        mock.add_mapping(0x2000, 0x1000, "fifo").unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.mapping_index("fifo").unwrap(), 1);
+        let fifo = dev.map_by_name("fifo").unwrap();
+        assert_eq!(fifo.len(), 0x1000);
+        match dev.map_by_name("status") {
+            Err(::linux::UioError::UnknownMapping { name, .. }) => assert_eq!(name, "status"),
+            res => panic!("expected UnknownMapping, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn mmio_ptr() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let regs = dev.map_mapping_ptr(0).unwrap();
+        assert_eq!(regs.len(), 0x1000);
+
+        let reg = regs.at::<u32>(0x10).unwrap();
+        unsafe { ptr::write_volatile(reg.as_ptr(), 0xdead_beef) };
+        assert_eq!(
+            unsafe { ptr::read_volatile(regs.at::<u32>(0x10).unwrap().as_ptr()) },
+            0xdead_beef
+        );
+        assert!(regs.at::<u32>(0xffc).is_some());
+        assert!(regs.at::<u32>(0xffd).is_none());
+        assert!(regs.at::<u32>(0x11).is_none());
+        assert!(regs.at::<u8>(usize::MAX).is_none());
+
+        dev.unmap(regs.as_c_void()).unwrap();
+        assert!(dev.unmap(regs.as_c_void()).is_err());
+    }
+
+    #[test]
+    fn region_owns_mapping() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+        let addr = region.as_ptr() as *mut ::libc::c_void;
+        match dev.unmap(addr) {
+            Err(::linux::UioError::Address) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(!dev.leak_mapping(addr));
+        region.write_u32(0, 1);
+        assert_eq!(region.read_u32(0), 1);
+        drop(region);
+        assert!(dev.tracked_mappings().is_empty());
+    }
+}
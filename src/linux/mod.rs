@@ -0,0 +1,63 @@
+//! UIO devices on Linux.
+//!
+//! `UioDevice` is spread over a module per subsystem: opening and locking
+//! (`device`), sysfs metadata (`sysfs`), memory mappings (`mmio`),
+//! interrupts (`irq`), PCI BARs (`pci`, feature `pci`) and DMA buffers
+//! (`dma`, feature `dma`). `UioDeviceGroup` (`group`) manages devices which
+//! belong together, `fpga` reloads the FPGA behind a device.
+
+mod device;
+#[cfg(feature = "dma")]
+mod dma;
+mod error;
+mod fpga;
+mod group;
+mod irq;
+mod mmio;
+#[cfg(feature = "pci")]
+mod pci;
+mod sysfs;
+
+pub use self::device::*;
+#[cfg(feature = "dma")]
+pub use self::dma::*;
+pub use self::error::*;
+pub use self::fpga::*;
+pub use self::group::*;
+pub use self::irq::*;
+pub use self::mmio::MmioPtr;
+#[cfg(feature = "pci")]
+pub use self::pci::*;
+pub use self::sysfs::*;
+
+#[cfg(any(test, feature = "mock"))]
+use mock::{injected_error, injected_short_read};
+#[cfg(not(any(test, feature = "mock")))]
+use std::io;
+#[cfg(not(any(test, feature = "mock")))]
+use std::path::Path;
+
+pub(crate) const PAGESIZE: usize = 4096;
+
+/// Without the mock backend no faults are ever injected.
+#[cfg(not(any(test, feature = "mock")))]
+fn injected_error(_op: Operation, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+fn injected_short_read(_path: &Path) -> bool {
+    false
+}
+
+/// A fake `uio_pci_generic` device with a BAR 5 and one mapping.
+#[cfg(test)]
+pub(crate) fn pci_mock() -> ::mock::MockUio {
+    let mut mock = ::mock::MockUio::new(0).unwrap();
+    mock.set_name("uio_pci_generic").unwrap();
+    mock.set_version("0.01.0").unwrap();
+    mock.set_subsystem("pci").unwrap();
+    mock.add_resource(5, 0x2000).unwrap();
+    mock.add_mapping(0xfebf_0000, 0x1000, "bar0").unwrap();
+    mock
+}
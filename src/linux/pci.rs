@@ -0,0 +1,543 @@
+//! PCI BARs, identity and configuration space of devices bound to
+//! `uio_pci_generic` (feature `pci`).
+
+use super::device::{Access, UioDevice};
+use super::error::{Operation, UioError};
+use super::mmio::{MapSource, MmioPtr};
+use libc;
+use parse;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use window::WindowedBar;
+use MappedRegion;
+
+/// A mappable resource (i.e., PCI BAR) of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceInfo {
+    /// E.g. the `5` in `.../device/resource5`
+    pub index: usize,
+    /// Size in bytes
+    pub size: u64,
+    /// `IORESOURCE_*` flags from the device's `resource` file, if present
+    pub flags: Option<u64>,
+    /// Whether the BAR is expected to be mappable
+    pub mappable: bool,
+}
+
+/// The identity of a PCI device, from its sysfs attributes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PciIdentity {
+    /// Bus address, e.g. "0000:01:00.0", if the device is a sysfs link
+    pub address: Option<String>,
+    pub vendor: u16,
+    pub device: u16,
+    pub subsystem_vendor: u16,
+    pub subsystem_device: u16,
+    /// Base class, sub-class and programming interface, e.g. 0x010802 for
+    /// an NVMe controller
+    pub class: u32,
+}
+
+/// Offset of the command register in the configuration space
+const COMMAND: usize = 0x04;
+/// Offset of the status register, bit 4 of which announces capabilities
+const STATUS: usize = 0x06;
+const STATUS_CAP_LIST: u8 = 0x10;
+/// Offset of the pointer to the first capability
+const CAPABILITIES: usize = 0x34;
+/// End of the standard header, device-specific registers follow
+const HEADER_END: usize = 0x40;
+/// Size of the extended configuration space of PCIe devices
+const CONFIG_SPACE_MAX: usize = 4096;
+
+/// The configuration space of a PCI device, saved by
+/// `UioDevice::save_config_state`.
+///
+/// Unprivileged processes can only read the first 64 bytes, so the state
+/// contains as much as the kernel allows (up to 4096 bytes for PCIe
+/// devices read by root).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciConfigState {
+    config: Vec<u8>,
+}
+
+impl PciConfigState {
+    /// Capability ID of MSI
+    pub const CAP_MSI: u8 = 0x05;
+    /// Capability ID of MSI-X
+    pub const CAP_MSIX: u8 = 0x11;
+
+    /// The saved configuration space.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.config
+    }
+
+    pub fn command(&self) -> u16 {
+        self.u16_at(COMMAND).unwrap_or(0)
+    }
+
+    /// The raw value of the base address register `bar_nr` (0 to 5).
+    pub fn bar(&self, bar_nr: usize) -> Option<u32> {
+        if bar_nr > 5 {
+            return None;
+        }
+        self.u32_at(0x10 + 4 * bar_nr)
+    }
+
+    /// The offset of the capability `id` (e.g. `CAP_MSI`), if the device
+    /// has it and it was saved.
+    pub fn capability(&self, id: u8) -> Option<usize> {
+        if self.config.get(STATUS)? & STATUS_CAP_LIST == 0 {
+            return None;
+        }
+        let mut offset = usize::from(*self.config.get(CAPABILITIES)? & !3);
+        // The list has at most 48 entries, don't loop forever if it's broken
+        for _ in 0..48 {
+            if offset < HEADER_END {
+                return None;
+            }
+            let header = self.config.get(offset..offset + 2)?;
+            if header[0] == id {
+                return Some(offset);
+            }
+            offset = usize::from(header[1] & !3);
+        }
+        None
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let b = self.config.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let b = self.config.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+/// Reads the configuration space from `file`, as much as the kernel allows.
+fn read_config(file: &File) -> io::Result<Vec<u8>> {
+    let mut config = vec![0; CONFIG_SPACE_MAX];
+    let mut len = 0;
+    while len < config.len() {
+        match file.read_at(&mut config[len..], len as u64)? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    config.truncate(len);
+    Ok(config)
+}
+
+impl UioDevice {
+    /// Return a vector of mappable resources (i.e., PCI bars) including their size.
+    ///
+    /// Devices without resource files (e.g., platform devices) return an
+    /// empty vector.
+    pub fn get_resource_info(&mut self) -> Result<Vec<(String, u64)>, UioError> {
+        let dir = self.sysfs.join("device");
+        let Some(paths) = Self::read_dir_if_exists(&dir)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut bars = Vec::new();
+        for p in paths {
+            let path = p.map_err(UioError::io(Operation::ReadDir, &dir))?;
+            let file_name = path
+                .file_name()
+                .into_string()
+                .expect("Is valid UTF-8 string.");
+
+            if file_name.starts_with("resource") && file_name.len() > "resource".len() {
+                let metadata = fs::metadata(path.path())
+                    .map_err(UioError::io(Operation::Metadata, path.path()))?;
+                bars.push((file_name, metadata.len()));
+            }
+        }
+
+        Ok(bars)
+    }
+
+    /// Lists the PCI BARs of the device, sorted by index.
+    ///
+    /// Unlike `get_resource_info`, this only includes the BARs (`resourceN`)
+    /// and not their write-combining variants (`resourceN_wc`), and tells
+    /// which of them can be mapped: I/O port and unassigned BARs have
+    /// resource files, but mapping them fails. This is inferred from the
+    /// flags in the device's `resource` file (without it, every non-empty BAR
+    /// is assumed to be mappable); use `probe_resource` to be sure.
+    pub fn resources(&self) -> Result<Vec<ResourceInfo>, UioError> {
+        let dir = self.sysfs.join("device");
+        let Some(entries) = Self::read_dir_if_exists(&dir)? else {
+            return Ok(Vec::new());
+        };
+        let table_path = dir.join("resource");
+        let table = match fs::read(&table_path) {
+            Ok(bytes) => parse::resources(&bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(UioError::io(Operation::Read, table_path)(e)),
+        };
+
+        let mut resources = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(UioError::io(Operation::ReadDir, &dir))?;
+            let Some(index) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| parse::index("resource", n))
+            else {
+                continue;
+            };
+            let size = entry
+                .metadata()
+                .map_err(UioError::io(Operation::Metadata, entry.path()))?
+                .len();
+            let flags = table.as_ref().and_then(|t| t.get(index)).map(|r| r.flags);
+            let mappable = size > 0 && flags.is_none_or(|f| f & parse::ResourceEntry::MEM != 0);
+            resources.push(ResourceInfo {
+                index,
+                size,
+                flags,
+                mappable,
+            });
+        }
+        resources.sort_by_key(|r| r.index);
+        Ok(resources)
+    }
+
+    /// Checks whether the PCI BAR `bar_nr` can be mapped by mapping and
+    /// unmapping it.
+    pub fn probe_resource(&self, bar_nr: usize) -> Result<bool, UioError> {
+        match self.mmap_source(MapSource::Resource(bar_nr), 0, None, None) {
+            Ok(mapping) => {
+                self.unmap_mapping(&mapping)?;
+                Ok(true)
+            }
+            Err(UioError::Map { .. }) | Err(UioError::Size { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The vendor, device and class IDs and bus address of a PCI device.
+    pub fn pci_identity(&self) -> Result<PciIdentity, UioError> {
+        let id = |name: &str| {
+            let value = self.device_attr_u64(name)?;
+            u16::try_from(value).map_err(|_| UioError::Parse {
+                path: self.sysfs.join("device").join(name),
+                value: format!("{:#x}", value),
+                reason: parse::NumberError::Overflow,
+            })
+        };
+        let address = fs::read_link(self.sysfs.join("device"))
+            .ok()
+            .and_then(|target| target.file_name().map(|n| n.to_string_lossy().into_owned()));
+        Ok(PciIdentity {
+            address,
+            vendor: id("vendor")?,
+            device: id("device")?,
+            subsystem_vendor: id("subsystem_vendor")?,
+            subsystem_device: id("subsystem_device")?,
+            class: self.device_attr_u64("class")? as u32,
+        })
+    }
+
+    /// Calls `f` with the `config` file of the device, using the one opened
+    /// by `preopen` if available.
+    fn with_config_file<T, F>(&self, op: Operation, f: F) -> Result<T, UioError>
+    where
+        F: FnOnce(&File) -> io::Result<T>,
+    {
+        let path = self.sysfs.join("device/config");
+        let opened;
+        let file = match self.preopened.get(&path) {
+            Some(file) => file,
+            None => {
+                opened = self.open_file(&path)?;
+                &opened
+            }
+        };
+        f(file).map_err(UioError::io(op, &path))
+    }
+
+    /// Saves the configuration space of a PCI device, e.g. before resetting
+    /// it, see `restore_config_state`.
+    pub fn save_config_state(&self) -> Result<PciConfigState, UioError> {
+        let config = self.with_config_file(Operation::Read, read_config)?;
+        Ok(PciConfigState { config })
+    }
+
+    /// Re-programs the configuration space saved by `save_config_state`,
+    /// e.g. after a reset cleared the BARs and disabled the device.
+    ///
+    /// Only registers which differ from the saved state are written: first
+    /// the device-specific registers and capabilities (highest offset first,
+    /// so MSI addresses and data are set before the MSI and MSI-X control
+    /// registers are written with their enable bits), then the header and
+    /// finally the command register, which re-enables memory decoding and
+    /// bus mastering. The status register is left alone.
+    ///
+    /// Fails with `PermissionDenied` for handles opened with `Access::Shared`.
+    pub fn restore_config_state(&self, state: &PciConfigState) -> Result<(), UioError> {
+        if self.access == Access::Shared {
+            return Err(UioError::PermissionDenied {
+                path: self.sysfs.join("device/config"),
+                group: None,
+            });
+        }
+        let controls: Vec<usize> = [PciConfigState::CAP_MSI, PciConfigState::CAP_MSIX]
+            .iter()
+            .filter_map(|&id| state.capability(id))
+            .map(|cap| cap + 2)
+            .collect();
+        self.with_config_file(Operation::Write, |file| {
+            let current = read_config(file)?;
+            let len = state.config.len().min(current.len()) & !3;
+            let restore = |offset: usize, width: usize| -> io::Result<()> {
+                let saved = &state.config[offset..offset + width];
+                if current.get(offset..offset + width) != Some(saved) {
+                    file.write_all_at(saved, offset as u64)?;
+                }
+                Ok(())
+            };
+
+            for offset in (HEADER_END..len).step_by(4).rev() {
+                // The low half holds the read-only ID and next pointer
+                if controls.contains(&(offset + 2)) {
+                    continue;
+                }
+                restore(offset, 4)?;
+            }
+            // 0x0 and 0x8 hold read-only IDs and the class
+            for offset in (0xc..len.min(HEADER_END)).step_by(4).rev() {
+                restore(offset, 4)?;
+            }
+            for &control in controls.iter().filter(|&&c| c + 2 <= len) {
+                restore(control, 2)?;
+            }
+            if len > COMMAND {
+                restore(COMMAND, 2)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Maps a given resource into the virtual address space of the process.
+    ///
+    /// # Arguments
+    ///   * bar_nr: The index to the given resource (i.e., 1 for /sys/class/uio/uioX/device/resource1)
+    pub fn map_resource(&self, bar_nr: usize) -> Result<*mut libc::c_void, UioError> {
+        self.map_resource_ptr(bar_nr).map(MmioPtr::as_c_void)
+    }
+
+    /// Like `map_resource`, but returns the mapping as an `MmioPtr`.
+    pub fn map_resource_ptr(&self, bar_nr: usize) -> Result<MmioPtr, UioError> {
+        self.map_tracked(MapSource::Resource(bar_nr))
+    }
+
+    /// Maps a given resource as a `MappedRegion`.
+    ///
+    /// The region is unmapped when dropped.
+    ///
+    /// # Arguments
+    ///   * bar_nr: The index to the given resource (i.e., 1 for /sys/class/uio/uioX/device/resource1)
+    pub fn map_resource_region(&self, bar_nr: usize) -> Result<MappedRegion<'_>, UioError> {
+        self.map_tracked_region(MapSource::Resource(bar_nr))
+    }
+
+    /// Size of the PCI BAR `bar_nr` in bytes, which may exceed what can be
+    /// mapped at once.
+    pub fn resource_size(&self, bar_nr: usize) -> Result<u64, UioError> {
+        let (_, size, _) = self.open_source(MapSource::Resource(bar_nr))?;
+        Ok(size)
+    }
+
+    /// Maps `len` bytes of the PCI BAR `bar_nr` from `start`, which must be
+    /// a multiple of the page size, as a `MappedRegion`.
+    ///
+    /// See `map_resource_windowed` for BARs too large to map at once.
+    pub fn map_resource_window(
+        &self,
+        bar_nr: usize,
+        start: u64,
+        len: usize,
+    ) -> Result<MappedRegion<'_>, UioError> {
+        let mapping = self.mmap_source(MapSource::Resource(bar_nr), start, Some(len), None)?;
+        Ok(self.track_region(mapping))
+    }
+
+    /// Accesses the PCI BAR `bar_nr` through at most `max_windows` mapped
+    /// windows of `window_size` bytes, for BARs too large to map at once
+    /// (see `window::WindowedBar`).
+    ///
+    /// # Panics
+    /// If `window_size` is not a non-zero multiple of the page size or
+    /// `max_windows` is 0.
+    pub fn map_resource_windowed(
+        &self,
+        bar_nr: usize,
+        window_size: usize,
+        max_windows: usize,
+    ) -> Result<WindowedBar<'_>, UioError> {
+        WindowedBar::new(self, bar_nr, window_size, max_windows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linux::pci_mock;
+
+    #[test]
+    fn map() {
+        let mock = pci_mock();
+        let res = mock.open().unwrap();
+        let bars = res.map_resource(5);
+        match bars {
+            Err(e) => {
+                panic!("Can not map PCI stuff: {:?}", e);
+            }
+            Ok(_f) => (),
+        }
+    }
+
+    #[test]
+    fn mappable_resources() {
+        let mock = pci_mock();
+        mock.add_resource(0, 0x100).unwrap();
+        mock.add_resource(1, 0x20).unwrap();
+        mock.set_attr(
+            "device/resource",
+            "0x00000000fe000000 0x00000000fe0000ff 0x0000000000040200\n\
+             0x000000000000e000 0x000000000000e01f 0x0000000000040101\n",
+        )
+        .unwrap();
+        let dev = mock.open().unwrap();
+        let mappable: Vec<_> = dev
+            .resources()
+            .unwrap()
+            .iter()
+            .map(|r| (r.index, r.mappable))
+            .collect();
+        // BAR 5 is not in the table, so it's assumed to be mappable
+        assert_eq!(mappable, vec![(0, true), (1, false), (5, true)]);
+        assert!(dev.probe_resource(5).unwrap());
+        assert!(dev.probe_resource(3).is_err());
+    }
+
+    #[test]
+    fn bar_info() {
+        let mock = pci_mock();
+        let mut res = mock.open().unwrap();
+        let bars = res.get_resource_info();
+        match bars {
+            Err(e) => {
+                panic!("Can not map PCI stuff: {:?}", e);
+            }
+            Ok(_f) => (),
+        }
+    }
+
+    #[test]
+    fn device_info() {
+        let mock = pci_mock();
+        mock.add_resource(0, 0x100).unwrap();
+        mock.set_driver("uio_pci_generic").unwrap();
+        for (attr, value) in [
+            ("vendor", "0x10ee"),
+            ("device", "0x7021"),
+            ("subsystem_vendor", "0x10ee"),
+            ("subsystem_device", "0x0007"),
+            ("class", "0x058000"),
+        ] {
+            mock.set_attr(::std::path::Path::new("device").join(attr), value)
+                .unwrap();
+        }
+        let mut dev = mock.open().unwrap();
+        let info = dev.info().unwrap();
+        assert_eq!(info.name, "uio_pci_generic");
+        assert_eq!(info.kind, ::linux::DeviceKind::Pci);
+        assert_eq!(info.mappings.len(), 1);
+        let resources: Vec<_> = info.resources.iter().map(|r| (r.index, r.size)).collect();
+        assert_eq!(resources, vec![(0, 0x100), (5, 0x2000)]);
+        assert_eq!(info.driver.as_deref(), Some("uio_pci_generic"));
+        assert_eq!(
+            info.pci,
+            Some(::linux::PciIdentity {
+                address: None,
+                vendor: 0x10ee,
+                device: 0x7021,
+                subsystem_vendor: 0x10ee,
+                subsystem_device: 0x7,
+                class: 0x58000,
+            })
+        );
+        #[cfg(feature = "json")]
+        {
+            let json = dev.to_json().unwrap();
+            assert!(json.contains("\"vendor\":4334"));
+            let back: ::linux::UioDeviceInfo = ::serde_json::from_str(&json).unwrap();
+            assert_eq!(back, info);
+        }
+
+        drop(dev);
+        let _holder = mock.hold_lock().unwrap();
+        let summary = mock.open().err().unwrap().summary();
+        assert_eq!(summary.kind, "WouldBlock");
+        assert_eq!(summary.path, Some(mock.dev_path()));
+    }
+
+    #[test]
+    fn config_state() {
+        let mock = pci_mock();
+        let path = mock.sysfs_path().join("device/config");
+        let mut config = vec![0u8; 256];
+        config[..4].copy_from_slice(&[0xee, 0x10, 0x21, 0x70]);
+        // Memory space and bus master enabled, with a capability list
+        config[0x04] = 0x06;
+        config[0x06] = STATUS_CAP_LIST;
+        config[0x10..0x14].copy_from_slice(&0xfe00_0000u32.to_le_bytes());
+        config[0x3c] = 11;
+        // MSI with 64-bit addresses, enabled, after a vendor capability
+        config[CAPABILITIES] = 0x48;
+        config[0x48..0x4a].copy_from_slice(&[0x09, 0x50]);
+        config[0x50..0x54].copy_from_slice(&[PciConfigState::CAP_MSI, 0x00, 0x81, 0x00]);
+        config[0x54..0x58].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+        config[0x5c..0x5e].copy_from_slice(&0x4041u16.to_le_bytes());
+        config[0x80..0x84].copy_from_slice(&0x1234u32.to_le_bytes());
+        fs::write(&path, &config).unwrap();
+
+        let dev = mock.open().unwrap();
+        let state = dev.save_config_state().unwrap();
+        assert_eq!(state.as_bytes(), &config[..]);
+        assert_eq!(state.command(), 0x6);
+        assert_eq!(state.bar(0), Some(0xfe00_0000));
+        assert_eq!(state.bar(6), None);
+        assert_eq!(state.capability(PciConfigState::CAP_MSI), Some(0x50));
+        assert_eq!(state.capability(PciConfigState::CAP_MSIX), None);
+
+        // A reset clears everything but the read-only registers
+        let mut reset = vec![0u8; 256];
+        reset[..4].copy_from_slice(&config[..4]);
+        reset[0x06] = STATUS_CAP_LIST;
+        reset[CAPABILITIES] = 0x48;
+        reset[0x48..0x4a].copy_from_slice(&[0x09, 0x50]);
+        reset[0x50] = PciConfigState::CAP_MSI;
+        fs::write(&path, &reset).unwrap();
+        dev.restore_config_state(&state).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), config);
+        drop(dev);
+
+        let shared = mock.builder().access(Access::Shared).open().unwrap();
+        assert!(matches!(
+            shared.restore_config_state(&state),
+            Err(UioError::PermissionDenied { .. })
+        ));
+    }
+}
@@ -0,0 +1,862 @@
+//! Device attributes and mapping metadata from sysfs.
+
+use super::device::{list_devices_in, UioDevice};
+use super::error::{Operation, UioError};
+use super::{injected_error, injected_short_read, PAGESIZE};
+use devicetree::{Node, SubRegion};
+use libc;
+use parse;
+use profile::DriverProfile;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Sysfs metadata cached by a device opened with `cache_metadata`.
+#[derive(Debug, Default)]
+pub(super) struct MetadataCache {
+    /// Contents of attributes which don't change while the device is bound,
+    /// keyed by path
+    attrs: HashMap<PathBuf, String>,
+    /// The `event` attribute, kept open
+    pub(super) event: Option<File>,
+}
+
+/// The name of the kernel driver bound to the device with the sysfs
+/// directory `sysfs`, from the `device/driver` link.
+fn driver_name(sysfs: &Path) -> Result<Option<String>, UioError> {
+    let link = sysfs.join("device/driver");
+    match fs::read_link(&link) {
+        Ok(target) => Ok(target.file_name().map(|n| n.to_string_lossy().into_owned())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(UioError::io(Operation::Read, link)(e)),
+    }
+}
+
+/// The profile of the driver bound to the device with the sysfs directory
+/// `sysfs`, if it is known.
+pub(super) fn driver_profile(sysfs: &Path) -> Result<Option<DriverProfile>, UioError> {
+    Ok(driver_name(sysfs)?.and_then(|d| DriverProfile::lookup(&d)))
+}
+
+/// Parses the contents `buffer` of the numeric attribute `path`.
+fn parse_attr(path: PathBuf, buffer: String) -> Result<u64, UioError> {
+    parse::number(buffer.as_bytes()).map_err(|reason| UioError::Parse {
+        path,
+        value: buffer,
+        reason,
+    })
+}
+
+/// Reads all of `file` from offset 0 with `pread`, as sysfs attributes are
+/// regenerated on every read from the start.
+fn read_at_start(file: &File, path: &Path) -> Result<String, UioError> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; PAGESIZE];
+    loop {
+        let n = file
+            .read_at(&mut chunk, bytes.len() as u64)
+            .map_err(UioError::io(Operation::Read, path))?;
+        if n == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The kind of device a UIO device is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DeviceKind {
+    /// A PCI device (e.g., bound to `uio_pci_generic`)
+    Pci,
+    /// A platform device (e.g., bound to `uio_pdrv_genirq` through device-tree)
+    Platform,
+    /// A device of another subsystem, e.g. "vmbus"
+    Other(String),
+    /// The UIO device has no parent device
+    Unknown,
+}
+
+/// All information about one of a UioDevice's Mapping
+/// This is a dump of everything contained in `/sys/class/uio/uio{n}/maps/map*/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MappingInfo {
+    /// Index of the Mapping
+    ///
+    /// E.g. the `0` in `.../maps/map0`
+    pub index: usize,
+
+    /// Physical address of the Mapping
+    pub addr: u64,
+
+    /// Length in bytes of the Mapping region
+    pub len: u64,
+
+    /// Name supplied by the UIO device
+    ///
+    /// Typically this would be set in a device-tree entry
+    pub name: String,
+
+    /// Name of the mapping in the `reg-names` of the device tree node, if
+    /// any
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub label: Option<String>,
+}
+
+impl UioDevice {
+    /// Lists `dir`, returning `None` if it doesn't exist.
+    pub(super) fn read_dir_if_exists(dir: &Path) -> Result<Option<fs::ReadDir>, UioError> {
+        match fs::read_dir(dir) {
+            Ok(entries) => Ok(Some(entries)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(UioError::io(Operation::ReadDir, dir)(e)),
+        }
+    }
+
+    /// The kind of device the UIO device is bound to.
+    ///
+    /// This is determined from the subsystem of `/sys/class/uio/uioX/device`.
+    pub fn device_kind(&self) -> Result<DeviceKind, UioError> {
+        let subsystem = self.sysfs.join("device/subsystem");
+        let target = match fs::read_link(&subsystem) {
+            Ok(target) => target,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(DeviceKind::Unknown),
+            Err(e) => return Err(UioError::io(Operation::Read, subsystem)(e)),
+        };
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(match name.as_str() {
+            "pci" => DeviceKind::Pci,
+            "platform" => DeviceKind::Platform,
+            _ => DeviceKind::Other(name),
+        })
+    }
+
+    /// The numbers of the other UIO devices of the same parent device, e.g.
+    /// the channels a kernel driver registers for one PCI function, so they
+    /// can be opened together (see `UioDeviceGroup`).
+    ///
+    /// They are listed in the `uio` directory of the parent device. Devices
+    /// without a parent have no siblings.
+    pub fn siblings(&self) -> Result<Vec<usize>, UioError> {
+        let mut siblings = list_devices_in(&self.sysfs.join("device/uio"))?;
+        siblings.retain(|&num| num != self.get_num());
+        Ok(siblings)
+    }
+
+    /// The name of the kernel driver the device is bound to (e.g.
+    /// `uio_pdrv_genirq`), `None` if there is no `device/driver` link.
+    pub fn driver_name(&self) -> Result<Option<String>, UioError> {
+        driver_name(&self.sysfs)
+    }
+
+    /// The profile of the driver the device is bound to, `None` for drivers
+    /// without a known profile (e.g., custom kernel drivers).
+    pub fn profile(&self) -> Result<Option<DriverProfile>, UioError> {
+        driver_profile(&self.sysfs)
+    }
+
+    pub(super) fn read_file(&self, path: &Path) -> Result<String, UioError> {
+        timed!("sysfs_read", self.read_attr(path), path = path)
+    }
+
+    fn read_attr(&self, path: &Path) -> Result<String, UioError> {
+        injected_error(Operation::Read, path).map_err(UioError::io(Operation::Read, path))?;
+        let mut buffer = String::new();
+        match self.preopened.get(path) {
+            Some(file) => buffer = read_at_start(file, path)?,
+            None => {
+                let mut file = injected_error(Operation::Open, path)
+                    .and_then(|()| File::open(path))
+                    .map_err(UioError::io(Operation::Open, path))?;
+                file.read_to_string(&mut buffer)
+                    .map_err(UioError::io(Operation::Read, &path))?;
+            }
+        }
+        if injected_short_read(path) {
+            let mut half = buffer.len() / 2;
+            while !buffer.is_char_boundary(half) {
+                half -= 1;
+            }
+            buffer.truncate(half);
+        }
+        Ok(buffer.trim().to_string())
+    }
+
+    /// Reads an attribute which doesn't change while the device is bound,
+    /// from the cache if enabled.
+    pub(crate) fn read_static(&self, path: &Path) -> Result<String, UioError> {
+        let Some(ref cache) = self.metadata else {
+            return self.read_file(path);
+        };
+        if let Some(value) = self.metadata_cache(cache).attrs.get(path) {
+            return Ok(value.clone());
+        }
+        let value = self.read_file(path)?;
+        self.metadata_cache(cache)
+            .attrs
+            .insert(path.to_path_buf(), value.clone());
+        Ok(value)
+    }
+
+    pub(super) fn metadata_cache<'c>(
+        &self,
+        cache: &'c Mutex<MetadataCache>,
+    ) -> MutexGuard<'c, MetadataCache> {
+        cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Drops cached sysfs metadata (see `UioDeviceBuilder::cache_metadata`),
+    /// so it is read again on next use.
+    pub fn refresh(&self) {
+        if let Some(ref cache) = self.metadata {
+            *self.metadata_cache(cache) = MetadataCache::default();
+        }
+    }
+
+    /// Opens all files the device needs ahead of time.
+    ///
+    /// This opens the sysfs attributes (name, version, event and all
+    /// `maps/mapN/*` files) as well as the PCI resource and config files, if
+    /// present. Later accesses use the open files instead of looking up
+    /// paths, so a process can call this while privileged and afterwards drop
+    /// privileges or enter a sandbox. Enumeration methods like
+    /// `get_resource_info` and `get_mapping_info` still list directories.
+    ///
+    /// This is also the setup needed for allocation-free interrupt handling
+    /// (see "Real-time use" in the `UioDevice` docs).
+    pub fn preopen(&mut self) -> Result<(), UioError> {
+        let base = self.sysfs.clone();
+        let mut files = HashMap::new();
+
+        for attr in &["name", "version", "event"] {
+            let path = base.join(attr);
+            let f = File::open(&path).map_err(UioError::io(Operation::Open, &path))?;
+            files.insert(path, f);
+        }
+
+        let maps = base.join("maps");
+        if let Ok(entries) = fs::read_dir(&maps) {
+            for entry in entries {
+                let entry = entry.map_err(UioError::io(Operation::ReadDir, &maps))?;
+                for attr in &["addr", "size", "name", "offset"] {
+                    let path = entry.path().join(attr);
+                    if let Ok(f) = File::open(&path) {
+                        files.insert(path, f);
+                    }
+                }
+            }
+        }
+
+        let device = base.join("device");
+        if let Ok(entries) = fs::read_dir(&device) {
+            for entry in entries {
+                let entry = entry.map_err(UioError::io(Operation::ReadDir, &device))?;
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                if (file_name.starts_with("resource") && file_name.len() > "resource".len())
+                    || file_name == "config"
+                {
+                    let path = entry.path();
+                    let f = self.open_file(&path)?;
+                    files.insert(path, f);
+                }
+            }
+        }
+
+        let event = base.join("event");
+        let event = files[&event]
+            .try_clone()
+            .map_err(UioError::io(Operation::Open, &event))?;
+        self.event = Some(Arc::new(event));
+        self.preopened = Arc::new(files);
+        Ok(())
+    }
+
+    /// The name of the UIO device.
+    pub fn get_name(&self) -> Result<String, UioError> {
+        self.read_static(&self.sysfs.join("name"))
+    }
+
+    /// The version of the UIO driver.
+    pub fn get_version(&self) -> Result<String, UioError> {
+        self.read_static(&self.sysfs.join("version"))
+    }
+
+    /// The size of a given mapping.
+    ///
+    /// The size is a `u64` as it may not fit a `usize` on 32-bit hosts;
+    /// such mappings can't be mapped as a whole.
+    ///
+    /// # Arguments
+    ///  * mapping: The given index of the mapping (i.e., 1 for /sys/class/uio/uioX/maps/map1)
+    pub fn map_size(&self, mapping: usize) -> Result<u64, UioError> {
+        let filename = self.sysfs.join(format!("maps/map{}/size", mapping));
+        let buffer = self.read_static(&filename)?;
+        parse_attr(filename, buffer)
+    }
+
+    /// The physical address of a given mapping.
+    ///
+    /// # Arguments
+    ///  * mapping: The given index of the mapping (i.e., 1 for /sys/class/uio/uioX/maps/map1)
+    pub fn map_addr(&self, mapping: usize) -> Result<u64, UioError> {
+        let filename = self.sysfs.join(format!("maps/map{}/addr", mapping));
+        let buffer = self.read_static(&filename)?;
+        parse_attr(filename, buffer)
+    }
+
+    /// The path of the attribute `name` in the `device` directory, rejecting
+    /// names which would leave it.
+    fn device_attr_path(&self, name: &str) -> Result<PathBuf, UioError> {
+        let path = self.sysfs.join("device").join(name);
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(UioError::io(Operation::Read, path)(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid attribute name {:?}", name),
+            )));
+        }
+        Ok(path)
+    }
+
+    /// The names of the attributes in `/sys/class/uio/uioX/device/`, sorted.
+    ///
+    /// These are all regular files in the directory: the attributes of the
+    /// bus (e.g., `vendor` or `resource0` for PCI devices) and the ones a
+    /// custom kernel driver exports (e.g., `fw_version` or `temperature`).
+    /// Subdirectories and links are left out. Devices without a `device`
+    /// directory have no attributes.
+    pub fn list_device_attrs(&self) -> Result<Vec<String>, UioError> {
+        let dir = self.sysfs.join("device");
+        let Some(entries) = Self::read_dir_if_exists(&dir)? else {
+            return Ok(Vec::new());
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(UioError::io(Operation::ReadDir, &dir))?;
+            let file_type = entry
+                .file_type()
+                .map_err(UioError::io(Operation::ReadDir, &dir))?;
+            if file_type.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The value of the attribute `name` in `/sys/class/uio/uioX/device/`,
+    /// without surrounding whitespace.
+    ///
+    /// Attributes are read on every call (never cached), as drivers may
+    /// report changing values like temperatures.
+    pub fn device_attr(&self, name: &str) -> Result<String, UioError> {
+        self.read_file(&self.device_attr_path(name)?)
+    }
+
+    /// The attribute `name` in `/sys/class/uio/uioX/device/` as an unsigned
+    /// number in any format `parse::number` accepts (e.g., `42` or `0x2a`).
+    pub fn device_attr_u64(&self, name: &str) -> Result<u64, UioError> {
+        self.read_number(self.device_attr_path(name)?)
+    }
+
+    /// The attribute `name` in `/sys/class/uio/uioX/device/` as a signed
+    /// number (see `parse::signed`).
+    pub fn device_attr_i64(&self, name: &str) -> Result<i64, UioError> {
+        let path = self.device_attr_path(name)?;
+        let buffer = self.read_file(&path)?;
+        parse::signed(buffer.as_bytes()).map_err(|reason| UioError::Parse {
+            path,
+            value: buffer,
+            reason,
+        })
+    }
+
+    /// Writes `value` to the attribute `name` in `/sys/class/uio/uioX/device/`,
+    /// e.g. `write_device_attr("mode", "loopback")`.
+    ///
+    /// The value is written as is with a single `write`, as sysfs expects.
+    /// Values the driver rejects fail with `UioError::InvalidValue`, missing
+    /// permissions with `UioError::PermissionDenied`. Empty values, values
+    /// containing NUL bytes or exceeding a page and writes through read-only
+    /// devices fail without touching the attribute.
+    pub fn write_device_attr(&self, name: &str, value: &str) -> Result<(), UioError> {
+        let path = self.device_attr_path(name)?;
+        let invalid = |msg: &str| UioError::Io {
+            op: Operation::Write,
+            path: path.clone(),
+            source: io::Error::new(io::ErrorKind::InvalidInput, msg),
+        };
+        if value.is_empty() || value.contains('\0') || value.len() >= PAGESIZE {
+            return Err(invalid(
+                "attribute values must be 1 to 4095 bytes without NUL",
+            ));
+        }
+        if self.read_only {
+            return Err(invalid("device is opened read-only"));
+        }
+        let mut file = injected_error(Operation::Open, &path)
+            .and_then(|()| OpenOptions::new().write(true).open(&path))
+            .map_err(UioError::io(Operation::Open, &path))?;
+        let res = timed!(
+            "sysfs_write",
+            injected_error(Operation::Write, &path).and_then(|()| file.write(value.as_bytes())),
+            path = path
+        );
+        match res {
+            Ok(n) if n == value.len() => Ok(()),
+            Ok(_) => Err(UioError::Io {
+                op: Operation::Write,
+                path,
+                source: io::ErrorKind::WriteZero.into(),
+            }),
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => Err(UioError::InvalidValue {
+                path,
+                value: value.to_string(),
+            }),
+            Err(e) => Err(UioError::io(Operation::Write, path)(e)),
+        }
+    }
+
+    /// Reads a numeric attribute in any format `parse::number` accepts,
+    /// bypassing the metadata cache.
+    pub(crate) fn read_number(&self, path: PathBuf) -> Result<u64, UioError> {
+        let buffer = self.read_file(&path)?;
+        parse_attr(path, buffer)
+    }
+
+    /// The name of a given mapping.
+    ///
+    /// # Arguments
+    ///  * mapping: The given index of the mapping (i.e., 1 for /sys/class/uio/uioX/maps/map1)
+    pub fn map_name(&self, mapping: usize) -> Result<String, UioError> {
+        self.read_static(&self.sysfs.join(format!("maps/map{}/name", mapping)))
+    }
+
+    /// Return a list of all possible memory mappings.
+    #[deprecated(since = "0.3.0", note = "Use get_mapping_info() instead")]
+    pub fn get_map_info(&mut self) -> Result<Vec<String>, UioError> {
+        let dir = self.sysfs.join("maps");
+        let Some(paths) = Self::read_dir_if_exists(&dir)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut map = Vec::new();
+        for p in paths {
+            let path = p.map_err(UioError::io(Operation::ReadDir, &dir))?;
+            let file_name = path
+                .file_name()
+                .into_string()
+                .expect("Is valid UTF-8 string.");
+
+            if file_name.starts_with("map") && file_name.len() > "map".len() {
+                map.push(file_name);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Complete information about all Mappings available
+    ///
+    /// This reads all files under `/sys/class/uio/uioN/maps/*`, where N ==
+    /// `self.uio_num`. If any of the files are missing or otherwise unreadable,
+    /// that Mapping will be skipped. Devices without memory mappings (e.g.,
+    /// interrupt-only devices without a `maps` directory) return an empty
+    /// vector.
+    pub fn get_mapping_info(&mut self) -> Result<Vec<MappingInfo>, UioError> {
+        let dir = self.sysfs.join("maps");
+        let Some(paths) = Self::read_dir_if_exists(&dir)? else {
+            return Ok(Vec::new());
+        };
+
+        let reg_names = self.reg_names()?;
+        let mut map = Vec::new();
+        'each_map_dir: for p in paths {
+            let entry = p.map_err(UioError::io(Operation::ReadDir, &dir))?;
+            let dir_name = entry.file_name();
+            let Some(dir_name) = dir_name.to_str() else {
+                continue 'each_map_dir;
+            };
+            let file_type = entry
+                .file_type()
+                .map_err(UioError::io(Operation::Metadata, entry.path()))?;
+            if !file_type.is_dir() {
+                continue 'each_map_dir;
+            }
+
+            let Some(index) = parse::index("map", dir_name) else {
+                continue 'each_map_dir;
+            };
+
+            let addr = self.map_addr(index)?;
+            let name = self.map_name(index)?;
+            let len = self.map_size(index)?;
+
+            map.push(MappingInfo {
+                index,
+                addr,
+                len,
+                name,
+                label: reg_names.get(index).cloned(),
+            });
+        }
+
+        Ok(map)
+    }
+
+    /// Returns the index of the mapping called `name` (as in
+    /// `/sys/class/uio/uioX/maps/mapN/name`, e.g. set by the device tree),
+    /// or else labelled `name` in the `reg-names` of the device tree node
+    /// (see `reg_names`).
+    ///
+    /// If several mappings have the same name, the lowest index is returned.
+    pub fn mapping_index(&self, name: &str) -> Result<usize, UioError> {
+        let dir = self.sysfs.join("maps");
+        let mut indices = Vec::new();
+        if let Some(entries) = Self::read_dir_if_exists(&dir)? {
+            for entry in entries {
+                let entry = entry.map_err(UioError::io(Operation::ReadDir, &dir))?;
+                if let Some(index) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|n| parse::index("map", n))
+                {
+                    indices.push(index);
+                }
+            }
+        }
+        indices.sort_unstable();
+        for &index in &indices {
+            if self.map_name(index)? == name {
+                return Ok(index);
+            }
+        }
+        let reg_names = self.reg_names()?;
+        if let Some(index) = reg_names.iter().position(|n| n == name) {
+            if indices.contains(&index) {
+                return Ok(index);
+            }
+        }
+        Err(UioError::UnknownMapping {
+            path: dir,
+            name: name.to_string(),
+        })
+    }
+
+    /// The device tree node of a platform device (`device/of_node`), `None`
+    /// for devices which aren't described in a device tree.
+    pub fn of_node(&self) -> Result<Option<Node>, UioError> {
+        let path = self.sysfs.join("device/of_node");
+        Node::open(&path).map_err(UioError::io(Operation::Read, path))
+    }
+
+    /// The names of the mappings from the `reg-names` property of the
+    /// device tree node, in mapping order. Empty if there is no node or it
+    /// has no `reg-names`.
+    pub fn reg_names(&self) -> Result<Vec<String>, UioError> {
+        let Some(node) = self.of_node()? else {
+            return Ok(Vec::new());
+        };
+        node.strings("reg-names")
+            .map_err(UioError::io(Operation::Read, node.path().join("reg-names")))
+    }
+
+    /// The blocks within the mappings described by child nodes of the device
+    /// tree node, see `uio::devicetree`. Blocks which aren't within a single
+    /// mapping are left out.
+    pub fn sub_regions(&mut self) -> Result<Vec<SubRegion>, UioError> {
+        let Some(node) = self.of_node()? else {
+            return Ok(Vec::new());
+        };
+        let blocks = node
+            .child_ranges()
+            .map_err(UioError::io(Operation::ReadDir, node.path()))?;
+        if blocks.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut mappings = self.get_mapping_info()?;
+        mappings.sort_by_key(|m| m.index);
+        Ok(blocks
+            .into_iter()
+            .filter_map(|(name, addr, len)| {
+                let m = mappings.iter().find(|m| {
+                    addr >= m.addr && addr.saturating_add(len) <= m.addr.saturating_add(m.len)
+                })?;
+                Some(SubRegion {
+                    name,
+                    mapping: m.index,
+                    offset: addr - m.addr,
+                    len,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use linux::pci_mock;
+    use mock::MockUio;
+    use std::fs;
+
+    #[test]
+    fn print_info() {
+        let mock = pci_mock();
+        let res = mock.open().unwrap();
+        let name = res.get_name().expect("Can't get name");
+        let version = res.get_version().expect("Can't get version");
+        let event_count = res.get_event_count().expect("Can't get event count");
+        assert_eq!(name, "uio_pci_generic");
+        assert_eq!(version, "0.01.0");
+        assert_eq!(event_count, 0);
+    }
+
+    #[test]
+    fn device_tree_names() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0xa000_0000, 0x10000, "dma").unwrap();
+        mock.add_mapping(0xa001_0000, 0x1000, "dma").unwrap();
+        let cells =
+            |cells: &[u32]| -> Vec<u8> { cells.iter().flat_map(|c| c.to_be_bytes()).collect() };
+        let node = mock.set_of_node("amba/dma@a0000000").unwrap();
+        fs::write(node.join("../#address-cells"), cells(&[1])).unwrap();
+        fs::write(node.join("reg-names"), "ctrl\0desc\0").unwrap();
+        fs::write(node.join("#address-cells"), cells(&[1])).unwrap();
+        fs::write(node.join("#size-cells"), cells(&[1])).unwrap();
+        fs::write(node.join("ranges"), cells(&[0, 0xa000_0000, 0x10000])).unwrap();
+        for (child, reg) in [("mm2s@0", [0, 0x30]), ("s2mm@30", [0x30, 0x30])] {
+            fs::create_dir(node.join(child)).unwrap();
+            fs::write(node.join(child).join("reg"), cells(&reg)).unwrap();
+        }
+        // Outside the ranges
+        fs::create_dir(node.join("misc@20000")).unwrap();
+        fs::write(node.join("misc@20000/reg"), cells(&[0x20000, 4])).unwrap();
+
+        let mut dev = mock.open().unwrap();
+        assert_eq!(dev.reg_names().unwrap(), ["ctrl", "desc"]);
+        assert_eq!(dev.mapping_index("desc").unwrap(), 1);
+        assert_eq!(dev.mapping_index("dma").unwrap(), 0);
+        assert_eq!(dev.map_by_name("desc").unwrap().len(), 0x1000);
+        let labels: Vec<_> = {
+            let mut info = dev.get_mapping_info().unwrap();
+            info.sort_by_key(|m| m.index);
+            info.into_iter().map(|m| m.label).collect()
+        };
+        assert_eq!(labels, [Some("ctrl".to_string()), Some("desc".to_string())]);
+        let sub_regions: Vec<_> = dev
+            .sub_regions()
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.mapping, r.offset, r.len))
+            .collect();
+        assert_eq!(
+            sub_regions,
+            [
+                ("mm2s".to_string(), 0, 0, 0x30),
+                ("s2mm".to_string(), 0, 0x30, 0x30)
+            ]
+        );
+
+        // Devices without a node have neither
+        let mut mock = MockUio::new(1).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let mut dev = mock.open().unwrap();
+        assert!(dev.reg_names().unwrap().is_empty());
+        assert!(dev.sub_regions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_device_tree() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0xa000_0000, 0x10000, "dma").unwrap();
+        let cells =
+            |cells: &[u32]| -> Vec<u8> { cells.iter().flat_map(|c| c.to_be_bytes()).collect() };
+        let node = mock.set_of_node("amba/dma@a0000000").unwrap();
+        fs::create_dir(node.join("mm2s@30")).unwrap();
+        fs::write(node.join("mm2s@30/reg"), cells(&[0x30, 0x30])).unwrap();
+
+        // No cells at all
+        for name in ["../#address-cells", "#address-cells", "#size-cells"] {
+            fs::write(node.join(name), cells(&[0])).unwrap();
+        }
+        fs::write(node.join("ranges"), cells(&[0, 0xa000_0000, 0x10000])).unwrap();
+        let mut dev = mock.open().unwrap();
+        assert!(dev.sub_regions().unwrap().is_empty());
+        drop(dev);
+
+        // A range whose translation overflows
+        fs::write(node.join("../#address-cells"), cells(&[2])).unwrap();
+        fs::write(node.join("#address-cells"), cells(&[1])).unwrap();
+        fs::write(node.join("#size-cells"), cells(&[1])).unwrap();
+        let ranges = cells(&[0, 0xffff_ffff, 0xffff_ffff, 0x10000]);
+        fs::write(node.join("ranges"), ranges).unwrap();
+        let mut dev = mock.open().unwrap();
+        assert!(dev.sub_regions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cache_metadata() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.builder().cache_metadata(true).open().unwrap();
+        assert_eq!(dev.map_size(0).unwrap(), 0x1000);
+        assert_eq!(dev.get_event_count().unwrap(), 0);
+
+        mock.set_attr("maps/map0/size", "0x2000").unwrap();
+        mock.set_event_count(3).unwrap();
+        assert_eq!(dev.map_size(0).unwrap(), 0x1000);
+        assert_eq!(dev.get_event_count().unwrap(), 3);
+        dev.refresh();
+        assert_eq!(dev.map_size(0).unwrap(), 0x2000);
+    }
+
+    #[test]
+    fn mapping_info() {
+        let mock = pci_mock();
+        let mut res = mock.open().unwrap();
+        let maps = res.get_mapping_info().unwrap();
+        assert_eq!(maps.len(), 1);
+        assert_eq!(maps[0].addr, 0xfebf_0000);
+        assert_eq!(maps[0].len, 0x1000);
+        assert_eq!(maps[0].name, "bar0");
+        assert_eq!(res.device_kind().unwrap(), ::linux::DeviceKind::Pci);
+
+        let region = res.map_region(0).unwrap();
+        region.write_u32(0x8, 0x1234_5678);
+        assert_eq!(region.read_u16(0x8), 0x5678);
+    }
+
+    #[test]
+    fn number_formats() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        mock.set_attr("maps/map0/addr", "fe000000").unwrap();
+        mock.set_attr("maps/map0/size", "4096").unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.map_addr(0).unwrap(), 0xfe00_0000);
+        assert_eq!(dev.map_size(0).unwrap(), 0x1000);
+
+        mock.set_attr("maps/map0/size", "x").unwrap();
+        let err = dev.map_size(0).unwrap_err();
+        assert_eq!(
+            err.path(),
+            Some(mock.sysfs_path().join("maps/map0/size").as_path())
+        );
+        assert!(matches!(
+            err,
+            ::linux::UioError::Parse {
+                reason: ::parse::NumberError::InvalidDigit,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn high_addresses() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x40_0000_0000, 0x1000, "high").unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.map_addr(0).unwrap(), 0x40_0000_0000);
+        assert_eq!(dev.map_size(0).unwrap(), 0x1000);
+    }
+
+    #[test]
+    fn siblings() {
+        let mock = MockUio::new(1).unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.siblings().unwrap(), Vec::<usize>::new());
+        for n in [0, 1, 2] {
+            fs::create_dir_all(mock.sysfs_path().join(format!("device/uio/uio{}", n))).unwrap();
+        }
+        assert_eq!(dev.siblings().unwrap(), vec![0, 2]);
+    }
+
+    #[test]
+    fn platform_device() {
+        let mock = MockUio::new(3).unwrap();
+        let mut res = mock.open().unwrap();
+        #[cfg(feature = "pci")]
+        assert_eq!(res.get_resource_info().unwrap(), vec![]);
+        assert_eq!(res.get_mapping_info().unwrap().len(), 0);
+        assert_eq!(res.device_kind().unwrap(), ::linux::DeviceKind::Unknown);
+    }
+
+    #[test]
+    fn device_attrs() {
+        let mock = pci_mock();
+        mock.set_attr("device/fw_version", "1.4.2\n").unwrap();
+        mock.set_attr("device/temperature", "-5000\n").unwrap();
+        mock.set_attr("device/mode", "0x2\n").unwrap();
+        let dev = mock.open().unwrap();
+
+        let attrs = dev.list_device_attrs().unwrap();
+        for name in &["fw_version", "mode", "temperature"] {
+            assert!(attrs.iter().any(|a| a == name), "{} in {:?}", name, attrs);
+        }
+        assert_eq!(dev.device_attr("fw_version").unwrap(), "1.4.2");
+        assert_eq!(dev.device_attr_i64("temperature").unwrap(), -5000);
+        assert_eq!(dev.device_attr_u64("mode").unwrap(), 2);
+        assert!(matches!(
+            dev.device_attr_u64("temperature"),
+            Err(::linux::UioError::Parse { .. })
+        ));
+        assert_eq!(
+            dev.device_attr("../name").unwrap_err().kind(),
+            ::std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            dev.device_attr("missing").unwrap_err().kind(),
+            ::std::io::ErrorKind::NotFound
+        );
+
+        let platform = MockUio::new(1).unwrap();
+        let dev = platform.open().unwrap();
+        assert_eq!(dev.list_device_attrs().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn write_device_attrs() {
+        use mock::Fault;
+        use Operation;
+
+        let mock = pci_mock();
+        mock.set_attr("device/mode", "normal\n").unwrap();
+        let dev = mock.open().unwrap();
+        dev.write_device_attr("mode", "loopback").unwrap();
+        assert_eq!(dev.device_attr("mode").unwrap(), "loopback");
+        assert!(dev.write_device_attr("mode", "").is_err());
+        assert!(dev.write_device_attr("../name", "x").is_err());
+
+        mock.inject(Operation::Write, Fault::Error(libc::EINVAL));
+        let err = dev.write_device_attr("mode", "bogus").unwrap_err();
+        assert!(
+            matches!(err, ::linux::UioError::InvalidValue { ref value, .. } if value == "bogus")
+        );
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        mock.clear_faults();
+
+        mock.inject(Operation::Write, Fault::Error(libc::EACCES));
+        assert!(matches!(
+            dev.write_device_attr("mode", "normal"),
+            Err(::linux::UioError::PermissionDenied { .. })
+        ));
+        mock.clear_faults();
+        assert_eq!(dev.device_attr("mode").unwrap(), "loopback");
+        drop(dev);
+
+        let dev = mock.builder().read_only(true).open().unwrap();
+        assert!(dev.write_device_attr("mode", "normal").is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! A fake UIO device for tests without hardware.
+//!
+//! `MockUio` creates a temporary directory with the same layout as
+//! `/sys/class/uio/uioN` and `/dev/uioN`, which a `UioDevice` can be opened
+//! from through `MockUio::builder`:
+//!
+//! ```
+//! use uio::mock::MockUio;
+//!
+//! let mut mock = MockUio::new(0).unwrap();
+//! mock.set_name("my_device").unwrap();
+//! mock.add_mapping(0x4000_0000, 0x1000, "control").unwrap();
+//!
+//! let dev = mock.open().unwrap();
+//! assert_eq!(dev.get_name().unwrap(), "my_device");
+//! let regs = dev.map_region(0).unwrap();
+//! regs.write_u32(0x10, 0xdead_beef);
+//! assert_eq!(regs.read_u32(0x10), 0xdead_beef);
+//! ```
+//!
+//! The device file is a regular file, so mappings are backed by plain memory
+//! and writes to them persist. Interrupts are not simulated: `irq_enable` and
+//! `irq_disable` succeed but have no effect and `irq_wait` must not be used.
+//!
+//! # Fault injection
+//!
+//! Error paths can be tested by injecting faults into the operations on a
+//! mock, which stay active until `clear_faults` is called or the mock is
+//! dropped:
+//!
+//! ```
+//! use uio::mock::{Fault, MockUio};
+//! use uio::Operation;
+//!
+//! let mock = MockUio::new(0).unwrap();
+//! mock.inject(Operation::Open, Fault::Error(libc::EACCES));
+//! assert!(mock.open().is_err());
+//!
+//! mock.clear_faults();
+//! let dev = mock.open().unwrap();
+//! mock.unplug();
+//! assert_eq!(dev.irq_enable().unwrap_err().raw_os_error(), Some(libc::ENODEV));
+//! ```
+//!
+//! # Simulated registers
+//!
+//! Driver logic written against `DeviceMemory` can also be tested without a
+//! device directory with `MockMemory`, plain memory with optional hooks that
+//! simulate the device's reaction to accesses:
+//!
+//! ```
+//! use uio::mock::MockMemory;
+//! use uio::DeviceMemory;
+//!
+//! let mut regs = MockMemory::new(0x100);
+//! // Writing 1 to CTRL (0x0) sets the done bit in STATUS (0x4)
+//! regs.on_write(|mem, offset, _width, value| {
+//!     if offset == 0x0 && value & 1 != 0 {
+//!         mem[0x4] |= 1;
+//!     }
+//! });
+//! regs.write_u32(0x0, 1);
+//! assert_eq!(regs.read_u32(0x4), 1);
+//! ```
+
+use libc;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use sys;
+use {DeviceMemory, Operation, UioDevice, UioDeviceBuilder, UioError};
+
+const PAGESIZE: u64 = 4096;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A fault injected with `MockUio::inject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with this errno (e.g. `libc::EACCES`).
+    Error(i32),
+    /// Reads return only the first half of the data. Applies to
+    /// `Operation::Read` only.
+    ShortRead,
+}
+
+#[derive(Debug)]
+struct Injection {
+    root: PathBuf,
+    op: Option<Operation>,
+    fault: Fault,
+}
+
+/// Faults of all live mocks, keyed by their root directory.
+static FAULTS: Mutex<Vec<Injection>> = Mutex::new(Vec::new());
+
+fn faults() -> MutexGuard<'static, Vec<Injection>> {
+    FAULTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn find_fault<F: Fn(Fault) -> bool>(op: Operation, path: &Path, pred: F) -> Option<Fault> {
+    faults()
+        .iter()
+        .filter(|i| i.op.is_none_or(|o| o == op) && path.starts_with(&i.root))
+        .map(|i| i.fault)
+        .find(|f| pred(*f))
+}
+
+/// Returns the error injected into `op` on `path`, if any.
+pub(crate) fn injected_error(op: Operation, path: &Path) -> io::Result<()> {
+    match find_fault(op, path, |f| matches!(f, Fault::Error(_))) {
+        Some(Fault::Error(errno)) => Err(io::Error::from_raw_os_error(errno)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether reads of `path` should be cut short.
+pub(crate) fn injected_short_read(path: &Path) -> bool {
+    find_fault(Operation::Read, path, |f| f == Fault::ShortRead).is_some()
+}
+
+/// A fake UIO device in a temporary directory, removed on drop.
+#[derive(Debug)]
+pub struct MockUio {
+    root: PathBuf,
+    uio_num: usize,
+    mappings: usize,
+}
+
+impl MockUio {
+    /// Creates a fake /dev/uio`uio_num` named "mock" with version "0.0.1",
+    /// an event count of 0 and no mappings or resources.
+    pub fn new(uio_num: usize) -> io::Result<MockUio> {
+        let root = std::env::temp_dir().join(format!(
+            "uio-mock-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mock = MockUio {
+            root,
+            uio_num,
+            mappings: 0,
+        };
+        fs::create_dir_all(mock.sysfs_path().join("device"))?;
+        fs::create_dir_all(mock.dev_root())?;
+        File::create(mock.dev_path())?;
+        mock.set_name("mock")?;
+        mock.set_version("0.0.1")?;
+        mock.set_event_count(0)?;
+        Ok(mock)
+    }
+
+    /// Root of the fake file system.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The directory passed to `UioDeviceBuilder::sysfs_root`.
+    pub fn sysfs_root(&self) -> PathBuf {
+        self.root.join("sys/class/uio")
+    }
+
+    /// The fake `/sys/class/uio/uioN` directory.
+    pub fn sysfs_path(&self) -> PathBuf {
+        self.sysfs_root().join(format!("uio{}", self.uio_num))
+    }
+
+    /// The directory passed to `UioDeviceBuilder::dev_root`.
+    pub fn dev_root(&self) -> PathBuf {
+        self.root.join("dev")
+    }
+
+    /// The fake `/dev/uioN` file.
+    pub fn dev_path(&self) -> PathBuf {
+        self.dev_root().join(format!("uio{}", self.uio_num))
+    }
+
+    /// Writes the attribute `name` (relative to the sysfs directory).
+    pub fn set_attr<P: AsRef<Path>>(&self, name: P, value: &str) -> io::Result<()> {
+        let path = self.sysfs_path().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Overwrite in place rather than truncating first, so a concurrent
+        // reader never sees an empty attribute
+        let value = format!("{}\n", value);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.write_all(value.as_bytes())?;
+        file.set_len(value.len() as u64)
+    }
+
+    /// Sets the name of the device.
+    pub fn set_name(&self, name: &str) -> io::Result<()> {
+        self.set_attr("name", name)
+    }
+
+    /// Sets the version of the driver.
+    pub fn set_version(&self, version: &str) -> io::Result<()> {
+        self.set_attr("version", version)
+    }
+
+    /// Sets the interrupt event counter.
+    pub fn set_event_count(&self, count: u32) -> io::Result<()> {
+        self.set_attr("event", &count.to_string())
+    }
+
+    /// Makes the device a child of a device of `subsystem` (e.g. "pci").
+    pub fn set_subsystem(&self, subsystem: &str) -> io::Result<()> {
+        let bus = self.root.join("sys/bus").join(subsystem);
+        fs::create_dir_all(&bus)?;
+        let link = self.sysfs_path().join("device/subsystem");
+        let _ = fs::remove_file(&link);
+        symlink(bus, link)
+    }
+
+    /// Binds the device to the kernel driver `driver` (e.g.
+    /// "uio_pdrv_genirq"), see `UioDevice::driver_name`.
+    pub fn set_driver(&self, driver: &str) -> io::Result<()> {
+        let dir = self.root.join("sys/bus/drivers").join(driver);
+        fs::create_dir_all(&dir)?;
+        let link = self.sysfs_path().join("device/driver");
+        let _ = fs::remove_file(&link);
+        symlink(dir, link)
+    }
+
+    /// Links the device to the device tree node `node` (e.g.
+    /// "amba/dma@a0000000"), which is created if needed, and returns the
+    /// node's directory for adding properties.
+    pub fn set_of_node(&self, node: &str) -> io::Result<PathBuf> {
+        let dir = self.root.join("sys/firmware/devicetree/base").join(node);
+        fs::create_dir_all(&dir)?;
+        let link = self.sysfs_path().join("device/of_node");
+        fs::create_dir_all(self.sysfs_path().join("device"))?;
+        let _ = fs::remove_file(&link);
+        symlink(&dir, link)?;
+        Ok(dir)
+    }
+
+    /// Adds the next memory mapping (`maps/mapN`) and returns its index.
+    ///
+    /// The device file is grown so the mapping is backed by zeroed memory.
+    /// Like with real UIO devices, mapping N is found at offset N * 4096 of
+    /// the device file, so mappings larger than a page overlap the following
+    /// ones.
+    pub fn add_mapping(&mut self, addr: u64, size: u64, name: &str) -> io::Result<usize> {
+        let index = self.mappings;
+        let map = format!("maps/map{}", index);
+        self.set_attr(format!("{}/addr", map), &format!("{:#x}", addr))?;
+        self.set_attr(format!("{}/size", map), &format!("{:#x}", size))?;
+        self.set_attr(format!("{}/offset", map), "0x0")?;
+        self.set_attr(format!("{}/name", map), name)?;
+
+        let end = index as u64 * PAGESIZE + size.div_ceil(PAGESIZE) * PAGESIZE;
+        let devfile = OpenOptions::new().write(true).open(self.dev_path())?;
+        if devfile.metadata()?.len() < end {
+            devfile.set_len(end)?;
+        }
+        self.mappings += 1;
+        Ok(index)
+    }
+
+    /// Adds (or resizes) the resource file `device/resourceN`.
+    pub fn add_resource(&self, bar_nr: usize, size: u64) -> io::Result<()> {
+        let path = self.sysfs_path().join(format!("device/resource{}", bar_nr));
+        let f = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        f.set_len(size)
+    }
+
+    /// Injects `fault` into every `op` on the files of this mock.
+    pub fn inject(&self, op: Operation, fault: Fault) {
+        faults().push(Injection {
+            root: self.root.clone(),
+            op: Some(op),
+            fault,
+        });
+    }
+
+    /// Simulates hot-unplugging the device: all further operations on it
+    /// fail with `ENODEV`.
+    pub fn unplug(&self) {
+        faults().push(Injection {
+            root: self.root.clone(),
+            op: None,
+            fault: Fault::Error(libc::ENODEV),
+        });
+    }
+
+    /// Removes all injected faults.
+    pub fn clear_faults(&self) {
+        faults().retain(|i| i.root != self.root);
+    }
+
+    /// Takes the lock on the device file like another process would, so
+    /// opening the device runs into lock contention until the returned file
+    /// is dropped.
+    pub fn hold_lock(&self) -> io::Result<File> {
+        let f = File::open(self.dev_path())?;
+        sys::try_lock_exclusive(&f)?;
+        Ok(f)
+    }
+
+    /// A builder to open the fake device, which can be further customized.
+    pub fn builder(&self) -> UioDeviceBuilder {
+        let mut builder = UioDeviceBuilder::new(self.uio_num);
+        builder
+            .sysfs_root(self.sysfs_root())
+            .dev_root(self.dev_root())
+            // Interrupt control writes must not clobber the mapping memory
+            .custom_flags(libc::O_APPEND);
+        builder
+    }
+
+    /// Opens the fake device with the default options.
+    pub fn open(&self) -> Result<UioDevice, UioError> {
+        self.builder().open()
+    }
+}
+
+impl Drop for MockUio {
+    fn drop(&mut self) {
+        self.clear_faults();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+type ReadHook = Box<dyn FnMut(&mut [u8], usize, usize) -> Option<u64> + Send>;
+type WriteHook = Box<dyn FnMut(&mut [u8], usize, usize, u64) + Send>;
+
+struct MemoryState {
+    data: Vec<u8>,
+    on_read: Option<ReadHook>,
+    on_write: Option<WriteHook>,
+}
+
+/// Zero-initialized memory implementing `DeviceMemory`, for testing driver
+/// logic against simulated registers.
+///
+/// Accesses are checked like those of a `MappedRegion` and values are
+/// stored in native byte order.
+pub struct MockMemory {
+    state: Mutex<MemoryState>,
+}
+
+macro_rules! mock_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            fn $read(&self, offset: usize) -> $ty {
+                const N: usize = ::std::mem::size_of::<$ty>();
+                let mut state = self.access(offset, N);
+                let MemoryState { ref mut data, ref mut on_read, .. } = *state;
+                if let Some(value) = on_read.as_mut().and_then(|f| f(data, offset, N)) {
+                    return value as $ty;
+                }
+                let mut bytes = [0u8; N];
+                bytes.copy_from_slice(&data[offset..offset + N]);
+                <$ty>::from_ne_bytes(bytes)
+            }
+
+            fn $write(&self, offset: usize, value: $ty) {
+                const N: usize = ::std::mem::size_of::<$ty>();
+                let mut state = self.access(offset, N);
+                let state = &mut *state;
+                state.data[offset..offset + N].copy_from_slice(&value.to_ne_bytes());
+                if let Some(f) = state.on_write.as_mut() {
+                    f(&mut state.data, offset, N, value as u64);
+                }
+            }
+        )*
+    };
+}
+
+impl MockMemory {
+    /// Creates `len` bytes of zeroed memory.
+    pub fn new(len: usize) -> MockMemory {
+        MockMemory::from_bytes(vec![0; len])
+    }
+
+    /// Creates memory with the initial contents `data`.
+    pub fn from_bytes(data: Vec<u8>) -> MockMemory {
+        MockMemory {
+            state: Mutex::new(MemoryState {
+                data,
+                on_read: None,
+                on_write: None,
+            }),
+        }
+    }
+
+    /// Calls `hook(memory, offset, width)` before every read. If it returns
+    /// a value, the read returns it instead of the memory contents, e.g. to
+    /// simulate read-to-clear registers.
+    pub fn on_read<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut [u8], usize, usize) -> Option<u64> + Send + 'static,
+    {
+        self.lock().on_read = Some(Box::new(hook));
+    }
+
+    /// Calls `hook(memory, offset, width, value)` after every write, e.g. to
+    /// update status registers in response to a command.
+    pub fn on_write<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut [u8], usize, usize, u64) + Send + 'static,
+    {
+        self.lock().on_write = Some(Box::new(hook));
+    }
+
+    /// A copy of the current contents, bypassing the hooks.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.lock().data.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn access(&self, offset: usize, size: usize) -> MutexGuard<'_, MemoryState> {
+        let state = self.lock();
+        assert!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end <= state.data.len()),
+            "access of {} bytes at offset {:#x} is out of bounds (len {:#x})",
+            size,
+            offset,
+            state.data.len()
+        );
+        assert!(
+            offset.is_multiple_of(size),
+            "access of {} bytes at offset {:#x} is misaligned",
+            size,
+            offset
+        );
+        state
+    }
+}
+
+impl DeviceMemory for MockMemory {
+    fn len(&self) -> usize {
+        self.lock().data.len()
+    }
+
+    mock_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+impl fmt::Debug for MockMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockMemory")
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,147 @@
+//! Watching the interrupt activity of devices owned by other processes.
+//!
+//! Only one process can hold a device open, but its event counter in sysfs
+//! can be read by anyone. An `EventMonitor` polls it on a background thread
+//! and calls back when it changes, e.g. to show activity in a status tool:
+//!
+//! ```ignore
+//! let monitor = EventMonitor::spawn(0, Duration::from_millis(100), |old, new| {
+//!     println!("uio0: {} interrupts", new.wrapping_sub(old));
+//! })?;
+//! // ...
+//! monitor.stop()?;
+//! ```
+//!
+//! The kernel doesn't notify readers of the attribute, so changes are only
+//! seen every polling interval and several interrupts in between are
+//! reported as one change.
+
+use parse;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Polls the event counter of a device on a background thread until it is
+/// stopped or reading fails (e.g. because the device went away).
+#[derive(Debug)]
+pub struct EventMonitor {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl EventMonitor {
+    /// Reads the event counter of /dev/uio`uio_num` every `interval` and
+    /// calls `on_change(previous, count)` when it changed.
+    ///
+    /// Fails if the counter can't be read initially.
+    pub fn spawn<F>(uio_num: usize, interval: Duration, on_change: F) -> io::Result<EventMonitor>
+    where
+        F: FnMut(u32, u32) + Send + 'static,
+    {
+        EventMonitor::spawn_in(Path::new("/sys/class/uio"), uio_num, interval, on_change)
+    }
+
+    /// Like `spawn`, with a custom sysfs directory (see
+    /// `UioDeviceBuilder::sysfs_root`).
+    pub fn spawn_in<F>(
+        sysfs_root: &Path,
+        uio_num: usize,
+        interval: Duration,
+        mut on_change: F,
+    ) -> io::Result<EventMonitor>
+    where
+        F: FnMut(u32, u32) + Send + 'static,
+    {
+        let path = sysfs_root.join(format!("uio{}/event", uio_num));
+        let file = File::open(&path)?;
+        let mut count = read_count(&file, &path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new()
+            .name(format!("uio{}-monitor", uio_num))
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    // `stop` unparks the thread, so it doesn't sleep out the
+                    // interval
+                    thread::park_timeout(interval);
+                    let new = read_count(&file, &path)?;
+                    if new != count {
+                        on_change(count, new);
+                        count = new;
+                    }
+                }
+                Ok(())
+            })?;
+        Ok(EventMonitor { stop, thread })
+    }
+
+    /// Whether the thread ended because reading the counter failed. `stop`
+    /// returns the error.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Stops monitoring and returns the error of the thread, if any.
+    pub fn stop(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("monitor callback panicked")))
+    }
+}
+
+fn read_count(file: &File, path: &Path) -> io::Result<u32> {
+    let mut buf = [0u8; 32];
+    let n = file.read_at(&mut buf, 0)?;
+    parse::number(&buf[..n])
+        .ok()
+        .and_then(|count| u32::try_from(count).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid event count in {}", path.display()),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+    use std::sync::mpsc;
+
+    #[test]
+    fn monitor() {
+        let mock = MockUio::new(3).unwrap();
+        mock.set_event_count(5).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let monitor = EventMonitor::spawn_in(
+            &mock.sysfs_root(),
+            3,
+            Duration::from_millis(1),
+            move |o, n| {
+                let _ = tx.send((o, n));
+            },
+        )
+        .unwrap();
+        mock.set_event_count(7).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout), Ok((5, 7)));
+        mock.set_event_count(12).unwrap();
+        assert_eq!(rx.recv_timeout(timeout), Ok((7, 12)));
+        assert!(!monitor.is_finished());
+        monitor.stop().unwrap();
+
+        assert!(
+            EventMonitor::spawn_in(&mock.sysfs_root(), 4, Duration::from_millis(1), |_, _| {})
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,651 @@
+//! Burst receive and transmit for NIC-style devices.
+//!
+//! Most network (and similar streaming) devices work the same way: the
+//! driver hands buffers to the device through a ring of descriptors, rings a
+//! doorbell register with the new tail and later finds the descriptors
+//! marked as done. This module implements that flow once, in bursts as in
+//! DPDK, and leaves the hardware specifics to the driver:
+//!
+//! * a `BufPool` splits DMA memory (e.g. a `DmaBuffer`) into fixed-size
+//!   packet buffers, referred to by `BufHandle`s,
+//! * the driver implements `Descriptors` for its descriptor format and
+//!   doorbell,
+//! * `RxQueue::rx_burst` and `TxQueue::tx_burst` move buffers between the
+//!   application and the device, refilling and reclaiming as they go.
+//!
+//! ```ignore
+//! struct MyRing<'a> { descs: &'a MappedRegion<'a>, regs: &'a MappedRegion<'a> }
+//!
+//! impl<'a> Descriptors for MyRing<'a> {
+//!     fn len(&self) -> usize { 256 }
+//!     fn post(&mut self, index: usize, addr: u64, len: usize) {
+//!         self.descs.write_u64(index * 16, addr);
+//!         self.descs.write_u32(index * 16 + 8, len as u32);
+//!         self.descs.write_u32(index * 16 + 12, 0);
+//!     }
+//!     fn completed(&mut self, index: usize) -> Option<usize> {
+//!         let status = self.descs.read_u32(index * 16 + 12);
+//!         if status & DONE != 0 { Some((status & 0xffff) as usize) } else { None }
+//!     }
+//!     fn doorbell(&mut self, tail: usize) {
+//!         self.regs.write_u32(RX_TAIL, tail as u32);
+//!     }
+//! }
+//!
+//! let mem = dev.map_dma_buffer(1)?;
+//! let pool = BufPool::new(&*mem, mem.bus_addr(), 2048, 512);
+//! let mut rx = RxQueue::new(MyRing { .. }, &pool);
+//! let mut bufs = [BufHandle::default(); 32];
+//! loop {
+//!     let n = rx.rx_burst(&mut bufs);
+//!     for buf in &bufs[..n] {
+//!         // process pool.bytes(buf), then give the buffer back
+//!         pool.free(*buf);
+//!     }
+//! }
+//! ```
+//!
+//! Descriptors are written before the doorbell with a memory fence in
+//! between, and a fence separates seeing a descriptor completed from
+//! reading its buffer.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{fence, Ordering};
+use {DeviceMemory, MappedRegion};
+
+/// A buffer of a `BufPool` with the length of the data in it.
+///
+/// Handles are plain values, like the buffer pointers of other burst APIs:
+/// whoever holds a handle owns the buffer until it is passed to a queue or
+/// back to `BufPool::free`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufHandle {
+    index: u32,
+    len: u32,
+}
+
+impl BufHandle {
+    /// The index of the buffer in its pool.
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    /// Length of the data in the buffer.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Fixed-size packet buffers in memory the device can access.
+///
+/// The pool is meant to be used from a single thread, like the queues
+/// borrowing it.
+pub struct BufPool<'m, M: DeviceMemory + ?Sized + 'm = MappedRegion<'m>> {
+    mem: &'m M,
+    bus_addr: u64,
+    buf_size: usize,
+    count: usize,
+    state: RefCell<PoolState>,
+}
+
+struct PoolState {
+    free: Vec<u32>,
+    in_use: Vec<bool>,
+}
+
+impl<'m, M: DeviceMemory + ?Sized> BufPool<'m, M> {
+    /// `count` buffers of `buf_size` bytes at the start of `mem`, which the
+    /// device sees at the bus address `bus_addr`. All buffers are free.
+    ///
+    /// # Panics
+    /// If `buf_size` or `count` is 0 or the buffers don't fit `mem`.
+    pub fn new(mem: &'m M, bus_addr: u64, buf_size: usize, count: usize) -> Self {
+        assert!(
+            buf_size > 0
+                && count > 0
+                && count <= u32::MAX as usize
+                && buf_size
+                    .checked_mul(count)
+                    .is_some_and(|len| len <= mem.len()),
+            "{} buffers of {:#x} bytes don't fit memory of {:#x} bytes",
+            count,
+            buf_size,
+            mem.len()
+        );
+        BufPool {
+            mem,
+            bus_addr,
+            buf_size,
+            count,
+            state: RefCell::new(PoolState {
+                // Hand out low indices first
+                free: (0..count as u32).rev().collect(),
+                in_use: vec![false; count],
+            }),
+        }
+    }
+
+    /// Size of each buffer in bytes.
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// Number of buffers.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Whether the pool has no buffers, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Number of free buffers.
+    pub fn available(&self) -> usize {
+        self.state.borrow().free.len()
+    }
+
+    /// Takes a free buffer, `None` if all are in use.
+    pub fn alloc(&self) -> Option<BufHandle> {
+        let mut state = self.state.borrow_mut();
+        let index = state.free.pop()?;
+        state.in_use[index as usize] = true;
+        Some(BufHandle { index, len: 0 })
+    }
+
+    /// Returns a buffer to the pool.
+    ///
+    /// # Panics
+    /// If the buffer is not from this pool or already free.
+    pub fn free(&self, buf: BufHandle) {
+        let mut state = self.state.borrow_mut();
+        assert!(
+            state.in_use.get(buf.index()) == Some(&true),
+            "buffer {} is not in use",
+            buf.index
+        );
+        state.in_use[buf.index()] = false;
+        state.free.push(buf.index);
+    }
+
+    /// Offset of the buffer in the memory of the pool.
+    pub fn offset(&self, buf: &BufHandle) -> usize {
+        assert!(
+            buf.index() < self.count,
+            "buffer {} out of range",
+            buf.index
+        );
+        buf.index() * self.buf_size
+    }
+
+    /// Bus address of the buffer, for descriptors.
+    pub fn bus_addr(&self, buf: &BufHandle) -> u64 {
+        self.bus_addr + self.offset(buf) as u64
+    }
+
+    /// Copies the data in the buffer (`buf.len()` bytes) into `dst` and
+    /// returns its length.
+    ///
+    /// # Panics
+    /// If `dst` is shorter than the data.
+    pub fn read(&self, buf: &BufHandle, dst: &mut [u8]) -> usize {
+        self.mem.read_bytes(self.offset(buf), &mut dst[..buf.len()]);
+        buf.len()
+    }
+
+    /// The data in the buffer.
+    pub fn bytes(&self, buf: &BufHandle) -> Vec<u8> {
+        let mut data = vec![0; buf.len()];
+        self.read(buf, &mut data);
+        data
+    }
+
+    /// Copies `data` into the buffer and sets its length.
+    ///
+    /// # Panics
+    /// If `data` is larger than a buffer.
+    pub fn write(&self, buf: &mut BufHandle, data: &[u8]) {
+        assert!(
+            data.len() <= self.buf_size,
+            "{} bytes don't fit a buffer of {} bytes",
+            data.len(),
+            self.buf_size
+        );
+        self.mem.write_bytes(self.offset(buf), data);
+        buf.len = data.len() as u32;
+    }
+}
+
+impl<'m, M: DeviceMemory + ?Sized> fmt::Debug for BufPool<'m, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufPool")
+            .field("bus_addr", &self.bus_addr)
+            .field("buf_size", &self.buf_size)
+            .field("count", &self.count)
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+/// The descriptor ring and doorbell of a queue, implemented by drivers for
+/// their hardware.
+///
+/// The queues use descriptors in order, wrapping around at `len`, and keep
+/// track of which ones are in use.
+pub trait Descriptors {
+    /// Number of descriptors in the ring.
+    fn len(&self) -> usize;
+
+    /// Whether the ring has no descriptors.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hands the buffer at bus address `addr` to the device in descriptor
+    /// `index`, clearing its completion status. For receive queues `len` is
+    /// the size of the buffer, for transmit queues the length of the data.
+    fn post(&mut self, index: usize, addr: u64, len: usize);
+
+    /// Whether the device is done with descriptor `index`, with the length
+    /// of the data it received (transmit queues may return any length).
+    fn completed(&mut self, index: usize) -> Option<usize>;
+
+    /// Tells the device that the descriptors before `tail` (wrapping
+    /// around) were posted, e.g. by writing a tail register.
+    fn doorbell(&mut self, tail: usize);
+}
+
+/// Buffers posted to a ring, in order.
+#[derive(Debug)]
+struct Slots {
+    bufs: Vec<Option<BufHandle>>,
+    /// Oldest posted descriptor
+    head: usize,
+    /// Next descriptor to post
+    tail: usize,
+    posted: usize,
+}
+
+impl Slots {
+    fn new(len: usize) -> Slots {
+        assert!(len > 0, "descriptor ring is empty");
+        Slots {
+            bufs: vec![None; len],
+            head: 0,
+            tail: 0,
+            posted: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.posted == self.bufs.len()
+    }
+
+    fn push(&mut self, buf: BufHandle) {
+        self.bufs[self.tail] = Some(buf);
+        self.tail = (self.tail + 1) % self.bufs.len();
+        self.posted += 1;
+    }
+
+    /// Takes the oldest buffer if the device completed it.
+    fn pop_completed<D: Descriptors>(&mut self, desc: &mut D) -> Option<BufHandle> {
+        if self.posted == 0 {
+            return None;
+        }
+        let len = desc.completed(self.head)?;
+        // Don't read the buffer before the device finished writing it
+        fence(Ordering::SeqCst);
+        let mut buf = self.bufs[self.head].take().expect("descriptor is posted");
+        buf.len = len as u32;
+        self.head = (self.head + 1) % self.bufs.len();
+        self.posted -= 1;
+        Some(buf)
+    }
+}
+
+/// Rings the doorbell after the descriptors written before reached memory.
+fn ring<D: Descriptors>(desc: &mut D, tail: usize) {
+    fence(Ordering::SeqCst);
+    desc.doorbell(tail);
+}
+
+/// A receive queue, which keeps its ring filled with buffers of a pool.
+pub struct RxQueue<'p, D: Descriptors, M: DeviceMemory + ?Sized + 'p = MappedRegion<'p>> {
+    desc: D,
+    pool: &'p BufPool<'p, M>,
+    slots: Slots,
+    /// Completions longer than a buffer, which were dropped
+    oversized: u64,
+}
+
+impl<'p, D: Descriptors, M: DeviceMemory + ?Sized> RxQueue<'p, D, M> {
+    /// Creates the queue and posts as many buffers of `pool` as the ring
+    /// holds.
+    ///
+    /// # Panics
+    /// If the ring has no descriptors.
+    pub fn new(desc: D, pool: &'p BufPool<'p, M>) -> Self {
+        let slots = Slots::new(desc.len());
+        let mut queue = RxQueue {
+            desc,
+            pool,
+            slots,
+            oversized: 0,
+        };
+        queue.refill();
+        queue
+    }
+
+    /// Posts free buffers of the pool to empty descriptors and returns how
+    /// many were posted. `rx_burst` does this as well.
+    pub fn refill(&mut self) -> usize {
+        let mut n = 0;
+        while !self.slots.is_full() {
+            let Some(buf) = self.pool.alloc() else {
+                break;
+            };
+            self.desc.post(
+                self.slots.tail,
+                self.pool.bus_addr(&buf),
+                self.pool.buf_size(),
+            );
+            self.slots.push(buf);
+            n += 1;
+        }
+        if n > 0 {
+            ring(&mut self.desc, self.slots.tail);
+        }
+        n
+    }
+
+    /// Moves up to `bufs.len()` received buffers into `bufs` and returns
+    /// how many, then refills the ring. The caller owns the returned
+    /// buffers and frees them when done.
+    ///
+    /// Buffers the device reports more data in than they hold are dropped
+    /// (see `oversized`).
+    pub fn rx_burst(&mut self, bufs: &mut [BufHandle]) -> usize {
+        let mut n = 0;
+        while n < bufs.len() {
+            let Some(buf) = self.slots.pop_completed(&mut self.desc) else {
+                break;
+            };
+            if buf.len() > self.pool.buf_size() {
+                self.oversized += 1;
+                self.pool.free(buf);
+                continue;
+            }
+            bufs[n] = buf;
+            n += 1;
+        }
+        self.refill();
+        n
+    }
+
+    /// The number of completions dropped by `rx_burst` because their
+    /// length exceeded the buffer size.
+    pub fn oversized(&self) -> u64 {
+        self.oversized
+    }
+
+    /// Number of buffers posted to the device.
+    pub fn posted(&self) -> usize {
+        self.slots.posted
+    }
+
+    /// The descriptors, e.g. to access driver state.
+    pub fn descriptors(&mut self) -> &mut D {
+        &mut self.desc
+    }
+}
+
+impl<'p, D: Descriptors, M: DeviceMemory + ?Sized> Drop for RxQueue<'p, D, M> {
+    /// Returns the posted buffers to the pool. The device must be stopped
+    /// before, or it may still write to them.
+    fn drop(&mut self) {
+        for buf in self.slots.bufs.drain(..).flatten() {
+            self.pool.free(buf);
+        }
+    }
+}
+
+/// A transmit queue, which returns sent buffers to their pool.
+pub struct TxQueue<'p, D: Descriptors, M: DeviceMemory + ?Sized + 'p = MappedRegion<'p>> {
+    desc: D,
+    pool: &'p BufPool<'p, M>,
+    slots: Slots,
+}
+
+impl<'p, D: Descriptors, M: DeviceMemory + ?Sized> TxQueue<'p, D, M> {
+    /// # Panics
+    /// If the ring has no descriptors.
+    pub fn new(desc: D, pool: &'p BufPool<'p, M>) -> Self {
+        let slots = Slots::new(desc.len());
+        TxQueue { desc, pool, slots }
+    }
+
+    /// Frees the buffers the device finished sending and returns how many.
+    /// `tx_burst` does this as well.
+    pub fn reclaim(&mut self) -> usize {
+        let mut n = 0;
+        while let Some(buf) = self.slots.pop_completed(&mut self.desc) {
+            self.pool.free(buf);
+            n += 1;
+        }
+        n
+    }
+
+    /// Posts the buffers of `bufs` in order, as far as the ring has room,
+    /// and rings the doorbell once. Returns how many were posted; the queue
+    /// owns those now and frees them once sent, the caller keeps the rest.
+    pub fn tx_burst(&mut self, bufs: &[BufHandle]) -> usize {
+        self.reclaim();
+        let mut n = 0;
+        for buf in bufs {
+            if self.slots.is_full() {
+                break;
+            }
+            self.desc
+                .post(self.slots.tail, self.pool.bus_addr(buf), buf.len());
+            self.slots.push(*buf);
+            n += 1;
+        }
+        if n > 0 {
+            ring(&mut self.desc, self.slots.tail);
+        }
+        n
+    }
+
+    /// Number of buffers posted to the device and not reclaimed yet.
+    pub fn posted(&self) -> usize {
+        self.slots.posted
+    }
+
+    /// The descriptors, e.g. to access driver state.
+    pub fn descriptors(&mut self) -> &mut D {
+        &mut self.desc
+    }
+}
+
+impl<'p, D: Descriptors, M: DeviceMemory + ?Sized> Drop for TxQueue<'p, D, M> {
+    /// Returns the posted buffers to the pool. The device must be stopped
+    /// before, or it may still read them.
+    fn drop(&mut self) {
+        for buf in self.slots.bufs.drain(..).flatten() {
+            self.pool.free(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+
+    const DONE: u32 = 1 << 31;
+    const TAIL: usize = 0x0;
+
+    /// 16-byte descriptors: address, length and a status word with a done
+    /// bit and the received length.
+    struct Ring<'a> {
+        descs: &'a MockMemory,
+        regs: &'a MockMemory,
+        len: usize,
+    }
+
+    impl<'a> Descriptors for Ring<'a> {
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn post(&mut self, index: usize, addr: u64, len: usize) {
+            self.descs.write_u64(index * 16, addr);
+            self.descs.write_u32(index * 16 + 8, len as u32);
+            self.descs.write_u32(index * 16 + 12, 0);
+        }
+
+        fn completed(&mut self, index: usize) -> Option<usize> {
+            let status = self.descs.read_u32(index * 16 + 12);
+            if status & DONE != 0 {
+                Some((status & 0xffff) as usize)
+            } else {
+                None
+            }
+        }
+
+        fn doorbell(&mut self, tail: usize) {
+            self.regs.write_u32(TAIL, tail as u32);
+        }
+    }
+
+    /// Completes descriptor `index` like the device would.
+    fn complete(descs: &MockMemory, index: usize, len: u32) {
+        descs.write_u32(index * 16 + 12, DONE | len);
+    }
+
+    #[test]
+    fn pool() {
+        let mem = MockMemory::new(0x1000);
+        let pool = BufPool::new(&mem, 0x8000_0000, 0x400, 4);
+        let mut a = pool.alloc().unwrap();
+        assert_eq!(a.index(), 0);
+        assert_eq!(pool.bus_addr(&a), 0x8000_0000);
+        pool.write(&mut a, b"hello");
+        assert_eq!(pool.bytes(&a), b"hello");
+        let b = pool.alloc().unwrap();
+        assert_eq!(pool.bus_addr(&b), 0x8000_0400);
+        assert_eq!(pool.available(), 2);
+        pool.free(a);
+        assert_eq!(pool.alloc().unwrap().index(), 0);
+        assert!(pool.alloc().is_some() && pool.alloc().is_some());
+        assert!(pool.alloc().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "not in use")]
+    fn double_free() {
+        let mem = MockMemory::new(0x1000);
+        let pool = BufPool::new(&mem, 0, 0x400, 4);
+        let buf = pool.alloc().unwrap();
+        pool.free(buf);
+        pool.free(buf);
+    }
+
+    #[test]
+    fn rx_burst() {
+        let mem = MockMemory::new(0x2000);
+        let (descs, regs) = (MockMemory::new(0x100), MockMemory::new(0x10));
+        let pool = BufPool::new(&mem, 0x8000_0000, 0x400, 8);
+        let ring = Ring {
+            descs: &descs,
+            regs: &regs,
+            len: 4,
+        };
+        let mut rx = RxQueue::new(ring, &pool);
+        assert_eq!(rx.posted(), 4);
+        assert_eq!(pool.available(), 4);
+        assert_eq!(regs.read_u32(TAIL), 0);
+        assert_eq!(descs.read_u64(16), 0x8000_0400);
+        assert_eq!(descs.read_u32(16 + 8), 0x400);
+
+        let mut bufs = [BufHandle::default(); 8];
+        assert_eq!(rx.rx_burst(&mut bufs), 0);
+        mem.write_bytes(0x400, b"packet");
+        complete(&descs, 0, 60);
+        complete(&descs, 1, 6);
+        assert_eq!(rx.rx_burst(&mut bufs), 2);
+        assert_eq!(bufs[0].len(), 60);
+        assert_eq!(pool.bytes(&bufs[1]), b"packet");
+        // Refilled with new buffers, the tail wrapped around
+        assert_eq!(rx.posted(), 4);
+        assert_eq!(regs.read_u32(TAIL), 2);
+        assert_eq!(descs.read_u64(0), 0x8000_1000);
+        assert_eq!(descs.read_u32(12), 0);
+
+        pool.free(bufs[0]);
+        pool.free(bufs[1]);
+        drop(rx);
+        assert_eq!(pool.available(), 8);
+    }
+
+    #[test]
+    fn rx_oversized() {
+        let mem = MockMemory::new(0x2000);
+        let (descs, regs) = (MockMemory::new(0x100), MockMemory::new(0x10));
+        let pool = BufPool::new(&mem, 0x8000_0000, 0x400, 8);
+        let ring = Ring {
+            descs: &descs,
+            regs: &regs,
+            len: 4,
+        };
+        let mut rx = RxQueue::new(ring, &pool);
+        let mut bufs = [BufHandle::default(); 8];
+        complete(&descs, 0, 0x401);
+        complete(&descs, 1, 0x400);
+        assert_eq!(rx.rx_burst(&mut bufs), 1);
+        assert_eq!(bufs[0].index(), 1);
+        assert_eq!(pool.bytes(&bufs[0]).len(), 0x400);
+        assert_eq!(rx.oversized(), 1);
+        // The dropped buffer went back to the pool and was posted again
+        assert_eq!(rx.posted(), 4);
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[test]
+    fn tx_burst() {
+        let mem = MockMemory::new(0x2000);
+        let (descs, regs) = (MockMemory::new(0x100), MockMemory::new(0x10));
+        let pool = BufPool::new(&mem, 0x8000_0000, 0x400, 8);
+        let ring = Ring {
+            descs: &descs,
+            regs: &regs,
+            len: 4,
+        };
+        let mut tx = TxQueue::new(ring, &pool);
+        let bufs: Vec<_> = (0..6)
+            .map(|i| {
+                let mut buf = pool.alloc().unwrap();
+                pool.write(&mut buf, &vec![i; 10 + i as usize]);
+                buf
+            })
+            .collect();
+        assert_eq!(tx.tx_burst(&bufs), 4);
+        assert_eq!(regs.read_u32(TAIL), 0);
+        assert_eq!(descs.read_u32(8), 10);
+        assert_eq!(descs.read_u64(3 * 16), 0x8000_0c00);
+        assert_eq!(tx.tx_burst(&bufs[4..]), 0);
+
+        complete(&descs, 0, 0);
+        complete(&descs, 1, 0);
+        assert_eq!(tx.tx_burst(&bufs[4..]), 2);
+        assert_eq!(regs.read_u32(TAIL), 2);
+        assert_eq!(pool.available(), 4);
+        drop(tx);
+        assert_eq!(pool.available(), 8);
+    }
+}
@@ -0,0 +1,194 @@
+//! Byte offsets and lengths which can't be mixed up with other numbers.
+//!
+//! With plain `usize`s, a mapping index, a byte offset and a register index
+//! look the same to the compiler. `RegOffset` and `ByteLen` only combine in
+//! ways which make sense, and the `_at` accessors of `DeviceMemory` take
+//! them:
+//!
+//! ```ignore
+//! const CTRL: RegOffset = RegOffset::new(0x10);
+//! const STATUS: RegOffset = CTRL.plus(ByteLen::of::<u32>());
+//! // The fourth 32-bit register of a table at 0x100
+//! const ENTRY3: RegOffset = RegOffset::new(0x100).plus(ByteLen::of::<u32>().times(3));
+//!
+//! regs.write_u32_at(CTRL, 1);
+//! let status = regs.read_u32_at(STATUS);
+//! ```
+
+use std::fmt;
+use std::mem;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+/// A byte offset into device memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct RegOffset(usize);
+
+impl RegOffset {
+    pub const ZERO: RegOffset = RegOffset(0);
+
+    /// The offset `bytes` bytes from the start.
+    pub const fn new(bytes: usize) -> RegOffset {
+        RegOffset(bytes)
+    }
+
+    /// The offset of register `index` in an array of registers of size
+    /// `stride`, starting at 0.
+    pub const fn index(index: usize, stride: ByteLen) -> RegOffset {
+        RegOffset(index * stride.0)
+    }
+
+    /// The offset in bytes.
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// The offset `len` bytes further, usable in constants (where overflow
+    /// fails compilation).
+    pub const fn plus(self, len: ByteLen) -> RegOffset {
+        RegOffset(self.0 + len.0)
+    }
+
+    /// The offset `len` bytes further, `None` on overflow.
+    pub fn checked_add(self, len: ByteLen) -> Option<RegOffset> {
+        self.0.checked_add(len.0).map(RegOffset)
+    }
+
+    /// The number of bytes from `earlier` to this offset, `None` if
+    /// `earlier` is after it.
+    pub fn checked_sub(self, earlier: RegOffset) -> Option<ByteLen> {
+        self.0.checked_sub(earlier.0).map(ByteLen)
+    }
+
+    /// Whether the offset is a multiple of `align`.
+    pub fn is_aligned(self, align: ByteLen) -> bool {
+        align.0 != 0 && self.0.is_multiple_of(align.0)
+    }
+}
+
+impl Add<ByteLen> for RegOffset {
+    type Output = RegOffset;
+
+    fn add(self, len: ByteLen) -> RegOffset {
+        RegOffset(self.0 + len.0)
+    }
+}
+
+impl AddAssign<ByteLen> for RegOffset {
+    fn add_assign(&mut self, len: ByteLen) {
+        self.0 += len.0;
+    }
+}
+
+/// The distance between two offsets.
+impl Sub for RegOffset {
+    type Output = ByteLen;
+
+    fn sub(self, earlier: RegOffset) -> ByteLen {
+        ByteLen(self.0 - earlier.0)
+    }
+}
+
+impl From<RegOffset> for usize {
+    fn from(offset: RegOffset) -> usize {
+        offset.0
+    }
+}
+
+impl fmt::Display for RegOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// A number of bytes, e.g. the size of a register or of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ByteLen(usize);
+
+impl ByteLen {
+    pub const ZERO: ByteLen = ByteLen(0);
+
+    pub const fn new(bytes: usize) -> ByteLen {
+        ByteLen(bytes)
+    }
+
+    /// The size of `T`, e.g. `ByteLen::of::<u32>()` for a 32-bit register.
+    pub const fn of<T>() -> ByteLen {
+        ByteLen(mem::size_of::<T>())
+    }
+
+    /// The length in bytes.
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// `count` times this length, usable in constants.
+    pub const fn times(self, count: usize) -> ByteLen {
+        ByteLen(self.0 * count)
+    }
+
+    /// `count` times this length, `None` on overflow.
+    pub fn checked_mul(self, count: usize) -> Option<ByteLen> {
+        self.0.checked_mul(count).map(ByteLen)
+    }
+}
+
+impl Add for ByteLen {
+    type Output = ByteLen;
+
+    fn add(self, other: ByteLen) -> ByteLen {
+        ByteLen(self.0 + other.0)
+    }
+}
+
+impl AddAssign for ByteLen {
+    fn add_assign(&mut self, other: ByteLen) {
+        self.0 += other.0;
+    }
+}
+
+impl Mul<usize> for ByteLen {
+    type Output = ByteLen;
+
+    fn mul(self, count: usize) -> ByteLen {
+        ByteLen(self.0 * count)
+    }
+}
+
+impl From<ByteLen> for usize {
+    fn from(len: ByteLen) -> usize {
+        len.0
+    }
+}
+
+impl fmt::Display for ByteLen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} bytes", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD: ByteLen = ByteLen::of::<u32>();
+    const TABLE: RegOffset = RegOffset::new(0x100);
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(TABLE.plus(WORD.times(3)), RegOffset::new(0x10c));
+        assert_eq!(TABLE + WORD * 3, RegOffset::new(0x10c));
+        assert_eq!(RegOffset::index(3, WORD), RegOffset::new(12));
+        let mut offset = TABLE;
+        offset += WORD;
+        assert_eq!(offset - TABLE, WORD);
+        assert_eq!(TABLE.checked_sub(offset), None);
+        assert_eq!(RegOffset::new(usize::MAX).checked_add(WORD), None);
+        assert_eq!(WORD.checked_mul(usize::MAX), None);
+        assert!(offset.is_aligned(WORD));
+        assert!(!offset.is_aligned(ByteLen::of::<u64>()));
+        assert!(!offset.is_aligned(ByteLen::ZERO));
+        assert_eq!(usize::from(offset), 0x104);
+        assert_eq!(offset.to_string(), "0x104");
+        assert_eq!((WORD + WORD).to_string(), "0x8 bytes");
+    }
+}
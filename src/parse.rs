@@ -0,0 +1,294 @@
+//! Parsers for the contents of UIO and PCI sysfs files.
+//!
+//! These functions only look at the bytes they are given and never panic,
+//! whatever the input, so unusual drivers can't crash the crate with
+//! malformed attributes. Invalid input is reported as `None` or an error.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::num::IntErrorKind;
+use std::str;
+
+/// Why a number could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NumberError {
+    /// There are no digits
+    Empty,
+    /// A character is not a digit of the detected base
+    InvalidDigit,
+    /// The number doesn't fit the target type
+    Overflow,
+}
+
+impl fmt::Display for NumberError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            NumberError::Empty => "no digits",
+            NumberError::InvalidDigit => "invalid digit",
+            NumberError::Overflow => "number too large",
+        })
+    }
+}
+
+impl Error for NumberError {}
+
+/// Parses a number in any of the formats drivers print, ignoring surrounding
+/// whitespace:
+///
+/// - `0x`/`0X`-prefixed hexadecimal (`0x1000`), as printed by the UIO core
+/// - bare decimal (`4096`)
+/// - bare hexadecimal, if it contains the digits a-f (`fe000000`)
+///
+/// Bare numbers consisting only of the digits 0-9 are ambiguous and read
+/// as decimal.
+pub fn number(bytes: &[u8]) -> Result<u64, NumberError> {
+    let s = str::from_utf8(bytes)
+        .map_err(|_| NumberError::InvalidDigit)?
+        .trim();
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None if s.bytes().all(|b| b.is_ascii_digit()) => (s, 10),
+        None => (s, 16),
+    };
+    if digits.is_empty() {
+        return Err(NumberError::Empty);
+    }
+    // from_str_radix accepts a leading sign
+    if !digits.bytes().all(|b| (b as char).is_digit(radix)) {
+        return Err(NumberError::InvalidDigit);
+    }
+    u64::from_str_radix(digits, radix).map_err(|e| match *e.kind() {
+        IntErrorKind::PosOverflow => NumberError::Overflow,
+        _ => NumberError::InvalidDigit,
+    })
+}
+
+/// Parses a number like `number`, which may be negative (e.g. a temperature
+/// of `-5000` millidegrees).
+pub fn signed(bytes: &[u8]) -> Result<i64, NumberError> {
+    let s = str::from_utf8(bytes)
+        .map_err(|_| NumberError::InvalidDigit)?
+        .trim();
+    match s.strip_prefix('-') {
+        Some(magnitude) => {
+            let n = number(magnitude.as_bytes())?;
+            0i64.checked_sub_unsigned(n).ok_or(NumberError::Overflow)
+        }
+        None => i64::try_from(number(s.as_bytes())?).map_err(|_| NumberError::Overflow),
+    }
+}
+
+/// Parses a hexadecimal number as found in `maps/mapN/addr`, `size` and
+/// `offset` (e.g. `0x00001000\n`). The `0x` prefix is optional and
+/// surrounding whitespace is ignored.
+pub fn hex(bytes: &[u8]) -> Option<u64> {
+    let s = str::from_utf8(bytes).ok()?.trim();
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Parses a decimal number such as the `event` counter, ignoring surrounding
+/// whitespace.
+pub fn decimal(bytes: &[u8]) -> Option<u64> {
+    let s = str::from_utf8(bytes).ok()?.trim();
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Parses the index of a numbered sysfs entry, e.g. 3 for `index("map",
+/// "map3")`. Returns `None` unless `name` is `prefix` followed by decimal
+/// digits only (so `resource0_wc` is not resource 0).
+pub fn index(prefix: &str, name: &str) -> Option<usize> {
+    let digits = name.strip_prefix(prefix)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Parses a list of NUL-separated strings, such as a device tree
+/// `compatible` property. Empty strings and a trailing newline are ignored.
+pub fn string_list(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .split(|&b| b == 0 || b == b'\n')
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// A line of a PCI device's `resource` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceEntry {
+    /// First physical address, 0 if the resource is unused
+    pub start: u64,
+    /// Last physical address (inclusive)
+    pub end: u64,
+    /// `IORESOURCE_*` flags
+    pub flags: u64,
+}
+
+impl ResourceEntry {
+    /// `IORESOURCE_IO`: the resource is an I/O port range.
+    pub const IO: u64 = 0x100;
+    /// `IORESOURCE_MEM`: the resource is a memory range.
+    pub const MEM: u64 = 0x200;
+
+    /// Size in bytes, 0 for unused resources.
+    pub fn size(&self) -> u64 {
+        if self.start == 0 && self.end == 0 {
+            0
+        } else {
+            self.end.wrapping_sub(self.start).wrapping_add(1)
+        }
+    }
+}
+
+/// Parses a PCI `resource` file, one `start end flags` line per resource.
+pub fn resources(bytes: &[u8]) -> Option<Vec<ResourceEntry>> {
+    let s = str::from_utf8(bytes).ok()?;
+    s.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let mut fields = l.split_whitespace().map(|f| hex(f.as_bytes()));
+            let entry = ResourceEntry {
+                start: fields.next()??,
+                end: fields.next()??,
+                flags: fields.next()??,
+            };
+            match fields.next() {
+                None => Some(entry),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses a `uevent` file into its `KEY=value` pairs. Lines without `=`
+/// are skipped.
+pub fn uevent(bytes: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+/// The identifying fields of a PCI configuration space header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigHeader {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+    pub revision: u8,
+    /// Base class, sub-class and programming interface, e.g. 0x010802 for
+    /// an NVMe controller
+    pub class: u32,
+    /// Header type without the multi-function bit
+    pub header_type: u8,
+    pub multi_function: bool,
+}
+
+/// Parses the start of a PCI `config` file. Returns `None` if fewer than
+/// the first 16 bytes are given.
+pub fn config_header(bytes: &[u8]) -> Option<ConfigHeader> {
+    let header = bytes.get(..16)?;
+    let u16_at = |o: usize| u16::from_le_bytes([header[o], header[o + 1]]);
+    Some(ConfigHeader {
+        vendor_id: u16_at(0x0),
+        device_id: u16_at(0x2),
+        command: u16_at(0x4),
+        status: u16_at(0x6),
+        revision: header[0x8],
+        class: u32::from_le_bytes([header[0x9], header[0xa], header[0xb], 0]),
+        header_type: header[0xe] & 0x7f,
+        multi_function: header[0xe] & 0x80 != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(hex(b"0x1000\n"), Some(0x1000));
+        assert_eq!(hex(b"ff"), Some(0xff));
+        assert_eq!(hex(b"0x"), None);
+        assert_eq!(hex(b"x"), None);
+        assert_eq!(hex(b""), None);
+        assert_eq!(hex(b"0x+1"), None);
+        assert_eq!(hex(b"0x10000000000000000"), None);
+        assert_eq!(decimal(b"42\n"), Some(42));
+        assert_eq!(number(b"0x1000\n"), Ok(0x1000));
+        assert_eq!(number(b"4096"), Ok(4096));
+        assert_eq!(number(b"fe000000"), Ok(0xfe00_0000));
+        assert_eq!(number(b"0x"), Err(NumberError::Empty));
+        assert_eq!(number(b" "), Err(NumberError::Empty));
+        assert_eq!(number(b"0xg"), Err(NumberError::InvalidDigit));
+        assert_eq!(number(b"-1"), Err(NumberError::InvalidDigit));
+        assert_eq!(
+            number(b"0x1_0000_0000_0000_0000"),
+            Err(NumberError::InvalidDigit)
+        );
+        assert_eq!(number(b"0x10000000000000000"), Err(NumberError::Overflow));
+        assert_eq!(decimal(b"-1"), None);
+        assert_eq!(signed(b"-5000\n"), Ok(-5000));
+        assert_eq!(signed(b"0x10"), Ok(16));
+        assert_eq!(signed(b"-9223372036854775808"), Ok(i64::MIN));
+        assert_eq!(signed(b"9223372036854775808"), Err(NumberError::Overflow));
+        assert_eq!(signed(b"--1"), Err(NumberError::InvalidDigit));
+        assert_eq!(index("map", "map12"), Some(12));
+        assert_eq!(index("resource", "resource0_wc"), None);
+        assert_eq!(index("uio", "uio"), None);
+        assert_eq!(index("uio", "uio+1"), None);
+    }
+
+    #[test]
+    fn files() {
+        let res = resources(
+            b"0x00000000fe000000 0x00000000fe00ffff 0x0000000000040200\n\
+              0x0000000000000000 0x0000000000000000 0x0000000000000000\n",
+        )
+        .unwrap();
+        assert_eq!(res[0].size(), 0x10000);
+        assert_ne!(res[0].flags & ResourceEntry::MEM, 0);
+        assert_eq!(res[1].size(), 0);
+        assert_eq!(resources(b"0x0 0x1\n"), None);
+        assert_eq!(
+            string_list(b"xlnx,axi-dma-1.00.a\0xlnx,axi-dma\0"),
+            ["xlnx,axi-dma-1.00.a", "xlnx,axi-dma"]
+        );
+
+        assert_eq!(
+            uevent(b"DRIVER=uio_pci_generic\nPCI_ID=8086:1D02\n"),
+            vec![
+                ("DRIVER".to_string(), "uio_pci_generic".to_string()),
+                ("PCI_ID".to_string(), "8086:1D02".to_string())
+            ]
+        );
+
+        let mut config = [0u8; 64];
+        config[..4].copy_from_slice(&[0x86, 0x80, 0x02, 0x1d]);
+        config[0x9..0xc].copy_from_slice(&[0x01, 0x06, 0x01]);
+        config[0xe] = 0x80;
+        let header = config_header(&config).unwrap();
+        assert_eq!((header.vendor_id, header.device_id), (0x8086, 0x1d02));
+        assert_eq!(header.class, 0x010601);
+        assert!(header.multi_function);
+        assert_eq!(config_header(&config[..15]), None);
+    }
+}
@@ -0,0 +1,153 @@
+//! How the UIO kernel drivers differ.
+//!
+//! The generic UIO drivers all use `/dev/uioX` for interrupts, but disagree
+//! on how an interrupt is masked and acknowledged and on where the device
+//! memory is found. A `DriverProfile` records this for a driver, so code
+//! which works with any UIO device can do the right thing:
+//!
+//! ```ignore
+//! use uio::profile::ResourceLayout;
+//!
+//! match dev.profile()?.map(|p| p.layout) {
+//!     Some(ResourceLayout::PciBars) => { /* registers are in BAR 0 */ }
+//!     _ => { /* registers are in map 0 */ }
+//! }
+//! ```
+//!
+//! `UioDevice::irq_wait` and `UioDevice::map_memory` consult the profile of
+//! the bound driver.
+
+/// Where the kernel masks the interrupt of a device after each event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqMask {
+    /// The INTx disable bit in the PCI command register. The interrupt is
+    /// level triggered, so the device has to be acknowledged before the
+    /// interrupt is unmasked or it fires again right away.
+    PciIntx,
+    /// The interrupt line (`disable_irq`). The device has to be
+    /// acknowledged before unmasking if the line is level triggered.
+    IrqLine,
+    /// The interrupt mask of the VMBus channel's inbound ring buffer. No
+    /// device acknowledge is needed.
+    Channel,
+}
+
+/// Where the memory of a device is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLayout {
+    /// In the PCI BARs (`device/resourceN`, see `UioDevice::map_resource`);
+    /// there are no UIO mappings.
+    PciBars,
+    /// In the UIO mappings (`maps/mapN`, see `UioDevice::map_mapping`).
+    Maps,
+    /// In the UIO mappings, followed by DMA buffers which the driver
+    /// allocates when the device is opened. Their `addr` is only valid while
+    /// the device is open.
+    MapsWithDma,
+    /// In the fixed VMBus mappings (see `hyperv`).
+    Vmbus,
+}
+
+/// The behavior of a UIO kernel driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriverProfile {
+    /// Name of the kernel driver, as in `/sys/class/uio/uioX/device/driver`
+    pub driver: &'static str,
+    /// Where the interrupt is masked after each event
+    pub irq_mask: IrqMask,
+    /// Whether the interrupt stays masked until it is re-enabled by writing
+    /// 1 to `/dev/uioX` (see `UioDevice::irq_enable`)
+    pub reenable: bool,
+    /// Where the device memory is found
+    pub layout: ResourceLayout,
+}
+
+/// The profiles of the generic drivers in the Linux kernel.
+pub const PROFILES: [DriverProfile; 4] = [
+    DriverProfile {
+        driver: "uio_pci_generic",
+        irq_mask: IrqMask::PciIntx,
+        reenable: true,
+        layout: ResourceLayout::PciBars,
+    },
+    DriverProfile {
+        driver: "uio_pdrv_genirq",
+        irq_mask: IrqMask::IrqLine,
+        reenable: true,
+        layout: ResourceLayout::Maps,
+    },
+    DriverProfile {
+        driver: "uio_dmem_genirq",
+        irq_mask: IrqMask::IrqLine,
+        reenable: true,
+        layout: ResourceLayout::MapsWithDma,
+    },
+    DriverProfile {
+        driver: "uio_hv_generic",
+        irq_mask: IrqMask::Channel,
+        reenable: true,
+        layout: ResourceLayout::Vmbus,
+    },
+];
+
+impl DriverProfile {
+    /// The profile of the kernel driver `driver`, if it is known.
+    pub fn lookup(driver: &str) -> Option<DriverProfile> {
+        PROFILES.iter().find(|p| p.driver == driver).copied()
+    }
+
+    /// Whether the device has to be acknowledged before its interrupt is
+    /// re-enabled.
+    pub fn ack_before_reenable(&self) -> bool {
+        self.irq_mask != IrqMask::Channel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+    use std::fs;
+
+    #[test]
+    fn profiles() {
+        let pci = DriverProfile::lookup("uio_pci_generic").unwrap();
+        assert_eq!(pci.layout, ResourceLayout::PciBars);
+        assert!(pci.ack_before_reenable());
+        assert!(!DriverProfile::lookup("uio_hv_generic")
+            .unwrap()
+            .ack_before_reenable());
+        assert_eq!(DriverProfile::lookup("mock"), None);
+
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(dev.driver_name().unwrap(), None);
+        assert_eq!(dev.profile().unwrap(), None);
+        assert_eq!(dev.map_memory(0).unwrap().len(), 0x1000);
+        drop(dev);
+
+        mock.set_driver("uio_pdrv_genirq").unwrap();
+        let dev = mock.open().unwrap();
+        assert_eq!(
+            dev.driver_name().unwrap().as_deref(),
+            Some("uio_pdrv_genirq")
+        );
+        assert_eq!(
+            dev.profile().unwrap().map(|p| p.layout),
+            Some(ResourceLayout::Maps)
+        );
+        assert_eq!(dev.map_memory(0).unwrap().len(), 0x1000);
+        // The interrupt is re-enabled before waiting, like with
+        // `auto_reenable(true)`. The mock appends the write to the device
+        // file (and then has no event to read).
+        let len = || fs::metadata(mock.dev_path()).unwrap().len();
+        let _ = dev.irq_wait();
+        assert_eq!(len(), 0x1004);
+        drop(dev);
+
+        let dev = mock.builder().auto_reenable(false).open().unwrap();
+        dev.irq_wait().unwrap();
+        assert_eq!(len(), 0x1004);
+    }
+}
@@ -0,0 +1,196 @@
+//! Python bindings, enabled with the `python` feature.
+//!
+//! Build the extension module (e.g. with maturin, or by hand):
+//!
+//! ```text
+//! $ cargo rustc --release --features python,pyo3/extension-module --crate-type cdylib
+//! $ cp target/release/libuio.so uio.so
+//! ```
+//!
+//! ```python
+//! import uio
+//!
+//! print(uio.list_devices())
+//! dev = uio.UioDevice(0)
+//! dev.poke(0, 0x10, 1)
+//! print(hex(dev.peek(0, 0x14)))
+//! dev.irq_enable()
+//! print(dev.irq_wait())
+//! ```
+//!
+//! Errors are raised as `OSError` with the `errno` of the underlying error.
+
+use libc;
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::Mutex;
+use {LockMode, UioDeviceBuilder, UioError};
+
+fn os_error(e: UioError) -> PyErr {
+    PyOSError::new_err((e.raw_os_error().unwrap_or(libc::EIO), e.to_string()))
+}
+
+fn io_error(e: ::std::io::Error) -> PyErr {
+    PyOSError::new_err((e.raw_os_error().unwrap_or(libc::EIO), e.to_string()))
+}
+
+/// Returns the numbers of all UIO devices.
+#[pyfunction]
+fn list_devices() -> PyResult<Vec<usize>> {
+    ::list_devices().map_err(os_error)
+}
+
+/// A UIO device, see `uio::UioDevice`.
+#[pyclass(name = "UioDevice")]
+pub struct Device {
+    dev: ::UioDevice,
+    /// Mappings by index: (address, length)
+    maps: Mutex<HashMap<usize, (usize, usize)>>,
+}
+
+impl Device {
+    fn new(dev: ::UioDevice) -> Device {
+        Device {
+            dev,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the address of byte `offset` in mapping `map`, mapping it on
+    /// first use.
+    fn address(&self, map: usize, offset: usize, width: usize) -> PyResult<usize> {
+        let bytes = match width {
+            8 | 16 | 32 | 64 => width / 8,
+            _ => return Err(PyValueError::new_err(format!("invalid width {}", width))),
+        };
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        let (addr, len) = match maps.get(&map) {
+            Some(&m) => m,
+            None => {
+                let len = self.dev.map_size(map).map_err(os_error)?;
+                let addr = self.dev.map_mapping(map).map_err(os_error)? as usize;
+                // The mapping succeeded, so its size fits
+                let len = len as usize;
+                maps.insert(map, (addr, len));
+                (addr, len)
+            }
+        };
+        if !offset.is_multiple_of(bytes) || offset.checked_add(bytes).is_none_or(|end| end > len) {
+            return Err(PyValueError::new_err(format!(
+                "offset {:#x} is misaligned or out of bounds (size {:#x})",
+                offset, len
+            )));
+        }
+        Ok(addr + offset)
+    }
+}
+
+#[pymethods]
+impl Device {
+    /// Opens /dev/uio<uio_num>. Unless `lock` is false, the device is locked
+    /// and opening fails if another process holds it.
+    #[new]
+    #[pyo3(signature = (uio_num, lock = true))]
+    fn open(uio_num: usize, lock: bool) -> PyResult<Device> {
+        let mode = if lock {
+            LockMode::NonBlocking
+        } else {
+            LockMode::Unlocked
+        };
+        UioDeviceBuilder::new(uio_num)
+            .lock(mode)
+            .open()
+            .map(Device::new)
+            .map_err(os_error)
+    }
+
+    #[getter]
+    fn name(&self) -> PyResult<String> {
+        self.dev.get_name().map_err(os_error)
+    }
+
+    #[getter]
+    fn version(&self) -> PyResult<String> {
+        self.dev.get_version().map_err(os_error)
+    }
+
+    #[getter]
+    fn event_count(&self) -> PyResult<u32> {
+        self.dev.get_event_count().map_err(os_error)
+    }
+
+    /// Reads the register at `offset` of mapping `map`.
+    #[pyo3(signature = (map, offset, width = 32))]
+    fn peek(&self, map: usize, offset: usize, width: usize) -> PyResult<u64> {
+        let addr = self.address(map, offset, width)?;
+        Ok(unsafe {
+            match width {
+                8 => ptr::read_volatile(addr as *const u8) as u64,
+                16 => ptr::read_volatile(addr as *const u16) as u64,
+                32 => ptr::read_volatile(addr as *const u32) as u64,
+                _ => ptr::read_volatile(addr as *const u64),
+            }
+        })
+    }
+
+    /// Writes `value` to the register at `offset` of mapping `map`.
+    #[pyo3(signature = (map, offset, value, width = 32))]
+    fn poke(&self, map: usize, offset: usize, value: u64, width: usize) -> PyResult<()> {
+        let addr = self.address(map, offset, width)?;
+        if width < 64 && value >> width != 0 {
+            return Err(PyValueError::new_err(format!(
+                "value {:#x} does not fit in {} bits",
+                value, width
+            )));
+        }
+        unsafe {
+            match width {
+                8 => ptr::write_volatile(addr as *mut u8, value as u8),
+                16 => ptr::write_volatile(addr as *mut u16, value as u16),
+                32 => ptr::write_volatile(addr as *mut u32, value as u32),
+                _ => ptr::write_volatile(addr as *mut u64, value),
+            }
+        }
+        Ok(())
+    }
+
+    fn irq_enable(&self) -> PyResult<()> {
+        self.dev.irq_enable().map_err(io_error)
+    }
+
+    fn irq_disable(&self) -> PyResult<()> {
+        self.dev.irq_disable().map_err(io_error)
+    }
+
+    /// Waits for an interrupt (releasing the GIL) and returns the event count.
+    fn irq_wait(&self, py: Python<'_>) -> PyResult<u32> {
+        py.detach(|| self.dev.irq_wait()).map_err(io_error)
+    }
+}
+
+#[pymodule]
+fn uio(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(python::list_devices, m)?)?;
+    m.add_class::<Device>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    #[test]
+    fn peek_poke() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = Device::new(mock.open().unwrap());
+        dev.poke(0, 0x10, 0xabcd, 32).unwrap();
+        assert_eq!(dev.peek(0, 0x10, 16).unwrap(), 0xabcd);
+        assert_eq!(dev.name().unwrap(), "mock");
+        assert!(dev.address(0, 0x1000, 32).is_err());
+        assert!(dev.address(0, 0x2, 32).is_err());
+    }
+}
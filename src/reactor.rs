@@ -0,0 +1,402 @@
+//! A small event loop for applications without an async runtime.
+//!
+//! A `Reactor` waits in `epoll` for interrupts of any number of devices,
+//! for timers and for a shutdown request, and calls the handler registered
+//! for each. That is enough for a typical single-threaded control daemon:
+//!
+//! ```ignore
+//! let mut reactor = Reactor::new()?;
+//! reactor.shutdown_on_signals(&[libc::SIGINT, libc::SIGTERM])?;
+//! reactor.add_device(adc, |dev, count| {
+//!     let regs = dev.map_region(0)?;
+//!     samples.push(regs.read_u32(DATA));
+//!     dev.irq_enable()
+//! })?;
+//! reactor.add_timer(Duration::from_secs(1), |_| {
+//!     println!("{} samples", samples.len());
+//!     Ok(())
+//! })?;
+//! reactor.run()?;
+//! ```
+//!
+//! Handlers run on the thread calling `run` or `turn`, one at a time, so
+//! they may borrow the same state. An error returned by a handler ends
+//! `run` with that error; the reactor can be run again afterwards.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{self, AsRawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use sys;
+use UioDevice;
+
+/// The tokens of the shutdown eventfd and signalfd, never handed out.
+const SHUTDOWN: u64 = u64::MAX;
+const SIGNALS: u64 = u64::MAX - 1;
+
+/// Identifies a device or timer added to a `Reactor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token(u64);
+
+type DeviceHandler<'a> = Box<dyn FnMut(&UioDevice, u32) -> io::Result<()> + 'a>;
+type TimerHandler<'a> = Box<dyn FnMut(u64) -> io::Result<()> + 'a>;
+
+enum Source<'a> {
+    Device {
+        device: Box<UioDevice>,
+        handler: DeviceHandler<'a>,
+    },
+    Timer {
+        file: File,
+        once: bool,
+        handler: TimerHandler<'a>,
+    },
+}
+
+/// Ends `Reactor::run` from anywhere, also from other threads. Clones
+/// refer to the same reactor.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    file: Arc<File>,
+}
+
+impl Shutdown {
+    /// Makes `run` return after the handlers which are ready have run.
+    pub fn trigger(&self) {
+        // Can only fail if the counter overflows, which still wakes the
+        // reactor
+        let _ = (&*self.file).write(&1u64.to_ne_bytes());
+    }
+}
+
+/// Dispatches device interrupts and timer expirations to handlers, see the
+/// module documentation.
+pub struct Reactor<'a> {
+    epoll: fd::OwnedFd,
+    sources: HashMap<u64, Source<'a>>,
+    next_token: u64,
+    shutdown: Shutdown,
+    signals: Option<File>,
+    shut_down: bool,
+}
+
+impl<'a> Reactor<'a> {
+    pub fn new() -> io::Result<Reactor<'a>> {
+        let epoll = sys::epoll_create()?;
+        let shutdown = File::from(sys::eventfd()?);
+        sys::epoll_add(epoll.as_raw_fd(), shutdown.as_raw_fd(), SHUTDOWN)?;
+        Ok(Reactor {
+            epoll,
+            sources: HashMap::new(),
+            next_token: 0,
+            shutdown: Shutdown {
+                file: Arc::new(shutdown),
+            },
+            signals: None,
+            shut_down: false,
+        })
+    }
+
+    fn add(&mut self, fd: fd::RawFd, source: Source<'a>) -> io::Result<Token> {
+        let token = self.next_token;
+        sys::epoll_add(self.epoll.as_raw_fd(), fd, token)?;
+        self.next_token += 1;
+        self.sources.insert(token, source);
+        Ok(Token(token))
+    }
+
+    /// Calls `handler` with the device and the event count after every
+    /// interrupt of `device`.
+    ///
+    /// The interrupt is read with `irq_wait`, which enables it first if the
+    /// device was opened with `auto_reenable`. Otherwise the handler has to
+    /// enable it again.
+    pub fn add_device<F>(&mut self, device: UioDevice, handler: F) -> io::Result<Token>
+    where
+        F: FnMut(&UioDevice, u32) -> io::Result<()> + 'a,
+    {
+        let fd = device.as_raw_fd();
+        self.add(
+            fd,
+            Source::Device {
+                device: Box::new(device),
+                handler: Box::new(handler),
+            },
+        )
+    }
+
+    fn add_timerfd(
+        &mut self,
+        first: Duration,
+        interval: Option<Duration>,
+        handler: TimerHandler<'a>,
+    ) -> io::Result<Token> {
+        let file = File::from(sys::timerfd_create()?);
+        // A zero expiration would disarm the timer
+        let first = first.max(Duration::from_nanos(1));
+        sys::timerfd_set(file.as_raw_fd(), first, interval)?;
+        let fd = file.as_raw_fd();
+        self.add(
+            fd,
+            Source::Timer {
+                file,
+                once: interval.is_none(),
+                handler,
+            },
+        )
+    }
+
+    /// Calls `handler` every `interval`, with the number of expirations
+    /// since the last call (more than 1 if handlers ran late).
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is zero.
+    pub fn add_timer<F>(&mut self, interval: Duration, handler: F) -> io::Result<Token>
+    where
+        F: FnMut(u64) -> io::Result<()> + 'a,
+    {
+        assert!(!interval.is_zero(), "timer intervals must not be zero");
+        self.add_timerfd(interval, Some(interval), Box::new(handler))
+    }
+
+    /// Calls `handler` once after `delay`, then removes the timer.
+    pub fn add_timeout<F>(&mut self, delay: Duration, handler: F) -> io::Result<Token>
+    where
+        F: FnOnce() -> io::Result<()> + 'a,
+    {
+        let mut handler = Some(handler);
+        self.add_timerfd(
+            delay,
+            None,
+            Box::new(move |_| handler.take().map_or(Ok(()), |h| h())),
+        )
+    }
+
+    /// Removes a device or timer. Returns the device, if `token` was one.
+    pub fn remove(&mut self, token: Token) -> Option<UioDevice> {
+        let source = self.sources.remove(&token.0)?;
+        let fd = match source {
+            Source::Device { ref device, .. } => device.as_raw_fd(),
+            Source::Timer { ref file, .. } => file.as_raw_fd(),
+        };
+        let _ = sys::epoll_delete(self.epoll.as_raw_fd(), fd);
+        match source {
+            Source::Device { device, .. } => Some(*device),
+            Source::Timer { .. } => None,
+        }
+    }
+
+    /// The number of devices and timers.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// A handle to end `run` from other threads.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Ends `run` when one of `signals` (e.g. `libc::SIGTERM`) arrives.
+    ///
+    /// The signals are received through a `signalfd` and blocked in the
+    /// calling thread, so they no longer terminate the process. They
+    /// should be blocked in all threads, so call this before starting any
+    /// and from the thread calling `run`.
+    pub fn shutdown_on_signals(&mut self, signals: &[libc::c_int]) -> io::Result<()> {
+        let file = File::from(sys::signalfd(signals)?);
+        if let Some(old) = self.signals.take() {
+            let _ = sys::epoll_delete(self.epoll.as_raw_fd(), old.as_raw_fd());
+        }
+        sys::epoll_add(self.epoll.as_raw_fd(), file.as_raw_fd(), SIGNALS)?;
+        self.signals = Some(file);
+        Ok(())
+    }
+
+    /// Whether a shutdown was requested. `run` returns right away until
+    /// `reset_shutdown` is called.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Allows running again after a shutdown.
+    pub fn reset_shutdown(&mut self) {
+        self.shut_down = false;
+    }
+
+    /// Waits up to `timeout` (forever if `None`) for events and calls the
+    /// handlers of those which are ready. Returns the number of handlers
+    /// called, 0 on timeout, on a signal interrupting the wait or on
+    /// shutdown.
+    pub fn turn(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        let timeout_ms = timeout.map_or(-1, |t| {
+            // Round up so we don't spin on sub-millisecond remainders
+            t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        });
+        let tokens = match sys::epoll_wait(self.epoll.as_raw_fd(), timeout_ms) {
+            Ok(tokens) => tokens,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut dispatched = 0;
+        for token in tokens {
+            match token {
+                SHUTDOWN => {
+                    drain(&self.shutdown.file, 8)?;
+                    self.shut_down = true;
+                }
+                SIGNALS => {
+                    if let Some(ref file) = self.signals {
+                        drain(file, 128)?;
+                    }
+                    self.shut_down = true;
+                }
+                _ => {
+                    // Removed by `remove` since
+                    let Some(source) = self.sources.get_mut(&token) else {
+                        continue;
+                    };
+                    let (res, once) = match *source {
+                        Source::Device {
+                            ref device,
+                            ref mut handler,
+                        } => {
+                            let count = device.irq_wait()?;
+                            (handler(device, count), false)
+                        }
+                        Source::Timer {
+                            ref file,
+                            once,
+                            ref mut handler,
+                        } => {
+                            let mut buf = [0u8; 8];
+                            match (&*file).read(&mut buf) {
+                                Ok(_) => {}
+                                // Already read, e.g. after rearming
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                                Err(e) => return Err(e),
+                            }
+                            (handler(u64::from_ne_bytes(buf)), once)
+                        }
+                    };
+                    // Also if the handler failed, a one-shot timer is disarmed
+                    if once {
+                        self.remove(Token(token));
+                    }
+                    res?;
+                    dispatched += 1;
+                }
+            }
+        }
+        Ok(dispatched)
+    }
+
+    /// Calls handlers as events arrive until a shutdown is requested (see
+    /// `shutdown_handle` and `shutdown_on_signals`) or a handler fails.
+    pub fn run(&mut self) -> io::Result<()> {
+        while !self.shut_down {
+            self.turn(None)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for Reactor<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reactor")
+            .field("sources", &self.sources.len())
+            .field("shut_down", &self.shut_down)
+            .finish()
+    }
+}
+
+/// Reads pending `size`-byte records from the non-blocking `file`.
+fn drain(mut file: &File, size: usize) -> io::Result<()> {
+    let mut buf = [0u8; 128];
+    loop {
+        match file.read(&mut buf[..size]) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+    use std::cell::{Cell, RefCell};
+    use std::os::fd::FromRawFd;
+    use std::thread;
+    use LockMode;
+
+    #[test]
+    fn reactor() {
+        // The mock device file is a regular file, which epoll can't watch,
+        // so the device reads its interrupts from a pipe instead
+        let mock = MockUio::new(0).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let (rx, mut tx) = unsafe { (fd::OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let dev = mock.builder().lock(LockMode::Unlocked).open_fd(rx).unwrap();
+
+        let counts = RefCell::new(Vec::new());
+        let ticks = Cell::new(0);
+        let fired = Cell::new(false);
+        let mut reactor = Reactor::new().unwrap();
+        let token = reactor
+            .add_device(dev, |_, count| {
+                counts.borrow_mut().push(count);
+                Ok(())
+            })
+            .unwrap();
+        reactor
+            .add_timer(Duration::from_millis(1), |n| {
+                ticks.set(ticks.get() + n);
+                Ok(())
+            })
+            .unwrap();
+        reactor
+            .add_timeout(Duration::from_millis(1), || {
+                fired.set(true);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(reactor.len(), 3);
+
+        tx.write_all(&7u32.to_ne_bytes()).unwrap();
+        while counts.borrow().is_empty() || !fired.get() || ticks.get() == 0 {
+            reactor.turn(Some(Duration::from_secs(5))).unwrap();
+        }
+        assert_eq!(*counts.borrow(), [7]);
+        // The timeout is gone after firing
+        assert_eq!(reactor.len(), 2);
+
+        let shutdown = reactor.shutdown_handle();
+        thread::scope(|s| {
+            s.spawn(|| shutdown.trigger());
+            reactor.run().unwrap();
+        });
+        assert!(reactor.is_shut_down());
+        assert!(reactor.remove(token).is_some());
+        assert!(reactor.remove(token).is_none());
+
+        reactor.reset_shutdown();
+        reactor
+            .add_timeout(Duration::from_millis(1), || {
+                Err(io::Error::other("handler failed"))
+            })
+            .unwrap();
+        assert!(reactor.run().is_err());
+        // The failed timeout is removed, the periodic timer stays
+        assert_eq!(reactor.len(), 1);
+    }
+}
@@ -0,0 +1,644 @@
+//! Recording and replaying of register accesses.
+//!
+//! A `Recorder` wraps a `MappedRegion` (or any other `DeviceMemory`) and logs
+//! every access to a writer, one line per access:
+//!
+//! ```text
+//! <nanoseconds since start> <R|W> <offset> <width in bytes> <value>
+//! 1520 W 0x10 4 0x1
+//! 3310 R 0x14 4 0x80000000
+//! ```
+//!
+//! A `Replay` reads such a log back and serves the recorded values for reads
+//! (and checks writes against the log), so driver logic can be re-run
+//! against a recorded device interaction without hardware. Both implement
+//! `DeviceMemory` themselves, so they can be passed to driver code which is
+//! generic over it.
+//!
+//! An `Audit` checks every access for bounds, alignment and allowed widths
+//! before it reaches the device, and numbers and remembers it before it is
+//! performed. When a device locks up, the last entry is the access which
+//! hung:
+//!
+//! ```ignore
+//! let mut audit = Audit::new(&regs);
+//! audit.widths(AccessWidths::U32).keep_last(64);
+//! audit.log_with(|entry| eprintln!("{}", entry));
+//! start_dma(&audit);
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use {AccessWidths, DeviceMemory, MappedRegion};
+
+/// Whether an `Access` was a read or a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single recorded register access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    /// Time since the recording started
+    pub timestamp: Duration,
+    pub kind: AccessKind,
+    /// Byte offset within the region
+    pub offset: usize,
+    /// Access width in bytes (1, 2, 4 or 8)
+    pub width: usize,
+    /// The value read or written
+    pub value: u64,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => 'R',
+            AccessKind::Write => 'W',
+        };
+        write!(
+            f,
+            "{} {} {:#x} {} {:#x}",
+            self.timestamp.as_nanos(),
+            kind,
+            self.offset,
+            self.width,
+            self.value
+        )
+    }
+}
+
+/// Error returned when parsing an invalid log line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAccessError(String);
+
+impl fmt::Display for ParseAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid access record {:?}", self.0)
+    }
+}
+
+impl ::std::error::Error for ParseAccessError {}
+
+impl FromStr for Access {
+    type Err = ParseAccessError;
+
+    fn from_str(s: &str) -> Result<Access, ParseAccessError> {
+        let err = || ParseAccessError(s.to_string());
+        let hex = |f: &str| {
+            f.strip_prefix("0x")
+                .and_then(|h| u64::from_str_radix(h, 16).ok())
+        };
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(err());
+        }
+        let nanos: u64 = fields[0].parse().map_err(|_| err())?;
+        let kind = match fields[1] {
+            "R" => AccessKind::Read,
+            "W" => AccessKind::Write,
+            _ => return Err(err()),
+        };
+        let offset = hex(fields[2]).ok_or_else(err)? as usize;
+        let width = match fields[3].parse() {
+            Ok(w @ 1) | Ok(w @ 2) | Ok(w @ 4) | Ok(w @ 8) => w,
+            _ => return Err(err()),
+        };
+        let value = hex(fields[4]).ok_or_else(err)?;
+        Ok(Access {
+            timestamp: Duration::from_nanos(nanos),
+            kind,
+            offset,
+            width,
+            value,
+        })
+    }
+}
+
+/// Logs all accesses to a `MappedRegion` or another `DeviceMemory`.
+///
+/// Errors while writing the log don't interrupt the register accesses, the
+/// first one is returned by `finish`.
+pub struct Recorder<'r, W: Write, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    region: &'r M,
+    start: Instant,
+    out: Mutex<(W, io::Result<()>)>,
+}
+
+macro_rules! recorded_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Reads a `", stringify!($ty), "` at byte `offset` and logs it.")]
+            pub fn $read(&self, offset: usize) -> $ty {
+                let value = self.region.$read(offset);
+                self.log(AccessKind::Read, offset, ::std::mem::size_of::<$ty>(), value as u64);
+                value
+            }
+
+            #[doc = concat!("Writes a `", stringify!($ty), "` at byte `offset` and logs it.")]
+            pub fn $write(&self, offset: usize, value: $ty) {
+                self.region.$write(offset, value);
+                self.log(AccessKind::Write, offset, ::std::mem::size_of::<$ty>(), value as u64);
+            }
+        )*
+    };
+}
+
+/// Implements `DeviceMemory` through the inherent accessors of the same
+/// names.
+macro_rules! device_memory_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            fn $read(&self, offset: usize) -> $ty {
+                Self::$read(self, offset)
+            }
+
+            fn $write(&self, offset: usize, value: $ty) {
+                Self::$write(self, offset, value)
+            }
+        )*
+    };
+}
+
+impl<'r, W: Write, M: DeviceMemory + ?Sized> Recorder<'r, W, M> {
+    /// Starts recording accesses to `region` into `out`.
+    pub fn new(region: &'r M, out: W) -> Self {
+        Recorder {
+            region,
+            start: Instant::now(),
+            out: Mutex::new((out, Ok(()))),
+        }
+    }
+
+    fn log(&self, kind: AccessKind, offset: usize, width: usize, value: u64) {
+        let access = Access {
+            timestamp: self.start.elapsed(),
+            kind,
+            offset,
+            width,
+            value,
+        };
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if out.1.is_ok() {
+            out.1 = writeln!(out.0, "{}", access);
+        }
+    }
+
+    recorded_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+
+    /// Flushes and returns the writer, or the first error writing the log.
+    pub fn finish(self) -> io::Result<W> {
+        let (mut out, res) = self.out.into_inner().unwrap_or_else(|e| e.into_inner());
+        res?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+impl<'r, W: Write, M: DeviceMemory + ?Sized> DeviceMemory for Recorder<'r, W, M> {
+    fn len(&self) -> usize {
+        self.region.len()
+    }
+
+    device_memory_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+/// Replays a recorded access log.
+///
+/// Reads return the recorded values. Every access has to match the next
+/// entry of the log in kind, offset, width and (for writes) value, otherwise
+/// the replay panics since the code under test diverged from the recording.
+#[derive(Debug)]
+pub struct Replay {
+    accesses: Vec<Access>,
+    len: usize,
+    pos: Mutex<usize>,
+}
+
+macro_rules! replayed_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Returns the recorded `", stringify!($ty), "` read at byte `offset`.")]
+            pub fn $read(&self, offset: usize) -> $ty {
+                self.next(AccessKind::Read, offset, ::std::mem::size_of::<$ty>(), None) as $ty
+            }
+
+            #[doc = concat!("Checks the recorded `", stringify!($ty), "` write at byte `offset`.")]
+            pub fn $write(&self, offset: usize, value: $ty) {
+                self.next(AccessKind::Write, offset, ::std::mem::size_of::<$ty>(), Some(value as u64));
+            }
+        )*
+    };
+}
+
+impl Replay {
+    /// Creates a replay of `accesses`.
+    pub fn new(accesses: Vec<Access>) -> Replay {
+        let len = accesses
+            .iter()
+            .map(|a| a.offset + a.width)
+            .max()
+            .unwrap_or(0);
+        Replay {
+            accesses,
+            len,
+            pos: Mutex::new(0),
+        }
+    }
+
+    /// Parses a log written by a `Recorder`. Empty lines are ignored.
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Replay> {
+        let mut accesses = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let access = line
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            accesses.push(access);
+        }
+        Ok(Replay::new(accesses))
+    }
+
+    fn next(&self, kind: AccessKind, offset: usize, width: usize, value: Option<u64>) -> u64 {
+        let mut pos = self.pos.lock().unwrap_or_else(|e| e.into_inner());
+        let expected = match self.accesses.get(*pos) {
+            Some(a) => a,
+            None => panic!(
+                "replay exhausted: unexpected {:?} of {} bytes at {:#x}",
+                kind, width, offset
+            ),
+        };
+        let matches = expected.kind == kind
+            && expected.offset == offset
+            && expected.width == width
+            && value.is_none_or(|v| v == expected.value);
+        assert!(
+            matches,
+            "replay diverged at entry {}: expected `{}`, got {:?} of {} bytes at {:#x} (value {:?})",
+            *pos, expected, kind, width, offset, value
+        );
+        *pos += 1;
+        expected.value
+    }
+
+    replayed_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+
+    /// Number of recorded accesses which haven't been replayed yet.
+    pub fn remaining(&self) -> usize {
+        let pos = self.pos.lock().unwrap_or_else(|e| e.into_inner());
+        self.accesses.len() - *pos
+    }
+
+    /// Whether all recorded accesses have been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+/// The length of a `Replay` is the end of the highest recorded access.
+impl DeviceMemory for Replay {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    device_memory_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+/// An access checked by an `Audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Number of the access, counting from 0
+    pub seq: u64,
+    pub kind: AccessKind,
+    /// Byte offset within the region
+    pub offset: usize,
+    /// Access width in bytes (1, 2, 4 or 8)
+    pub width: usize,
+    /// The value written, or read once the read completed
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => 'R',
+            AccessKind::Write => 'W',
+        };
+        write!(
+            f,
+            "#{} {} {:#x} {}",
+            self.seq, kind, self.offset, self.width
+        )?;
+        match self.value {
+            Some(value) => write!(f, " {:#x}", value),
+            None => f.write_str(" ?"),
+        }
+    }
+}
+
+struct AuditState {
+    next_seq: u64,
+    /// The last accesses, oldest first
+    history: VecDeque<AuditEntry>,
+}
+
+type AuditSink<'r> = Box<dyn Fn(&AuditEntry) + Send + Sync + 'r>;
+
+/// Checks and numbers all accesses to a `MappedRegion` or another
+/// `DeviceMemory` (see the module documentation).
+///
+/// A violation panics before the memory is accessed, with the sequence
+/// number the access would have had.
+pub struct Audit<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    region: &'r M,
+    widths: AccessWidths,
+    capacity: usize,
+    sink: Option<AuditSink<'r>>,
+    state: Mutex<AuditState>,
+}
+
+macro_rules! audited_accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Checks, logs and reads a `", stringify!($ty), "` at byte `offset`.")]
+            pub fn $read(&self, offset: usize) -> $ty {
+                let seq = self.begin(AccessKind::Read, offset, ::std::mem::size_of::<$ty>(), None);
+                let value = self.region.$read(offset);
+                self.complete(seq, value as u64);
+                value
+            }
+
+            #[doc = concat!("Checks, logs and writes a `", stringify!($ty), "` at byte `offset`.")]
+            pub fn $write(&self, offset: usize, value: $ty) {
+                self.begin(AccessKind::Write, offset, ::std::mem::size_of::<$ty>(), Some(value as u64));
+                self.region.$write(offset, value);
+            }
+        )*
+    };
+}
+
+impl<'r, M: DeviceMemory + ?Sized> Audit<'r, M> {
+    /// Audits accesses to `region`, allowing all widths and remembering the
+    /// last 16 accesses.
+    pub fn new(region: &'r M) -> Self {
+        Audit {
+            region,
+            widths: AccessWidths::ALL,
+            capacity: 16,
+            sink: None,
+            state: Mutex::new(AuditState {
+                next_seq: 0,
+                history: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Only allows accesses of the sizes `widths`.
+    pub fn widths(&mut self, widths: AccessWidths) -> &mut Self {
+        self.widths = widths;
+        self
+    }
+
+    /// Remembers the last `n` accesses (see `last_accesses`).
+    pub fn keep_last(&mut self, n: usize) -> &mut Self {
+        self.capacity = n;
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        while state.history.len() > n {
+            state.history.pop_front();
+        }
+        self
+    }
+
+    /// Calls `sink` with every access after it was checked and before it is
+    /// performed (so reads have no value yet), e.g. to log it.
+    pub fn log_with<F>(&mut self, sink: F) -> &mut Self
+    where
+        F: Fn(&AuditEntry) + Send + Sync + 'r,
+    {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, AuditState> {
+        // A panicking check leaves the state consistent
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks an access and logs it, returning its sequence number.
+    fn begin(&self, kind: AccessKind, offset: usize, width: usize, value: Option<u64>) -> u64 {
+        let mut state = self.state();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let entry = AuditEntry {
+            seq,
+            kind,
+            offset,
+            width,
+            value,
+        };
+        let violation = if offset
+            .checked_add(width)
+            .is_none_or(|end| end > self.region.len())
+        {
+            Some("is out of bounds")
+        } else if !offset.is_multiple_of(width) {
+            Some("is misaligned")
+        } else if !self.widths.contains(width) {
+            Some("has a width not allowed in this region")
+        } else {
+            None
+        };
+        if let Some(violation) = violation {
+            drop(state);
+            panic!("audited access `{}` {}", entry, violation);
+        }
+        if self.capacity > 0 {
+            if state.history.len() == self.capacity {
+                state.history.pop_front();
+            }
+            state.history.push_back(entry);
+        }
+        drop(state);
+        if let Some(ref sink) = self.sink {
+            sink(&entry);
+        }
+        seq
+    }
+
+    /// Records the value of the completed read `seq`.
+    fn complete(&self, seq: u64, value: u64) {
+        let mut state = self.state();
+        if let Some(entry) = state.history.iter_mut().rev().find(|e| e.seq == seq) {
+            entry.value = Some(value);
+        }
+    }
+
+    /// The last accesses, oldest first. A read without a value was started
+    /// but didn't complete.
+    pub fn last_accesses(&self) -> Vec<AuditEntry> {
+        self.state().history.iter().copied().collect()
+    }
+
+    /// The number of accesses so far, including rejected ones.
+    pub fn count(&self) -> u64 {
+        self.state().next_seq
+    }
+
+    audited_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+impl<'r, M: DeviceMemory + ?Sized> DeviceMemory for Audit<'r, M> {
+    fn len(&self) -> usize {
+        self.region.len()
+    }
+
+    device_memory_accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+impl<'r, M: DeviceMemory + ?Sized> fmt::Debug for Audit<'r, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Audit")
+            .field("widths", &self.widths)
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockMemory, MockUio};
+
+    #[test]
+    fn record_and_replay() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        let recorder = Recorder::new(&region, Vec::new());
+        recorder.write_u32(0x10, 0xabcd);
+        assert_eq!(recorder.read_u16(0x10), 0xabcd);
+        let log = recorder.finish().unwrap();
+
+        let replay = Replay::from_reader(&log[..]).unwrap();
+        assert_eq!(replay.remaining(), 2);
+        replay.write_u32(0x10, 0xabcd);
+        assert_eq!(replay.read_u16(0x10), 0xabcd);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn record_mock_memory() {
+        let mem = MockMemory::new(0x100);
+        let recorder = Recorder::new(&mem, Vec::new());
+        let memory: &dyn DeviceMemory = &recorder;
+        memory.write_bytes(0x8, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(memory.read_u64(0x8), mem.read_u64(0x8));
+        let log = recorder.finish().unwrap();
+
+        let replay = Replay::from_reader(&log[..]).unwrap();
+        assert_eq!(DeviceMemory::len(&replay), 0x10);
+        assert_eq!(replay.remaining(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "replay diverged")]
+    fn replay_diverges() {
+        let replay = Replay::from_reader(&b"0 W 0x10 4 0x1\n"[..]).unwrap();
+        replay.write_u32(0x10, 2);
+    }
+
+    #[test]
+    fn audit() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::Arc;
+
+        let mem = MockMemory::new(0x10);
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let mut audit = Audit::new(&mem);
+        let l = logged.clone();
+        audit
+            .widths(AccessWidths::U32 | AccessWidths::U64)
+            .keep_last(2)
+            .log_with(move |e| l.lock().unwrap().push(e.to_string()));
+
+        audit.write_u32(0x4, 7);
+        assert_eq!(audit.read_u32(0x4), 7);
+        audit.write_u64(0x8, 1);
+        assert_eq!(
+            audit.last_accesses(),
+            [
+                AuditEntry {
+                    seq: 1,
+                    kind: AccessKind::Read,
+                    offset: 0x4,
+                    width: 4,
+                    value: Some(7),
+                },
+                AuditEntry {
+                    seq: 2,
+                    kind: AccessKind::Write,
+                    offset: 0x8,
+                    width: 8,
+                    value: Some(1),
+                }
+            ]
+        );
+        assert_eq!(
+            *logged.lock().unwrap(),
+            ["#0 W 0x4 4 0x7", "#1 R 0x4 4 ?", "#2 W 0x8 8 0x1"]
+        );
+
+        for bad in [
+            &(|a: &Audit<MockMemory>| a.write_u8(0x0, 1)) as &dyn Fn(&Audit<MockMemory>),
+            &|a| a.write_u32(0x2, 1),
+            &|a| a.write_u32(0x10, 1),
+        ] {
+            assert!(catch_unwind(AssertUnwindSafe(|| bad(&audit))).is_err());
+        }
+        // Nothing reached the memory
+        assert_eq!(mem.read_u64(0x0), 7 << 32);
+        assert_eq!(audit.count(), 6);
+        assert_eq!(audit.last_accesses()[1].seq, 2);
+    }
+}
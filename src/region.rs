@@ -0,0 +1,1340 @@
+use codec::Codec;
+use copy;
+use libc;
+use linux::UioDevice;
+use offset::{ByteLen, RegOffset};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ops::BitOr;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use sys;
+
+/// Volatile access to device memory at byte offsets.
+///
+/// Implemented by `MappedRegion` and by in-memory fakes such as
+/// `mock::MockMemory`, so driver logic written against `DeviceMemory` can be
+/// unit-tested without hardware:
+///
+/// ```ignore
+/// fn start<M: DeviceMemory + ?Sized>(regs: &M) {
+///     regs.write_u32(CTRL, regs.read_u32(CTRL) | CTRL_EN);
+/// }
+/// ```
+///
+/// The accessors panic if the access is out of bounds or `offset` is not
+/// naturally aligned.
+pub trait DeviceMemory {
+    /// Length of the memory in bytes.
+    fn len(&self) -> usize;
+
+    /// Whether the memory has a length of zero.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_u8(&self, offset: usize) -> u8;
+    fn read_u16(&self, offset: usize) -> u16;
+    fn read_u32(&self, offset: usize) -> u32;
+    fn read_u64(&self, offset: usize) -> u64;
+    fn write_u8(&self, offset: usize, value: u8);
+    fn write_u16(&self, offset: usize, value: u16);
+    fn write_u32(&self, offset: usize, value: u32);
+    fn write_u64(&self, offset: usize, value: u64);
+
+    /// Copies `buf.len()` bytes at `offset` into `buf`, with 32-bit reads if
+    /// `offset` and the length are multiples of 4 and byte reads otherwise.
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        read_bytes_with(self, offset, buf)
+    }
+
+    /// Copies `buf` to `offset`, with the same access widths as `read_bytes`.
+    fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        write_bytes_with(self, offset, buf)
+    }
+
+    /// Whether `len` bytes at `offset` are within the memory.
+    fn contains(&self, offset: RegOffset, len: ByteLen) -> bool {
+        offset
+            .checked_add(len)
+            .is_some_and(|end| end.get() <= self.len())
+    }
+
+    // The accessors with typed offsets, see `RegOffset`.
+
+    fn read_u8_at(&self, offset: RegOffset) -> u8 {
+        self.read_u8(offset.get())
+    }
+    fn read_u16_at(&self, offset: RegOffset) -> u16 {
+        self.read_u16(offset.get())
+    }
+    fn read_u32_at(&self, offset: RegOffset) -> u32 {
+        self.read_u32(offset.get())
+    }
+    fn read_u64_at(&self, offset: RegOffset) -> u64 {
+        self.read_u64(offset.get())
+    }
+    fn write_u8_at(&self, offset: RegOffset, value: u8) {
+        self.write_u8(offset.get(), value)
+    }
+    fn write_u16_at(&self, offset: RegOffset, value: u16) {
+        self.write_u16(offset.get(), value)
+    }
+    fn write_u32_at(&self, offset: RegOffset, value: u32) {
+        self.write_u32(offset.get(), value)
+    }
+    fn write_u64_at(&self, offset: RegOffset, value: u64) {
+        self.write_u64(offset.get(), value)
+    }
+    fn read_bytes_at(&self, offset: RegOffset, buf: &mut [u8]) {
+        self.read_bytes(offset.get(), buf)
+    }
+    fn write_bytes_at(&self, offset: RegOffset, buf: &[u8]) {
+        self.write_bytes(offset.get(), buf)
+    }
+
+    /// Writes `value` to the 32-bit register at `offset` and reads it back.
+    ///
+    /// Only the bits set in `mask` are compared, so status or self-clearing
+    /// bits can be left out. On a mismatch the write is repeated up to
+    /// `retries` times before failing with the value read last.
+    fn write_verify(
+        &self,
+        offset: usize,
+        value: u32,
+        mask: u32,
+        retries: u32,
+    ) -> Result<(), VerifyError> {
+        let mut attempts = 0;
+        loop {
+            self.write_u32(offset, value);
+            let actual = self.read_u32(offset);
+            attempts += 1;
+            if (actual ^ value) & mask == 0 {
+                return Ok(());
+            }
+            if attempts > retries {
+                return Err(VerifyError {
+                    offset,
+                    expected: value,
+                    actual,
+                    mask,
+                    attempts,
+                });
+            }
+        }
+    }
+}
+
+/// A register which didn't read back what `DeviceMemory::write_verify`
+/// wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyError {
+    pub offset: usize,
+    /// The value written
+    pub expected: u32,
+    /// The value read back after the last attempt
+    pub actual: u32,
+    /// The bits which were compared
+    pub mask: u32,
+    /// The number of writes
+    pub attempts: u32,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "register {:#x} reads {:#x} after writing {:#x} (mask {:#x}, {} attempts)",
+            self.offset, self.actual, self.expected, self.mask, self.attempts
+        )
+    }
+}
+
+impl Error for VerifyError {}
+
+impl From<VerifyError> for io::Error {
+    fn from(e: VerifyError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// `DeviceMemory::read_bytes` on top of the accessors of `memory`.
+fn read_bytes_with<M: DeviceMemory + ?Sized>(memory: &M, offset: usize, buf: &mut [u8]) {
+    if offset.is_multiple_of(4) && buf.len().is_multiple_of(4) {
+        for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&memory.read_u32(offset + i * 4).to_ne_bytes());
+        }
+    } else {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = memory.read_u8(offset + i);
+        }
+    }
+}
+
+/// `DeviceMemory::write_bytes` on top of the accessors of `memory`.
+fn write_bytes_with<M: DeviceMemory + ?Sized>(memory: &M, offset: usize, buf: &[u8]) {
+    if offset.is_multiple_of(4) && buf.len().is_multiple_of(4) {
+        for (i, chunk) in buf.chunks_exact(4).enumerate() {
+            let word = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            memory.write_u32(offset + i * 4, word);
+        }
+    } else {
+        for (i, b) in buf.iter().enumerate() {
+            memory.write_u8(offset + i, *b);
+        }
+    }
+}
+
+/// The checksums of a transfer by `MappedRegion::copy_from_slice_crc` or
+/// `copy_to_slice_crc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferCrc {
+    /// CRC-32 of the host buffer
+    pub host: u32,
+    /// CRC-32 of the device memory, read back after the transfer
+    pub device: u32,
+}
+
+impl TransferCrc {
+    /// Whether the device memory matches the host buffer.
+    pub fn matches(&self) -> bool {
+        self.host == self.device
+    }
+}
+
+/// The access sizes a region of device memory tolerates.
+///
+/// ```ignore
+/// // The register block faults on anything but 32-bit accesses
+/// regs.set_access_widths(AccessWidths::U32, Unsupported::Widen);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessWidths(u8);
+
+impl AccessWidths {
+    pub const U8: AccessWidths = AccessWidths(1);
+    pub const U16: AccessWidths = AccessWidths(2);
+    pub const U32: AccessWidths = AccessWidths(4);
+    pub const U64: AccessWidths = AccessWidths(8);
+    /// Any access size, the default
+    pub const ALL: AccessWidths = AccessWidths(1 | 2 | 4 | 8);
+
+    /// Whether accesses of `size` bytes are allowed.
+    pub fn contains(self, size: usize) -> bool {
+        size <= 8 && self.0 & size as u8 != 0
+    }
+
+    /// The smallest allowed size above `size`.
+    fn wider(self, size: usize) -> Option<usize> {
+        [2, 4, 8]
+            .iter()
+            .copied()
+            .find(|&wide| wide > size && self.contains(wide))
+    }
+}
+
+impl Default for AccessWidths {
+    fn default() -> AccessWidths {
+        AccessWidths::ALL
+    }
+}
+
+impl BitOr for AccessWidths {
+    type Output = AccessWidths;
+
+    fn bitor(self, rhs: AccessWidths) -> AccessWidths {
+        AccessWidths(self.0 | rhs.0)
+    }
+}
+
+/// What the accessors of a region do with access sizes it doesn't allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unsupported {
+    /// Panic, like for out of bounds accesses.
+    #[default]
+    Reject,
+    /// Use the next larger allowed size, on the aligned location containing
+    /// the access. Writes read, modify and write back the larger location,
+    /// so they also write its other bytes (with the values just read).
+    /// Panics if there is no larger allowed size.
+    Widen,
+}
+
+/// A memory region mapped from a `UioDevice`.
+///
+/// All accesses are volatile. The region is unmapped when it is dropped and
+/// borrows the device, so it can't outlive it, unless it is detached with
+/// `leak` or `into_raw_parts`.
+///
+/// # Thread safety
+///
+/// `MappedRegion` is `Send` and `Sync`: the accessors only take `&self`
+/// and perform single volatile loads/stores. Concurrent accesses to the same
+/// register from several threads are not synchronized by this crate, their
+/// outcome is defined by the device.
+///
+/// # Read-only devices
+///
+/// Regions of devices opened with `UioDeviceBuilder::read_only` are mapped
+/// without write permission. Their write accessors panic instead of
+/// faulting; the unsafe views (`as_registers`, `as_mut`, `atomic_u32`...)
+/// must not be written through.
+///
+/// # Access widths
+///
+/// By default the accessors access memory with the size of their type. For
+/// registers which only tolerate some sizes, `set_access_widths` makes the
+/// accessors (including `read_bytes`, `write_bytes` and `write_batch`)
+/// reject or widen the other sizes. References from `as_registers`,
+/// `as_ref` and `as_mut` are not restricted.
+pub struct MappedRegion<'a> {
+    ptr: NonNull<u8>,
+    len: usize,
+    /// The device tracking the mapping, `None` if the region owns it
+    device: Option<&'a UioDevice>,
+    /// Whether the memory is mapped with write permission
+    writable: bool,
+    widths: AccessWidths,
+    unsupported: Unsupported,
+}
+
+unsafe impl<'a> Send for MappedRegion<'a> {}
+unsafe impl<'a> Sync for MappedRegion<'a> {}
+
+macro_rules! accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Reads a `", stringify!($ty), "` at byte `offset`.")]
+            ///
+            /// # Panics
+            /// If the access is out of bounds or `offset` is not naturally aligned.
+            pub fn $read(&self, offset: usize) -> $ty {
+                const SIZE: usize = ::std::mem::size_of::<$ty>();
+                self.check(offset, SIZE);
+                if !self.widths.contains(SIZE) {
+                    let mut bytes = [0; SIZE];
+                    self.read_widened(offset, &mut bytes);
+                    return <$ty>::from_ne_bytes(bytes);
+                }
+                unsafe { ptr::read_volatile(self.ptr.as_ptr().add(offset) as *const $ty) }
+            }
+
+            #[doc = concat!("Writes a `", stringify!($ty), "` at byte `offset`.")]
+            ///
+            /// # Panics
+            /// If the access is out of bounds, `offset` is not naturally aligned
+            /// or the region is read-only.
+            pub fn $write(&self, offset: usize, value: $ty) {
+                const SIZE: usize = ::std::mem::size_of::<$ty>();
+                self.check_writable();
+                self.check(offset, SIZE);
+                if !self.widths.contains(SIZE) {
+                    return self.write_widened(offset, &value.to_ne_bytes());
+                }
+                unsafe { ptr::write_volatile(self.ptr.as_ptr().add(offset) as *mut $ty, value) }
+            }
+        )*
+    };
+}
+
+impl<'a> MappedRegion<'a> {
+    /// Wraps a mapping tracked by `device`; dropping the region unmaps it.
+    pub(crate) fn new(
+        device: &'a UioDevice,
+        ptr: *mut libc::c_void,
+        len: usize,
+        writable: bool,
+    ) -> Self {
+        MappedRegion {
+            ptr: NonNull::new(ptr as *mut u8).expect("mmap never returns NULL"),
+            len,
+            device: Some(device),
+            writable,
+            widths: AccessWidths::ALL,
+            unsupported: Unsupported::Reject,
+        }
+    }
+
+    /// Takes ownership of a mapping released with `into_raw_parts`; the
+    /// region unmaps it when it is dropped. Writes panic unless `writable`
+    /// is set.
+    ///
+    /// # Safety
+    /// `ptr` and `len` must describe a shared mapping of device memory (as
+    /// returned by `into_raw_parts`) which nothing else unmaps or owns, and
+    /// which is writable if `writable` is set.
+    pub unsafe fn from_raw_parts(
+        ptr: *mut u8,
+        len: usize,
+        writable: bool,
+    ) -> MappedRegion<'static> {
+        MappedRegion {
+            ptr: NonNull::new(ptr).expect("from_raw_parts called with NULL"),
+            len,
+            device: None,
+            writable,
+            widths: AccessWidths::ALL,
+            unsupported: Unsupported::Reject,
+        }
+    }
+
+    /// Releases the mapping from the device and the region and returns its
+    /// start, length and whether it is writable, e.g. to hand it to a C
+    /// library which manages its lifetime.
+    ///
+    /// The memory stays mapped after the device is closed, until it is
+    /// unmapped with `munmap(ptr, len)` or turned back into a region with
+    /// `from_raw_parts`.
+    pub fn into_raw_parts(self) -> (*mut u8, usize, bool) {
+        let this = mem::ManuallyDrop::new(self);
+        if let Some(device) = this.device {
+            device.leak_region(this.ptr.as_ptr() as *mut libc::c_void);
+        }
+        (this.ptr.as_ptr(), this.len, this.writable)
+    }
+
+    /// Keeps the mapping for the rest of the process, independent of the
+    /// device, like `Box::leak`.
+    pub fn leak(self) -> &'static MappedRegion<'static> {
+        let (ptr, len, writable) = self.into_raw_parts();
+        // Safety: the mapping was just released and is never unmapped
+        let region = unsafe { MappedRegion::from_raw_parts(ptr, len, writable) };
+        Box::leak(Box::new(region))
+    }
+
+    /// Length of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the region has a length of zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Start of the region.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Restricts the accessors to the access sizes `widths`, handling other
+    /// sizes as `unsupported` says (see "Access widths").
+    pub fn set_access_widths(&mut self, widths: AccessWidths, unsupported: Unsupported) {
+        self.widths = widths;
+        self.unsupported = unsupported;
+    }
+
+    /// The allowed access sizes and what happens to others.
+    pub fn access_widths(&self) -> (AccessWidths, Unsupported) {
+        (self.widths, self.unsupported)
+    }
+
+    /// Copies `buf.len()` bytes at `offset` into `buf`.
+    ///
+    /// Uses 32-bit volatile reads where `offset` and the length allow it and
+    /// byte reads otherwise.
+    ///
+    /// # Panics
+    /// If the range is out of bounds.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        self.check_bounds(offset, buf.len());
+        if self.widths != AccessWidths::ALL {
+            return read_bytes_with(self, offset, buf);
+        }
+        let src = unsafe { self.ptr.as_ptr().add(offset) };
+        if offset.is_multiple_of(4) && buf.len().is_multiple_of(4) {
+            for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+                let word = unsafe { ptr::read_volatile((src as *const u32).add(i)) };
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+        } else {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = unsafe { ptr::read_volatile(src.add(i)) };
+            }
+        }
+    }
+
+    /// Copies `buf` to `offset`, with the same access widths as `read_bytes`.
+    ///
+    /// # Panics
+    /// If the range is out of bounds or the region is read-only.
+    pub fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        self.check_writable();
+        self.check_bounds(offset, buf.len());
+        if self.widths != AccessWidths::ALL {
+            return write_bytes_with(self, offset, buf);
+        }
+        let dst = unsafe { self.ptr.as_ptr().add(offset) };
+        if offset.is_multiple_of(4) && buf.len().is_multiple_of(4) {
+            for (i, chunk) in buf.chunks_exact(4).enumerate() {
+                let word = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                unsafe { ptr::write_volatile((dst as *mut u32).add(i), word) };
+            }
+        } else {
+            for (i, b) in buf.iter().enumerate() {
+                unsafe { ptr::write_volatile(dst.add(i), *b) };
+            }
+        }
+    }
+
+    /// Copies `src` to `offset` as fast as possible, with the widest aligned
+    /// accesses the CPU has (up to 256 bits on x86-64 with AVX) and byte and
+    /// word accesses at the unaligned ends. Then issues a memory fence, which
+    /// also drains write combining buffers, so the data reaches the device
+    /// before later register writes (e.g. a doorbell).
+    ///
+    /// Use `write_bytes` for registers which need 32-bit accesses. In regions
+    /// with restricted access widths (see `set_access_widths`) this is the
+    /// same as `write_bytes`.
+    ///
+    /// # Panics
+    /// If the range is out of bounds or the region is read-only.
+    pub fn copy_from_slice(&self, offset: usize, src: &[u8]) {
+        self.check_writable();
+        self.check_bounds(offset, src.len());
+        if self.widths != AccessWidths::ALL {
+            return write_bytes_with(self, offset, src);
+        }
+        unsafe { copy::to_device(self.ptr.as_ptr().add(offset), src) };
+        fence(Ordering::SeqCst);
+    }
+
+    /// Copies `dst.len()` bytes at `offset` into `dst` as fast as possible,
+    /// with the access widths of `copy_from_slice`.
+    ///
+    /// # Panics
+    /// If the range is out of bounds.
+    pub fn copy_to_slice(&self, offset: usize, dst: &mut [u8]) {
+        self.check_bounds(offset, dst.len());
+        if self.widths != AccessWidths::ALL {
+            return read_bytes_with(self, offset, dst);
+        }
+        unsafe { copy::from_device(dst, self.ptr.as_ptr().add(offset)) };
+    }
+
+    /// `copy_from_slice`, then reads the range back and returns the CRC-32
+    /// of `src` and of what the device holds.
+    ///
+    /// For verifying e.g. calibration tables before arming the device. The
+    /// checksum is the standard CRC-32 (as computed by zlib), so it can also
+    /// be compared to one shipped with the data.
+    ///
+    /// # Panics
+    /// If the range is out of bounds or the region is read-only.
+    pub fn copy_from_slice_crc(&self, offset: usize, src: &[u8]) -> TransferCrc {
+        self.copy_from_slice(offset, src);
+        TransferCrc {
+            host: copy::crc32(0, src),
+            device: self.crc32(offset, src.len()),
+        }
+    }
+
+    /// `copy_to_slice`, then reads the range again and returns the CRC-32
+    /// of `dst` and of the second read, which differ if the memory changed
+    /// in between or reads are unreliable.
+    ///
+    /// # Panics
+    /// If the range is out of bounds.
+    pub fn copy_to_slice_crc(&self, offset: usize, dst: &mut [u8]) -> TransferCrc {
+        self.copy_to_slice(offset, dst);
+        TransferCrc {
+            host: copy::crc32(0, dst),
+            device: self.crc32(offset, dst.len()),
+        }
+    }
+
+    /// The CRC-32 of `len` bytes at `offset`, read in chunks with the
+    /// access widths of `copy_to_slice`.
+    fn crc32(&self, offset: usize, len: usize) -> u32 {
+        let mut buf = [0u8; 256];
+        let mut crc = 0;
+        let mut done = 0;
+        while done < len {
+            let n = buf.len().min(len - done);
+            self.copy_to_slice(offset + done, &mut buf[..n]);
+            crc = copy::crc32(crc, &buf[..n]);
+            done += n;
+        }
+        crc
+    }
+
+    /// Writes `value` to `offset` for each pair in `writes`, in order, then
+    /// issues a single memory fence.
+    ///
+    /// Cheaper than fencing every store when programming many registers at
+    /// once. All offsets are checked before the first store, so an invalid
+    /// entry doesn't leave a partially written configuration.
+    ///
+    /// # Panics
+    /// If any access is out of bounds or misaligned, or the region is
+    /// read-only.
+    pub fn write_batch(&self, writes: &[(usize, u32)]) {
+        self.check_writable();
+        for &(offset, _) in writes {
+            self.check(offset, 4);
+            if !self.widths.contains(4) {
+                self.widened(offset, 4);
+            }
+        }
+        for &(offset, value) in writes {
+            if !self.widths.contains(4) {
+                self.write_widened(offset, &value.to_ne_bytes());
+                continue;
+            }
+            unsafe { ptr::write_volatile(self.ptr.as_ptr().add(offset) as *mut u32, value) };
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Like `write_batch`, but also reads back the last written register,
+    /// which forces posted writes (e.g. over PCIe) to reach the device before
+    /// this returns.
+    ///
+    /// # Panics
+    /// If any access is out of bounds or misaligned.
+    pub fn write_batch_flush(&self, writes: &[(usize, u32)]) {
+        self.write_batch(writes);
+        if let Some(&(offset, _)) = writes.last() {
+            self.read_u32(offset);
+        }
+    }
+
+    /// Reads a `T` at `offset`, decoded with the codec `C` (see `uio::codec`).
+    ///
+    /// # Panics
+    /// If the struct doesn't fit the region at `offset`.
+    pub fn read_struct<T, C: Codec<T>>(&self, offset: usize) -> Result<T, C::Error> {
+        let mut buf = vec![0; C::len()];
+        self.read_bytes(offset, &mut buf);
+        C::decode(&buf)
+    }
+
+    /// Writes `value` at `offset`, encoded with the codec `C`.
+    ///
+    /// Nothing is written if encoding fails.
+    ///
+    /// # Panics
+    /// If the struct doesn't fit the region at `offset`.
+    pub fn write_struct<T, C: Codec<T>>(&self, offset: usize, value: &T) -> Result<(), C::Error> {
+        let mut buf = vec![0; C::len()];
+        C::encode(value, &mut buf)?;
+        self.write_bytes(offset, &buf);
+        Ok(())
+    }
+
+    /// Views the memory at `offset` as a register block of type `T`.
+    ///
+    /// This allows reusing register definitions written for bare-metal
+    /// firmware, e.g. structs of `tock_registers::registers::ReadWrite<u32>`
+    /// declared with `register_structs!` or `#[repr(C)]` structs of
+    /// `volatile_register::RW<u32>`:
+    ///
+    /// ```ignore
+    /// register_structs! {
+    ///     Regs {
+    ///         (0x0 => ctrl: ReadWrite<u32>),
+    ///         (0x4 => status: ReadOnly<u32>),
+    ///         (0x8 => @END),
+    ///     }
+    /// }
+    ///
+    /// let regs: &Regs = unsafe { region.as_registers(0) };
+    /// regs.ctrl.set(1);
+    /// ```
+    ///
+    /// # Safety
+    /// `T` must only consist of types which access their memory through
+    /// volatile reads and writes from `&self` (like the register types of
+    /// `tock-registers` and `volatile-register`), so no references to plain
+    /// data in device memory are created.
+    ///
+    /// # Panics
+    /// If `T` doesn't fit the region at `offset` or `offset` is not aligned
+    /// for `T`.
+    pub unsafe fn as_registers<T>(&self, offset: usize) -> &T {
+        &*self.typed_ptr::<T>(offset)
+    }
+
+    /// Views the memory at `offset` as a plain `T`, e.g. a `#[repr(C)]`
+    /// descriptor in device memory, instead of casting `as_ptr()`.
+    ///
+    /// # Safety
+    /// Every bit pattern must be a valid `T`, and the memory must not change
+    /// while the reference is alive (the compiler may assume it doesn't and
+    /// skip or reorder reads), so this is only suitable for memory the
+    /// device doesn't modify concurrently. Use the volatile accessors or
+    /// `as_registers` otherwise.
+    ///
+    /// # Panics
+    /// If `T` doesn't fit the region at `offset` or `offset` is not aligned
+    /// for `T`.
+    pub unsafe fn as_ref<T>(&self, offset: usize) -> &T {
+        &*self.typed_ptr::<T>(offset)
+    }
+
+    /// Mutable version of `as_ref`.
+    ///
+    /// # Safety
+    /// As for `as_ref`, and the memory must not be accessed through other
+    /// references or mappings while the returned reference is alive.
+    ///
+    /// # Panics
+    /// If `T` doesn't fit the region at `offset` or `offset` is not aligned
+    /// for `T`.
+    pub unsafe fn as_mut<T>(&mut self, offset: usize) -> &mut T {
+        &mut *self.typed_ptr::<T>(offset)
+    }
+
+    /// Atomic operations on the 32-bit word at `offset`, e.g. for a ring
+    /// index in memory shared with the device:
+    ///
+    /// ```ignore
+    /// let head = unsafe { region.atomic_u32(HEAD) };
+    /// let idx = head.fetch_add(1, Ordering::AcqRel);
+    /// ```
+    ///
+    /// # Safety
+    /// The memory has to support atomic instructions, which is usually the
+    /// case for RAM (e.g. DMA buffers or shared memory BARs) but not for
+    /// device registers. It must not be accessed non-atomically (e.g.
+    /// through the volatile accessors) while it is accessed atomically
+    /// elsewhere.
+    ///
+    /// # Panics
+    /// If the word doesn't fit the region at `offset` or `offset` is not
+    /// 4-byte aligned.
+    pub unsafe fn atomic_u32(&self, offset: usize) -> MappedAtomicU32<'_> {
+        MappedAtomicU32 {
+            atomic: AtomicU32::from_ptr(self.typed_ptr::<u32>(offset)),
+        }
+    }
+
+    /// Atomic operations on the 64-bit word at `offset`, see `atomic_u32`.
+    ///
+    /// # Safety
+    /// As for `atomic_u32`.
+    ///
+    /// # Panics
+    /// If the word doesn't fit the region at `offset` or `offset` is not
+    /// 8-byte aligned.
+    pub unsafe fn atomic_u64(&self, offset: usize) -> MappedAtomicU64<'_> {
+        MappedAtomicU64 {
+            atomic: AtomicU64::from_ptr(self.typed_ptr::<u64>(offset)),
+        }
+    }
+
+    /// Pointer to a `T` at `offset`, checked to be in bounds and aligned.
+    fn typed_ptr<T>(&self, offset: usize) -> *mut T {
+        let size = ::std::mem::size_of::<T>();
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "{} of {} bytes at offset {:#x} is out of bounds (len {:#x})",
+            ::std::any::type_name::<T>(),
+            size,
+            offset,
+            self.len
+        );
+        let ptr = unsafe { self.ptr.as_ptr().add(offset) };
+        assert!(
+            (ptr as usize).is_multiple_of(::std::mem::align_of::<T>()),
+            "{} at offset {:#x} is misaligned",
+            ::std::any::type_name::<T>(),
+            offset
+        );
+        ptr as *mut T
+    }
+
+    /// The offset and size of the allowed access containing the `size`
+    /// bytes at `offset`, which is not allowed itself.
+    fn widened(&self, offset: usize, size: usize) -> (usize, usize) {
+        let wide = match (self.unsupported, self.widths.wider(size)) {
+            (Unsupported::Widen, Some(wide)) => wide,
+            _ => panic!(
+                "access of {} bytes at offset {:#x} has a width not allowed in this region",
+                size, offset
+            ),
+        };
+        let base = offset - offset % wide;
+        self.check_bounds(base, wide);
+        (base, wide)
+    }
+
+    /// Reads `bytes.len()` bytes at `offset` with a wider access.
+    fn read_widened(&self, offset: usize, bytes: &mut [u8]) {
+        let (base, wide) = self.widened(offset, bytes.len());
+        let word = self.read_wide(base, wide);
+        let at = offset - base;
+        bytes.copy_from_slice(&word[at..at + bytes.len()]);
+    }
+
+    /// Writes `bytes` to `offset` by reading, modifying and writing back a
+    /// wider location.
+    fn write_widened(&self, offset: usize, bytes: &[u8]) {
+        let (base, wide) = self.widened(offset, bytes.len());
+        let mut word = self.read_wide(base, wide);
+        let at = offset - base;
+        word[at..at + bytes.len()].copy_from_slice(bytes);
+        let src = unsafe { self.ptr.as_ptr().add(base) };
+        unsafe {
+            match wide {
+                2 => ptr::write_volatile(src as *mut u16, u16::from_ne_bytes([word[0], word[1]])),
+                4 => ptr::write_volatile(
+                    src as *mut u32,
+                    u32::from_ne_bytes([word[0], word[1], word[2], word[3]]),
+                ),
+                _ => ptr::write_volatile(src as *mut u64, u64::from_ne_bytes(word)),
+            }
+        }
+    }
+
+    /// Reads the `wide` bytes at the aligned `base` into the start of the
+    /// result.
+    fn read_wide(&self, base: usize, wide: usize) -> [u8; 8] {
+        let src = unsafe { self.ptr.as_ptr().add(base) };
+        let mut word = [0; 8];
+        unsafe {
+            match wide {
+                2 => {
+                    word[..2].copy_from_slice(&ptr::read_volatile(src as *const u16).to_ne_bytes())
+                }
+                4 => {
+                    word[..4].copy_from_slice(&ptr::read_volatile(src as *const u32).to_ne_bytes())
+                }
+                _ => word = ptr::read_volatile(src as *const u64).to_ne_bytes(),
+            }
+        }
+        word
+    }
+
+    fn check_writable(&self) {
+        assert!(self.writable, "write to a region mapped read-only");
+    }
+
+    fn check(&self, offset: usize, size: usize) {
+        self.check_bounds(offset, size);
+        assert!(
+            offset.is_multiple_of(size),
+            "access of {} bytes at offset {:#x} is misaligned",
+            size,
+            offset
+        );
+    }
+
+    fn check_bounds(&self, offset: usize, size: usize) {
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "access of {} bytes at offset {:#x} is out of bounds (len {:#x})",
+            size,
+            offset,
+            self.len
+        );
+    }
+
+    accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+impl<'a> DeviceMemory for MappedRegion<'a> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_u8(&self, offset: usize) -> u8 {
+        MappedRegion::read_u8(self, offset)
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        MappedRegion::read_u16(self, offset)
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        MappedRegion::read_u32(self, offset)
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        MappedRegion::read_u64(self, offset)
+    }
+
+    fn write_u8(&self, offset: usize, value: u8) {
+        MappedRegion::write_u8(self, offset, value)
+    }
+
+    fn write_u16(&self, offset: usize, value: u16) {
+        MappedRegion::write_u16(self, offset, value)
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        MappedRegion::write_u32(self, offset, value)
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        MappedRegion::write_u64(self, offset, value)
+    }
+
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        MappedRegion::read_bytes(self, offset, buf)
+    }
+
+    fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        MappedRegion::write_bytes(self, offset, buf)
+    }
+}
+
+macro_rules! mapped_atomic {
+    ($($name:ident, $ty:ty, $atomic:ty, $ctor:literal);*) => {
+        $(
+            #[doc = concat!("An atomic `", stringify!($ty), "` in a `MappedRegion`, see `MappedRegion::", $ctor, "`.")]
+            ///
+            /// The operations are those of the standard atomics, with
+            /// explicit orderings.
+            #[derive(Debug, Clone, Copy)]
+            pub struct $name<'a> {
+                atomic: &'a $atomic,
+            }
+
+            impl<'a> $name<'a> {
+                pub fn load(&self, order: Ordering) -> $ty {
+                    self.atomic.load(order)
+                }
+
+                pub fn store(&self, value: $ty, order: Ordering) {
+                    self.atomic.store(value, order)
+                }
+
+                pub fn swap(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.swap(value, order)
+                }
+
+                /// Stores `new` if the value is `current`. Returns the
+                /// previous value, as `Err` if it wasn't `current`.
+                pub fn compare_exchange(
+                    &self,
+                    current: $ty,
+                    new: $ty,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<$ty, $ty> {
+                    self.atomic.compare_exchange(current, new, success, failure)
+                }
+
+                /// Like `compare_exchange`, but may fail spuriously, for
+                /// use in loops.
+                pub fn compare_exchange_weak(
+                    &self,
+                    current: $ty,
+                    new: $ty,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<$ty, $ty> {
+                    self.atomic.compare_exchange_weak(current, new, success, failure)
+                }
+
+                /// Adds `value` (wrapping) and returns the previous value.
+                pub fn fetch_add(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.fetch_add(value, order)
+                }
+
+                /// Subtracts `value` (wrapping) and returns the previous
+                /// value.
+                pub fn fetch_sub(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.fetch_sub(value, order)
+                }
+
+                pub fn fetch_and(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.fetch_and(value, order)
+                }
+
+                pub fn fetch_or(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.fetch_or(value, order)
+                }
+
+                pub fn fetch_xor(&self, value: $ty, order: Ordering) -> $ty {
+                    self.atomic.fetch_xor(value, order)
+                }
+
+                /// The address of the word.
+                pub fn as_ptr(&self) -> *mut $ty {
+                    self.atomic.as_ptr()
+                }
+            }
+        )*
+    };
+}
+
+mapped_atomic! {
+    MappedAtomicU32, u32, AtomicU32, "atomic_u32";
+    MappedAtomicU64, u64, AtomicU64, "atomic_u64"
+}
+
+impl<'a> fmt::Debug for MappedRegion<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedRegion")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .field("widths", &self.widths)
+            .finish()
+    }
+}
+
+impl<'a> Drop for MappedRegion<'a> {
+    fn drop(&mut self) {
+        match self.device {
+            Some(device) => {
+                let _ = device.unmap_region(self.ptr.as_ptr() as *mut libc::c_void);
+            }
+            None => {
+                let _ = unsafe { sys::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tock_registers;
+
+    use self::tock_registers::interfaces::{Readable, Writeable};
+    use self::tock_registers::registers::{ReadOnly, ReadWrite};
+    use codec::Codec;
+    use mock::MockUio;
+
+    self::tock_registers::register_structs! {
+        Regs {
+            (0x0 => ctrl: ReadWrite<u32>),
+            (0x4 => status: ReadOnly<u32>),
+            (0x8 => @END),
+        }
+    }
+
+    #[test]
+    fn tock_registers() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        let regs: &Regs = unsafe { region.as_registers(0x10) };
+        regs.ctrl.set(0xabcd);
+        region.write_u32(0x14, 7);
+        assert_eq!(region.read_u32(0x10), 0xabcd);
+        assert_eq!(regs.status.get(), 7);
+    }
+
+    struct Pair(u16, u32);
+
+    /// Packs a `Pair` into 6 little-endian bytes.
+    enum PairCodec {}
+
+    impl Codec<Pair> for PairCodec {
+        type Error = ();
+
+        fn len() -> usize {
+            6
+        }
+
+        fn decode(b: &[u8]) -> Result<Pair, ()> {
+            Ok(Pair(
+                u16::from_le_bytes([b[0], b[1]]),
+                u32::from_le_bytes([b[2], b[3], b[4], b[5]]),
+            ))
+        }
+
+        fn encode(p: &Pair, b: &mut [u8]) -> Result<(), ()> {
+            b[..2].copy_from_slice(&p.0.to_le_bytes());
+            b[2..].copy_from_slice(&p.1.to_le_bytes());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn struct_round_trip() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        region
+            .write_struct::<_, PairCodec>(0x21, &Pair(0x1234, 0xdead_beef))
+            .unwrap();
+        assert_eq!(region.read_u8(0x21), 0x34);
+        let mut pair: Pair = region.read_struct::<_, PairCodec>(0x21).unwrap();
+        assert_eq!((pair.0, pair.1), (0x1234, 0xdead_beef));
+        pair.0 += 1;
+        region.write_struct::<_, PairCodec>(0x21, &pair).unwrap();
+        assert_eq!(region.read_struct::<_, PairCodec>(0x21).unwrap().0, 0x1235);
+
+        let mut words = [0u8; 8];
+        region.write_bytes(0x40, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        region.read_bytes(0x40, &mut words);
+        assert_eq!(words, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn batch_writes() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        region.write_batch_flush(&[(0x0, 1), (0x8, 2), (0x4, 3)]);
+        assert_eq!(region.read_u32(0x0), 1);
+        assert_eq!(region.read_u32(0x4), 3);
+        assert_eq!(region.read_u32(0x8), 2);
+
+        let res = ::std::panic::catch_unwind(|| region.write_batch(&[(0x10, 1), (0x1000, 2)]));
+        assert!(res.is_err());
+        assert_eq!(region.read_u32(0x10), 0);
+    }
+
+    #[repr(C)]
+    struct Descriptor {
+        addr: u64,
+        len: u32,
+        flags: u32,
+    }
+
+    #[test]
+    fn typed_references() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let mut region = dev.map_region(0).unwrap();
+
+        let desc: &mut Descriptor = unsafe { region.as_mut(0x100) };
+        desc.addr = 0x1234_5678;
+        desc.len = 64;
+        assert_eq!(region.read_u64(0x100), 0x1234_5678);
+        assert_eq!(unsafe { region.as_ref::<Descriptor>(0x100) }.len, 64);
+
+        let misaligned =
+            ::std::panic::catch_unwind(|| unsafe { region.as_ref::<Descriptor>(0x104) }.flags);
+        assert!(misaligned.is_err());
+        let out_of_bounds =
+            ::std::panic::catch_unwind(|| unsafe { region.as_ref::<Descriptor>(0xff8) }.flags);
+        assert!(out_of_bounds.is_err());
+    }
+
+    #[test]
+    fn atomics() {
+        use std::sync::atomic::Ordering;
+        use std::thread;
+
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "shm").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        let flag = unsafe { region.atomic_u32(0x10) };
+        flag.store(5, Ordering::Release);
+        assert_eq!(
+            flag.compare_exchange(4, 6, Ordering::AcqRel, Ordering::Acquire),
+            Err(5)
+        );
+        assert_eq!(
+            flag.compare_exchange(5, 6, Ordering::AcqRel, Ordering::Acquire),
+            Ok(5)
+        );
+        assert_eq!(flag.fetch_or(0x100, Ordering::AcqRel), 6);
+        assert_eq!(flag.swap(0, Ordering::AcqRel), 0x106);
+
+        let counter = unsafe { region.atomic_u64(0x18) };
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Ordering::Acquire), 4000);
+        assert_eq!(region.read_u64(0x18), 4000);
+        assert_eq!(counter.as_ptr() as usize, region.as_ptr() as usize + 0x18);
+
+        let misaligned = ::std::panic::catch_unwind(|| {
+            unsafe { region.atomic_u64(0x14) }.load(Ordering::Relaxed)
+        });
+        assert!(misaligned.is_err());
+    }
+
+    #[test]
+    fn raw_parts() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        mock.add_mapping(0x2000, 0x1000, "data").unwrap();
+        let dev = mock.open().unwrap();
+        let regs = dev.map_region(0).unwrap();
+        regs.write_u32(0x10, 7);
+        let (ptr, len, writable) = regs.into_raw_parts();
+        assert!(writable);
+        let data = dev.map_region(1).unwrap().leak();
+        data.write_u32(0, 9);
+        dev.close().unwrap();
+
+        // Both mappings survive the device
+        let regs = unsafe { super::MappedRegion::from_raw_parts(ptr, len, writable) };
+        assert_eq!(regs.len(), 0x1000);
+        assert_eq!(regs.read_u32(0x10), 7);
+        assert_eq!(data.read_u32(0), 9);
+    }
+
+    #[test]
+    fn typed_offsets() {
+        use mock::MockMemory;
+        use {ByteLen, DeviceMemory, RegOffset};
+
+        const CTRL: RegOffset = RegOffset::new(0x10);
+        const WORD: ByteLen = ByteLen::of::<u32>();
+        let regs = MockMemory::new(0x20);
+        regs.write_u32_at(CTRL, 0x1234_5678);
+        assert_eq!(regs.read_u32(0x10), 0x1234_5678);
+        assert_eq!(regs.read_u16_at(CTRL + ByteLen::of::<u16>()), 0x1234);
+        regs.write_bytes_at(CTRL.plus(WORD), &[1, 2, 3, 4]);
+        let mut buf = [0; 4];
+        regs.read_bytes_at(RegOffset::index(5, WORD), &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(regs.contains(CTRL, WORD * 4));
+        assert!(!regs.contains(CTRL, WORD * 5));
+        assert!(!regs.contains(RegOffset::new(usize::MAX), WORD));
+    }
+
+    #[test]
+    fn transfer_crc() {
+        use {AccessWidths, Unsupported};
+
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "table").unwrap();
+        let dev = mock.open().unwrap();
+        let mut region = dev.map_region(0).unwrap();
+
+        let table: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let crc = region.copy_from_slice_crc(3, &table);
+        assert!(crc.matches());
+        assert_eq!(crc.host, ::copy::crc32(0, &table));
+        let crc = region.copy_from_slice_crc(0x800, b"123456789");
+        assert_eq!(crc.device, 0xcbf4_3926);
+
+        let mut back = vec![0; 1000];
+        let crc = region.copy_to_slice_crc(3, &mut back);
+        assert!(crc.matches());
+        assert_eq!(crc.host, ::copy::crc32(0, &table));
+        assert_eq!(back, table);
+        region.set_access_widths(AccessWidths::U32, Unsupported::Widen);
+        let crc = region.copy_to_slice_crc(0x800, &mut back[..9]);
+        assert_eq!((crc.host, crc.device), (0xcbf4_3926, 0xcbf4_3926));
+    }
+
+    #[test]
+    fn write_verify() {
+        use mock::MockMemory;
+        use std::io;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use DeviceMemory;
+
+        const CTRL: usize = 0x4;
+        // The first two writes don't stick, bit 31 always reads as set
+        let dropped = Arc::new(AtomicU32::new(2));
+        let mut regs = MockMemory::new(0x10);
+        let d = dropped.clone();
+        regs.on_write(move |m, offset, _, _| {
+            if offset == CTRL && d.load(Ordering::SeqCst) > 0 {
+                d.fetch_sub(1, Ordering::SeqCst);
+                m[CTRL..CTRL + 4].copy_from_slice(&0u32.to_ne_bytes());
+            }
+        });
+        regs.on_read(|m, offset, _| {
+            let mut raw = [0; 4];
+            raw.copy_from_slice(&m[offset..offset + 4]);
+            Some((u32::from_ne_bytes(raw) | 1 << 31) as u64)
+        });
+
+        let err = regs.write_verify(CTRL, 0x5, !0, 1).unwrap_err();
+        assert_eq!(err.actual, 1 << 31);
+        assert_eq!(err.attempts, 2);
+        assert_eq!(
+            err.to_string(),
+            "register 0x4 reads 0x80000000 after writing 0x5 (mask 0xffffffff, 2 attempts)"
+        );
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+        // Bit 31 never matches unless it is masked
+        assert!(regs.write_verify(CTRL, 0x5, !0, 5).is_err());
+        dropped.store(1, Ordering::SeqCst);
+        // The retry succeeds
+        regs.write_verify(CTRL, 0x5, 0x7fff_ffff, 1).unwrap();
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "mapped read-only")]
+    fn read_only() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.builder().read_only(true).open().unwrap();
+        let region = dev.map_region(0).unwrap();
+        assert_eq!(region.read_u32(0), 0);
+        region.write_u32(0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "mapped read-only")]
+    fn raw_parts_read_only() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.builder().read_only(true).open().unwrap();
+        let (ptr, len, writable) = dev.map_region(0).unwrap().into_raw_parts();
+        assert!(!writable);
+        let region = unsafe { super::MappedRegion::from_raw_parts(ptr, len, writable) };
+        assert_eq!(region.read_u32(0), 0);
+        region.write_u32(0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn registers_out_of_bounds() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+        unsafe { region.as_registers::<Regs>(0xffc) };
+    }
+
+    #[test]
+    fn access_widths() {
+        use super::{AccessWidths, Unsupported};
+
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let mut regs = dev.map_region(0).unwrap();
+        regs.write_u32(0, 0x4433_2211);
+
+        regs.set_access_widths(AccessWidths::U32, Unsupported::Widen);
+        assert_eq!(regs.read_u8(1), 0x22);
+        assert_eq!(regs.read_u16(2), u16::from_ne_bytes([0x33, 0x44]));
+        regs.write_u8(3, 0x55);
+        assert_eq!(regs.read_u32(0), 0x5533_2211);
+        let mut buf = [0; 3];
+        regs.read_bytes(1, &mut buf);
+        assert_eq!(buf, [0x22, 0x33, 0x55]);
+        regs.write_batch(&[(4, 7)]);
+        assert_eq!(regs.read_u32(4), 7);
+
+        regs.set_access_widths(AccessWidths::U32, Unsupported::Reject);
+        let res = ::std::panic::catch_unwind(|| regs.read_u8(0));
+        assert!(res.is_err());
+        // There is no wider size to widen to
+        regs.set_access_widths(AccessWidths::U32, Unsupported::Widen);
+        let res = ::std::panic::catch_unwind(|| regs.read_u64(0));
+        assert!(res.is_err());
+        regs.set_access_widths(AccessWidths::U32 | AccessWidths::U64, Unsupported::Widen);
+        assert_eq!(regs.read_u64(0), regs.read_u64(0));
+    }
+}
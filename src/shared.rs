@@ -0,0 +1,432 @@
+//! Sharing one mapping between threads.
+//!
+//! The accessors of `MappedRegion` take `&self`, so a region can be used
+//! from several threads, but nothing keeps two threads from interleaving
+//! their accesses to the same registers. An `RwRegion` hands out read and
+//! write guards for byte ranges of a region, like an `RwLock` per range:
+//! any number of readers or a single writer for overlapping ranges, while
+//! guards for disjoint ranges don't wait for each other.
+//!
+//! ```ignore
+//! let shared = RwRegion::new(dev.map_region(0)?);
+//! thread::scope(|s| {
+//!     s.spawn(|| {
+//!         let dma = shared.write(0x100..0x140);
+//!         dma.write_u32(0x0, addr); // offset 0x100 of the region
+//!         dma.write_u32(0x4, len);
+//!     });
+//!     s.spawn(|| {
+//!         let stats = shared.read(0x200..0x240);
+//!         println!("{} packets", stats.read_u32(0x8));
+//!     });
+//! });
+//! ```
+//!
+//! Offsets passed to a guard are relative to the start of its range, and
+//! accesses outside of it panic. Requesting a guard which conflicts with
+//! one held by the same thread panics instead of deadlocking.
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use DeviceMemory;
+
+#[derive(Debug)]
+struct Held {
+    id: u64,
+    range: Range<usize>,
+    write: bool,
+    thread: ThreadId,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    held: Vec<Held>,
+    next_id: u64,
+}
+
+/// Device memory shared between threads through range guards, see the
+/// module documentation.
+pub struct RwRegion<M> {
+    memory: M,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+impl<M: DeviceMemory> RwRegion<M> {
+    pub fn new(memory: M) -> RwRegion<M> {
+        RwRegion {
+            memory,
+            state: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Returns the memory, no guards can be alive.
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+
+    /// Length of the memory in bytes.
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A guard conflicting with a new one for `range`, one of the thread
+    /// `me` if there is any.
+    fn conflict<'s>(
+        state: &'s State,
+        range: &Range<usize>,
+        write: bool,
+        me: ThreadId,
+    ) -> Option<&'s Held> {
+        let mut conflicts = state.held.iter().filter(|h| {
+            (write || h.write) && h.range.start < range.end && range.start < h.range.end
+        });
+        conflicts
+            .clone()
+            .find(|h| h.thread == me)
+            .or_else(|| conflicts.next())
+    }
+
+    /// Registers a guard for `range`, waiting for conflicting ones if
+    /// `wait`. Returns its id, `None` if it would have to wait.
+    fn acquire(&self, range: &Range<usize>, write: bool, wait: bool) -> Option<u64> {
+        assert!(
+            range.start <= range.end && range.end <= self.memory.len(),
+            "range {:#x}..{:#x} is out of bounds (len {:#x})",
+            range.start,
+            range.end,
+            self.memory.len()
+        );
+        let me = thread::current().id();
+        let mut state = self.lock();
+        while let Some(held) = Self::conflict(&state, range, write, me) {
+            assert!(
+                held.thread != me,
+                "{:#x}..{:#x} is already held by this thread ({:#x}..{:#x})",
+                range.start,
+                range.end,
+                held.range.start,
+                held.range.end
+            );
+            if !wait {
+                return None;
+            }
+            state = self.released.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.held.push(Held {
+            id,
+            range: range.clone(),
+            write,
+            thread: me,
+        });
+        Some(id)
+    }
+
+    fn release(&self, id: u64) {
+        self.lock().held.retain(|h| h.id != id);
+        self.released.notify_all();
+    }
+
+    /// Read access to `range`, waiting while a writer holds an overlapping
+    /// range.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds or conflicts with a guard of this thread.
+    pub fn read(&self, range: Range<usize>) -> ReadGuard<'_, M> {
+        let id = self.acquire(&range, false, true).unwrap();
+        ReadGuard {
+            inner: Guard {
+                region: self,
+                range,
+                id,
+            },
+        }
+    }
+
+    /// Read access to `range`, `None` if a writer holds an overlapping range.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds or conflicts with a guard of this thread.
+    pub fn try_read(&self, range: Range<usize>) -> Option<ReadGuard<'_, M>> {
+        let id = self.acquire(&range, false, false)?;
+        Some(ReadGuard {
+            inner: Guard {
+                region: self,
+                range,
+                id,
+            },
+        })
+    }
+
+    /// Exclusive access to `range`, waiting while others hold an
+    /// overlapping range.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds or conflicts with a guard of this thread.
+    pub fn write(&self, range: Range<usize>) -> WriteGuard<'_, M> {
+        let id = self.acquire(&range, true, true).unwrap();
+        WriteGuard {
+            inner: Guard {
+                region: self,
+                range,
+                id,
+            },
+        }
+    }
+
+    /// Exclusive access to `range`, `None` if others hold an overlapping
+    /// range.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds or conflicts with a guard of this thread.
+    pub fn try_write(&self, range: Range<usize>) -> Option<WriteGuard<'_, M>> {
+        let id = self.acquire(&range, true, false)?;
+        Some(WriteGuard {
+            inner: Guard {
+                region: self,
+                range,
+                id,
+            },
+        })
+    }
+
+    /// Exclusive access to all of the memory, like `Mutex::lock`.
+    pub fn lock_all(&self) -> WriteGuard<'_, M> {
+        self.write(0..self.memory.len())
+    }
+}
+
+impl<M> fmt::Debug for RwRegion<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f.debug_struct("RwRegion")
+            .field(
+                "held",
+                &state
+                    .held
+                    .iter()
+                    .map(|h| (&h.range, h.write))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// What both guards have in common.
+struct Guard<'r, M: DeviceMemory> {
+    region: &'r RwRegion<M>,
+    range: Range<usize>,
+    id: u64,
+}
+
+impl<'r, M: DeviceMemory> Guard<'r, M> {
+    /// The offset in the memory of `size` bytes at `offset` in the range.
+    fn at(&self, offset: usize, size: usize) -> usize {
+        assert!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end <= self.range.len()),
+            "access of {} bytes at offset {:#x} is outside of the guarded range (len {:#x})",
+            size,
+            offset,
+            self.range.len()
+        );
+        self.range.start + offset
+    }
+}
+
+impl<'r, M: DeviceMemory> Drop for Guard<'r, M> {
+    fn drop(&mut self) {
+        self.region.release(self.id);
+    }
+}
+
+macro_rules! guard_reads {
+    ($($ty:ty, $read:ident);*) => {
+        $(
+            #[doc = concat!("Reads a `", stringify!($ty), "` at byte `offset` of the range.")]
+            ///
+            /// # Panics
+            /// If the access is outside of the range or `offset` is not
+            /// naturally aligned.
+            pub fn $read(&self, offset: usize) -> $ty {
+                let memory = &self.inner.region.memory;
+                memory.$read(self.inner.at(offset, ::std::mem::size_of::<$ty>()))
+            }
+        )*
+    };
+}
+
+/// Shared access to a range of an `RwRegion`, released when dropped.
+pub struct ReadGuard<'r, M: DeviceMemory> {
+    inner: Guard<'r, M>,
+}
+
+impl<'r, M: DeviceMemory> ReadGuard<'r, M> {
+    /// The guarded range of the region.
+    pub fn range(&self) -> Range<usize> {
+        self.inner.range.clone()
+    }
+
+    guard_reads! {
+        u8, read_u8;
+        u16, read_u16;
+        u32, read_u32;
+        u64, read_u64
+    }
+
+    /// Copies `buf.len()` bytes at `offset` of the range into `buf`.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        let at = self.inner.at(offset, buf.len());
+        self.inner.region.memory.read_bytes(at, buf)
+    }
+}
+
+impl<'r, M: DeviceMemory> fmt::Debug for ReadGuard<'r, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadGuard")
+            .field("range", &self.inner.range)
+            .finish()
+    }
+}
+
+/// Exclusive access to a range of an `RwRegion`, released when dropped.
+///
+/// Implements `DeviceMemory` for the range, so driver code written against
+/// it can run on a part of a shared mapping.
+pub struct WriteGuard<'r, M: DeviceMemory> {
+    inner: Guard<'r, M>,
+}
+
+impl<'r, M: DeviceMemory> WriteGuard<'r, M> {
+    /// The guarded range of the region.
+    pub fn range(&self) -> Range<usize> {
+        self.inner.range.clone()
+    }
+}
+
+impl<'r, M: DeviceMemory> DeviceMemory for WriteGuard<'r, M> {
+    fn len(&self) -> usize {
+        self.inner.range.len()
+    }
+
+    fn read_u8(&self, offset: usize) -> u8 {
+        self.inner.region.memory.read_u8(self.inner.at(offset, 1))
+    }
+    fn read_u16(&self, offset: usize) -> u16 {
+        self.inner.region.memory.read_u16(self.inner.at(offset, 2))
+    }
+    fn read_u32(&self, offset: usize) -> u32 {
+        self.inner.region.memory.read_u32(self.inner.at(offset, 4))
+    }
+    fn read_u64(&self, offset: usize) -> u64 {
+        self.inner.region.memory.read_u64(self.inner.at(offset, 8))
+    }
+    fn write_u8(&self, offset: usize, value: u8) {
+        self.inner
+            .region
+            .memory
+            .write_u8(self.inner.at(offset, 1), value)
+    }
+    fn write_u16(&self, offset: usize, value: u16) {
+        self.inner
+            .region
+            .memory
+            .write_u16(self.inner.at(offset, 2), value)
+    }
+    fn write_u32(&self, offset: usize, value: u32) {
+        self.inner
+            .region
+            .memory
+            .write_u32(self.inner.at(offset, 4), value)
+    }
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.inner
+            .region
+            .memory
+            .write_u64(self.inner.at(offset, 8), value)
+    }
+
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        let at = self.inner.at(offset, buf.len());
+        self.inner.region.memory.read_bytes(at, buf)
+    }
+
+    fn write_bytes(&self, offset: usize, buf: &[u8]) {
+        let at = self.inner.at(offset, buf.len());
+        self.inner.region.memory.write_bytes(at, buf)
+    }
+}
+
+impl<'r, M: DeviceMemory> fmt::Debug for WriteGuard<'r, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteGuard")
+            .field("range", &self.inner.range)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+    use std::panic;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn guards() {
+        let shared = RwRegion::new(MockMemory::new(0x100));
+        {
+            let regs = shared.write(0x10..0x20);
+            regs.write_u32(0x4, 42);
+            assert_eq!(regs.len(), 0x10);
+            // Disjoint ranges don't conflict
+            assert!(shared.try_write(0x20..0x30).is_some());
+        }
+        let a = shared.read(0x10..0x20);
+        let b = shared.read(0x14..0x18);
+        assert_eq!(a.read_u32(0x4), 42);
+        assert_eq!(b.read_u32(0x0), 42);
+        let outside = panic::catch_unwind(panic::AssertUnwindSafe(|| b.read_u32(0x4)));
+        assert!(outside.is_err());
+        drop((a, b));
+
+        // A writer waits for readers on another thread
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            let reader = shared.read(0x0..0x100);
+            s.spawn(|| {
+                assert!(shared.try_write(0x18..0x1c).is_none());
+                let regs = shared.write(0x18..0x1c);
+                tx.send(()).unwrap();
+                regs.write_u32(0, 7);
+            });
+            assert!(rx.recv_timeout(Duration::from_millis(20)).is_err());
+            drop(reader);
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        });
+        assert_eq!(shared.lock_all().read_u32(0x18), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "already held by this thread")]
+    fn same_thread_conflict() {
+        let shared = RwRegion::new(MockMemory::new(0x100));
+        let _reader = shared.read(0x0..0x10);
+        shared.write(0x8..0xc);
+    }
+}
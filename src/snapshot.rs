@@ -0,0 +1,261 @@
+//! Saving and restoring register contents.
+//!
+//! A `Snapshot` copies a byte range of a `MappedRegion` (or any other
+//! `DeviceMemory`) into an owned buffer, which can later be written back,
+//! e.g. to preserve the device configuration across a reset:
+//!
+//! ```ignore
+//! use uio::snapshot::Snapshot;
+//!
+//! // 0x40 is a read-to-clear interrupt status register
+//! let saved = Snapshot::take(&region, 0..0x100, &[0x40..0x44]);
+//! reset(&region);
+//! saved.restore(&region);
+//! ```
+//!
+//! Registers in the skip list are neither read (reading them could have
+//! side effects) nor written back, they read as zero in the snapshot.
+//!
+//! Two snapshots of the same range can be compared with `diff` to see which
+//! registers changed in between, e.g. while the firmware ran:
+//!
+//! ```ignore
+//! let names = [(0x0, "CTRL"), (0x4, "STATUS")]
+//!     .iter()
+//!     .map(|&(o, n)| (o, n.to_string()))
+//!     .collect();
+//! for change in diff_with_names(&before, &after, &names) {
+//!     println!("{}", change); // 0x4 STATUS: 0x0 -> 0x80000001
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use DeviceMemory;
+
+/// The contents of a byte range of a region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    offset: usize,
+    data: Vec<u8>,
+    skip: Vec<Range<usize>>,
+}
+
+impl Snapshot {
+    /// Reads `range` of `region`, except for the byte ranges in `skip`.
+    ///
+    /// The range is read with the access widths of `DeviceMemory::read_bytes`,
+    /// so skip ranges should cover whole registers and keep the remaining
+    /// pieces 4-byte aligned for 32-bit accesses.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn take<M: DeviceMemory + ?Sized>(
+        region: &M,
+        range: Range<usize>,
+        skip: &[Range<usize>],
+    ) -> Snapshot {
+        let mut snapshot = Snapshot {
+            offset: range.start,
+            data: vec![0; range.end.saturating_sub(range.start)],
+            skip: skip.to_vec(),
+        };
+        snapshot.skip.sort_by_key(|r| r.start);
+        for span in snapshot.spans() {
+            let buf = &mut snapshot.data[span.start - range.start..span.end - range.start];
+            region.read_bytes(span.start, buf);
+        }
+        snapshot
+    }
+
+    /// Writes the saved contents back to `region` in ascending order of
+    /// offsets, leaving the skipped registers untouched.
+    ///
+    /// # Panics
+    /// If the snapshot is out of bounds of `region`.
+    pub fn restore<M: DeviceMemory + ?Sized>(&self, region: &M) {
+        for span in self.spans() {
+            region.write_bytes(
+                span.start,
+                &self.data[span.start - self.offset..span.end - self.offset],
+            );
+        }
+    }
+
+    /// Offset of the first saved byte in the region.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Length of the saved range in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the saved range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The saved bytes, with skipped registers set to zero.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the byte at `offset` (relative to the region) was skipped.
+    pub fn is_skipped(&self, offset: usize) -> bool {
+        self.skip.iter().any(|r| r.contains(&offset))
+    }
+
+    /// The saved byte ranges, i.e. the snapshot range without the skip
+    /// ranges.
+    fn spans(&self) -> Vec<Range<usize>> {
+        let end = self.offset + self.data.len();
+        let mut spans = Vec::new();
+        let mut start = self.offset;
+        for skip in &self.skip {
+            if skip.start > start {
+                spans.push(start..skip.start.min(end));
+            }
+            start = start.max(skip.end);
+            if start >= end {
+                break;
+            }
+        }
+        if start < end {
+            spans.push(start..end);
+        }
+        spans
+    }
+}
+
+/// A 32-bit word which differs between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Offset of the word in the region
+    pub offset: usize,
+    pub old: u32,
+    pub new: u32,
+    /// Register name, if the diff was given a register map
+    pub name: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.offset)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ": {:#x} -> {:#x}", self.old, self.new)
+    }
+}
+
+/// Compares two snapshots of the same range word by word (in native byte
+/// order, at offsets which are multiples of 4) and returns the changed words
+/// in ascending order. Words skipped in either snapshot are ignored; a
+/// trailing partial word is compared as if padded with zeros.
+///
+/// # Panics
+/// If the snapshots don't cover the same range.
+pub fn diff(a: &Snapshot, b: &Snapshot) -> Vec<Change> {
+    assert!(
+        a.offset == b.offset && a.len() == b.len(),
+        "snapshots of different ranges ({:#x}+{:#x} and {:#x}+{:#x})",
+        a.offset,
+        a.len(),
+        b.offset,
+        b.len()
+    );
+    let end = a.offset + a.len();
+    let word = |s: &Snapshot, offset: usize| {
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let o = offset + i;
+            if o >= s.offset && o < end {
+                *byte = s.data[o - s.offset];
+            }
+        }
+        u32::from_ne_bytes(bytes)
+    };
+    let skipped = |offset: usize| (offset..offset + 4).any(|o| a.is_skipped(o) || b.is_skipped(o));
+
+    let mut changes = Vec::new();
+    let mut offset = a.offset & !3;
+    while offset < end {
+        let (old, new) = (word(a, offset), word(b, offset));
+        if old != new && !skipped(offset) {
+            changes.push(Change {
+                offset,
+                old,
+                new,
+                name: None,
+            });
+        }
+        offset += 4;
+    }
+    changes
+}
+
+/// Like `diff`, but names each change after the register at its offset in
+/// `names` (a register map from offsets to names).
+pub fn diff_with_names(a: &Snapshot, b: &Snapshot, names: &BTreeMap<usize, String>) -> Vec<Change> {
+    let mut changes = diff(a, b);
+    for change in &mut changes {
+        change.name = names.get(&change.offset).cloned();
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    #[test]
+    fn snapshot_restore() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        for i in 0..8 {
+            region.write_u32(0x100 + i * 4, i as u32 + 1);
+        }
+        let saved = Snapshot::take(&region, 0x100..0x120, &[0x108..0x10c, 0x11c..0x130]);
+        assert_eq!(saved.len(), 0x20);
+        assert!(saved.is_skipped(0x109) && !saved.is_skipped(0x10c));
+        assert_eq!(&saved.as_bytes()[8..12], &[0; 4]);
+        assert_eq!(&saved.as_bytes()[12..16], &4u32.to_ne_bytes());
+
+        for i in 0..8 {
+            region.write_u32(0x100 + i * 4, 0xff);
+        }
+        saved.restore(&region);
+        let words: Vec<u32> = (0..8).map(|i| region.read_u32(0x100 + i * 4)).collect();
+        assert_eq!(words, [1, 2, 0xff, 4, 5, 6, 7, 0xff]);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+
+        let before = Snapshot::take(&region, 0x0..0x10, &[0x0..0x4, 0xc..0x10]);
+        region.write_u32(0x0, 5);
+        region.write_u32(0x4, 0x8000_0001);
+        region.write_u32(0xc, 1);
+        region.write_u8(0x8, 3);
+        let after = Snapshot::take(&region, 0x0..0x10, &[]);
+
+        let names = vec![(0x4, "STATUS".to_string())].into_iter().collect();
+        let changes = diff_with_names(&before, &after, &names);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].to_string(), "0x4 STATUS: 0x0 -> 0x80000001");
+        assert_eq!(changes[1].offset, 0x8);
+        assert_eq!(changes[1].name, None);
+        assert!(diff(&after, &after).is_empty());
+    }
+}
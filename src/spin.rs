@@ -0,0 +1,148 @@
+//! Busy-polling instead of sleeping in the kernel.
+//!
+//! `UioDevice::irq_wait` blocks in `read`, so the process is woken by the
+//! scheduler after the interrupt, which can take tens of microseconds. Paths
+//! which can't afford that may spin on a status register (or the event
+//! counter) instead, with a hard deadline:
+//!
+//! ```ignore
+//! let spin = SpinWait::new(Duration::from_micros(50));
+//! spin.until_u32(&regs, STATUS, STATUS_DONE, STATUS_DONE)?;
+//! ```
+//!
+//! Spinning keeps a CPU busy, so this is best combined with a dedicated core
+//! (see `driver::ThreadConfig`).
+
+use std::hint;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use {DeviceMemory, UioDevice};
+
+/// How long and how to busy-poll for a condition.
+///
+/// By default every poll is followed by a `spin_loop` hint only, see
+/// `yield_after` to give up the CPU in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinWait {
+    timeout: Duration,
+    yield_after: Option<u32>,
+}
+
+impl SpinWait {
+    /// Polls for at most `timeout`.
+    pub fn new(timeout: Duration) -> SpinWait {
+        SpinWait {
+            timeout,
+            yield_after: None,
+        }
+    }
+
+    /// Calls `thread::yield_now` instead of spinning after `spins` polls,
+    /// so other threads on the same CPU can run (`None` never yields).
+    pub fn yield_after(&mut self, spins: Option<u32>) -> &mut Self {
+        self.yield_after = spins;
+        self
+    }
+
+    /// Polls `condition` until it returns a value, which is returned.
+    ///
+    /// The condition is polled at least once. Fails with `TimedOut` if it
+    /// didn't return a value before the timeout.
+    pub fn until<T, F>(&self, mut condition: F) -> io::Result<T>
+    where
+        F: FnMut() -> io::Result<Option<T>>,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut spins = 0u32;
+        loop {
+            if let Some(value) = condition()? {
+                return Ok(value);
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("condition not met within {:?}", self.timeout),
+                ));
+            }
+            match self.yield_after {
+                Some(n) if spins >= n => thread::yield_now(),
+                _ => {
+                    spins = spins.saturating_add(1);
+                    hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Polls the 32-bit register at `offset` until the bits in `mask` equal
+    /// `value` and returns the register.
+    pub fn until_u32<M: DeviceMemory + ?Sized>(
+        &self,
+        regs: &M,
+        offset: usize,
+        mask: u32,
+        value: u32,
+    ) -> io::Result<u32> {
+        self.until(|| {
+            let reg = regs.read_u32(offset);
+            Ok(if reg & mask == value { Some(reg) } else { None })
+        })
+    }
+}
+
+impl UioDevice {
+    /// Busy-polls the event counter until it reaches `target`, like
+    /// `wait_event_count` without sleeping.
+    ///
+    /// Call `preopen` first so polling doesn't open the `event` attribute
+    /// every time. The interrupt is not enabled, even with `auto_reenable`.
+    pub fn spin_event_count(&self, target: u32, spin: &SpinWait) -> io::Result<u32> {
+        spin.until(|| {
+            let count = self.get_event_count()?;
+            Ok(if count.wrapping_sub(target) as i32 >= 0 {
+                Some(count)
+            } else {
+                None
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockMemory, MockUio};
+
+    #[test]
+    fn until_register() {
+        let mut regs = MockMemory::new(0x10);
+        let mut reads = 0;
+        regs.on_read(move |_, offset, _| {
+            reads += 1;
+            Some(if offset == 4 && reads > 3 { 0x81 } else { 0 })
+        });
+        let mut spin = SpinWait::new(Duration::from_secs(1));
+        spin.yield_after(Some(1));
+        assert_eq!(spin.until_u32(&regs, 4, 0x1, 0x1).unwrap(), 0x81);
+
+        let err = SpinWait::new(Duration::from_millis(1))
+            .until_u32(&regs, 8, 0x1, 0x1)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn spin_event_count() {
+        let mock = MockUio::new(0).unwrap();
+        mock.set_event_count(5).unwrap();
+        let mut dev = mock.open().unwrap();
+        dev.preopen().unwrap();
+        let spin = SpinWait::new(Duration::from_millis(1));
+        assert_eq!(dev.spin_event_count(4, &spin).unwrap(), 5);
+        assert_eq!(
+            dev.spin_event_count(6, &spin).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,903 @@
+//! Register descriptions from CMSIS-SVD files.
+//!
+//! Vendors describe the registers of their IP in SVD, an XML format listing
+//! the peripherals of a device with their registers and bit fields. A
+//! `Device` loaded from such a file gives name-based access to a mapped
+//! peripheral, so no offset tables have to be written by hand:
+//!
+//! ```ignore
+//! let svd = svd::Device::load("timer.svd")?;
+//! let regs = svd::Registers::new(&region, svd.peripheral("TIMER0").unwrap());
+//! regs.write("LOAD", 1000)?;
+//! regs.write("CTRL.EN", 1)?;
+//! while regs.read("STATUS.DONE")? == 0 {}
+//! ```
+//!
+//! `REG` names a whole register and `REG.FIELD` one of its fields, which is
+//! written with a read-modify-write of the register. Offsets are relative to
+//! the peripheral's base address, so the region has to be the mapping of
+//! the peripheral.
+//!
+//! For tools working with names interactively, `Device::lookup` resolves
+//! `PERIPHERAL.REG.FIELD` to the peripheral and a `Location` with the
+//! offset, size and bits of the register or field.
+//!
+//! Register arrays (`dim`) are expanded with the names the file gives them
+//! (`CH%s` becomes `CH0`, `CH1`, ...). Clusters, enumerated values and
+//! access permissions are ignored.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use {parse, DeviceMemory, MappedRegion};
+
+/// Error when loading an SVD file or accessing a register by name.
+#[derive(Debug)]
+pub enum SvdError {
+    /// Reading the file failed.
+    Io(io::Error),
+    /// The file is not well-formed XML.
+    Xml { line: usize, message: String },
+    /// The element `element` is missing or has an invalid value.
+    Invalid { element: String, message: String },
+    /// The device has no peripheral `name`.
+    UnknownPeripheral(String),
+    /// The peripheral has no register `name`.
+    UnknownRegister(String),
+    /// The register `register` has no field `field`.
+    UnknownField { register: String, field: String },
+    /// The register `name` lies outside the mapped region.
+    OutOfBounds(String),
+    /// `value` doesn't fit the register or field `name`.
+    Overflow { name: String, value: u64 },
+}
+
+impl fmt::Display for SvdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SvdError::Io(ref e) => write!(f, "reading SVD file: {}", e),
+            SvdError::Xml { line, ref message } => {
+                write!(f, "invalid SVD file, line {}: {}", line, message)
+            }
+            SvdError::Invalid {
+                ref element,
+                ref message,
+            } => write!(f, "invalid SVD element <{}>: {}", element, message),
+            SvdError::UnknownPeripheral(ref name) => write!(f, "no peripheral {}", name),
+            SvdError::UnknownRegister(ref name) => write!(f, "no register {}", name),
+            SvdError::UnknownField {
+                ref register,
+                ref field,
+            } => write!(f, "register {} has no field {}", register, field),
+            SvdError::OutOfBounds(ref name) => {
+                write!(f, "register {} is outside the mapped region", name)
+            }
+            SvdError::Overflow { ref name, value } => {
+                write!(f, "value {:#x} doesn't fit {}", value, name)
+            }
+        }
+    }
+}
+
+impl Error for SvdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SvdError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SvdError {
+    fn from(e: io::Error) -> SvdError {
+        SvdError::Io(e)
+    }
+}
+
+/// A device described by an SVD file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+/// A peripheral, i.e. a block of registers at a base address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peripheral {
+    pub name: String,
+    pub base_address: u64,
+    pub registers: Vec<Register>,
+}
+
+/// A register of a peripheral.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub name: String,
+    /// Offset from the base address of the peripheral
+    pub offset: u64,
+    /// Size in bits: 8, 16, 32 or 64
+    pub size: u32,
+    pub reset_value: Option<u64>,
+    pub fields: Vec<Field>,
+}
+
+/// A bit field of a register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    /// Position of the least significant bit
+    pub lsb: u32,
+    /// Number of bits
+    pub width: u32,
+}
+
+/// Where a register or field is found, see `Peripheral::lookup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// Offset of the register from the base address of the peripheral
+    pub offset: u64,
+    /// Size of the register in bits
+    pub size: u32,
+    /// The bits of the field in the register, or all bits for a register
+    pub mask: u64,
+    /// Position of the least significant bit of the field, 0 for registers
+    pub lsb: u32,
+}
+
+impl Location {
+    /// Whether this is a field rather than a whole register.
+    pub fn is_field(&self) -> bool {
+        self.mask != u64::MAX >> (64 - self.size)
+    }
+
+    /// The value of the field in the register value `register`.
+    pub fn extract(&self, register: u64) -> u64 {
+        (register & self.mask) >> self.lsb
+    }
+
+    /// The register value `register` with the field set to `value`, or
+    /// `None` if `value` doesn't fit.
+    pub fn insert(&self, register: u64, value: u64) -> Option<u64> {
+        let bits = value << self.lsb;
+        if bits >> self.lsb != value || bits & !self.mask != 0 {
+            return None;
+        }
+        Some((register & !self.mask) | bits)
+    }
+}
+
+impl Device {
+    /// Loads the SVD file `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Device, SvdError> {
+        Device::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of an SVD file.
+    pub fn parse(svd: &str) -> Result<Device, SvdError> {
+        let root = Reader { s: svd, pos: 0 }.document()?;
+        if root.name != "device" {
+            return Err(invalid(&root.name, "the root element is not <device>"));
+        }
+        let size = match root.text("size") {
+            Some(size) => register_size(size)?,
+            None => 32,
+        };
+        let mut peripherals = Vec::new();
+        if let Some(list) = root.child("peripherals") {
+            for p in list.children("peripheral") {
+                peripherals.push((p.attr("derivedFrom"), peripheral(p, size)?));
+            }
+        }
+        // Derived peripherals share the registers of their base unless they
+        // list their own
+        let bases = peripherals.clone();
+        for (base, p) in &mut peripherals {
+            let Some(base) = *base else { continue };
+            if !p.registers.is_empty() {
+                continue;
+            }
+            match bases.iter().find(|(_, b)| b.name == base) {
+                Some((_, b)) => p.registers = b.registers.clone(),
+                None => return Err(invalid("peripheral", &format!("no peripheral {}", base))),
+            }
+        }
+        Ok(Device {
+            name: root.text("name").unwrap_or_default().to_string(),
+            peripherals: peripherals.into_iter().map(|(_, p)| p).collect(),
+        })
+    }
+
+    /// The peripheral `name`.
+    pub fn peripheral(&self, name: &str) -> Option<&Peripheral> {
+        self.peripherals.iter().find(|p| p.name == name)
+    }
+
+    /// Resolves `PERIPHERAL.REG` or `PERIPHERAL.REG.FIELD`.
+    pub fn lookup(&self, name: &str) -> Result<(&Peripheral, Location), SvdError> {
+        let (peripheral, reg) = name
+            .split_once('.')
+            .ok_or_else(|| SvdError::UnknownRegister(name.to_string()))?;
+        let peripheral = self
+            .peripheral(peripheral)
+            .ok_or_else(|| SvdError::UnknownPeripheral(peripheral.to_string()))?;
+        Ok((peripheral, peripheral.lookup(reg)?))
+    }
+}
+
+impl Peripheral {
+    /// The register `name`.
+    pub fn register(&self, name: &str) -> Option<&Register> {
+        self.registers.iter().find(|r| r.name == name)
+    }
+
+    /// Resolves `REG` or `REG.FIELD`.
+    pub fn lookup(&self, name: &str) -> Result<Location, SvdError> {
+        let (reg, field) = match name.split_once('.') {
+            Some((reg, field)) => (reg, Some(field)),
+            None => (name, None),
+        };
+        let register = self
+            .register(reg)
+            .ok_or_else(|| SvdError::UnknownRegister(reg.to_string()))?;
+        let (mask, lsb) = match field {
+            Some(field) => {
+                let field = register
+                    .field(field)
+                    .ok_or_else(|| SvdError::UnknownField {
+                        register: reg.to_string(),
+                        field: field.to_string(),
+                    })?;
+                (field.mask(), field.lsb)
+            }
+            None => (u64::MAX >> (64 - register.size), 0),
+        };
+        Ok(Location {
+            offset: register.offset,
+            size: register.size,
+            mask,
+            lsb,
+        })
+    }
+}
+
+impl Register {
+    /// The field `name`.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+impl Field {
+    /// The bits of the field, in place.
+    pub fn mask(&self) -> u64 {
+        (u64::MAX >> (64 - self.width)) << self.lsb
+    }
+}
+
+/// Name-based access to the registers of `peripheral`, mapped at `region`.
+pub struct Registers<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    region: &'r M,
+    peripheral: &'r Peripheral,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> Registers<'r, M> {
+    pub fn new(region: &'r M, peripheral: &'r Peripheral) -> Self {
+        Registers { region, peripheral }
+    }
+
+    /// Reads the register or field (`REG.FIELD`) `name`. Fields are shifted
+    /// down to bit 0.
+    pub fn read(&self, name: &str) -> Result<u64, SvdError> {
+        let location = self.resolve(name)?;
+        Ok(location.extract(self.read_register(&location)))
+    }
+
+    /// Writes `value` to the register or field (`REG.FIELD`) `name`. The
+    /// other fields of the register keep their value.
+    pub fn write(&self, name: &str, value: u64) -> Result<(), SvdError> {
+        let location = self.resolve(name)?;
+        let old = if location.is_field() {
+            self.read_register(&location)
+        } else {
+            0
+        };
+        let new = location
+            .insert(old, value)
+            .ok_or_else(|| SvdError::Overflow {
+                name: name.to_string(),
+                value,
+            })?;
+        self.write_register(&location, new);
+        Ok(())
+    }
+
+    fn resolve(&self, name: &str) -> Result<Location, SvdError> {
+        let location = self.peripheral.lookup(name)?;
+        let end = location.offset.checked_add(u64::from(location.size / 8));
+        if end.is_none_or(|end| end > self.region.len() as u64) {
+            let reg = name.split('.').next().unwrap_or(name);
+            return Err(SvdError::OutOfBounds(reg.to_string()));
+        }
+        Ok(location)
+    }
+
+    fn read_register(&self, location: &Location) -> u64 {
+        let offset = location.offset as usize;
+        match location.size {
+            8 => u64::from(self.region.read_u8(offset)),
+            16 => u64::from(self.region.read_u16(offset)),
+            32 => u64::from(self.region.read_u32(offset)),
+            _ => self.region.read_u64(offset),
+        }
+    }
+
+    fn write_register(&self, location: &Location, value: u64) {
+        let offset = location.offset as usize;
+        match location.size {
+            8 => self.region.write_u8(offset, value as u8),
+            16 => self.region.write_u16(offset, value as u16),
+            32 => self.region.write_u32(offset, value as u32),
+            _ => self.region.write_u64(offset, value),
+        }
+    }
+}
+
+fn invalid(element: &str, message: &str) -> SvdError {
+    SvdError::Invalid {
+        element: element.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Parses a `scaledNonNegativeInteger`: decimal, `0x` hexadecimal or `#`
+/// binary, optionally followed by k, M, G or T.
+fn number(element: &str, s: &str) -> Result<u64, SvdError> {
+    let s = s.trim();
+    let err = || invalid(element, &format!("invalid number {:?}", s));
+    let (s, scale) = match s.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'm') | Some(b'M') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'g') | Some(b'G') => (&s[..s.len() - 1], 1 << 30),
+        Some(b't') | Some(b'T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    let value = match s.strip_prefix('#') {
+        Some(bin) => u64::from_str_radix(bin, 2).map_err(|_| err())?,
+        None if s.starts_with("0x") || s.starts_with("0X") => {
+            parse::number(s.as_bytes()).map_err(|_| err())?
+        }
+        None => s.parse().map_err(|_| err())?,
+    };
+    value.checked_mul(scale).ok_or_else(err)
+}
+
+fn required<'e>(e: &'e Element, name: &str) -> Result<&'e str, SvdError> {
+    e.text(name)
+        .ok_or_else(|| invalid(&e.name, &format!("no <{}>", name)))
+}
+
+fn register_size(s: &str) -> Result<u32, SvdError> {
+    match number("size", s)? {
+        size @ (8 | 16 | 32 | 64) => Ok(size as u32),
+        size => Err(invalid(
+            "size",
+            &format!("unsupported register size {}", size),
+        )),
+    }
+}
+
+fn peripheral(p: &Element, size: u32) -> Result<Peripheral, SvdError> {
+    let size = match p.text("size") {
+        Some(s) => register_size(s)?,
+        None => size,
+    };
+    let mut registers = Vec::new();
+    if let Some(list) = p.child("registers") {
+        for r in list.children("register") {
+            registers.extend(register(r, size)?);
+        }
+    }
+    Ok(Peripheral {
+        name: required(p, "name")?.to_string(),
+        base_address: number("baseAddress", required(p, "baseAddress")?)?,
+        registers,
+    })
+}
+
+/// The register `r`, or the registers of the array `r`.
+fn register(r: &Element, size: u32) -> Result<Vec<Register>, SvdError> {
+    let name = required(r, "name")?;
+    let mut fields = Vec::new();
+    if let Some(list) = r.child("fields") {
+        for f in list.children("field") {
+            fields.push(field(f)?);
+        }
+    }
+    let register = Register {
+        name: name.to_string(),
+        offset: number("addressOffset", required(r, "addressOffset")?)?,
+        size: match r.text("size") {
+            Some(s) => register_size(s)?,
+            None => size,
+        },
+        reset_value: r
+            .text("resetValue")
+            .map(|v| number("resetValue", v))
+            .transpose()?,
+        fields,
+    };
+    if let Some(f) = register
+        .fields
+        .iter()
+        .find(|f| f.lsb + f.width > register.size)
+    {
+        return Err(invalid(
+            "field",
+            &format!("{} doesn't fit register {}", f.name, name),
+        ));
+    }
+    let Some(dim) = r.text("dim") else {
+        return Ok(vec![register]);
+    };
+    let dim = number("dim", dim)?;
+    let increment = number("dimIncrement", required(r, "dimIncrement")?)?;
+    let indices: Vec<String> = match r.text("dimIndex") {
+        Some(list) => match list.split_once('-') {
+            Some((first, last)) if !list.contains(',') => {
+                let (first, last) = (number("dimIndex", first)?, number("dimIndex", last)?);
+                (first..=last).map(|i| i.to_string()).collect()
+            }
+            _ => list.split(',').map(|s| s.trim().to_string()).collect(),
+        },
+        None => (0..dim).map(|i| i.to_string()).collect(),
+    };
+    if indices.len() as u64 != dim {
+        return Err(invalid(
+            "dimIndex",
+            &format!("{} indices for dim {}", indices.len(), dim),
+        ));
+    }
+    indices
+        .iter()
+        .enumerate()
+        .map(|(i, index)| {
+            let offset = (i as u64)
+                .checked_mul(increment)
+                .and_then(|o| o.checked_add(register.offset))
+                .ok_or_else(|| invalid("dimIncrement", "offset overflows"))?;
+            Ok(Register {
+                name: name.replace("[%s]", index).replace("%s", index),
+                offset,
+                ..register.clone()
+            })
+        })
+        .collect()
+}
+
+/// The width of the bits `lsb` to `msb` (inclusive).
+fn bit_width(element: &str, lsb: u64, msb: u64) -> Result<u64, SvdError> {
+    if msb < lsb {
+        return Err(invalid(
+            element,
+            &format!("msb {} is below lsb {}", msb, lsb),
+        ));
+    }
+    Ok(msb - lsb + 1)
+}
+
+fn field(f: &Element) -> Result<Field, SvdError> {
+    let name = required(f, "name")?;
+    let (lsb, width) = if let Some(offset) = f.text("bitOffset") {
+        let width = f
+            .text("bitWidth")
+            .map_or(Ok(1), |w| number("bitWidth", w))?;
+        (number("bitOffset", offset)?, width)
+    } else if let (Some(lsb), Some(msb)) = (f.text("lsb"), f.text("msb")) {
+        let (lsb, msb) = (number("lsb", lsb)?, number("msb", msb)?);
+        (lsb, bit_width("msb", lsb, msb)?)
+    } else if let Some(range) = f.text("bitRange") {
+        let err = || invalid("bitRange", &format!("invalid range {:?}", range));
+        let inner = range
+            .trim()
+            .strip_prefix('[')
+            .and_then(|r| r.strip_suffix(']'))
+            .ok_or_else(err)?;
+        let (msb, lsb) = inner.split_once(':').ok_or_else(err)?;
+        let (lsb, msb) = (number("bitRange", lsb)?, number("bitRange", msb)?);
+        (lsb, bit_width("bitRange", lsb, msb)?)
+    } else {
+        return Err(invalid("field", &format!("{} has no bit position", name)));
+    };
+    if width == 0 || lsb.checked_add(width).is_none_or(|end| end > 64) {
+        return Err(invalid("field", &format!("{} has invalid bits", name)));
+    }
+    Ok(Field {
+        name: name.to_string(),
+        lsb: lsb as u32,
+        width: width as u32,
+    })
+}
+
+/// An XML element, with the text it contains directly.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn children<'e>(&'e self, name: &'e str) -> impl Iterator<Item = &'e Element> + 'e {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The trimmed text of the child `name`.
+    fn text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.trim())
+    }
+}
+
+/// Just enough of an XML parser for SVD files: elements, attributes, text,
+/// CDATA and the predefined and numeric entities. Comments, processing
+/// instructions and the DOCTYPE are skipped.
+struct Reader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> SvdError {
+        SvdError::Xml {
+            line: self.s[..self.pos].matches('\n').count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), SvdError> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", prefix)))
+        }
+    }
+
+    /// Returns the text up to `end` and moves past `end`.
+    fn until(&mut self, end: &str) -> Result<&'a str, SvdError> {
+        match self.rest().find(end) {
+            Some(i) => {
+                let text = &self.rest()[..i];
+                self.pos += i + end.len();
+                Ok(text)
+            }
+            None => Err(self.error(&format!("missing {:?}", end))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips a comment, processing instruction or declaration.
+    fn skip_markup(&mut self) -> Result<bool, SvdError> {
+        if self.eat("<!--") {
+            self.until("-->")?;
+        } else if self.eat("<?") {
+            self.until("?>")?;
+        } else if self.rest().starts_with("<!") && !self.rest().starts_with("<![CDATA[") {
+            self.until(">")?;
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn document(mut self) -> Result<Element, SvdError> {
+        loop {
+            self.skip_whitespace();
+            if !self.skip_markup()? {
+                break;
+            }
+        }
+        let root = self.element()?;
+        loop {
+            self.skip_whitespace();
+            if !self.skip_markup()? {
+                break;
+            }
+        }
+        if !self.rest().is_empty() {
+            return Err(self.error("content after the root element"));
+        }
+        Ok(root)
+    }
+
+    fn name(&mut self) -> Result<String, SvdError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || "/>=".contains(c))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += len;
+        Ok(rest[..len].to_string())
+    }
+
+    fn element(&mut self) -> Result<Element, SvdError> {
+        self.expect("<")?;
+        let mut element = Element {
+            name: self.name()?,
+            attrs: Vec::new(),
+            children: Vec::new(),
+            text: String::new(),
+        };
+        loop {
+            self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(element);
+            }
+            if self.eat(">") {
+                break;
+            }
+            let name = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.eat("\"") {
+                "\""
+            } else {
+                self.expect("'")?;
+                "'"
+            };
+            let value = self.until(quote)?;
+            element.attrs.push((name, self.unescape(value)?));
+        }
+        loop {
+            if self.eat("</") {
+                if self.name()? != element.name {
+                    return Err(self.error(&format!("expected </{}>", element.name)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.eat("<![CDATA[") {
+                let text = self.until("]]>")?;
+                element.text.push_str(text);
+            } else if self.skip_markup()? {
+            } else if self.rest().starts_with('<') {
+                element.children.push(self.element()?);
+            } else if self.rest().is_empty() {
+                return Err(self.error(&format!("missing </{}>", element.name)));
+            } else {
+                let len = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = &self.rest()[..len];
+                let text = self.unescape(text)?;
+                element.text.push_str(&text);
+                self.pos += len;
+            }
+        }
+    }
+
+    fn unescape(&self, s: &str) -> Result<String, SvdError> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('&') {
+            out.push_str(&rest[..i]);
+            let end = rest[i..]
+                .find(';')
+                .ok_or_else(|| self.error("unterminated entity"))?;
+            let entity = &rest[i + 1..i + end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|d| d.parse().ok()),
+                }
+                .and_then(::std::char::from_u32),
+            };
+            out.push(c.ok_or_else(|| self.error(&format!("unknown entity &{};", entity)))?);
+            rest = &rest[i + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockUio;
+
+    const SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- A timer & a copy of it -->
+<device schemaVersion="1.3" xmlns:xs="http://www.w3.org/2001/XMLSchema-instance">
+  <name>DEMO</name>
+  <size>32</size>
+  <peripherals>
+    <peripheral>
+      <name>TIMER0</name>
+      <baseAddress>0x40001000</baseAddress>
+      <registers>
+        <register>
+          <name>CTRL</name>
+          <description><![CDATA[Control <register>]]></description>
+          <addressOffset>0x0</addressOffset>
+          <resetValue>0x00000000</resetValue>
+          <fields>
+            <field><name>EN</name><bitOffset>0</bitOffset><bitWidth>1</bitWidth></field>
+            <field><name>MODE</name><lsb>4</lsb><msb>6</msb></field>
+            <field><name>PRESCALE</name><bitRange>[15:8]</bitRange></field>
+          </fields>
+        </register>
+        <register>
+          <name>STATUS</name>
+          <addressOffset>4</addressOffset>
+          <size>8</size>
+        </register>
+        <register>
+          <name>CMP%s</name>
+          <addressOffset>0x10</addressOffset>
+          <dim>3</dim>
+          <dimIncrement>4</dimIncrement>
+          <dimIndex>A,B,C</dimIndex>
+        </register>
+      </registers>
+    </peripheral>
+    <peripheral derivedFrom="TIMER0">
+      <name>TIMER1</name>
+      <baseAddress>0x40002000</baseAddress>
+    </peripheral>
+  </peripherals>
+</device>
+"#;
+
+    #[test]
+    fn load_svd() {
+        let device = Device::parse(SVD).unwrap();
+        assert_eq!(device.name, "DEMO");
+        let timer = device.peripheral("TIMER0").unwrap();
+        assert_eq!(timer.base_address, 0x4000_1000);
+        let names: Vec<_> = timer.registers.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["CTRL", "STATUS", "CMPA", "CMPB", "CMPC"]);
+        assert_eq!(timer.register("CMPC").unwrap().offset, 0x18);
+        assert_eq!(timer.register("STATUS").unwrap().size, 8);
+        let ctrl = timer.register("CTRL").unwrap();
+        assert_eq!(ctrl.field("MODE").unwrap().mask(), 0x70);
+        assert_eq!(ctrl.field("PRESCALE").unwrap().mask(), 0xff00);
+        assert_eq!(
+            device.peripheral("TIMER1").unwrap().registers,
+            timer.registers
+        );
+
+        let mut mock = MockUio::new(0).unwrap();
+        mock.add_mapping(0x4000_1000, 0x1000, "timer").unwrap();
+        let dev = mock.open().unwrap();
+        let region = dev.map_region(0).unwrap();
+        let regs = Registers::new(&region, timer);
+        regs.write("CTRL.PRESCALE", 0x12).unwrap();
+        regs.write("CTRL.MODE", 5).unwrap();
+        regs.write("CTRL.EN", 1).unwrap();
+        assert_eq!(region.read_u32(0), 0x1251);
+        assert_eq!(regs.read("CTRL.MODE").unwrap(), 5);
+        regs.write("CMPB", 0xdead_beef).unwrap();
+        assert_eq!(region.read_u32(0x14), 0xdead_beef);
+        regs.write("STATUS", 0xff).unwrap();
+        assert_eq!(region.read_u32(4), 0xff);
+
+        assert!(matches!(
+            regs.write("CTRL.MODE", 8),
+            Err(SvdError::Overflow { value: 8, .. })
+        ));
+        assert!(matches!(
+            regs.write("STATUS", 0x100),
+            Err(SvdError::Overflow { .. })
+        ));
+        assert!(matches!(
+            regs.read("LOAD"),
+            Err(SvdError::UnknownRegister(_))
+        ));
+        assert!(matches!(
+            regs.read("CTRL.GO"),
+            Err(SvdError::UnknownField { .. })
+        ));
+        let small = ::mock::MockMemory::new(0x10);
+        let small = Registers::new(&small, timer);
+        assert!(matches!(small.read("CMPA"), Err(SvdError::OutOfBounds(_))));
+    }
+
+    #[test]
+    fn lookup() {
+        let device = Device::parse(SVD).unwrap();
+        let (timer, mode) = device.lookup("TIMER1.CTRL.MODE").unwrap();
+        assert_eq!(timer.base_address, 0x4000_2000);
+        assert_eq!(
+            mode,
+            Location {
+                offset: 0,
+                size: 32,
+                mask: 0x70,
+                lsb: 4
+            }
+        );
+        assert!(mode.is_field());
+        assert_eq!(mode.extract(0x1251), 5);
+        assert_eq!(mode.insert(0xffff_ffff, 2), Some(0xffff_ffaf));
+        assert_eq!(mode.insert(0, 8), None);
+
+        let (_, status) = device.lookup("TIMER0.STATUS").unwrap();
+        assert_eq!((status.offset, status.size, status.mask), (4, 8, 0xff));
+        assert!(!status.is_field());
+        assert_eq!(status.insert(0x12, 0x34), Some(0x34));
+        assert_eq!(status.insert(0, 0x100), None);
+
+        assert!(matches!(
+            device.lookup("TIMER2.CTRL"),
+            Err(SvdError::UnknownPeripheral(_))
+        ));
+        assert!(matches!(
+            device.lookup("TIMER0"),
+            Err(SvdError::UnknownRegister(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_svd() {
+        let line = |svd: &str| match Device::parse(svd) {
+            Err(SvdError::Xml { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(line("<device>\n<name>x</nam>\n</device>"), 2);
+        assert_eq!(line("<device>\n<name>&bogus;</name>"), 2);
+        assert_eq!(line("<device/>\n<device/>"), 2);
+        assert!(matches!(
+            Device::parse("<peripheral/>"),
+            Err(SvdError::Invalid { .. })
+        ));
+        let svd = SVD.replace("<size>8</size>", "<size>24</size>");
+        assert!(matches!(Device::parse(&svd), Err(SvdError::Invalid { .. })));
+
+        // Reversed and overflowing bit positions
+        for (from, to) in [
+            ("[15:8]", "[8:15]"),
+            ("<msb>6</msb>", "<msb>3</msb>"),
+            (
+                "<bitOffset>0</bitOffset>",
+                "<bitOffset>0xffffffffffffffff</bitOffset>",
+            ),
+        ] {
+            let svd = SVD.replace(from, to);
+            assert!(matches!(Device::parse(&svd), Err(SvdError::Invalid { .. })));
+        }
+    }
+}
@@ -0,0 +1,808 @@
+//! The system calls used by the crate.
+//!
+//! With the default `nix` feature they go through `nix` and `fs2`, without
+//! it they are implemented directly on `libc`.
+
+use libc;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::fd::{self, FromRawFd};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(feature = "nix")]
+mod imp {
+    use fs2::FileExt;
+    use libc;
+    #[cfg(feature = "events")]
+    use nix::errno::Errno;
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use nix::poll::{poll, PollFd, PollFlags};
+    #[cfg(feature = "events")]
+    use nix::sched::{sched_setaffinity, CpuSet};
+    #[cfg(feature = "events")]
+    use nix::sys::epoll::{
+        epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+    };
+    #[cfg(feature = "events")]
+    use nix::sys::eventfd::{eventfd, EfdFlags};
+    use nix::sys::mman::{MapFlags, ProtFlags};
+    #[cfg(feature = "events")]
+    use nix::sys::signal::{SigSet, Signal};
+    #[cfg(feature = "events")]
+    use nix::sys::signalfd::{signalfd, SfdFlags};
+    use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+    #[cfg(feature = "events")]
+    use nix::sys::time::TimeSpec;
+    #[cfg(feature = "events")]
+    use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+    #[cfg(feature = "events")]
+    use nix::unistd::Pid;
+    #[cfg(feature = "events")]
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::{self, IoSlice, IoSliceMut};
+    #[cfg(feature = "events")]
+    use std::mem::{self, ManuallyDrop};
+    use std::num::NonZeroUsize;
+    use std::os::unix::io::RawFd;
+    #[cfg(feature = "events")]
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    #[cfg(feature = "events")]
+    use std::time::Duration;
+
+    pub fn lock_exclusive(file: &File) -> io::Result<()> {
+        file.lock_exclusive()
+    }
+
+    pub fn try_lock_exclusive(file: &File) -> io::Result<()> {
+        file.try_lock_exclusive()
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        FileExt::unlock(file)
+    }
+
+    pub fn set_ofd_lock(fd: RawFd, lock: &libc::flock, wait: bool) -> io::Result<()> {
+        if wait {
+            fcntl(fd, FcntlArg::F_OFD_SETLKW(lock))?;
+        } else {
+            fcntl(fd, FcntlArg::F_OFD_SETLK(lock))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+        let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD)?);
+        let flags = if cloexec {
+            flags | FdFlag::FD_CLOEXEC
+        } else {
+            flags - FdFlag::FD_CLOEXEC
+        };
+        fcntl(fd, FcntlArg::F_SETFD(flags))?;
+        Ok(())
+    }
+
+    pub fn status_flags(fd: RawFd) -> io::Result<libc::c_int> {
+        Ok(fcntl(fd, FcntlArg::F_GETFL)?)
+    }
+
+    pub unsafe fn mmap(
+        addr: Option<usize>,
+        len: usize,
+        writable: bool,
+        fd: RawFd,
+        offset: usize,
+    ) -> io::Result<*mut libc::c_void> {
+        let length = NonZeroUsize::new(len).ok_or(io::ErrorKind::InvalidInput)?;
+        let mut flags = MapFlags::MAP_SHARED;
+        if addr.is_some() {
+            flags |= MapFlags::MAP_FIXED;
+        }
+        let mut prot = ProtFlags::PROT_READ;
+        if writable {
+            prot |= ProtFlags::PROT_WRITE;
+        }
+        Ok(nix::sys::mman::mmap(
+            addr.and_then(NonZeroUsize::new),
+            length,
+            prot,
+            flags,
+            fd,
+            offset as libc::off_t,
+        )?)
+    }
+
+    pub unsafe fn munmap(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+        Ok(nix::sys::mman::munmap(addr, len)?)
+    }
+
+    pub unsafe fn protect_none(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+        Ok(nix::sys::mman::mprotect(addr, len, ProtFlags::PROT_NONE)?)
+    }
+
+    pub fn poll_readable(fd: RawFd, timeout_ms: libc::c_int) -> io::Result<bool> {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        Ok(poll(&mut fds, timeout_ms)? > 0)
+    }
+
+    pub fn poll_many(fds: &[RawFd], timeout_ms: libc::c_int) -> io::Result<Vec<usize>> {
+        let mut pfds: Vec<PollFd> = fds
+            .iter()
+            .map(|&fd| PollFd::new(fd, PollFlags::POLLIN))
+            .collect();
+        poll(&mut pfds, timeout_ms)?;
+        Ok(pfds
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.revents().is_some_and(|r| !r.is_empty()))
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_create() -> io::Result<RawFd> {
+        Ok(epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_add(epfd: RawFd, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, token);
+        Ok(epoll_ctl(epfd, EpollOp::EpollCtlAdd, fd, &mut event)?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_delete(epfd: RawFd, fd: RawFd) -> io::Result<()> {
+        Ok(epoll_ctl(epfd, EpollOp::EpollCtlDel, fd, None)?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_wait_tokens(epfd: RawFd, timeout_ms: libc::c_int) -> io::Result<Vec<u64>> {
+        let mut events = [EpollEvent::empty(); 32];
+        let n = epoll_wait(epfd, &mut events, timeout_ms as isize)?;
+        Ok(events[..n].iter().map(EpollEvent::data).collect())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn timerfd_create() -> io::Result<RawFd> {
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_CLOEXEC | TimerFlags::TFD_NONBLOCK,
+        )?;
+        let fd = timer.as_raw_fd();
+        mem::forget(timer);
+        Ok(fd)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn timerfd_set(fd: RawFd, first: Duration, interval: Option<Duration>) -> io::Result<()> {
+        // Borrow the descriptor, `TimerFd` closes it when dropped
+        let timer = ManuallyDrop::new(unsafe { TimerFd::from_raw_fd(fd) });
+        let first = TimeSpec::from_duration(first);
+        let expiration = match interval {
+            Some(interval) => Expiration::IntervalDelayed(first, TimeSpec::from_duration(interval)),
+            None => Expiration::OneShot(first),
+        };
+        Ok(timer.set(expiration, TimerSetTimeFlags::empty())?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn eventfd_create() -> io::Result<RawFd> {
+        Ok(eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn signalfd_create(signals: &[libc::c_int]) -> io::Result<RawFd> {
+        let mut mask = SigSet::empty();
+        for &signal in signals {
+            mask.add(Signal::try_from(signal)?);
+        }
+        mask.thread_block()?;
+        Ok(signalfd(
+            -1,
+            &mask,
+            SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK,
+        )?)
+    }
+
+    pub fn send_fd(socket: RawFd, data: &[u8], fd: RawFd) -> io::Result<usize> {
+        let fds = [fd];
+        Ok(sendmsg::<()>(
+            socket,
+            &[IoSlice::new(data)],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::MSG_NOSIGNAL,
+            None,
+        )?)
+    }
+
+    pub fn recv_fds(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+        let mut iov = [IoSliceMut::new(buf)];
+        let mut space = nix::cmsg_space!([RawFd; 1]);
+        let msg = recvmsg::<()>(
+            socket,
+            &mut iov,
+            Some(&mut space),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        Ok((msg.bytes, fds))
+    }
+
+    #[cfg(feature = "events")]
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        let mut set = CpuSet::new();
+        for &cpu in cpus {
+            set.set(cpu)?;
+        }
+        Ok(sched_setaffinity(Pid::from_raw(0), &set)?)
+    }
+
+    #[cfg(feature = "events")]
+    pub fn set_scheduler(policy: libc::c_int, priority: libc::c_int) -> io::Result<()> {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        // nix has no wrapper, and pthread functions return the error number
+        match unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) } {
+            0 => Ok(()),
+            errno => Err(Errno::from_i32(errno).into()),
+        }
+    }
+}
+
+#[cfg(not(feature = "nix"))]
+mod imp {
+    use libc;
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+    #[cfg(feature = "events")]
+    use std::time::Duration;
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    fn flock(file: &File, op: libc::c_int) -> io::Result<()> {
+        check(unsafe { libc::flock(file.as_raw_fd(), op) }).map(|_| ())
+    }
+
+    pub fn lock_exclusive(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX)
+    }
+
+    pub fn try_lock_exclusive(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_UN)
+    }
+
+    pub fn set_ofd_lock(fd: RawFd, lock: &libc::flock, wait: bool) -> io::Result<()> {
+        let cmd = if wait {
+            libc::F_OFD_SETLKW
+        } else {
+            libc::F_OFD_SETLK
+        };
+        check(unsafe { libc::fcntl(fd, cmd, lock as *const libc::flock) }).map(|_| ())
+    }
+
+    pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+        let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        check(unsafe { libc::fcntl(fd, libc::F_SETFD, flags) }).map(|_| ())
+    }
+
+    pub fn status_flags(fd: RawFd) -> io::Result<libc::c_int> {
+        check(unsafe { libc::fcntl(fd, libc::F_GETFL) })
+    }
+
+    pub unsafe fn mmap(
+        addr: Option<usize>,
+        len: usize,
+        writable: bool,
+        fd: RawFd,
+        offset: usize,
+    ) -> io::Result<*mut libc::c_void> {
+        if len == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut flags = libc::MAP_SHARED;
+        if addr.is_some() {
+            flags |= libc::MAP_FIXED;
+        }
+        let mut prot = libc::PROT_READ;
+        if writable {
+            prot |= libc::PROT_WRITE;
+        }
+        let ret = libc::mmap(
+            addr.unwrap_or(0) as *mut libc::c_void,
+            len,
+            prot,
+            flags,
+            fd,
+            offset as libc::off_t,
+        );
+        if ret == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    pub unsafe fn munmap(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+        check(libc::munmap(addr, len)).map(|_| ())
+    }
+
+    pub unsafe fn protect_none(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+        check(libc::mprotect(addr, len, libc::PROT_NONE)).map(|_| ())
+    }
+
+    pub fn poll_readable(fd: RawFd, timeout_ms: libc::c_int) -> io::Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        check(unsafe { libc::poll(&mut pfd, 1, timeout_ms) }).map(|n| n > 0)
+    }
+
+    pub fn poll_many(fds: &[RawFd], timeout_ms: libc::c_int) -> io::Result<Vec<usize>> {
+        let mut pfds: Vec<libc::pollfd> = fds
+            .iter()
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        check(unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) })?;
+        Ok(pfds
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.revents != 0)
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_create() -> io::Result<RawFd> {
+        check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_add(epfd: RawFd, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        check(unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, &mut event) }).map(|_| ())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_delete(epfd: RawFd, fd: RawFd) -> io::Result<()> {
+        check(unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_DEL, fd, ptr::null_mut()) })
+            .map(|_| ())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn epoll_wait_tokens(epfd: RawFd, timeout_ms: libc::c_int) -> io::Result<Vec<u64>> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 32];
+        let n = check(unsafe {
+            libc::epoll_wait(
+                epfd,
+                events.as_mut_ptr(),
+                events.len() as libc::c_int,
+                timeout_ms,
+            )
+        })?;
+        Ok(events[..n as usize].iter().map(|e| e.u64).collect())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn timerfd_create() -> io::Result<RawFd> {
+        check(unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+            )
+        })
+    }
+
+    #[cfg(feature = "events")]
+    fn timespec(d: Duration) -> libc::timespec {
+        libc::timespec {
+            tv_sec: d.as_secs() as libc::time_t,
+            tv_nsec: d.subsec_nanos() as libc::c_long,
+        }
+    }
+
+    #[cfg(feature = "events")]
+    pub fn timerfd_set(fd: RawFd, first: Duration, interval: Option<Duration>) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval.unwrap_or_default()),
+            it_value: timespec(first),
+        };
+        check(unsafe { libc::timerfd_settime(fd, 0, &spec, ptr::null_mut()) }).map(|_| ())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn eventfd_create() -> io::Result<RawFd> {
+        check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })
+    }
+
+    #[cfg(feature = "events")]
+    pub fn signalfd_create(signals: &[libc::c_int]) -> io::Result<RawFd> {
+        // Safety: `sigemptyset` initializes the set
+        let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut mask) };
+        for &signal in signals {
+            check(unsafe { libc::sigaddset(&mut mask, signal) })?;
+        }
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, ptr::null_mut()) } {
+            0 => {}
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+        check(unsafe { libc::signalfd(-1, &mask, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK) })
+    }
+
+    /// A control message buffer with room for one fd, aligned for `cmsghdr`.
+    fn cmsg_buffer() -> Vec<u64> {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+        vec![0; space.div_ceil(mem::size_of::<u64>())]
+    }
+
+    pub fn send_fd(socket: RawFd, data: &[u8], fd: RawFd) -> io::Result<usize> {
+        let mut cbuf = cmsg_buffer();
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // Safety: the control buffer has room for the header and one fd
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            let n = libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL);
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        }
+    }
+
+    pub fn recv_fds(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+        let mut cbuf = cmsg_buffer();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Safety: the kernel fills at most `msg_controllen` bytes of the
+        // control buffer, which are walked with the CMSG macros
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+            // Room for exactly one fd (`CMSG_SPACE` would fit two), the
+            // kernel closes the ones which don't fit
+            msg.msg_controllen = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            let n = libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut fds = Vec::new();
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg);
+                    let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                    for i in 0..len / mem::size_of::<RawFd>() {
+                        fds.push(ptr::read_unaligned((data as *const RawFd).add(i)));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok((n as usize, fds))
+        }
+    }
+
+    #[cfg(feature = "events")]
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        // Safety: all-zero is an empty `cpu_set_t`
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        check(unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) }).map(|_| ())
+    }
+
+    #[cfg(feature = "events")]
+    pub fn set_scheduler(policy: libc::c_int, priority: libc::c_int) -> io::Result<()> {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        match unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+/// Takes an exclusive `flock` on `file`, waiting for other holders.
+pub fn lock_exclusive(file: &File) -> io::Result<()> {
+    imp::lock_exclusive(file)
+}
+
+/// Takes an exclusive `flock` on `file`, failing with `WouldBlock` if it is
+/// held elsewhere.
+pub fn try_lock_exclusive(file: &File) -> io::Result<()> {
+    imp::try_lock_exclusive(file)
+}
+
+/// Releases a `flock` on `file`.
+pub fn unlock(file: &File) -> io::Result<()> {
+    imp::unlock(file)
+}
+
+/// Takes (or with `lock_type` `F_UNLCK` releases) an open file description
+/// lock of type `lock_type` on byte `offset` of `file`.
+///
+/// Unlike `flock`, these locks can be shared and exclusive on different
+/// bytes of the same file. Without `wait` a conflicting lock fails with
+/// `WouldBlock`.
+fn ofd_lock(file: &File, offset: u64, lock_type: libc::c_int, wait: bool) -> io::Result<()> {
+    // Safety: all-zero is a valid `flock`, and `l_pid` must be 0 for OFD locks
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = lock_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = offset as libc::off_t;
+    lock.l_len = 1;
+    imp::set_ofd_lock(file.as_raw_fd(), &lock, wait).map_err(|e| {
+        // F_OFD_SETLK reports conflicts as EACCES or EAGAIN
+        if e.raw_os_error() == Some(libc::EACCES) {
+            io::Error::from(io::ErrorKind::WouldBlock)
+        } else {
+            e
+        }
+    })
+}
+
+/// Locks byte `offset` of `file` shared or, if `exclusive`, exclusively,
+/// waiting for conflicting holders if `wait` is set.
+pub fn lock_byte(file: &File, offset: u64, exclusive: bool, wait: bool) -> io::Result<()> {
+    let lock_type = if exclusive {
+        libc::F_WRLCK
+    } else {
+        libc::F_RDLCK
+    };
+    ofd_lock(file, offset, lock_type, wait)
+}
+
+/// Releases a lock taken with `lock_byte`.
+pub fn unlock_byte(file: &File, offset: u64) -> io::Result<()> {
+    ofd_lock(file, offset, libc::F_UNLCK, false)
+}
+
+/// Sets or clears `FD_CLOEXEC` on `file`.
+pub fn set_cloexec(file: &File, cloexec: bool) -> io::Result<()> {
+    imp::set_cloexec(file.as_raw_fd(), cloexec)
+}
+
+/// Whether `file` was opened for writing (`O_WRONLY` or `O_RDWR`).
+pub fn is_writable(file: &File) -> io::Result<bool> {
+    let flags = imp::status_flags(file.as_raw_fd())?;
+    Ok(flags & libc::O_ACCMODE != libc::O_RDONLY)
+}
+
+/// Maps `len` bytes at `offset` of `fd` shared, at exactly `addr` if given.
+pub unsafe fn mmap(
+    addr: Option<usize>,
+    len: usize,
+    writable: bool,
+    fd: RawFd,
+    offset: usize,
+) -> io::Result<*mut libc::c_void> {
+    imp::mmap(addr, len, writable, fd, offset)
+}
+
+/// Unmaps `len` bytes at `addr`.
+pub unsafe fn munmap(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+    imp::munmap(addr, len)
+}
+
+/// Makes `len` bytes at `addr` inaccessible, keeping the address range
+/// reserved.
+pub unsafe fn protect_none(addr: *mut libc::c_void, len: usize) -> io::Result<()> {
+    imp::protect_none(addr, len)
+}
+
+/// Creates an epoll instance.
+#[cfg(feature = "events")]
+pub fn epoll_create() -> io::Result<fd::OwnedFd> {
+    // Safety: the descriptor is new and owned by nobody else
+    imp::epoll_create().map(|fd| unsafe { fd::OwnedFd::from_raw_fd(fd) })
+}
+
+/// Watches `fd` for readability in the epoll instance `epfd`, reporting it
+/// as `token`.
+#[cfg(feature = "events")]
+pub fn epoll_add(epfd: RawFd, fd: RawFd, token: u64) -> io::Result<()> {
+    imp::epoll_add(epfd, fd, token)
+}
+
+/// Stops watching `fd` in the epoll instance `epfd`.
+#[cfg(feature = "events")]
+pub fn epoll_delete(epfd: RawFd, fd: RawFd) -> io::Result<()> {
+    imp::epoll_delete(epfd, fd)
+}
+
+/// Waits up to `timeout_ms` (-1 for no timeout) for watched descriptors of
+/// `epfd` to become ready. Returns the tokens of (up to 32) ready ones,
+/// empty on timeout.
+#[cfg(feature = "events")]
+pub fn epoll_wait(epfd: RawFd, timeout_ms: libc::c_int) -> io::Result<Vec<u64>> {
+    imp::epoll_wait_tokens(epfd, timeout_ms)
+}
+
+/// Creates a non-blocking `CLOCK_MONOTONIC` timerfd, see `timerfd_set`.
+#[cfg(feature = "events")]
+pub fn timerfd_create() -> io::Result<fd::OwnedFd> {
+    // Safety: the descriptor is new and owned by nobody else
+    imp::timerfd_create().map(|fd| unsafe { fd::OwnedFd::from_raw_fd(fd) })
+}
+
+/// Arms the timerfd `fd` to expire after `first` and then every `interval`.
+/// A zero `first` disarms it.
+#[cfg(feature = "events")]
+pub fn timerfd_set(
+    fd: RawFd,
+    first: std::time::Duration,
+    interval: Option<std::time::Duration>,
+) -> io::Result<()> {
+    imp::timerfd_set(fd, first, interval)
+}
+
+/// Creates a non-blocking eventfd.
+#[cfg(feature = "events")]
+pub fn eventfd() -> io::Result<fd::OwnedFd> {
+    // Safety: the descriptor is new and owned by nobody else
+    imp::eventfd_create().map(|fd| unsafe { fd::OwnedFd::from_raw_fd(fd) })
+}
+
+/// Blocks `signals` in the calling thread and returns a non-blocking
+/// signalfd receiving them.
+#[cfg(feature = "events")]
+pub fn signalfd(signals: &[libc::c_int]) -> io::Result<fd::OwnedFd> {
+    // Safety: the descriptor is new and owned by nobody else
+    imp::signalfd_create(signals).map(|fd| unsafe { fd::OwnedFd::from_raw_fd(fd) })
+}
+
+/// Sends `data` and the file descriptor `fd` (`SCM_RIGHTS`) over the Unix
+/// socket `socket`. Returns the number of bytes sent.
+pub fn send_fd(socket: RawFd, data: &[u8], fd: RawFd) -> io::Result<usize> {
+    imp::send_fd(socket, data, fd)
+}
+
+/// Receives a message into `buf` from the Unix socket `socket`, with the file
+/// descriptor sent along with it, if any. Further descriptors are closed.
+pub fn recv_fd(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<fd::OwnedFd>)> {
+    let (n, fds) = imp::recv_fds(socket, buf)?;
+    // Safety: the received descriptors are new and owned by nobody else
+    let fds: Vec<fd::OwnedFd> = fds
+        .into_iter()
+        .map(|fd| unsafe { fd::OwnedFd::from_raw_fd(fd) })
+        .collect();
+    Ok((n, fds.into_iter().next()))
+}
+
+/// Waits up to `timeout_ms` (-1 for no timeout) for `fd` to become readable.
+/// Returns false on timeout.
+pub fn poll_readable(fd: RawFd, timeout_ms: libc::c_int) -> io::Result<bool> {
+    imp::poll_readable(fd, timeout_ms)
+}
+
+/// Waits up to `timeout_ms` (-1 for no timeout) for any of `fds` to become
+/// readable (or fail). Returns the indices of the ready fds, empty on
+/// timeout.
+pub fn poll_many(fds: &[RawFd], timeout_ms: libc::c_int) -> io::Result<Vec<usize>> {
+    imp::poll_many(fds, timeout_ms)
+}
+
+/// Restricts the calling thread to the CPUs `cpus`.
+#[cfg(feature = "events")]
+pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    imp::set_affinity(cpus)
+}
+
+/// Sets the scheduling policy (e.g. `SCHED_FIFO`) and priority of the
+/// calling thread.
+#[cfg(feature = "events")]
+pub fn set_scheduler(policy: libc::c_int, priority: libc::c_int) -> io::Result<()> {
+    imp::set_scheduler(policy, priority)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::ptr;
+
+    #[test]
+    fn recv_fd_closes_extra() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let mut pipe = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        // Safety: the pipe ends are new and owned by nobody else
+        let (read_end, write_end) = unsafe {
+            (
+                fd::OwnedFd::from_raw_fd(pipe[0]),
+                fd::OwnedFd::from_raw_fd(pipe[1]),
+            )
+        };
+
+        // `send_fd` sends only one, pass the write end as a second fd
+        let fds = [read_end.as_raw_fd(), write_end.as_raw_fd()];
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of_val(&fds) as u32) } as usize;
+        let mut cbuf = vec![0u64; space.div_ceil(mem::size_of::<u64>())];
+        let data = [1u8];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // Safety: the control buffer has room for the header and both fds
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(&fds) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, 2);
+            assert_eq!(libc::sendmsg(tx.as_raw_fd(), &msg, 0), 1);
+        }
+        drop(write_end);
+
+        let mut buf = [0u8; 4];
+        let (n, fd) = recv_fd(rx.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(n, 1);
+        assert!(fd.is_some());
+        // The received copy of the write end is closed, so the pipe is at
+        // EOF instead of blocking
+        assert!(poll_readable(read_end.as_raw_fd(), 1000).unwrap());
+    }
+}
@@ -0,0 +1,38 @@
+//! Instrumentation helpers, which compile to nothing without the `tracing`
+//! and `metrics` features.
+
+/// Evaluates the `Result` expression `$e` and emits a debug event named
+/// `$name` with its duration, outcome and the given fields.
+macro_rules! timed {
+    ($name:literal, $e:expr $(, $k:ident = $v:expr)*) => {{
+        #[cfg(feature = "tracing")]
+        let start = ::std::time::Instant::now();
+        let res = $e;
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!(
+            duration_us = start.elapsed().as_micros() as u64,
+            ok = res.is_ok(),
+            $($k = ?$v,)*
+            $name
+        );
+        res
+    }};
+}
+
+/// Enters a debug span named `$name` with the given fields until the end of
+/// the enclosing scope.
+macro_rules! span {
+    ($name:literal $(, $k:ident = $v:expr)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!($name, $($k = ?$v),*).entered();
+    };
+}
+
+/// Updates the metric `$name` of the device `$device` (a path) through the
+/// `metrics` facade, e.g. `metric!(counter, "uio_interrupts_total", path, increment(1))`.
+macro_rules! metric {
+    ($kind:ident, $name:literal, $device:expr, $op:ident($v:expr)) => {
+        #[cfg(feature = "metrics")]
+        ::metrics::$kind!($name, "device" => $device.display().to_string()).$op($v);
+    };
+}
@@ -0,0 +1,297 @@
+//! Access to PCI BARs too large to map at once.
+//!
+//! A `WindowedBar` maps fixed-size windows of a BAR on demand and keeps the
+//! most recently used ones mapped, so a 64 GB BAR can be accessed by offset
+//! on hosts which can't map all of it:
+//!
+//! ```ignore
+//! // At most four 16 MB windows mapped at any time
+//! let bar = dev.map_resource_windowed(0, 16 << 20, 4)?;
+//! bar.write_u64(40 << 30, 1)?;
+//! ```
+//!
+//! The windows are unmapped when they are evicted or the `WindowedBar` is
+//! dropped. Accesses lock the window cache, so the handle can be shared
+//! between threads.
+
+use linux::PAGESIZE;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use {AccessWidths, MappedRegion, UioDevice, UioError, Unsupported};
+
+struct Window<'a> {
+    start: u64,
+    region: MappedRegion<'a>,
+}
+
+/// A PCI BAR accessed through a small number of mapped windows, see the
+/// module documentation.
+pub struct WindowedBar<'a> {
+    device: &'a UioDevice,
+    bar_nr: usize,
+    size: u64,
+    window_size: usize,
+    max_windows: usize,
+    widths: (AccessWidths, Unsupported),
+    /// The mapped windows, least recently used first
+    windows: Mutex<Vec<Window<'a>>>,
+}
+
+macro_rules! accessors {
+    ($($ty:ty, $read:ident, $write:ident);*) => {
+        $(
+            #[doc = concat!("Reads a `", stringify!($ty), "` at byte `offset` of the BAR.")]
+            ///
+            /// Fails if the window containing `offset` can't be mapped.
+            ///
+            /// # Panics
+            /// If the access is out of bounds or `offset` is not naturally aligned.
+            pub fn $read(&self, offset: u64) -> Result<$ty, UioError> {
+                self.check_aligned(offset, ::std::mem::size_of::<$ty>());
+                self.with_window(offset, |region, offset| region.$read(offset))
+            }
+
+            #[doc = concat!("Writes a `", stringify!($ty), "` at byte `offset` of the BAR.")]
+            ///
+            /// Fails if the window containing `offset` can't be mapped.
+            ///
+            /// # Panics
+            /// If the access is out of bounds or `offset` is not naturally aligned.
+            pub fn $write(&self, offset: u64, value: $ty) -> Result<(), UioError> {
+                self.check_aligned(offset, ::std::mem::size_of::<$ty>());
+                self.with_window(offset, |region, offset| region.$write(offset, value))
+            }
+        )*
+    };
+}
+
+impl<'a> WindowedBar<'a> {
+    /// Accesses the PCI BAR `bar_nr` of `device` through at most
+    /// `max_windows` windows of `window_size` bytes.
+    ///
+    /// # Panics
+    /// If `window_size` is not a non-zero multiple of the page size or
+    /// `max_windows` is 0.
+    pub(crate) fn new(
+        device: &'a UioDevice,
+        bar_nr: usize,
+        window_size: usize,
+        max_windows: usize,
+    ) -> Result<WindowedBar<'a>, UioError> {
+        assert!(
+            window_size > 0 && window_size.is_multiple_of(PAGESIZE),
+            "window size {:#x} is not a multiple of the page size",
+            window_size
+        );
+        assert!(max_windows > 0, "at least one window is needed");
+        Ok(WindowedBar {
+            device,
+            bar_nr,
+            size: device.resource_size(bar_nr)?,
+            window_size,
+            max_windows,
+            widths: (AccessWidths::ALL, Unsupported::Reject),
+            windows: Mutex::new(Vec::with_capacity(max_windows)),
+        })
+    }
+
+    /// Size of the BAR in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Size of the windows in bytes.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// The number of currently mapped windows.
+    pub fn mapped_windows(&self) -> usize {
+        self.windows().len()
+    }
+
+    /// Restricts the access sizes of all windows, see
+    /// `MappedRegion::set_access_widths`.
+    pub fn set_access_widths(&mut self, widths: AccessWidths, unsupported: Unsupported) {
+        self.widths = (widths, unsupported);
+        for window in self.windows().iter_mut() {
+            window.region.set_access_widths(widths, unsupported);
+        }
+    }
+
+    /// Unmaps all windows.
+    pub fn unmap_all(&self) {
+        self.windows().clear();
+    }
+
+    fn windows(&self) -> MutexGuard<'_, Vec<Window<'a>>> {
+        // Evicting or mapping a window can't leave the cache inconsistent
+        self.windows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_bounds(&self, offset: u64, size: usize) {
+        assert!(
+            offset
+                .checked_add(size as u64)
+                .is_some_and(|end| end <= self.size),
+            "access of {} bytes at offset {:#x} is out of bounds (size {:#x})",
+            size,
+            offset,
+            self.size
+        );
+    }
+
+    fn check_aligned(&self, offset: u64, size: usize) {
+        self.check_bounds(offset, size);
+        assert!(
+            offset.is_multiple_of(size as u64),
+            "access of {} bytes at offset {:#x} is misaligned",
+            size,
+            offset
+        );
+    }
+
+    /// Calls `f` with the window containing `offset`, mapping it (and
+    /// evicting the least recently used window) if needed, and the offset
+    /// within the window.
+    fn with_window<T, F>(&self, offset: u64, f: F) -> Result<T, UioError>
+    where
+        F: FnOnce(&MappedRegion<'a>, usize) -> T,
+    {
+        let start = offset - offset % self.window_size as u64;
+        let mut windows = self.windows();
+        match windows.iter().position(|w| w.start == start) {
+            Some(i) => {
+                let window = windows.remove(i);
+                windows.push(window);
+            }
+            None => {
+                if windows.len() == self.max_windows {
+                    windows.remove(0);
+                }
+                let len = (self.size - start).min(self.window_size as u64) as usize;
+                let mut region = self.device.map_resource_window(self.bar_nr, start, len)?;
+                region.set_access_widths(self.widths.0, self.widths.1);
+                windows.push(Window { start, region });
+            }
+        }
+        let window = windows.last().expect("window was just used");
+        Ok(f(&window.region, (offset - start) as usize))
+    }
+
+    /// Calls `f` for each window-sized piece of the `len` bytes at
+    /// `offset`, with its offset in the window and in the bytes.
+    fn for_each_piece<F>(&self, offset: u64, len: usize, mut f: F) -> Result<(), UioError>
+    where
+        F: FnMut(&MappedRegion<'a>, usize, usize, usize),
+    {
+        self.check_bounds(offset, len);
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let in_window = self.window_size - (at % self.window_size as u64) as usize;
+            let n = in_window.min(len - done);
+            self.with_window(at, |region, window_offset| {
+                f(region, window_offset, done, n)
+            })?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copies `buf.len()` bytes at `offset` into `buf`, see
+    /// `MappedRegion::read_bytes`. The bytes may span several windows.
+    ///
+    /// # Panics
+    /// If the access is out of bounds.
+    pub fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), UioError> {
+        self.for_each_piece(offset, buf.len(), |region, at, done, n| {
+            region.read_bytes(at, &mut buf[done..done + n])
+        })
+    }
+
+    /// Copies `buf` to `offset`, see `MappedRegion::write_bytes`. The bytes
+    /// may span several windows.
+    ///
+    /// # Panics
+    /// If the access is out of bounds.
+    pub fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), UioError> {
+        self.for_each_piece(offset, buf.len(), |region, at, done, n| {
+            region.write_bytes(at, &buf[done..done + n])
+        })
+    }
+
+    accessors!(
+        u8, read_u8, write_u8;
+        u16, read_u16, write_u16;
+        u32, read_u32, write_u32;
+        u64, read_u64, write_u64
+    );
+}
+
+impl<'a> fmt::Debug for WindowedBar<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WindowedBar")
+            .field("bar_nr", &self.bar_nr)
+            .field("size", &self.size)
+            .field("window_size", &self.window_size)
+            .field(
+                "windows",
+                &self.windows().iter().map(|w| w.start).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mock::MockUio;
+    use {AccessWidths, Unsupported};
+
+    #[test]
+    fn windows() {
+        let mock = MockUio::new(0).unwrap();
+        // Sparse, so it takes no space
+        mock.add_resource(0, 64 << 30).unwrap();
+        let dev = mock.open().unwrap();
+        let mut bar = dev.map_resource_windowed(0, 0x10000, 2).unwrap();
+        assert_eq!(bar.size(), 64 << 30);
+        assert_eq!(bar.mapped_windows(), 0);
+
+        bar.write_u64((64 << 30) - 8, 0x1122_3344_5566_7788)
+            .unwrap();
+        bar.write_u32(0x4, 42).unwrap();
+        assert_eq!(bar.mapped_windows(), 2);
+        // Evicts the window at the end of the BAR, not the more recently used one
+        bar.write_u16(0x20000, 7).unwrap();
+        assert_eq!(bar.mapped_windows(), 2);
+        assert_eq!(bar.read_u32(0x4).unwrap(), 42);
+        assert_eq!(bar.read_u16(0x20000).unwrap(), 7);
+        assert_eq!(bar.read_u64((64 << 30) - 8).unwrap(), 0x1122_3344_5566_7788);
+
+        // Across a window boundary
+        let data: Vec<u8> = (0..32).collect();
+        bar.write_bytes(0x2fff0, &data).unwrap();
+        let mut buf = [0u8; 32];
+        bar.read_bytes(0x2fff0, &mut buf).unwrap();
+        assert_eq!(buf[..], data[..]);
+        assert_eq!(bar.read_u8(0x30000).unwrap(), 16);
+
+        bar.set_access_widths(AccessWidths::U32, Unsupported::Widen);
+        assert_eq!(bar.read_u8(0x30001).unwrap(), 17);
+        assert_eq!(bar.read_u8((64 << 30) - 1).unwrap(), 0x11);
+
+        bar.unmap_all();
+        assert_eq!(bar.mapped_windows(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn window_out_of_bounds() {
+        let mock = MockUio::new(0).unwrap();
+        mock.add_resource(0, 0x2000).unwrap();
+        let dev = mock.open().unwrap();
+        let bar = dev.map_resource_windowed(0, 0x1000, 1).unwrap();
+        let _ = bar.read_u32(0x2000);
+    }
+}
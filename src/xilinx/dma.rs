@@ -0,0 +1,543 @@
+//! AXI DMA (PG021) in simple and scatter-gather mode.
+//!
+//! The core has two channels: MM2S reads from memory and sends to an AXI4
+//! stream, S2MM receives a stream and writes it to memory. The buffer
+//! addresses given to it are bus (physical) addresses, e.g. from a
+//! `uio_dmem_genirq` mapping or a reserved memory region.
+//!
+//! A simple mode transfer:
+//!
+//! ```ignore
+//! let dma = AxiDma::new(&regs);
+//! dma.reset()?;
+//! let rx = dma.s2mm();
+//! rx.enable_interrupts(IRQ_IOC | IRQ_ERROR);
+//! rx.transfer(buf_phys, 4096)?;
+//! rx.wait(&dev)?;
+//! let received = rx.transferred();
+//! ```
+//!
+//! In scatter-gather mode the transfers are described by an `SgRing` of
+//! descriptors in memory the core can access.
+
+use std::io;
+use std::time::{Duration, Instant};
+use {DeviceMemory, MappedRegion, UioDevice};
+
+const DMACR: usize = 0x00;
+const DMASR: usize = 0x04;
+const CURDESC: usize = 0x08;
+const TAILDESC: usize = 0x10;
+/// Source (MM2S) or destination (S2MM) address in simple mode
+const ADDR: usize = 0x18;
+const LENGTH: usize = 0x28;
+
+/// DMACR: run/stop.
+const CR_RUN: u32 = 1 << 0;
+/// DMACR: soft reset of both channels.
+const CR_RESET: u32 = 1 << 2;
+
+/// Interrupt on completion.
+pub const IRQ_IOC: u32 = 1 << 12;
+/// Interrupt on the delay timer.
+pub const IRQ_DELAY: u32 = 1 << 13;
+/// Interrupt on errors.
+pub const IRQ_ERROR: u32 = 1 << 14;
+/// All interrupts.
+pub const IRQ_ALL: u32 = IRQ_IOC | IRQ_DELAY | IRQ_ERROR;
+
+/// Largest transfer of the core with the maximum buffer length register
+/// width (26 bits).
+pub const MAX_TRANSFER: u32 = (1 << 26) - 1;
+
+/// How long `AxiDma::reset` waits for the reset to complete.
+const RESET_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A channel of the core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Memory to stream
+    Mm2s,
+    /// Stream to memory
+    S2mm,
+}
+
+impl Direction {
+    fn base(self) -> usize {
+        match self {
+            Direction::Mm2s => 0x00,
+            Direction::S2mm => 0x30,
+        }
+    }
+}
+
+/// The contents of a channel's status register (DMASR).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub u32);
+
+impl Status {
+    pub const HALTED: u32 = 1 << 0;
+    pub const IDLE: u32 = 1 << 1;
+    /// The core was built with scatter-gather support
+    pub const SG_INCLUDED: u32 = 1 << 3;
+    pub const INTERNAL_ERROR: u32 = 1 << 4;
+    pub const SLAVE_ERROR: u32 = 1 << 5;
+    pub const DECODE_ERROR: u32 = 1 << 6;
+    pub const SG_INTERNAL_ERROR: u32 = 1 << 8;
+    pub const SG_SLAVE_ERROR: u32 = 1 << 9;
+    pub const SG_DECODE_ERROR: u32 = 1 << 10;
+    const ERRORS: u32 = 0x770;
+
+    pub fn halted(self) -> bool {
+        self.0 & Status::HALTED != 0
+    }
+
+    pub fn idle(self) -> bool {
+        self.0 & Status::IDLE != 0
+    }
+
+    pub fn sg_included(self) -> bool {
+        self.0 & Status::SG_INCLUDED != 0
+    }
+
+    /// The error bits, 0 if there are none.
+    pub fn errors(self) -> u32 {
+        self.0 & Status::ERRORS
+    }
+
+    /// The pending `IRQ_*` interrupts.
+    pub fn interrupts(self) -> u32 {
+        self.0 & IRQ_ALL
+    }
+
+    fn check(self) -> io::Result<Status> {
+        match self.errors() {
+            0 => Ok(self),
+            errors => Err(io::Error::other(format!(
+                "DMA error (status {:#x})",
+                errors
+            ))),
+        }
+    }
+}
+
+/// An AXI DMA core.
+#[derive(Debug)]
+pub struct AxiDma<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    regs: &'r M,
+    addr64: bool,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> AxiDma<'r, M> {
+    /// A core with 32-bit addresses, whose registers are `regs`.
+    pub fn new(regs: &'r M) -> Self {
+        AxiDma {
+            regs,
+            addr64: false,
+        }
+    }
+
+    /// Whether the core was built with an address width above 32 bits, so
+    /// the upper halves of addresses are written as well.
+    pub fn with_64bit_addresses(mut self, addr64: bool) -> Self {
+        self.addr64 = addr64;
+        self
+    }
+
+    /// Resets both channels, which stops them and clears all settings.
+    ///
+    /// Fails with `TimedOut` if the core doesn't complete the reset (e.g.
+    /// because the stream clock is not running).
+    pub fn reset(&self) -> io::Result<()> {
+        self.regs.write_u32(DMACR, CR_RESET);
+        let deadline = Instant::now() + RESET_TIMEOUT;
+        while [Direction::Mm2s, Direction::S2mm]
+            .iter()
+            .any(|d| self.channel(*d).control() & CR_RESET != 0)
+        {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "DMA reset timed out",
+                ));
+            }
+            ::std::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// One of the channels.
+    pub fn channel(&self, direction: Direction) -> DmaChannel<'_, M> {
+        DmaChannel {
+            regs: self.regs,
+            base: direction.base(),
+            addr64: self.addr64,
+        }
+    }
+
+    /// The memory to stream channel.
+    pub fn mm2s(&self) -> DmaChannel<'_, M> {
+        self.channel(Direction::Mm2s)
+    }
+
+    /// The stream to memory channel.
+    pub fn s2mm(&self) -> DmaChannel<'_, M> {
+        self.channel(Direction::S2mm)
+    }
+}
+
+/// A channel of an `AxiDma`.
+#[derive(Debug)]
+pub struct DmaChannel<'d, M: DeviceMemory + ?Sized + 'd> {
+    regs: &'d M,
+    base: usize,
+    addr64: bool,
+}
+
+impl<'d, M: DeviceMemory + ?Sized> DmaChannel<'d, M> {
+    fn read(&self, reg: usize) -> u32 {
+        self.regs.read_u32(self.base + reg)
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        self.regs.write_u32(self.base + reg, value)
+    }
+
+    fn check_addr(&self, addr: u64) -> io::Result<()> {
+        if !self.addr64 && addr > u64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("address {:#x} needs a core with 64-bit addresses", addr),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_addr(&self, reg: usize, addr: u64) -> io::Result<()> {
+        self.check_addr(addr)?;
+        self.write(reg, addr as u32);
+        if self.addr64 {
+            self.write(reg + 4, (addr >> 32) as u32);
+        }
+        Ok(())
+    }
+
+    /// The control register (DMACR).
+    pub fn control(&self) -> u32 {
+        self.read(DMACR)
+    }
+
+    /// The status register.
+    pub fn status(&self) -> Status {
+        Status(self.read(DMASR))
+    }
+
+    /// Enables the `IRQ_*` interrupts in `mask`.
+    pub fn enable_interrupts(&self, mask: u32) {
+        self.write(DMACR, self.control() | (mask & IRQ_ALL));
+    }
+
+    /// Disables the `IRQ_*` interrupts in `mask`.
+    pub fn disable_interrupts(&self, mask: u32) {
+        self.write(DMACR, self.control() & !(mask & IRQ_ALL));
+    }
+
+    /// Acknowledges all pending interrupts and returns the status from
+    /// before.
+    pub fn ack(&self) -> Status {
+        let status = self.status();
+        self.write(DMASR, status.interrupts());
+        status
+    }
+
+    /// Sets the run bit. The channel only starts transferring once it is
+    /// given a length (simple mode) or tail descriptor (scatter-gather).
+    pub fn start(&self) {
+        self.write(DMACR, self.control() | CR_RUN);
+    }
+
+    /// Clears the run bit; the channel halts after the current transfer.
+    pub fn stop(&self) {
+        self.write(DMACR, self.control() & !CR_RUN);
+    }
+
+    /// Starts a simple mode transfer of `len` bytes from or to the bus
+    /// address `addr`.
+    ///
+    /// Fails if the core is in scatter-gather mode, `len` is 0 or larger
+    /// than `MAX_TRANSFER` or the channel reports an error.
+    pub fn transfer(&self, addr: u64, len: u32) -> io::Result<()> {
+        let status = self.status().check()?;
+        if status.sg_included() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "simple transfer on a core in scatter-gather mode",
+            ));
+        }
+        if len == 0 || len > MAX_TRANSFER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid DMA transfer length {:#x}", len),
+            ));
+        }
+        self.check_addr(addr)?;
+        self.start();
+        self.write_addr(ADDR, addr)?;
+        // Writing the length starts the transfer
+        self.write(LENGTH, len);
+        Ok(())
+    }
+
+    /// Bytes transferred by the last simple mode transfer, which for S2MM
+    /// may be less than requested if the stream packet ended early.
+    pub fn transferred(&self) -> u32 {
+        self.read(LENGTH) & MAX_TRANSFER
+    }
+
+    /// Processes the descriptors of `ring` from `first` to `last`
+    /// (inclusive, wrapping around). The channel must be halted, e.g.
+    /// after `AxiDma::reset`.
+    pub fn start_sg<R: DeviceMemory + ?Sized>(
+        &self,
+        ring: &SgRing<'_, R>,
+        first: usize,
+        last: usize,
+    ) -> io::Result<()> {
+        let status = self.status().check()?;
+        if !status.halted() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "channel must be halted to start scatter-gather",
+            ));
+        }
+        let (current, tail) = (ring.phys_addr(first), ring.phys_addr(last));
+        self.check_addr(current)?;
+        self.check_addr(tail)?;
+        self.write_addr(CURDESC, current)?;
+        self.start();
+        // Writing the tail descriptor starts the transfer
+        self.write_addr(TAILDESC, tail)
+    }
+
+    /// Enables the UIO interrupt, waits for it and acknowledges the
+    /// channel's interrupts. Fails if the channel reports an error.
+    pub fn wait(&self, device: &UioDevice) -> io::Result<Status> {
+        device.irq_enable()?;
+        device.irq_wait()?;
+        self.ack().check()
+    }
+}
+
+const DESC_LEN: usize = 0x40;
+const DESC_NEXT: usize = 0x00;
+const DESC_BUFFER: usize = 0x08;
+const DESC_CONTROL: usize = 0x18;
+const DESC_STATUS: usize = 0x1c;
+/// CONTROL: first descriptor of a packet (MM2S)
+const CONTROL_SOF: u32 = 1 << 27;
+/// CONTROL: last descriptor of a packet (MM2S)
+const CONTROL_EOF: u32 = 1 << 26;
+
+/// The status word of a scatter-gather descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptorStatus(pub u32);
+
+impl DescriptorStatus {
+    /// The descriptor was processed
+    pub const COMPLETE: u32 = 1 << 31;
+    pub const DECODE_ERROR: u32 = 1 << 30;
+    pub const SLAVE_ERROR: u32 = 1 << 29;
+    pub const INTERNAL_ERROR: u32 = 1 << 28;
+    /// The buffer holds the start of a stream packet (S2MM)
+    pub const RX_SOF: u32 = 1 << 27;
+    /// The buffer holds the end of a stream packet (S2MM)
+    pub const RX_EOF: u32 = 1 << 26;
+
+    pub fn complete(self) -> bool {
+        self.0 & DescriptorStatus::COMPLETE != 0
+    }
+
+    /// The error bits, 0 if there are none.
+    pub fn errors(self) -> u32 {
+        self.0 & 0x7000_0000
+    }
+
+    /// Bytes transferred to or from the buffer.
+    pub fn transferred(self) -> u32 {
+        self.0 & MAX_TRANSFER
+    }
+}
+
+/// A circular list of scatter-gather descriptors in memory accessible to
+/// the core.
+#[derive(Debug)]
+pub struct SgRing<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    mem: &'r M,
+    offset: usize,
+    phys: u64,
+    count: usize,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> SgRing<'r, M> {
+    /// `count` descriptors at `offset` of `mem`, which the core sees at the
+    /// bus address `phys`. The descriptors are linked into a ring and
+    /// cleared.
+    ///
+    /// # Panics
+    /// If `offset` or `phys` are not 64-byte aligned, `count` is 0 or the
+    /// descriptors don't fit `mem`.
+    pub fn new(mem: &'r M, offset: usize, phys: u64, count: usize) -> Self {
+        assert!(
+            offset.is_multiple_of(DESC_LEN) && phys.is_multiple_of(DESC_LEN as u64),
+            "descriptors must be 64-byte aligned"
+        );
+        assert!(
+            count > 0
+                && count
+                    .checked_mul(DESC_LEN)
+                    .and_then(|len| len.checked_add(offset))
+                    .is_some_and(|end| end <= mem.len()),
+            "{} descriptors at offset {:#x} don't fit memory of {:#x} bytes",
+            count,
+            offset,
+            mem.len()
+        );
+        let ring = SgRing {
+            mem,
+            offset,
+            phys,
+            count,
+        };
+        for i in 0..count {
+            let desc = ring.offset(i);
+            mem.write_bytes(desc, &[0; DESC_LEN]);
+            let next = ring.phys_addr((i + 1) % count);
+            mem.write_u32(desc + DESC_NEXT, next as u32);
+            mem.write_u32(desc + DESC_NEXT + 4, (next >> 32) as u32);
+        }
+        ring
+    }
+
+    fn offset(&self, index: usize) -> usize {
+        assert!(index < self.count, "descriptor {} out of range", index);
+        self.offset + index * DESC_LEN
+    }
+
+    /// Number of descriptors.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Whether the ring has no descriptors, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Bus address of descriptor `index`.
+    pub fn phys_addr(&self, index: usize) -> u64 {
+        self.offset(index);
+        self.phys + (index * DESC_LEN) as u64
+    }
+
+    /// Points descriptor `index` at the buffer of `len` bytes at bus address
+    /// `addr` and clears its status. `sof` and `eof` mark the first and last
+    /// buffer of a packet for MM2S and are ignored for S2MM.
+    ///
+    /// # Panics
+    /// If `len` is larger than `MAX_TRANSFER`.
+    pub fn set(&self, index: usize, addr: u64, len: u32, sof: bool, eof: bool) {
+        assert!(len <= MAX_TRANSFER, "buffer length {:#x} too large", len);
+        let desc = self.offset(index);
+        self.mem.write_u32(desc + DESC_BUFFER, addr as u32);
+        self.mem
+            .write_u32(desc + DESC_BUFFER + 4, (addr >> 32) as u32);
+        let mut control = len;
+        if sof {
+            control |= CONTROL_SOF;
+        }
+        if eof {
+            control |= CONTROL_EOF;
+        }
+        self.mem.write_u32(desc + DESC_CONTROL, control);
+        self.mem.write_u32(desc + DESC_STATUS, 0);
+    }
+
+    /// The status of descriptor `index`, written by the core.
+    pub fn status(&self, index: usize) -> DescriptorStatus {
+        DescriptorStatus(self.mem.read_u32(self.offset(index) + DESC_STATUS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+
+    /// Registers of a DMA core which completes resets immediately.
+    fn core() -> MockMemory {
+        let mut regs = MockMemory::new(0x100);
+        regs.on_write(|mem, offset, _, value| {
+            if offset == DMACR && value as u32 & CR_RESET != 0 {
+                mem[DMACR] = 0;
+                mem[DMASR] = Status::HALTED as u8;
+                mem[0x30 + DMASR] = Status::HALTED as u8;
+            }
+        });
+        regs
+    }
+
+    #[test]
+    fn simple_transfer() {
+        let regs = core();
+        let dma = AxiDma::new(&regs);
+        dma.reset().unwrap();
+        let rx = dma.s2mm();
+        assert!(rx.status().halted());
+        rx.enable_interrupts(IRQ_IOC | IRQ_ERROR);
+        rx.transfer(0x3000_0000, 4096).unwrap();
+        assert_eq!(regs.read_u32(0x30 + DMACR), CR_RUN | IRQ_IOC | IRQ_ERROR);
+        assert_eq!(regs.read_u32(0x30 + ADDR), 0x3000_0000);
+        assert_eq!(rx.transferred(), 4096);
+
+        rx.stop();
+        assert!(rx.transfer(0x1_0000_0000, 16).is_err());
+        assert_eq!(rx.control() & CR_RUN, 0);
+        regs.write_u32(0x30 + DMASR, Status::SLAVE_ERROR | IRQ_ERROR);
+        assert!(rx.transfer(0x3000_0000, 16).is_err());
+        assert_eq!(rx.ack().interrupts(), IRQ_ERROR);
+    }
+
+    #[test]
+    fn scatter_gather() {
+        let regs = core();
+        let descs = MockMemory::new(0x1000);
+        let ring = SgRing::new(&descs, 0x100, 0x8000_0100, 4);
+        ring.set(0, 0x9000_0000, 0x800, true, false);
+        ring.set(1, 0x9000_0800, 0x800, false, true);
+        assert_eq!(descs.read_u32(0x100 + DESC_NEXT), 0x8000_0140);
+        assert_eq!(descs.read_u32(0x1c0 + DESC_NEXT), 0x8000_0100);
+        assert_eq!(descs.read_u32(0x100 + DESC_CONTROL), 0x800 | CONTROL_SOF);
+
+        let dma = AxiDma::new(&regs).with_64bit_addresses(true);
+        dma.reset().unwrap();
+        let tx = dma.mm2s();
+        tx.start_sg(&ring, 0, 1).unwrap();
+        assert_eq!(regs.read_u32(CURDESC), 0x8000_0100);
+        assert_eq!(regs.read_u32(TAILDESC), 0x8000_0140);
+
+        descs.write_u32(0x100 + DESC_STATUS, DescriptorStatus::COMPLETE | 0x800);
+        assert!(ring.status(0).complete());
+        assert_eq!(ring.status(0).transferred(), 0x800);
+        assert!(!ring.status(1).complete());
+
+        // The tail lies above 4 GiB, the channel must stay halted
+        let regs = core();
+        let high = SgRing::new(&descs, 0x200, 0xffff_ff80, 4);
+        let dma = AxiDma::new(&regs);
+        dma.reset().unwrap();
+        let tx = dma.mm2s();
+        assert!(tx.start_sg(&high, 0, 2).is_err());
+        assert_eq!(tx.control() & CR_RUN, 0);
+        assert_eq!(regs.read_u32(CURDESC), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! AXI GPIO (PG144) with one or two channels of up to 32 pins.
+
+use {DeviceMemory, MappedRegion};
+
+const DATA: usize = 0x00;
+const TRI: usize = 0x04;
+/// Global interrupt enable
+const GIER: usize = 0x11c;
+const GIER_ENABLE: u32 = 1 << 31;
+/// Interrupt status, bits are cleared by writing 1 ("toggle on write")
+const IP_ISR: usize = 0x120;
+const IP_IER: usize = 0x128;
+
+/// A channel of the core. The second one only exists if the core was
+/// built with dual channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    First,
+    Second,
+}
+
+impl Channel {
+    fn base(self) -> usize {
+        match self {
+            Channel::First => 0x00,
+            Channel::Second => 0x08,
+        }
+    }
+
+    /// The bit of the channel in the interrupt registers.
+    pub fn irq_mask(self) -> u32 {
+        match self {
+            Channel::First => 1,
+            Channel::Second => 2,
+        }
+    }
+}
+
+/// An AXI GPIO core.
+#[derive(Debug)]
+pub struct AxiGpio<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    regs: &'r M,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> AxiGpio<'r, M> {
+    /// A core whose registers are `regs`.
+    pub fn new(regs: &'r M) -> Self {
+        AxiGpio { regs }
+    }
+
+    /// Configures the pins in `inputs` as inputs and all others as outputs.
+    pub fn set_inputs(&self, channel: Channel, inputs: u32) {
+        self.regs.write_u32(channel.base() + TRI, inputs);
+    }
+
+    /// The levels of the input pins and the driven values of the outputs.
+    pub fn read(&self, channel: Channel) -> u32 {
+        self.regs.read_u32(channel.base() + DATA)
+    }
+
+    /// Sets the output pins to `value`.
+    pub fn write(&self, channel: Channel, value: u32) {
+        self.regs.write_u32(channel.base() + DATA, value);
+    }
+
+    /// Drives the output pins in `mask` high, leaving the others unchanged.
+    pub fn set_bits(&self, channel: Channel, mask: u32) {
+        self.write(channel, self.read(channel) | mask);
+    }
+
+    /// Drives the output pins in `mask` low, leaving the others unchanged.
+    pub fn clear_bits(&self, channel: Channel, mask: u32) {
+        self.write(channel, self.read(channel) & !mask);
+    }
+
+    /// Enables the interrupt on input changes for `channel` (if the core
+    /// was built with interrupt support).
+    pub fn enable_interrupt(&self, channel: Channel) {
+        let ier = self.regs.read_u32(IP_IER);
+        self.regs.write_u32(IP_IER, ier | channel.irq_mask());
+        self.regs.write_u32(GIER, GIER_ENABLE);
+    }
+
+    /// Disables the interrupt of `channel`.
+    pub fn disable_interrupt(&self, channel: Channel) {
+        let ier = self.regs.read_u32(IP_IER);
+        self.regs.write_u32(IP_IER, ier & !channel.irq_mask());
+    }
+
+    /// Acknowledges and returns the pending interrupts, as a mask of
+    /// `Channel::irq_mask` bits.
+    pub fn ack(&self) -> u32 {
+        let pending = self.regs.read_u32(IP_ISR) & 3;
+        if pending != 0 {
+            self.regs.write_u32(IP_ISR, pending);
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+
+    #[test]
+    fn gpio() {
+        let mut regs = MockMemory::new(0x200);
+        // The driver writes back the pending bits to clear them, byte
+        // writes simulate the core raising them
+        regs.on_write(|mem, offset, width, _| {
+            if offset == IP_ISR && width == 4 {
+                mem[IP_ISR..IP_ISR + 4].copy_from_slice(&[0; 4]);
+            }
+        });
+        let gpio = AxiGpio::new(&regs);
+        gpio.set_inputs(Channel::Second, 0xff);
+        gpio.write(Channel::First, 0b1010);
+        gpio.set_bits(Channel::First, 0b0001);
+        gpio.clear_bits(Channel::First, 0b1000);
+        assert_eq!(gpio.read(Channel::First), 0b0011);
+        assert_eq!(regs.read_u32(0x0c), 0xff);
+
+        gpio.enable_interrupt(Channel::Second);
+        assert_eq!(regs.read_u32(IP_IER), 2);
+        assert_eq!(regs.read_u32(GIER), GIER_ENABLE);
+        assert_eq!(gpio.ack(), 0);
+        regs.write_u8(IP_ISR, 2);
+        assert_eq!(gpio.ack(), 2);
+        assert_eq!(gpio.ack(), 0);
+    }
+}
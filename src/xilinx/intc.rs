@@ -0,0 +1,119 @@
+//! AXI Interrupt Controller (PG099), which combines up to 32 interrupt
+//! lines of other cores into one.
+
+use {DeviceMemory, MappedRegion};
+
+/// Interrupt status
+const ISR: usize = 0x00;
+/// Interrupt pending (status and enabled)
+const IPR: usize = 0x04;
+/// Interrupt enable
+const IER: usize = 0x08;
+/// Interrupt acknowledge
+const IAR: usize = 0x0c;
+/// Master enable
+const MER: usize = 0x1c;
+/// MER: enable the interrupt output
+const MER_ME: u32 = 1 << 0;
+/// MER: enable hardware interrupts (as opposed to software ones only),
+/// can't be cleared again once set
+const MER_HIE: u32 = 1 << 1;
+
+/// An AXI INTC core.
+#[derive(Debug)]
+pub struct AxiIntc<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    regs: &'r M,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> AxiIntc<'r, M> {
+    /// A core whose registers are `regs`.
+    pub fn new(regs: &'r M) -> Self {
+        AxiIntc { regs }
+    }
+
+    /// Enables the interrupt output for hardware interrupts. The inputs
+    /// still need to be enabled with `enable`.
+    pub fn start(&self) {
+        self.regs.write_u32(MER, MER_ME | MER_HIE);
+    }
+
+    /// Enables the inputs in `mask`.
+    pub fn enable(&self, mask: u32) {
+        let ier = self.regs.read_u32(IER);
+        self.regs.write_u32(IER, ier | mask);
+    }
+
+    /// Disables the inputs in `mask`.
+    pub fn disable(&self, mask: u32) {
+        let ier = self.regs.read_u32(IER);
+        self.regs.write_u32(IER, ier & !mask);
+    }
+
+    /// The active inputs, whether enabled or not.
+    pub fn status(&self) -> u32 {
+        self.regs.read_u32(ISR)
+    }
+
+    /// The active and enabled inputs.
+    pub fn pending(&self) -> u32 {
+        self.regs.read_u32(IPR)
+    }
+
+    /// Acknowledges the inputs in `mask`. Level-triggered inputs have to be
+    /// cleared at their source first.
+    pub fn ack(&self, mask: u32) {
+        self.regs.write_u32(IAR, mask);
+    }
+
+    /// Calls `handler` with the number of every pending input, lowest
+    /// first, acknowledging each after the handler returns. Returns the
+    /// mask of handled inputs.
+    pub fn dispatch<F: FnMut(u32)>(&self, mut handler: F) -> u32 {
+        let pending = self.pending();
+        let mut bits = pending;
+        while bits != 0 {
+            let irq = bits.trailing_zeros();
+            handler(irq);
+            self.ack(1 << irq);
+            bits &= bits - 1;
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+
+    #[test]
+    fn intc() {
+        let mut regs = MockMemory::new(0x100);
+        // Acknowledging clears the status, IPR is ISR & IER
+        regs.on_write(|mem, offset, _, value| {
+            let word = |mem: &[u8], o: usize| {
+                u32::from_ne_bytes([mem[o], mem[o + 1], mem[o + 2], mem[o + 3]])
+            };
+            let mut isr = word(mem, ISR);
+            if offset == IAR {
+                isr &= !(value as u32);
+            }
+            let ipr = isr & word(mem, IER);
+            mem[ISR..ISR + 4].copy_from_slice(&isr.to_ne_bytes());
+            mem[IPR..IPR + 4].copy_from_slice(&ipr.to_ne_bytes());
+        });
+        let intc = AxiIntc::new(&regs);
+        intc.start();
+        assert_eq!(regs.read_u32(MER), 3);
+        intc.enable(0b1101);
+        intc.disable(0b0100);
+        regs.write_u32(ISR, 0b1011);
+        assert_eq!(intc.pending(), 0b1001);
+
+        let mut handled = Vec::new();
+        assert_eq!(intc.dispatch(|irq| handled.push(irq)), 0b1001);
+        assert_eq!(handled, [0, 3]);
+        assert_eq!(intc.status(), 0b0010);
+        assert_eq!(intc.pending(), 0);
+    }
+}
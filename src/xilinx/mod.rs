@@ -0,0 +1,20 @@
+//! Helpers for Xilinx IP cores, which are commonly driven through UIO on
+//! Zynq and other FPGA platforms (feature `xilinx`).
+//!
+//! The helpers (AXI DMA, GPIO, interrupt controller and timer) program the
+//! registers of a core through any `DeviceMemory`, usually the
+//! `MappedRegion` of the UIO device the core is exposed as:
+//!
+//! ```ignore
+//! use uio::xilinx::dma::{AxiDma, Direction};
+//!
+//! let dev = UioDevice::blocking_new(0)?;
+//! let regs = dev.map_region(0)?;
+//! let dma = AxiDma::new(&regs);
+//! dma.reset()?;
+//! ```
+
+pub mod dma;
+pub mod gpio;
+pub mod intc;
+pub mod timer;
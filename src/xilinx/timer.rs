@@ -0,0 +1,141 @@
+//! AXI Timer (PG079) with two 32-bit counters.
+
+use {DeviceMemory, MappedRegion};
+
+/// Control/status
+const TCSR: usize = 0x00;
+/// Load value
+const TLR: usize = 0x04;
+/// Counter value
+const TCR: usize = 0x08;
+
+/// TCSR: count down
+const UDT: u32 = 1 << 1;
+/// TCSR: reload and keep counting when the counter expires
+const ARHT: u32 = 1 << 4;
+/// TCSR: load TLR into the counter
+const LOAD: u32 = 1 << 5;
+/// TCSR: interrupt enable
+const ENIT: u32 = 1 << 6;
+/// TCSR: counter enable
+const ENT: u32 = 1 << 7;
+/// TCSR: interrupt pending, cleared by writing 1
+const TINT: u32 = 1 << 8;
+
+/// An AXI Timer core.
+#[derive(Debug)]
+pub struct AxiTimer<'r, M: DeviceMemory + ?Sized + 'r = MappedRegion<'r>> {
+    regs: &'r M,
+}
+
+impl<'r, M: DeviceMemory + ?Sized> AxiTimer<'r, M> {
+    /// A core whose registers are `regs`.
+    pub fn new(regs: &'r M) -> Self {
+        AxiTimer { regs }
+    }
+
+    /// Counter 0 or 1 of the core.
+    ///
+    /// # Panics
+    /// If `index` is larger than 1.
+    pub fn counter(&self, index: usize) -> Counter<'_, M> {
+        assert!(index < 2, "AXI Timer has no counter {}", index);
+        Counter {
+            regs: self.regs,
+            base: index * 0x10,
+        }
+    }
+}
+
+/// A counter of an `AxiTimer`, counting down in generate mode.
+#[derive(Debug)]
+pub struct Counter<'t, M: DeviceMemory + ?Sized + 't> {
+    regs: &'t M,
+    base: usize,
+}
+
+impl<'t, M: DeviceMemory + ?Sized> Counter<'t, M> {
+    fn start(&self, ticks: u32, mode: u32, interrupt: bool) {
+        let irq = if interrupt { ENIT } else { 0 };
+        self.regs.write_u32(self.base + TCSR, 0);
+        self.regs.write_u32(self.base + TLR, ticks);
+        self.regs.write_u32(self.base + TCSR, LOAD | TINT);
+        self.regs
+            .write_u32(self.base + TCSR, UDT | mode | irq | ENT);
+    }
+
+    /// Expires every `ticks` clock cycles, with an interrupt if
+    /// `interrupt` is set.
+    pub fn start_periodic(&self, ticks: u32, interrupt: bool) {
+        self.start(ticks, ARHT, interrupt);
+    }
+
+    /// Expires once after `ticks` clock cycles.
+    pub fn start_oneshot(&self, ticks: u32, interrupt: bool) {
+        self.start(ticks, 0, interrupt);
+    }
+
+    /// Stops counting.
+    pub fn stop(&self) {
+        let tcsr = self.regs.read_u32(self.base + TCSR);
+        self.regs.write_u32(self.base + TCSR, tcsr & !(ENT | TINT));
+    }
+
+    /// The current counter value.
+    pub fn value(&self) -> u32 {
+        self.regs.read_u32(self.base + TCR)
+    }
+
+    /// Whether the counter expired since the last `ack`.
+    pub fn expired(&self) -> bool {
+        self.regs.read_u32(self.base + TCSR) & TINT != 0
+    }
+
+    /// Clears the expiry (interrupt) flag, returning whether it was set.
+    pub fn ack(&self) -> bool {
+        let tcsr = self.regs.read_u32(self.base + TCSR);
+        if tcsr & TINT != 0 {
+            self.regs.write_u32(self.base + TCSR, tcsr);
+        }
+        tcsr & TINT != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockMemory;
+
+    #[test]
+    fn timer() {
+        let mut regs = MockMemory::new(0x20);
+        // LOAD copies TLR to TCR, writing TINT clears it
+        regs.on_write(|mem, offset, _, value| {
+            let base = offset & !0xf;
+            if offset & 0xf == TCSR {
+                let mut tcsr = value as u32 & !TINT;
+                if value as u32 & LOAD != 0 {
+                    let tlr = base + TLR;
+                    let load = [mem[tlr], mem[tlr + 1], mem[tlr + 2], mem[tlr + 3]];
+                    mem[base + TCR..base + TCR + 4].copy_from_slice(&load);
+                    tcsr &= !LOAD;
+                }
+                mem[base..base + 4].copy_from_slice(&tcsr.to_ne_bytes());
+            }
+        });
+        let timer = AxiTimer::new(&regs);
+        let counter = timer.counter(1);
+        counter.start_periodic(100_000, true);
+        assert_eq!(regs.read_u32(0x10 + TCSR), UDT | ARHT | ENIT | ENT);
+        assert_eq!(counter.value(), 100_000);
+        assert!(!counter.expired());
+
+        regs.write_u8(0x11, (TINT >> 8) as u8);
+        assert!(counter.expired());
+        assert!(counter.ack());
+        assert!(!counter.ack());
+        counter.stop();
+        assert_eq!(regs.read_u32(0x10 + TCSR), UDT | ARHT | ENIT);
+        assert_eq!(timer.counter(0).value(), 0);
+    }
+}
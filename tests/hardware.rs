@@ -0,0 +1,161 @@
+//! Tests against a real UIO device.
+//!
+//! The device is described by environment variables, all tests are skipped
+//! if `UIO_TEST_DEVICE` isn't set:
+//!
+//! - `UIO_TEST_DEVICE`: the UIO number to test (0 for /dev/uio0)
+//! - `UIO_TEST_NAME`: expected device name, e.g. `uio_pci_generic`
+//! - `UIO_TEST_VERSION`: expected driver version, e.g. `0.01.0`
+//! - `UIO_TEST_BARS`: comma-separated PCI BARs that can be mapped, e.g. `0,5`
+//! - `UIO_TEST_MAPS`: comma-separated mapping indices that can be mapped
+//!
+//! Alternatively, `UIO_TEST_CONFIG` can point to a file with one
+//! `KEY=value` line per variable (without the `UIO_TEST_` prefix, `#` starts
+//! a comment). Environment variables take precedence over the file.
+//!
+//! ```text
+//! $ UIO_TEST_DEVICE=0 UIO_TEST_NAME=uio_pci_generic UIO_TEST_BARS=5 cargo test --test hardware
+//! ```
+
+extern crate uio;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use uio::{LockMode, UioDevice, UioDeviceBuilder};
+
+struct Config {
+    device: usize,
+    vars: HashMap<String, String>,
+}
+
+impl Config {
+    /// Reads the configuration, or returns `None` if no device is configured.
+    fn load() -> Option<Config> {
+        let mut vars = HashMap::new();
+        if let Ok(path) = env::var("UIO_TEST_CONFIG") {
+            let contents = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("can't read UIO_TEST_CONFIG {}: {}", path, e));
+            for line in contents.lines() {
+                let line = line.split('#').next().unwrap().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let (key, value) = line
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("invalid line in {}: {:?}", path, line));
+                vars.insert(key.trim().to_uppercase(), value.trim().to_string());
+            }
+        }
+        for (key, value) in env::vars() {
+            if let Some(key) = key.strip_prefix("UIO_TEST_") {
+                vars.insert(key.to_string(), value);
+            }
+        }
+
+        let device = match vars.get("DEVICE") {
+            Some(d) => d.parse().expect("UIO_TEST_DEVICE must be a number"),
+            None => {
+                eprintln!("UIO_TEST_DEVICE not set, skipping hardware test");
+                return None;
+            }
+        };
+        Some(Config { device, vars })
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(String::as_str)
+    }
+
+    fn list(&self, key: &str) -> Vec<usize> {
+        self.get(key)
+            .map(|l| {
+                l.split(',')
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| {
+                        s.trim()
+                            .parse()
+                            .unwrap_or_else(|_| panic!("invalid entry {:?} in UIO_TEST_{}", s, key))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn open(&self) -> UioDevice {
+        UioDeviceBuilder::new(self.device)
+            .lock(LockMode::Unlocked)
+            .open()
+            .unwrap_or_else(|e| panic!("can't open /dev/uio{}: {}", self.device, e))
+    }
+}
+
+#[test]
+fn open() {
+    if let Some(config) = Config::load() {
+        config.open();
+    }
+}
+
+#[test]
+fn print_info() {
+    let config = match Config::load() {
+        Some(c) => c,
+        None => return,
+    };
+    let dev = config.open();
+    let name = dev.get_name().expect("Can't get name");
+    let version = dev.get_version().expect("Can't get version");
+    dev.get_event_count().expect("Can't get event count");
+    if let Some(expected) = config.get("NAME") {
+        assert_eq!(name, expected);
+    }
+    if let Some(expected) = config.get("VERSION") {
+        assert_eq!(version, expected);
+    }
+}
+
+#[test]
+fn map_bars() {
+    let config = match Config::load() {
+        Some(c) => c,
+        None => return,
+    };
+    let dev = config.open();
+    for bar in config.list("BARS") {
+        dev.map_resource_region(bar)
+            .unwrap_or_else(|e| panic!("Can't map BAR {}: {}", bar, e));
+    }
+}
+
+#[test]
+fn map_mappings() {
+    let config = match Config::load() {
+        Some(c) => c,
+        None => return,
+    };
+    let dev = config.open();
+    for map in config.list("MAPS") {
+        dev.map_region(map)
+            .unwrap_or_else(|e| panic!("Can't map mapping {}: {}", map, e));
+    }
+}
+
+#[test]
+fn bar_info() {
+    let config = match Config::load() {
+        Some(c) => c,
+        None => return,
+    };
+    let mut dev = config.open();
+    let resources = dev.get_resource_info().expect("Can't get resource info");
+    for bar in config.list("BARS") {
+        let name = format!("resource{}", bar);
+        assert!(
+            resources.iter().any(|(n, _)| *n == name),
+            "{} missing from {:?}",
+            name,
+            resources
+        );
+    }
+}
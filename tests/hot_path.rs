@@ -0,0 +1,68 @@
+//! Checks that the hot path (see "Real-time use" in the `UioDevice` docs)
+//! doesn't allocate.
+//!
+//! This is a test binary of its own because it installs a counting global
+//! allocator, which would otherwise apply to all unit tests. Tracing and
+//! metrics allocate, so there is nothing to check with them.
+
+#![cfg(not(any(feature = "tracing", feature = "metrics")))]
+
+extern crate uio;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::Duration;
+use uio::mock::MockUio;
+use uio::EventLog;
+
+/// Counts the heap allocations of the current thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+#[test]
+fn hot_path_allocation_free() {
+    let mut mock = MockUio::new(0).unwrap();
+    mock.add_mapping(0x1000, 0x1000, "regs").unwrap();
+    mock.set_event_count(3).unwrap();
+    let mut dev = mock
+        .builder()
+        .auto_reenable(false)
+        .event_log(Some(EventLog::new(16)))
+        .open()
+        .unwrap();
+    dev.preopen().unwrap();
+    let regs = dev.map_region(0).unwrap();
+
+    let before = ALLOCATIONS.with(|a| a.get());
+    for i in 0..100 {
+        regs.write_u32(4, i);
+        assert_eq!(regs.read_u32(4), i);
+        // No `irq_enable`, the mock appends writes to the device file
+        // and `irq_wait` would then read at its end
+        dev.irq_wait().unwrap();
+        assert_eq!(dev.get_event_count().unwrap(), 3);
+        assert_eq!(
+            dev.wait_event_count(3, Some(Duration::from_secs(0)))
+                .unwrap(),
+            3
+        );
+    }
+    assert_eq!(ALLOCATIONS.with(|a| a.get()), before);
+}